@@ -0,0 +1,82 @@
+//! Throughput of receive filters compared to the frame rate of saturated CAN FD buses.
+//!
+//! Run with `cargo bench --bench filters`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use icsneo::filter::Filter;
+use icsneo::message::{CanMessage, EthMessage, Message};
+use icsneo::network::NetworkId;
+
+/// Upper bound of frames per second on one CAN FD bus, reached with empty frames at 1 Mbit/s
+/// arbitration and 8 Mbit/s data rate.
+const SATURATED_FRAMES_PER_SECOND: f64 = 30_000.0;
+
+const NETIDS: [NetworkId; 4] = [
+    NetworkId::HSCAN,
+    NetworkId::HSCAN2,
+    NetworkId::HSCAN3,
+    NetworkId::HSCAN4,
+];
+
+/// CAN FD traffic on four buses with some classic, extended, error and Ethernet frames mixed
+/// in.
+fn traffic() -> Vec<Message> {
+    (0..100_000u32)
+        .map(|i| match i % 10 {
+            9 => {
+                // IPv4 and ARP
+                let mut frame = [0; 64];
+                frame[12..14].copy_from_slice(&[0x08, (i % 20 / 10) as u8 * 6]);
+                Message::Eth(EthMessage::new(NetworkId::ETHERNET.0, &frame))
+            }
+            n => {
+                let netid = NETIDS[i as usize % NETIDS.len()].0;
+                let mut m = CanMessage::new(netid, 0x700 + i % 0x100, &[0; 64]);
+                m.set_fd(n < 6);
+                m.set_extended(n == 6);
+                m.set_error_frame(n == 7);
+                Message::Can(m)
+            }
+        })
+        .collect()
+}
+
+fn measure(name: &str, filter: &Filter, messages: &[Message]) {
+    let mut evaluated = 0u64;
+    let mut matched = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        for message in messages {
+            matched += u64::from(filter.matches(black_box(message)));
+        }
+        evaluated += messages.len() as u64;
+    }
+    let rate = evaluated as f64 / start.elapsed().as_secs_f64();
+    println!(
+        "{name:<24} {:>8.1} M messages/s, {:>6.0} saturated buses, {:.0}% matched",
+        rate / 1e6,
+        rate / SATURATED_FRAMES_PER_SECOND,
+        matched as f64 / evaluated as f64 * 100.0
+    );
+}
+
+fn main() {
+    let messages = traffic();
+    measure("netid", &Filter::netids([NetworkId::HSCAN2]), &messages);
+    measure("id/mask", &Filter::id_mask(0x7e0, 0x7f0), &messages);
+    measure(
+        "fd on two netids",
+        &Filter::netids([NetworkId::HSCAN, NetworkId::HSCAN3]).and(Filter::Fd),
+        &messages,
+    );
+    measure(
+        "composite",
+        &Filter::netids([NetworkId::HSCAN, NetworkId::HSCAN2])
+            .and(Filter::id_mask(0x700, 0x780).or(Filter::Extended))
+            .and(!Filter::ErrorFrame)
+            .and(Filter::Received),
+        &messages,
+    );
+    measure("ethertype", &Filter::EtherType(0x0800), &messages);
+}
@@ -0,0 +1,1537 @@
+//! ASAM MCD-2 MC (A2L) ECU descriptions.
+//!
+//! [Database] parses the measurements, characteristics, conversion methods, module parameters
+//! and XCP interface settings of an A2L file, so signals can be looked up by name and
+//! measured with an [XcpMaster](crate::xcp::XcpMaster):
+//! ```
+//! use icsneo::a2l::{DataType, Database};
+//!
+//! let db: Database = r#"
+//! /begin MEASUREMENT EngineSpeed "Engine speed" UWORD CM_Speed 0 0 0 8000
+//!     ECU_ADDRESS 0x40001000
+//! /end MEASUREMENT
+//! /begin COMPU_METHOD CM_Speed "" LINEAR "%6.1" "rpm"
+//!     COEFFS_LINEAR 0.25 0
+//! /end COMPU_METHOD
+//! "#
+//! .parse()
+//! .unwrap();
+//!
+//! let speed = db.measurement("EngineSpeed").unwrap();
+//! assert_eq!(speed.datatype, DataType::UWord);
+//! assert_eq!(speed.odt_entry().unwrap().address, 0x4000_1000);
+//! assert_eq!(db.physical("EngineSpeed", 10000), Some(2500.0));
+//! ```
+//! Only the first module of a project is read.
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::dbc::ByteOrder;
+use crate::native::*;
+use crate::xcp::OdtEntry;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Bit 31 of an XCP on CAN identifier marks an extended (29 bit) identifier.
+const EXTENDED_ID_FLAG: u32 = 0x8000_0000;
+
+/// Conversion method name meaning raw values are physical values.
+pub const NO_COMPU_METHOD: &str = "NO_COMPU_METHOD";
+
+/// Datatype of a value in ECU memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataType {
+    UByte,
+    SByte,
+    UWord,
+    SWord,
+    ULong,
+    SLong,
+    AUint64,
+    AInt64,
+    Float16Ieee,
+    Float32Ieee,
+    Float64Ieee,
+}
+
+impl DataType {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "UBYTE" => Self::UByte,
+            "SBYTE" => Self::SByte,
+            "UWORD" => Self::UWord,
+            "SWORD" => Self::SWord,
+            "ULONG" => Self::ULong,
+            "SLONG" => Self::SLong,
+            "A_UINT64" => Self::AUint64,
+            "A_INT64" => Self::AInt64,
+            "FLOAT16_IEEE" => Self::Float16Ieee,
+            "FLOAT32_IEEE" => Self::Float32Ieee,
+            "FLOAT64_IEEE" => Self::Float64Ieee,
+            _ => return None,
+        })
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> u8 {
+        match self {
+            Self::UByte | Self::SByte => 1,
+            Self::UWord | Self::SWord | Self::Float16Ieee => 2,
+            Self::ULong | Self::SLong | Self::Float32Ieee => 4,
+            Self::AUint64 | Self::AInt64 | Self::Float64Ieee => 8,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Self::SByte | Self::SWord | Self::SLong | Self::AInt64)
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(
+            self,
+            Self::Float16Ieee | Self::Float32Ieee | Self::Float64Ieee
+        )
+    }
+
+    /// Interprets the low [size](Self::size) bytes of `raw`.
+    pub fn to_f64(&self, raw: u64) -> f64 {
+        let bits = u32::from(self.size()) * 8;
+        match self {
+            Self::Float16Ieee => f16_to_f64(raw as u16),
+            Self::Float32Ieee => f64::from(f32::from_bits(raw as u32)),
+            Self::Float64Ieee => f64::from_bits(raw),
+            // Sign extend by shifting the sign bit to bit 63 and back.
+            _ if self.is_signed() => ((raw << (64 - bits)) as i64 >> (64 - bits)) as f64,
+            _ if bits == 64 => raw as f64,
+            _ => (raw & ((1 << bits) - 1)) as f64,
+        }
+    }
+}
+
+fn f16_to_f64(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from(bits >> 10 & 0x1f);
+    let fraction = f64::from(bits & 0x3ff);
+    sign * match exponent {
+        0 => fraction * 2f64.powi(-24),
+        0x1f if fraction == 0.0 => f64::INFINITY,
+        0x1f => f64::NAN,
+        _ => (1.0 + fraction / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+/// A value the ECU measures, read with uploads or DAQ lists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    pub long_identifier: String,
+    pub datatype: DataType,
+    /// Name of the [CompuMethod], or [NO_COMPU_METHOD].
+    pub conversion: String,
+    pub lower_limit: f64,
+    pub upper_limit: f64,
+    pub address: Option<u32>,
+    pub address_extension: u8,
+    /// `None` uses the module's [byte order](Database::byte_order).
+    pub byte_order: Option<ByteOrder>,
+    /// Number of elements of arrays, 1 for scalars.
+    pub array_size: usize,
+    pub bit_mask: Option<u64>,
+    /// Overrides the unit of the conversion method.
+    pub unit: Option<String>,
+}
+
+impl Measurement {
+    /// DAQ entry sampling the whole value, or `None` without an address or for arrays over
+    /// 8 bytes.
+    pub fn odt_entry(&self) -> Option<OdtEntry> {
+        let size = usize::from(self.datatype.size()) * self.array_size;
+        if size > 8 {
+            return None;
+        }
+        Some(OdtEntry {
+            address: self.address?,
+            extension: self.address_extension,
+            size: size as u8,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CharacteristicType {
+    Value,
+    ValBlk,
+    Ascii,
+    Curve,
+    Map,
+    Cuboid,
+    Cube4,
+    Cube5,
+}
+
+/// An adjustable value, written with downloads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Characteristic {
+    pub name: String,
+    pub long_identifier: String,
+    pub kind: CharacteristicType,
+    pub address: u32,
+    /// Name of the record layout describing the memory layout.
+    pub record_layout: String,
+    /// Datatype of the values from the record layout, if it's known.
+    pub datatype: Option<DataType>,
+    pub max_diff: f64,
+    /// Name of the [CompuMethod], or [NO_COMPU_METHOD].
+    pub conversion: String,
+    pub lower_limit: f64,
+    pub upper_limit: f64,
+    pub address_extension: u8,
+    /// `None` uses the module's [byte order](Database::byte_order).
+    pub byte_order: Option<ByteOrder>,
+    /// Number of values of `VAL_BLK` and `ASCII` characteristics.
+    pub number: Option<usize>,
+    pub matrix_dim: Vec<usize>,
+    pub bit_mask: Option<u64>,
+    pub unit: Option<String>,
+}
+
+/// How raw values convert to physical values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conversion {
+    Identical,
+    /// `physical = a * raw + b`
+    Linear {
+        a: f64,
+        b: f64,
+    },
+    /// `raw = (a * phys² + b * phys + c) / (d * phys² + e * phys + f)`, from `COEFFS`.
+    RationalFunction([f64; 6]),
+    /// Points of raw and physical values, ordered by raw value.
+    Table {
+        values: Vec<(f64, f64)>,
+        interpolate: bool,
+        default: Option<f64>,
+    },
+    /// Texts for raw values.
+    Verbal {
+        values: Vec<(f64, String)>,
+        default: Option<String>,
+    },
+    /// A formula of `X1`, like `X1 * 0.1 - 40`.
+    Formula(String),
+}
+
+impl Conversion {
+    /// Converts a raw value. Returns `None` for verbal tables, table values without a default
+    /// and rational functions that aren't invertible without solving a quadratic.
+    pub fn to_physical(&self, raw: f64) -> Option<f64> {
+        match self {
+            Self::Identical => Some(raw),
+            Self::Linear { a, b } => Some(a * raw + b),
+            Self::RationalFunction([a, b, c, d, e, f]) => {
+                if *a != 0.0 || *d != 0.0 {
+                    return None;
+                }
+                // raw * (e * phys + f) = b * phys + c
+                let divisor = e * raw - b;
+                (divisor != 0.0).then(|| (c - f * raw) / divisor)
+            }
+            Self::Table {
+                values,
+                interpolate,
+                default,
+            } => {
+                if !interpolate {
+                    return values
+                        .iter()
+                        .find(|(x, _)| *x == raw)
+                        .map(|(_, y)| *y)
+                        .or(*default);
+                }
+                let (first, last) = (values.first()?, values.last()?);
+                if raw <= first.0 {
+                    return Some(first.1);
+                }
+                if raw >= last.0 {
+                    return Some(last.1);
+                }
+                let upper = values.iter().position(|(x, _)| *x >= raw)?;
+                let ((x0, y0), (x1, y1)) = (values[upper - 1], values[upper]);
+                Some(y0 + (y1 - y0) * (raw - x0) / (x1 - x0))
+            }
+            Self::Verbal { .. } => None,
+            Self::Formula(formula) => evaluate(formula, raw),
+        }
+    }
+
+    /// Text of a raw value of a verbal table.
+    pub fn text(&self, raw: f64) -> Option<&str> {
+        match self {
+            Self::Verbal { values, default } => values
+                .iter()
+                .find(|(x, _)| *x == raw)
+                .map(|(_, text)| text.as_str())
+                .or(default.as_deref()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompuMethod {
+    pub name: String,
+    pub long_identifier: String,
+    /// printf style format, like `%6.2`.
+    pub format: String,
+    pub unit: String,
+    pub conversion: Conversion,
+}
+
+/// Module parameters from `MOD_PAR`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModPar {
+    pub comment: String,
+    pub version: Option<String>,
+    /// Addresses of the EPROM identifier.
+    pub addr_epk: Vec<u32>,
+    pub epk: Option<String>,
+    pub supplier: Option<String>,
+    pub customer: Option<String>,
+    pub ecu: Option<String>,
+    pub cpu_type: Option<String>,
+}
+
+/// `PROTOCOL_LAYER` of the XCP settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolLayer {
+    pub version: u16,
+    /// Timeouts T1 to T7.
+    pub timeouts: [Duration; 7],
+    pub max_cto: u16,
+    pub max_dto: u16,
+    pub byte_order: ByteOrder,
+    pub address_granularity: u8,
+}
+
+/// A DAQ event channel of the slave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XcpEvent {
+    pub name: String,
+    pub short_name: String,
+    /// Number to use for [DaqList::event](crate::xcp::DaqList::event).
+    pub channel: u16,
+    pub max_daq_lists: u8,
+    /// `None` for events that aren't cyclic.
+    pub cycle: Option<Duration>,
+    pub priority: u8,
+}
+
+/// `XCP_ON_CAN` of the XCP settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XcpOnCan {
+    pub version: u16,
+    pub broadcast_id: Option<u32>,
+    /// ID the master sends commands on.
+    pub master_id: Option<u32>,
+    /// ID the slave responds on.
+    pub slave_id: Option<u32>,
+    /// The IDs are extended (29 bit).
+    pub extended: bool,
+    pub baudrate: Option<u32>,
+    /// The slave uses CAN FD.
+    pub fd: bool,
+}
+
+/// `IF_DATA XCP` settings of the module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XcpSettings {
+    pub protocol_layer: Option<ProtocolLayer>,
+    pub events: Vec<XcpEvent>,
+    pub can: Option<XcpOnCan>,
+}
+
+impl XcpSettings {
+    /// Looks up an event by name or short name.
+    pub fn event(&self, name: &str) -> Option<&XcpEvent> {
+        self.events
+            .iter()
+            .find(|event| event.name == name || event.short_name == name)
+    }
+}
+
+/// A parsed A2L file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Database {
+    pub asap2_version: Option<(u32, u32)>,
+    pub project: String,
+    pub module: String,
+    pub mod_par: ModPar,
+    /// Default byte order from `MOD_COMMON`.
+    pub byte_order: Option<ByteOrder>,
+    pub xcp: Option<XcpSettings>,
+    measurements: Vec<Measurement>,
+    characteristics: Vec<Characteristic>,
+    compu_methods: HashMap<String, CompuMethod>,
+}
+
+impl Database {
+    /// Reads an A2L file. Files that aren't valid UTF-8 are read as Latin-1.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        match String::from_utf8(bytes) {
+            Ok(text) => text.parse(),
+            Err(e) => e
+                .into_bytes()
+                .iter()
+                .map(|&b| char::from(b))
+                .collect::<String>()
+                .parse(),
+        }
+    }
+
+    pub fn measurements(&self) -> &[Measurement] {
+        &self.measurements
+    }
+
+    pub fn measurement(&self, name: &str) -> Option<&Measurement> {
+        self.measurements.iter().find(|m| m.name == name)
+    }
+
+    pub fn characteristics(&self) -> &[Characteristic] {
+        &self.characteristics
+    }
+
+    pub fn characteristic(&self, name: &str) -> Option<&Characteristic> {
+        self.characteristics.iter().find(|c| c.name == name)
+    }
+
+    pub fn compu_method(&self, name: &str) -> Option<&CompuMethod> {
+        self.compu_methods.get(name)
+    }
+
+    /// Conversion of a measurement or characteristic. Missing conversion methods and
+    /// [NO_COMPU_METHOD] are [identical](Conversion::Identical).
+    pub fn conversion(&self, name: &str) -> Option<&Conversion> {
+        let conversion = match self.measurement(name) {
+            Some(measurement) => &measurement.conversion,
+            None => &self.characteristic(name)?.conversion,
+        };
+        Some(
+            self.compu_methods
+                .get(conversion)
+                .map_or(&Conversion::Identical, |method| &method.conversion),
+        )
+    }
+
+    /// Converts a raw value of a measurement or characteristic, as read from the ECU, to its
+    /// physical value. The bit mask and datatype are applied first.
+    pub fn physical(&self, name: &str, raw: u64) -> Option<f64> {
+        let (datatype, bit_mask) = match self.measurement(name) {
+            Some(measurement) => (measurement.datatype, measurement.bit_mask),
+            None => {
+                let characteristic = self.characteristic(name)?;
+                (characteristic.datatype?, characteristic.bit_mask)
+            }
+        };
+        let raw = match bit_mask {
+            Some(mask) if mask != 0 => (raw & mask) >> mask.trailing_zeros(),
+            _ => raw,
+        };
+        self.conversion(name)?.to_physical(datatype.to_f64(raw))
+    }
+}
+
+impl std::str::FromStr for Database {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let items = Parser::new(s)?.parse()?;
+        let mut builder = Builder::default();
+        builder.visit(&items)?;
+        builder.finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Begin(String),
+    End(String),
+}
+
+/// Splits A2L text into tokens with their line numbers.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        let start_line = line;
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if previous == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            previous = c;
+                        }
+                        None => {
+                            return Err(Error::ParseError(format!(
+                                "A2L line {start_line}: unterminated comment"
+                            )))
+                        }
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        // A doubled quote is an escaped quote.
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            s.push('"');
+                        }
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c) => s.push(c),
+                            None => break,
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            s.push(c);
+                        }
+                        None => {
+                            return Err(Error::ParseError(format!(
+                                "A2L line {start_line}: unterminated string"
+                            )))
+                        }
+                    }
+                }
+                tokens.push((start_line, Token::Str(s)));
+            }
+            c => {
+                let mut s = String::from(c);
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '"') {
+                    s.push(c);
+                }
+                let token = match s.as_str() {
+                    "/begin" | "/end" => {
+                        let keyword = loop {
+                            match chars.next() {
+                                Some('\n') => line += 1,
+                                Some(c) if c.is_whitespace() => {}
+                                Some(c) => break c,
+                                None => {
+                                    return Err(Error::ParseError(format!(
+                                        "A2L line {start_line}: {s} without keyword"
+                                    )))
+                                }
+                            }
+                        };
+                        let mut keyword = String::from(keyword);
+                        while let Some(c) = chars.next_if(|&c| !c.is_whitespace()) {
+                            keyword.push(c);
+                        }
+                        match s.as_str() {
+                            "/begin" => Token::Begin(keyword),
+                            _ => Token::End(keyword),
+                        }
+                    }
+                    _ => Token::Word(s),
+                };
+                tokens.push((start_line, token));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A `/begin` ... `/end` block.
+#[derive(Debug, Clone, PartialEq)]
+struct Block {
+    keyword: String,
+    line: usize,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    Word(String),
+    Str(String),
+    Block(Block),
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<(usize, Token)>,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(text)?.into_iter(),
+        })
+    }
+
+    fn parse(mut self) -> Result<Vec<Item>> {
+        self.items(None)
+    }
+
+    /// Items up to the `/end` of `block`, or to the end of the text.
+    fn items(&mut self, block: Option<(&str, usize)>) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        while let Some((line, token)) = self.tokens.next() {
+            items.push(match token {
+                Token::Word(s) => Item::Word(s),
+                Token::Str(s) => Item::Str(s),
+                Token::Begin(keyword) => {
+                    let items = self.items(Some((&keyword, line)))?;
+                    Item::Block(Block {
+                        keyword,
+                        line,
+                        items,
+                    })
+                }
+                Token::End(keyword) => match block {
+                    Some((name, _)) if name == keyword => return Ok(items),
+                    Some((name, _)) => {
+                        return Err(Error::ParseError(format!(
+                            "A2L line {line}: /end {keyword} in {name}"
+                        )))
+                    }
+                    None => {
+                        return Err(Error::ParseError(format!(
+                            "A2L line {line}: /end {keyword} without /begin"
+                        )))
+                    }
+                },
+            });
+        }
+        match block {
+            Some((name, line)) => Err(Error::ParseError(format!(
+                "A2L line {line}: {name} without /end"
+            ))),
+            None => Ok(items),
+        }
+    }
+}
+
+/// Reads the parameters of a block in order.
+struct Fields<'a> {
+    block: &'a Block,
+    items: std::slice::Iter<'a, Item>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(block: &'a Block) -> Self {
+        Self {
+            block,
+            items: block.items.iter(),
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a Item> {
+        self.items.next()
+    }
+
+    fn word(&mut self) -> Result<&'a str> {
+        match self.next() {
+            Some(Item::Word(s)) => Ok(s),
+            _ => Err(self.error("expected identifier")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Item::Str(s)) => Ok(s.clone()),
+            _ => Err(self.error("expected string")),
+        }
+    }
+
+    fn uint<T: TryFrom<u64>>(&mut self) -> Result<T> {
+        let word = self.word()?;
+        parse_uint(word)
+            .and_then(|n| T::try_from(n).ok())
+            .ok_or_else(|| self.error(&format!("invalid number {word}")))
+    }
+
+    fn float(&mut self) -> Result<f64> {
+        let word = self.word()?;
+        parse_float(word).ok_or_else(|| self.error(&format!("invalid number {word}")))
+    }
+
+    fn byte_order(&mut self) -> Result<ByteOrder> {
+        match self.word()? {
+            "MSB_LAST" | "LITTLE_ENDIAN" | "BYTE_ORDER_MSB_LAST" => Ok(ByteOrder::LittleEndian),
+            "MSB_FIRST" | "BIG_ENDIAN" | "BYTE_ORDER_MSB_FIRST" => Ok(ByteOrder::BigEndian),
+            word => Err(self.error(&format!("invalid byte order {word}"))),
+        }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let name = match self.block.items.first() {
+            Some(Item::Word(name)) => format!(" {name}"),
+            _ => String::new(),
+        };
+        Error::ParseError(format!(
+            "A2L line {}: {}{name}: {message}",
+            self.block.line, self.block.keyword
+        ))
+    }
+}
+
+fn parse_uint(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_float(s: &str) -> Option<f64> {
+    match s.strip_prefix('-') {
+        Some(rest) if rest.starts_with("0x") || rest.starts_with("0X") => {
+            parse_uint(rest).map(|n| -(n as f64))
+        }
+        _ => parse_uint(s).map(|n| n as f64).or_else(|| s.parse().ok()),
+    }
+}
+
+/// Collects the definitions of the parsed blocks.
+#[derive(Default)]
+struct Builder {
+    database: Database,
+    /// Module blocks seen, only the first is read.
+    modules: usize,
+    /// `COMPU_TAB` and `COMPU_VTAB` tables by name.
+    tables: HashMap<String, Conversion>,
+    /// Conversion methods referencing tables, by table name.
+    table_references: Vec<(String, String)>,
+    /// Function value datatypes of record layouts.
+    record_layouts: HashMap<String, DataType>,
+}
+
+impl Builder {
+    fn visit(&mut self, items: &[Item]) -> Result<()> {
+        let mut words = items.iter();
+        while let Some(item) = words.next() {
+            let block = match item {
+                Item::Word(word) if word == "ASAP2_VERSION" => {
+                    let version = (words.next(), words.next());
+                    if let (Some(Item::Word(major)), Some(Item::Word(minor))) = version {
+                        self.database.asap2_version = major.parse().ok().zip(minor.parse().ok());
+                    }
+                    continue;
+                }
+                Item::Block(block) => block,
+                _ => continue,
+            };
+            let mut fields = Fields::new(block);
+            match block.keyword.as_str() {
+                "PROJECT" => {
+                    self.database.project = fields.word()?.to_string();
+                    self.visit(&block.items)?;
+                }
+                "MODULE" => {
+                    self.modules += 1;
+                    if self.modules == 1 {
+                        self.database.module = fields.word()?.to_string();
+                        self.visit(&block.items)?;
+                    }
+                }
+                "MOD_PAR" => self.mod_par(fields)?,
+                "MOD_COMMON" => {
+                    fields.string()?;
+                    while let Some(item) = fields.next() {
+                        if matches!(item, Item::Word(w) if w == "BYTE_ORDER") {
+                            self.database.byte_order = Some(fields.byte_order()?);
+                        }
+                    }
+                }
+                "MEASUREMENT" => {
+                    let measurement = measurement(fields)?;
+                    self.database.measurements.push(measurement);
+                }
+                "CHARACTERISTIC" => {
+                    let characteristic = characteristic(fields)?;
+                    self.database.characteristics.push(characteristic);
+                }
+                "COMPU_METHOD" => self.compu_method(fields)?,
+                "COMPU_TAB" | "COMPU_VTAB" => self.conversion_table(fields)?,
+                "RECORD_LAYOUT" => {
+                    let name = fields.word()?.to_string();
+                    while let Some(item) = fields.next() {
+                        if matches!(item, Item::Word(w) if w == "FNC_VALUES") {
+                            let _position: u64 = fields.uint()?;
+                            let datatype = fields.word()?;
+                            let datatype = DataType::from_name(datatype).ok_or_else(|| {
+                                fields.error(&format!("invalid datatype {datatype}"))
+                            })?;
+                            self.record_layouts.insert(name.clone(), datatype);
+                        }
+                    }
+                }
+                "IF_DATA" if matches!(block.items.first(), Some(Item::Word(w)) if w == "XCP" || w == "XCPplus") =>
+                {
+                    fields.word()?;
+                    self.database.xcp = Some(xcp(fields)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn mod_par(&mut self, mut fields: Fields) -> Result<()> {
+        let mod_par = &mut self.database.mod_par;
+        mod_par.comment = fields.string()?;
+        while let Some(item) = fields.next() {
+            let Item::Word(keyword) = item else { continue };
+            match keyword.as_str() {
+                "VERSION" => mod_par.version = Some(fields.string()?),
+                "ADDR_EPK" => mod_par.addr_epk.push(fields.uint()?),
+                "EPK" => mod_par.epk = Some(fields.string()?),
+                "SUPPLIER" => mod_par.supplier = Some(fields.string()?),
+                "CUSTOMER" => mod_par.customer = Some(fields.string()?),
+                "ECU" => mod_par.ecu = Some(fields.string()?),
+                "CPU_TYPE" => mod_par.cpu_type = Some(fields.string()?),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn compu_method(&mut self, mut fields: Fields) -> Result<()> {
+        let name = fields.word()?.to_string();
+        let long_identifier = fields.string()?;
+        let kind = fields.word()?;
+        let format = fields.string()?;
+        let unit = fields.string()?;
+        let mut conversion = match kind {
+            "IDENTICAL" | "TAB_INTP" | "TAB_NOINTP" | "TAB_VERB" => Conversion::Identical,
+            "LINEAR" => Conversion::Linear { a: 1.0, b: 0.0 },
+            "RAT_FUNC" => Conversion::RationalFunction([0.0, 1.0, 0.0, 0.0, 0.0, 1.0]),
+            "FORM" => Conversion::Formula(String::new()),
+            kind => return Err(fields.error(&format!("invalid conversion type {kind}"))),
+        };
+        while let Some(item) = fields.next() {
+            match item {
+                Item::Word(keyword) if keyword == "COEFFS_LINEAR" => {
+                    conversion = Conversion::Linear {
+                        a: fields.float()?,
+                        b: fields.float()?,
+                    };
+                }
+                Item::Word(keyword) if keyword == "COEFFS" => {
+                    let mut coefficients = [0.0; 6];
+                    for coefficient in &mut coefficients {
+                        *coefficient = fields.float()?;
+                    }
+                    conversion = Conversion::RationalFunction(coefficients);
+                }
+                Item::Word(keyword) if keyword == "COMPU_TAB_REF" => {
+                    let table = fields.word()?.to_string();
+                    self.table_references.push((name.clone(), table));
+                }
+                Item::Block(formula) if formula.keyword == "FORMULA" => {
+                    conversion = Conversion::Formula(Fields::new(formula).string()?);
+                }
+                _ => {}
+            }
+        }
+        self.database.compu_methods.insert(
+            name.clone(),
+            CompuMethod {
+                name,
+                long_identifier,
+                format,
+                unit,
+                conversion,
+            },
+        );
+        Ok(())
+    }
+
+    fn conversion_table(&mut self, mut fields: Fields) -> Result<()> {
+        let verbal = fields.block.keyword == "COMPU_VTAB";
+        let name = fields.word()?.to_string();
+        let _long_identifier = fields.string()?;
+        let interpolate = match fields.word()? {
+            "TAB_INTP" => true,
+            "TAB_NOINTP" | "TAB_VERB" => false,
+            kind => return Err(fields.error(&format!("invalid conversion type {kind}"))),
+        };
+        let count: usize = fields.uint()?;
+        let mut numeric = Vec::with_capacity(count);
+        let mut texts = Vec::with_capacity(count);
+        for _ in 0..count {
+            let raw = fields.float()?;
+            match verbal {
+                true => texts.push((raw, fields.string()?)),
+                false => numeric.push((raw, fields.float()?)),
+            }
+        }
+        let mut default_text = None;
+        let mut default_value = None;
+        while let Some(item) = fields.next() {
+            match item {
+                Item::Word(keyword) if keyword == "DEFAULT_VALUE" => {
+                    default_text = Some(fields.string()?);
+                }
+                Item::Word(keyword) if keyword == "DEFAULT_VALUE_NUMERIC" => {
+                    default_value = Some(fields.float()?);
+                }
+                _ => {}
+            }
+        }
+        let table = match verbal {
+            true => Conversion::Verbal {
+                values: texts,
+                default: default_text,
+            },
+            false => {
+                numeric.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Conversion::Table {
+                    values: numeric,
+                    interpolate,
+                    default: default_value,
+                }
+            }
+        };
+        self.tables.insert(name, table);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Database> {
+        for (method, table) in self.table_references {
+            let conversion = match self.tables.get(&table) {
+                Some(conversion) => conversion.clone(),
+                None => {
+                    return Err(Error::ParseError(format!(
+                        "A2L: COMPU_METHOD {method} references unknown table {table}"
+                    )))
+                }
+            };
+            if let Some(method) = self.database.compu_methods.get_mut(&method) {
+                method.conversion = conversion;
+            }
+        }
+        for characteristic in &mut self.database.characteristics {
+            characteristic.datatype = self
+                .record_layouts
+                .get(&characteristic.record_layout)
+                .copied();
+        }
+        Ok(self.database)
+    }
+}
+
+fn measurement(mut fields: Fields) -> Result<Measurement> {
+    let name = fields.word()?.to_string();
+    let long_identifier = fields.string()?;
+    let datatype = fields.word()?;
+    let datatype = DataType::from_name(datatype)
+        .ok_or_else(|| fields.error(&format!("invalid datatype {datatype}")))?;
+    let conversion = fields.word()?.to_string();
+    let _resolution = fields.float()?;
+    let _accuracy = fields.float()?;
+    let mut measurement = Measurement {
+        name,
+        long_identifier,
+        datatype,
+        conversion,
+        lower_limit: fields.float()?,
+        upper_limit: fields.float()?,
+        address: None,
+        address_extension: 0,
+        byte_order: None,
+        array_size: 1,
+        bit_mask: None,
+        unit: None,
+    };
+    while let Some(item) = fields.next() {
+        let Item::Word(keyword) = item else { continue };
+        match keyword.as_str() {
+            "ECU_ADDRESS" => measurement.address = Some(fields.uint()?),
+            "ECU_ADDRESS_EXTENSION" => measurement.address_extension = fields.uint()?,
+            "BYTE_ORDER" => measurement.byte_order = Some(fields.byte_order()?),
+            "ARRAY_SIZE" => measurement.array_size = fields.uint()?,
+            "BIT_MASK" => measurement.bit_mask = Some(fields.uint()?),
+            "PHYS_UNIT" => measurement.unit = Some(fields.string()?),
+            "MATRIX_DIM" => {
+                // One to three dimensions, as many numbers as follow.
+                let mut size = 1usize;
+                while let Some(Item::Word(word)) = fields.items.clone().next() {
+                    let Some(dimension) = parse_uint(word) else {
+                        break;
+                    };
+                    fields.next();
+                    size = usize::try_from(dimension.max(1))
+                        .ok()
+                        .and_then(|dimension| size.checked_mul(dimension))
+                        .ok_or_else(|| fields.error("MATRIX_DIM overflows"))?;
+                }
+                measurement.array_size = size;
+            }
+            _ => {}
+        }
+    }
+    Ok(measurement)
+}
+
+fn characteristic(mut fields: Fields) -> Result<Characteristic> {
+    let name = fields.word()?.to_string();
+    let long_identifier = fields.string()?;
+    let kind = match fields.word()? {
+        "VALUE" => CharacteristicType::Value,
+        "VAL_BLK" => CharacteristicType::ValBlk,
+        "ASCII" => CharacteristicType::Ascii,
+        "CURVE" => CharacteristicType::Curve,
+        "MAP" => CharacteristicType::Map,
+        "CUBOID" => CharacteristicType::Cuboid,
+        "CUBE_4" => CharacteristicType::Cube4,
+        "CUBE_5" => CharacteristicType::Cube5,
+        kind => return Err(fields.error(&format!("invalid characteristic type {kind}"))),
+    };
+    let mut characteristic = Characteristic {
+        name,
+        long_identifier,
+        kind,
+        address: fields.uint()?,
+        record_layout: fields.word()?.to_string(),
+        datatype: None,
+        max_diff: fields.float()?,
+        conversion: fields.word()?.to_string(),
+        lower_limit: fields.float()?,
+        upper_limit: fields.float()?,
+        address_extension: 0,
+        byte_order: None,
+        number: None,
+        matrix_dim: Vec::new(),
+        bit_mask: None,
+        unit: None,
+    };
+    while let Some(item) = fields.next() {
+        let Item::Word(keyword) = item else { continue };
+        match keyword.as_str() {
+            "ECU_ADDRESS_EXTENSION" => characteristic.address_extension = fields.uint()?,
+            "BYTE_ORDER" => characteristic.byte_order = Some(fields.byte_order()?),
+            "NUMBER" => characteristic.number = Some(fields.uint()?),
+            "BIT_MASK" => characteristic.bit_mask = Some(fields.uint()?),
+            "PHYS_UNIT" => characteristic.unit = Some(fields.string()?),
+            "MATRIX_DIM" => {
+                while let Some(Item::Word(word)) = fields.items.clone().next() {
+                    let Some(dimension) = parse_uint(word) else {
+                        break;
+                    };
+                    fields.next();
+                    let dimension = usize::try_from(dimension)
+                        .map_err(|_| fields.error(&format!("invalid dimension {dimension}")))?;
+                    characteristic.matrix_dim.push(dimension);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(characteristic)
+}
+
+fn xcp(mut fields: Fields) -> Result<XcpSettings> {
+    let mut settings = XcpSettings::default();
+    while let Some(item) = fields.next() {
+        let Item::Block(block) = item else { continue };
+        match block.keyword.as_str() {
+            "PROTOCOL_LAYER" => settings.protocol_layer = Some(protocol_layer(Fields::new(block))?),
+            "DAQ" => {
+                for item in &block.items {
+                    if let Item::Block(event) = item {
+                        if event.keyword == "EVENT" {
+                            settings.events.push(xcp_event(Fields::new(event))?);
+                        }
+                    }
+                }
+            }
+            "XCP_ON_CAN" => settings.can = Some(xcp_on_can(Fields::new(block))?),
+            _ => {}
+        }
+    }
+    Ok(settings)
+}
+
+fn protocol_layer(mut fields: Fields) -> Result<ProtocolLayer> {
+    let version = fields.uint()?;
+    let mut timeouts = [Duration::ZERO; 7];
+    for timeout in &mut timeouts {
+        *timeout = Duration::from_millis(fields.uint()?);
+    }
+    Ok(ProtocolLayer {
+        version,
+        timeouts,
+        max_cto: fields.uint()?,
+        max_dto: fields.uint()?,
+        byte_order: fields.byte_order()?,
+        address_granularity: match fields.word()? {
+            "ADDRESS_GRANULARITY_BYTE" => 1,
+            "ADDRESS_GRANULARITY_WORD" => 2,
+            "ADDRESS_GRANULARITY_DWORD" => 4,
+            word => return Err(fields.error(&format!("invalid address granularity {word}"))),
+        },
+    })
+}
+
+fn xcp_event(mut fields: Fields) -> Result<XcpEvent> {
+    let name = fields.string()?;
+    let short_name = fields.string()?;
+    let channel = fields.uint()?;
+    let _direction = fields.word()?;
+    let max_daq_lists = fields.uint()?;
+    let cycle: u64 = fields.uint()?;
+    let unit: u32 = fields.uint()?;
+    let priority = fields.uint()?;
+    // Units are 1 ns times a power of ten.
+    let cycle_ns = cycle
+        .checked_mul(10u64.pow(unit.min(9)))
+        .ok_or_else(|| fields.error(&format!("cycle {cycle} overflows")))?;
+    Ok(XcpEvent {
+        name,
+        short_name,
+        channel,
+        max_daq_lists,
+        cycle: (cycle != 0).then(|| Duration::from_nanos(cycle_ns)),
+        priority,
+    })
+}
+
+fn xcp_on_can(mut fields: Fields) -> Result<XcpOnCan> {
+    let mut can = XcpOnCan {
+        version: fields.uint()?,
+        broadcast_id: None,
+        master_id: None,
+        slave_id: None,
+        extended: false,
+        baudrate: None,
+        fd: false,
+    };
+    while let Some(item) = fields.next() {
+        match item {
+            Item::Word(keyword) => match keyword.as_str() {
+                "CAN_ID_BROADCAST" => can.broadcast_id = Some(can_id(&mut fields, &mut can)?),
+                "CAN_ID_MASTER" => can.master_id = Some(can_id(&mut fields, &mut can)?),
+                "CAN_ID_SLAVE" => can.slave_id = Some(can_id(&mut fields, &mut can)?),
+                "BAUDRATE" => can.baudrate = Some(fields.uint()?),
+                _ => {}
+            },
+            Item::Block(block) if block.keyword == "CAN_FD" => can.fd = true,
+            _ => {}
+        }
+    }
+    Ok(can)
+}
+
+/// Reads an identifier, setting `extended` if it has the extended flag.
+fn can_id(fields: &mut Fields, can: &mut XcpOnCan) -> Result<u32> {
+    let id: u32 = fields.uint()?;
+    can.extended |= id & EXTENDED_ID_FLAG != 0;
+    Ok(id & !EXTENDED_ID_FLAG)
+}
+
+/// Evaluates a `FORMULA` of `X1` (or `X`) with `+ - * /`, parentheses and the functions
+/// `abs`, `sqrt`, `exp`, `log` (natural), `log10`, `sin`, `cos` and `tan`.
+fn evaluate(formula: &str, x: f64) -> Option<f64> {
+    let mut evaluator = Evaluator {
+        chars: formula.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+        x,
+    };
+    let value = evaluator.sum()?;
+    (evaluator.position == evaluator.chars.len()).then_some(value)
+}
+
+struct Evaluator {
+    chars: Vec<char>,
+    position: usize,
+    x: f64,
+}
+
+impl Evaluator {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn sum(&mut self) -> Option<f64> {
+        let mut value = self.product()?;
+        while let Some(c @ ('+' | '-')) = self.peek() {
+            self.position += 1;
+            let operand = self.product()?;
+            value = if c == '+' {
+                value + operand
+            } else {
+                value - operand
+            };
+        }
+        Some(value)
+    }
+
+    fn product(&mut self) -> Option<f64> {
+        let mut value = self.factor()?;
+        while let Some(c @ ('*' | '/')) = self.peek() {
+            self.position += 1;
+            let operand = self.factor()?;
+            value = if c == '*' {
+                value * operand
+            } else {
+                value / operand
+            };
+        }
+        Some(value)
+    }
+
+    fn factor(&mut self) -> Option<f64> {
+        match self.peek()? {
+            '-' => {
+                self.position += 1;
+                Some(-self.factor()?)
+            }
+            '+' => {
+                self.position += 1;
+                self.factor()
+            }
+            '(' => {
+                self.position += 1;
+                let value = self.sum()?;
+                (self.peek()? == ')').then_some(())?;
+                self.position += 1;
+                Some(value)
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let start = self.position;
+                self.position += 1;
+                while let Some(c) = self.peek() {
+                    let exponent_sign = (c == '-' || c == '+')
+                        && matches!(self.chars[self.position - 1], 'e' | 'E')
+                        && !self.chars[start..self.position].starts_with(&['0', 'x']);
+                    if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                        self.position += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                parse_float(&number)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    self.position += 1;
+                }
+                let name: String = self.chars[start..self.position].iter().collect();
+                match name.as_str() {
+                    "X" | "X1" | "x" | "x1" => Some(self.x),
+                    function => {
+                        (self.peek()? == '(').then_some(())?;
+                        let argument = self.factor()?;
+                        Some(match function {
+                            "abs" => argument.abs(),
+                            "sqrt" => argument.sqrt(),
+                            "exp" => argument.exp(),
+                            "log" => argument.ln(),
+                            "log10" => argument.log10(),
+                            "sin" => argument.sin(),
+                            "cos" => argument.cos(),
+                            "tan" => argument.tan(),
+                            _ => return None,
+                        })
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Database {
+        Database::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/testdata/engine.a2l"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let db = database();
+        assert_eq!(db.asap2_version, Some((1, 71)));
+        assert_eq!(db.project, "Engine");
+        assert_eq!(db.module, "ECU");
+        assert_eq!(db.byte_order, Some(ByteOrder::LittleEndian));
+        assert_eq!(
+            db.mod_par,
+            ModPar {
+                comment: "ECU parameters".to_string(),
+                version: Some("V1.2.3".to_string()),
+                addr_epk: vec![0x8000_0000],
+                epk: Some("EPK_2024_01".to_string()),
+                supplier: Some("Supplier".to_string()),
+                customer: Some("Customer".to_string()),
+                ecu: Some("EMS".to_string()),
+                cpu_type: Some("TC397".to_string()),
+            }
+        );
+
+        assert_eq!(db.measurements().len(), 6);
+        assert!(db.measurement("Ignored").is_none());
+        let speed = db.measurement("EngineSpeed").unwrap();
+        assert_eq!(speed.long_identifier, "Engine speed");
+        assert_eq!(speed.datatype, DataType::UWord);
+        assert_eq!(speed.conversion, "CM_Speed");
+        assert_eq!((speed.lower_limit, speed.upper_limit), (0.0, 16383.75));
+        assert_eq!(speed.address, Some(0x5000_1000));
+        assert_eq!(speed.byte_order, None);
+        let temp = db.measurement("CoolantTemp").unwrap();
+        assert_eq!(temp.address_extension, 1);
+        assert_eq!(temp.byte_order, Some(ByteOrder::BigEndian));
+        assert_eq!(temp.lower_limit, -40.0);
+        assert_eq!(db.measurement("Gear").unwrap().bit_mask, Some(0xf0));
+        assert_eq!(db.measurement("Lambda").unwrap().array_size, 2);
+        assert_eq!(db.measurement("Samples").unwrap().array_size, 8);
+        assert_eq!(db.measurement("Load").unwrap().unit.as_deref(), Some("%"));
+
+        assert_eq!(db.characteristics().len(), 3);
+        let idle = db.characteristic("IdleSpeed").unwrap();
+        assert_eq!(idle.kind, CharacteristicType::Value);
+        assert_eq!(idle.address, 0x5000_2000);
+        assert_eq!(idle.record_layout, "RL_SWORD");
+        assert_eq!(idle.datatype, Some(DataType::SWord));
+        assert_eq!(idle.conversion, "CM_Speed");
+        let thresholds = db.characteristic("Thresholds").unwrap();
+        assert_eq!(thresholds.kind, CharacteristicType::ValBlk);
+        assert_eq!(thresholds.number, Some(4));
+        assert_eq!(thresholds.address_extension, 2);
+        assert_eq!(thresholds.datatype, Some(DataType::UByte));
+        let gains = db.characteristic("Gains").unwrap();
+        assert_eq!(gains.matrix_dim, [4, 3]);
+        assert_eq!(gains.datatype, None);
+
+        let method = db.compu_method("CM_Speed").unwrap();
+        assert_eq!(
+            (method.format.as_str(), method.unit.as_str()),
+            ("%8.2", "rpm")
+        );
+        assert_eq!(method.conversion, Conversion::Linear { a: 0.25, b: 0.0 });
+    }
+
+    #[test]
+    fn test_xcp_settings() {
+        let xcp = database().xcp.unwrap();
+        let protocol = xcp.protocol_layer.clone().unwrap();
+        assert_eq!(protocol.version, 0x104);
+        assert_eq!(protocol.timeouts[0], Duration::from_secs(1));
+        assert_eq!(protocol.timeouts[1], Duration::from_secs(2));
+        assert_eq!((protocol.max_cto, protocol.max_dto), (8, 8));
+        assert_eq!(protocol.byte_order, ByteOrder::LittleEndian);
+        assert_eq!(protocol.address_granularity, 1);
+
+        assert_eq!(xcp.events.len(), 2);
+        let task = xcp.event("10ms").unwrap();
+        assert_eq!(task.name, "10ms task");
+        assert_eq!(task.channel, 0);
+        assert_eq!(task.cycle, Some(Duration::from_millis(10)));
+        let crank = xcp.event("Crank angle").unwrap();
+        assert_eq!((crank.channel, crank.cycle, crank.priority), (1, None, 1));
+        assert!(xcp.event("100ms").is_none());
+
+        assert_eq!(
+            xcp.can,
+            Some(XcpOnCan {
+                version: 0x104,
+                broadcast_id: Some(0x100),
+                master_id: Some(0x101),
+                slave_id: Some(0x102),
+                extended: true,
+                baudrate: Some(500_000),
+                fd: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_physical() {
+        let db = database();
+        assert_eq!(db.physical("EngineSpeed", 10000), Some(2500.0));
+        // RAT_FUNC: raw = phys + 40
+        assert_eq!(db.physical("CoolantTemp", 130), Some(90.0));
+        assert_eq!(db.physical("Lambda", 4096), Some(2.0));
+        // -4096 as SWORD
+        assert_eq!(db.physical("Lambda", 0xf000), Some(0.0));
+        assert_eq!(db.physical("Load", 42.5f32.to_bits().into()), Some(42.5));
+        // Characteristics use the datatype of their record layout.
+        assert_eq!(db.physical("IdleSpeed", 0xfffc), Some(-1.0));
+        assert_eq!(db.physical("Thresholds", 7), Some(7.0));
+        assert_eq!(db.physical("Gains", 7), None);
+        assert_eq!(db.physical("Unknown", 7), None);
+
+        let gear = db.conversion("Gear").unwrap();
+        assert_eq!(gear.text(1.0), Some("First"));
+        assert_eq!(gear.text(9.0), Some("Invalid"));
+        assert_eq!(gear.to_physical(1.0), None);
+        // Verbal tables have no physical values.
+        assert_eq!(db.physical("Gear", 0x20), None);
+        assert_eq!(db.conversion("Samples"), Some(&Conversion::Identical));
+    }
+
+    #[test]
+    fn test_conversions() {
+        let db = database();
+        let pedal = &db.compu_method("CM_Pedal").unwrap().conversion;
+        // Points are sorted by raw value.
+        assert_eq!(pedal.to_physical(0.0), Some(0.0));
+        assert_eq!(pedal.to_physical(50.0), Some(12.5));
+        assert_eq!(pedal.to_physical(150.0), Some(62.5));
+        assert_eq!(pedal.to_physical(300.0), Some(100.0));
+
+        let table = Conversion::Table {
+            values: vec![(1.0, 10.0), (2.0, 20.0)],
+            interpolate: false,
+            default: Some(-1.0),
+        };
+        assert_eq!(table.to_physical(2.0), Some(20.0));
+        assert_eq!(table.to_physical(1.5), Some(-1.0));
+
+        // raw = (2 * phys + 4) / (phys + 1), so phys = (4 - raw) / (raw - 2)
+        let rational = Conversion::RationalFunction([0.0, 2.0, 4.0, 0.0, 1.0, 1.0]);
+        assert_eq!(rational.to_physical(3.0), Some(1.0));
+        assert_eq!(rational.to_physical(2.0), None);
+        let quadratic = Conversion::RationalFunction([1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(quadratic.to_physical(4.0), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("X1", 3.0), Some(3.0));
+        assert_eq!(evaluate("x * 0.1 - 40", 500.0), Some(10.0));
+        assert_eq!(evaluate("-X1 + 2 * 3", 1.0), Some(5.0));
+        assert_eq!(evaluate("(X + 1) / 2", 3.0), Some(2.0));
+        assert_eq!(evaluate("1e-3 * X", 2000.0), Some(2.0));
+        assert_eq!(evaluate("0x10 + X", 1.0), Some(17.0));
+        assert_eq!(evaluate("sqrt(X1) + abs(-1)", 16.0), Some(5.0));
+        assert_eq!(evaluate("log10(X)", 100.0), Some(2.0));
+        assert_eq!(evaluate("X1 >> 2", 16.0), None);
+        assert_eq!(evaluate("unknown(X)", 1.0), None);
+        assert_eq!(evaluate("(X", 1.0), None);
+        assert_eq!(evaluate("", 1.0), None);
+    }
+
+    #[test]
+    fn test_datatypes() {
+        assert_eq!(DataType::UByte.to_f64(0x1ff), 255.0);
+        assert_eq!(DataType::SByte.to_f64(0xff), -1.0);
+        assert_eq!(DataType::SWord.to_f64(0x8000), -32768.0);
+        assert_eq!(DataType::ULong.to_f64(0xffff_ffff), 4294967295.0);
+        assert_eq!(DataType::SLong.to_f64(0xffff_fffe), -2.0);
+        assert_eq!(DataType::AInt64.to_f64(u64::MAX), -1.0);
+        assert_eq!(DataType::AUint64.to_f64(1 << 63), 9223372036854775808.0);
+        assert_eq!(DataType::Float16Ieee.to_f64(0x3c00), 1.0);
+        assert_eq!(DataType::Float16Ieee.to_f64(0xc100), -2.5);
+        assert_eq!(DataType::Float16Ieee.to_f64(0x0001), 2f64.powi(-24));
+        assert_eq!(DataType::Float16Ieee.to_f64(0x7c00), f64::INFINITY);
+        assert_eq!(DataType::Float64Ieee.to_f64(1.5f64.to_bits()), 1.5);
+        assert_eq!(DataType::Float32Ieee.size(), 4);
+        assert!(DataType::SWord.is_signed() && !DataType::UWord.is_signed());
+        assert!(DataType::Float16Ieee.is_float());
+    }
+
+    #[test]
+    fn test_odt_entries() {
+        let db = database();
+        assert_eq!(
+            db.measurement("CoolantTemp").unwrap().odt_entry(),
+            Some(OdtEntry {
+                address: 0x5000_1002,
+                extension: 1,
+                size: 1,
+            })
+        );
+        assert_eq!(
+            db.measurement("Lambda").unwrap().odt_entry().unwrap().size,
+            4
+        );
+        // 16 bytes don't fit one entry.
+        assert_eq!(db.measurement("Samples").unwrap().odt_entry(), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match text.parse::<Database>() {
+            Err(Error::ParseError(message)) => message,
+            result => panic!("expected a parse error, got {result:?}"),
+        };
+        assert_eq!(
+            error("/begin MODULE m \"\"\n/end PROJECT"),
+            "A2L line 2: /end PROJECT in MODULE"
+        );
+        assert_eq!(
+            error("\n/begin MODULE m \"\""),
+            "A2L line 2: MODULE without /end"
+        );
+        assert_eq!(
+            error("/end MODULE"),
+            "A2L line 1: /end MODULE without /begin"
+        );
+        assert_eq!(error("\"text"), "A2L line 1: unterminated string");
+        assert_eq!(error("/* comment"), "A2L line 1: unterminated comment");
+        assert_eq!(
+            error("/begin MEASUREMENT m \"\" UINT NO_COMPU_METHOD 0 0 0 1 /end MEASUREMENT"),
+            "A2L line 1: MEASUREMENT m: invalid datatype UINT"
+        );
+        assert_eq!(
+            error("\n\n/begin MEASUREMENT m \"\" UBYTE NO_COMPU_METHOD 0 0 0\n/end MEASUREMENT"),
+            "A2L line 3: MEASUREMENT m: expected identifier"
+        );
+        assert_eq!(
+            error(
+                "/begin COMPU_METHOD c \"\" TAB_VERB \"\" \"\" COMPU_TAB_REF t /end COMPU_METHOD"
+            ),
+            "A2L: COMPU_METHOD c references unknown table t"
+        );
+        assert_eq!(
+            error(
+                "/begin MEASUREMENT m \"\" UBYTE NO_COMPU_METHOD 0 0 0 1\n\
+                 MATRIX_DIM 4294967296 4294967296 /end MEASUREMENT"
+            ),
+            "A2L line 1: MEASUREMENT m: MATRIX_DIM overflows"
+        );
+        assert_eq!(
+            error(
+                "/begin IF_DATA XCP /begin DAQ DYNAMIC 0 1 0 OPTIMISATION_TYPE_DEFAULT\n\
+                 /begin EVENT \"e\" \"e\" 0 DAQ 1 18446744073709551615 9 0 /end EVENT\n\
+                 /end DAQ /end IF_DATA"
+            ),
+            "A2L line 2: EVENT: cycle 18446744073709551615 overflows"
+        );
+
+        // Quotes are escaped by doubling or with a backslash.
+        let db: Database = "/begin MOD_PAR \"a \"\"b\"\" \\\"c\\\"\" // comment\n/end MOD_PAR"
+            .parse()
+            .unwrap();
+        assert_eq!(db.mod_par.comment, "a \"b\" \"c\"");
+    }
+}
@@ -0,0 +1,285 @@
+//! Transmit and receive abstractions over a device.
+//!
+//! Higher level code (replay, protocols) sends through [Transmit] and receives through
+//! [Receive] instead of calling [transmit](crate::native::transmit) and
+//! [get_messages](crate::native::get_messages) directly, so it can be tested against a
+//! [MockSink] or the endpoints of a [VirtualBus].
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Something messages can be transmitted on.
+pub trait Transmit {
+    fn transmit(&mut self, message: &Message) -> Result<()>;
+
+    /// Transmits `messages` in order. The default calls [transmit](Transmit::transmit) for
+    /// each one.
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        messages
+            .iter()
+            .try_for_each(|message| self.transmit(message))
+    }
+}
+
+impl Transmit for NeoDevice {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        transmit(self, &message.to_neo_message())
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        // The data pointers borrow from `messages`, which outlives the call.
+        let messages = messages.iter().map(Message::to_neo_message).collect();
+        transmit_messages(self, messages)
+    }
+}
+
+impl<T: Transmit + ?Sized> Transmit for &mut T {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        (**self).transmit(message)
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        (**self).transmit_messages(messages)
+    }
+}
+
+/// Something messages can be received from.
+pub trait Receive {
+    /// Returns the messages received since the last call. Waits up to `timeout` if there
+    /// are none yet, returning an empty list if none arrive.
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>>;
+}
+
+/// Requires message polling, see [enable_message_polling](crate::native::enable_message_polling).
+/// Message types other than CAN, CAN error counts and Ethernet are dropped.
+impl Receive for NeoDevice {
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        let messages = get_messages(self, timeout.as_millis() as u64)?;
+        // The payloads stay valid until the next get_messages call on this device.
+        Ok(messages
+            .iter()
+            .filter_map(|message| unsafe { Message::from_neo(message) })
+            .collect())
+    }
+}
+
+impl<T: Receive + ?Sized> Receive for &mut T {
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        (**self).receive(timeout)
+    }
+}
+
+/// Records transmitted messages with the time of `clock` when they were handed over.
+#[derive(Debug)]
+pub struct MockSink<C: Clock> {
+    clock: C,
+    latency: Duration,
+    /// Transmitted messages and the time each one was transmitted at.
+    pub messages: Vec<(Duration, Message)>,
+    /// Number of [transmit_messages](Transmit::transmit_messages) calls.
+    pub batches: usize,
+}
+
+impl<C: Clock> MockSink<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            latency: Duration::ZERO,
+            messages: Vec::new(),
+            batches: 0,
+        }
+    }
+
+    /// Makes every transmit call take `latency` on the clock, like a slow driver would.
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    fn sleep(&self) {
+        if !self.latency.is_zero() {
+            self.clock.sleep_until(self.clock.now() + self.latency);
+        }
+    }
+}
+
+impl<C: Clock> Transmit for MockSink<C> {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        self.messages.push((self.clock.now(), message.clone()));
+        self.sleep();
+        Ok(())
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        let now = self.clock.now();
+        self.messages
+            .extend(messages.iter().map(|message| (now, message.clone())));
+        self.batches += 1;
+        self.sleep();
+        Ok(())
+    }
+}
+
+/// An in-memory bus connecting any number of [VirtualEndpoint]s, for testing protocols
+/// end to end without hardware.
+///
+/// A message transmitted on one endpoint is received by all others with the transmit flag
+/// cleared and a timestamp in nanoseconds since the bus was created.
+#[derive(Debug, Clone)]
+pub struct VirtualBus {
+    epoch: Instant,
+    endpoints: Arc<Mutex<Vec<Arc<Queue>>>>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: Mutex<VecDeque<Message>>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn push(&self, message: Message) {
+        self.messages.lock().unwrap().push_back(message);
+        self.ready.notify_all();
+    }
+}
+
+impl VirtualBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects a new endpoint to the bus.
+    pub fn endpoint(&self) -> VirtualEndpoint {
+        let queue = Arc::new(Queue::default());
+        self.endpoints.lock().unwrap().push(queue.clone());
+        VirtualEndpoint {
+            bus: self.clone(),
+            queue,
+            echo: false,
+        }
+    }
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            endpoints: Arc::default(),
+        }
+    }
+}
+
+/// One device on a [VirtualBus].
+#[derive(Debug)]
+pub struct VirtualEndpoint {
+    bus: VirtualBus,
+    queue: Arc<Queue>,
+    echo: bool,
+}
+
+impl VirtualEndpoint {
+    /// Also receive own transmits with the transmit flag set, like a device does. Off by
+    /// default.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+}
+
+impl Drop for VirtualEndpoint {
+    /// Disconnects the endpoint, so the bus stops queueing messages for it.
+    fn drop(&mut self) {
+        self.bus
+            .endpoints
+            .lock()
+            .unwrap()
+            .retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+    }
+}
+
+impl Transmit for VirtualEndpoint {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        let mut message = message.clone();
+        message.set_timestamp(self.bus.epoch.elapsed().as_nanos() as u64);
+        for queue in self.bus.endpoints.lock().unwrap().iter() {
+            let own = Arc::ptr_eq(queue, &self.queue);
+            if own && !self.echo {
+                continue;
+            }
+            let mut message = message.clone();
+            match &mut message {
+                Message::Can(m) => m.set_transmit(own),
+                Message::Eth(m) => m.set_transmit(own),
+                Message::CanError(_) => {}
+            }
+            queue.push(message);
+        }
+        Ok(())
+    }
+}
+
+impl Receive for VirtualEndpoint {
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        let messages = self.queue.messages.lock().unwrap();
+        let (mut messages, _) = self
+            .queue
+            .ready
+            .wait_timeout_while(messages, timeout, |messages| messages.is_empty())
+            .unwrap();
+        Ok(messages.drain(..).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_bus() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        let mut b = bus.endpoint();
+        let mut c = bus.endpoint();
+        c.set_echo(true);
+
+        let mut frame = CanMessage::new(1, 0x123, &[1, 2, 3]);
+        frame.set_transmit(true);
+        a.transmit(&Message::Can(frame)).unwrap();
+        c.transmit(&Message::Eth(EthMessage::new(93, &[0xff; 14])))
+            .unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(a.receive(timeout).unwrap().len(), 1);
+        let received = b.receive(timeout).unwrap();
+        assert_eq!(received.len(), 2);
+        let Message::Can(m) = &received[0] else {
+            panic!("{:?}", received[0])
+        };
+        assert!(!m.is_transmit());
+        assert_eq!(m.data(), &[1, 2, 3]);
+        assert!(received[1].timestamp() >= received[0].timestamp());
+        let received = c.receive(timeout).unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(matches!(&received[1], Message::Eth(m) if m.is_transmit()));
+
+        // Waits for messages from another thread, or returns empty after the timeout.
+        assert!(b.receive(timeout).unwrap().is_empty());
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            a.transmit(&Message::Can(CanMessage::new(1, 0x1, &[])))
+                .unwrap();
+        });
+        assert_eq!(b.receive(Duration::from_secs(5)).unwrap().len(), 1);
+        sender.join().unwrap();
+
+        // Dropped endpoints are disconnected.
+        assert_eq!(bus.endpoints.lock().unwrap().len(), 2);
+        drop(c);
+        assert_eq!(bus.endpoints.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,856 @@
+//! CANopen (CiA 301) master: NMT, heartbeat and node guarding, SDO client and PDO
+//! decoding.
+//!
+//! [CanOpenMaster] talks to the nodes of one CAN network. SDO transfers block until the
+//! node answered, everything else received meanwhile is kept for [poll](CanOpenMaster::poll):
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::canopen::{CanOpenEvent, CanOpenMaster, NmtCommand};
+//! use icsneo::network::NetworkId;
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut master = CanOpenMaster::new(device, NetworkId::HSCAN);
+//! let name = master.sdo_upload(5, 0x1008, 0).unwrap();
+//! println!("{}", String::from_utf8_lossy(&name));
+//! let mapping = master.read_tpdo_mapping(5, 1).unwrap();
+//! master.add_pdo(mapping);
+//! master.monitor_heartbeat(5, Duration::from_millis(500));
+//! master.nmt(NmtCommand::Start, 5).unwrap();
+//! loop {
+//!     for event in master.poll(Duration::from_secs(1)).unwrap() {
+//!         if let CanOpenEvent::Pdo { values, .. } = event {
+//!             println!("{values:?}");
+//!         }
+//!     }
+//!     assert!(master.missing_nodes().is_empty());
+//! }
+//! ```
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::dbc::{extract, ByteOrder};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const COB_NMT: u32 = 0x000;
+const COB_EMERGENCY: u32 = 0x080;
+const COB_SDO_RESPONSE: u32 = 0x580;
+const COB_SDO_REQUEST: u32 = 0x600;
+const COB_HEARTBEAT: u32 = 0x700;
+
+/// SDO abort code sent when the node doesn't answer.
+pub const SDO_ABORT_TIMEOUT: u32 = 0x0504_0000;
+/// SDO abort code sent for an unexpected or invalid response.
+pub const SDO_ABORT_INVALID_COMMAND: u32 = 0x0504_0001;
+/// SDO abort code sent when the toggle bit didn't alternate.
+pub const SDO_ABORT_TOGGLE: u32 = 0x0503_0000;
+
+/// NMT command specifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NmtCommand {
+    Start = 0x01,
+    Stop = 0x02,
+    EnterPreOperational = 0x80,
+    ResetNode = 0x81,
+    ResetCommunication = 0x82,
+}
+
+/// NMT state of a node, as reported by heartbeat and node guarding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NmtState {
+    BootUp = 0x00,
+    Stopped = 0x04,
+    Operational = 0x05,
+    PreOperational = 0x7f,
+}
+
+impl NmtState {
+    pub fn from_byte(state: u8) -> Option<Self> {
+        match state {
+            0x00 => Some(Self::BootUp),
+            0x04 => Some(Self::Stopped),
+            0x05 => Some(Self::Operational),
+            0x7f => Some(Self::PreOperational),
+            _ => None,
+        }
+    }
+}
+
+/// One object mapped into a PDO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MappedObject {
+    pub index: u16,
+    pub subindex: u8,
+    pub bits: u8,
+}
+
+impl MappedObject {
+    /// Decodes a mapping parameter entry (0x1600/0x1A00 subindex 1 and up).
+    pub fn from_u32(entry: u32) -> Self {
+        Self {
+            index: (entry >> 16) as u16,
+            subindex: (entry >> 8) as u8,
+            bits: entry as u8,
+        }
+    }
+}
+
+/// The objects mapped into the PDO sent with `cob_id`, in order from the first data bit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PdoMapping {
+    pub cob_id: u32,
+    pub objects: Vec<MappedObject>,
+}
+
+impl PdoMapping {
+    /// Splits PDO data into the mapped objects' raw values. Objects past the end of `data`
+    /// are left out.
+    pub fn decode(&self, data: &[u8]) -> Vec<PdoValue> {
+        let mut bit = 0;
+        self.objects
+            .iter()
+            .map_while(|object| {
+                let raw = extract(data, bit, object.bits.into(), ByteOrder::LittleEndian)?;
+                bit += u32::from(object.bits);
+                Some(PdoValue {
+                    object: *object,
+                    raw,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The raw value of an object received in a PDO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PdoValue {
+    pub object: MappedObject,
+    pub raw: u64,
+}
+
+/// Something a node sent, see [poll](CanOpenMaster::poll).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanOpenEvent {
+    Heartbeat {
+        node: u8,
+        state: NmtState,
+    },
+    Emergency {
+        node: u8,
+        code: u16,
+        error_register: u8,
+        data: [u8; 5],
+    },
+    /// A PDO with a mapping added with [add_pdo](CanOpenMaster::add_pdo).
+    Pdo {
+        cob_id: u32,
+        values: Vec<PdoValue>,
+    },
+}
+
+/// Heartbeat monitoring state of a node.
+struct Monitor {
+    timeout: Duration,
+    /// Time of the last heartbeat, or of the start of monitoring.
+    last_seen: Duration,
+}
+
+/// A CANopen master on one network.
+pub struct CanOpenMaster<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    netid: neonetid_t,
+    sdo_timeout: Duration,
+    states: BTreeMap<u8, NmtState>,
+    monitors: BTreeMap<u8, Monitor>,
+    /// Expected toggle bit of the next node guarding response of each node.
+    guard_toggles: HashMap<u8, bool>,
+    pdos: HashMap<u32, PdoMapping>,
+    pending: VecDeque<Message>,
+    events: Vec<CanOpenEvent>,
+}
+
+impl<B: Transmit + Receive> CanOpenMaster<B> {
+    pub fn new(bus: B, netid: impl Into<NetworkId>) -> Self {
+        Self::with_clock(bus, netid, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> CanOpenMaster<B, C> {
+    pub fn with_clock(bus: B, netid: impl Into<NetworkId>, clock: C) -> Self {
+        Self {
+            bus,
+            clock,
+            netid: netid.into().0,
+            sdo_timeout: Duration::from_secs(1),
+            states: BTreeMap::new(),
+            monitors: BTreeMap::new(),
+            guard_toggles: HashMap::new(),
+            pdos: HashMap::new(),
+            pending: VecDeque::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Time to wait for each SDO response. Defaults to 1 s.
+    pub fn set_sdo_timeout(&mut self, timeout: Duration) {
+        self.sdo_timeout = timeout;
+    }
+
+    /// Sends an NMT command to `node`, or to all nodes if `node` is 0.
+    pub fn nmt(&mut self, command: NmtCommand, node: u8) -> Result<()> {
+        if node != 0 {
+            check_node(node)?;
+        }
+        self.transmit_frame(COB_NMT, &[command as u8, node], false)
+    }
+
+    /// The last state `node` reported by heartbeat or node guarding.
+    pub fn node_state(&self, node: u8) -> Option<NmtState> {
+        self.states.get(&node).copied()
+    }
+
+    /// Expects a heartbeat from `node` at least every `timeout`, see
+    /// [missing_nodes](Self::missing_nodes).
+    pub fn monitor_heartbeat(&mut self, node: u8, timeout: Duration) {
+        let last_seen = self.clock.now();
+        self.monitors.insert(node, Monitor { timeout, last_seen });
+    }
+
+    pub fn stop_monitoring(&mut self, node: u8) {
+        self.monitors.remove(&node);
+    }
+
+    /// Monitored nodes whose heartbeat is overdue. Only heartbeats received by
+    /// [poll](Self::poll) or while waiting for SDO responses count.
+    pub fn missing_nodes(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        self.monitors
+            .iter()
+            .filter(|(_, monitor)| now - monitor.last_seen > monitor.timeout)
+            .map(|(node, _)| *node)
+            .collect()
+    }
+
+    /// Polls `node` with a node guarding remote frame and returns its state. The toggle bit
+    /// of the response has to alternate between calls.
+    pub fn node_guard(&mut self, node: u8) -> Result<NmtState> {
+        check_node(node)?;
+        let id = COB_HEARTBEAT + u32::from(node);
+        self.transmit_frame(id, &[], true)?;
+        let deadline = self.clock.now() + self.sdo_timeout;
+        let response = self
+            .wait_for(id, 1, deadline)?
+            .ok_or_else(|| Error::Timeout(format!("No node guarding response from node {node}")))?;
+        let toggle = response[0] & 0x80 != 0;
+        let expected = self.guard_toggles.entry(node).or_insert(false);
+        if toggle != *expected {
+            return Err(Error::ProtocolError(format!(
+                "Node guarding toggle bit of node {node} didn't alternate"
+            )));
+        }
+        *expected = !toggle;
+        let state = NmtState::from_byte(response[0] & 0x7f).ok_or_else(|| {
+            Error::ProtocolError(format!("Invalid NMT state {:#04x}", response[0] & 0x7f))
+        })?;
+        self.states.insert(node, state);
+        Ok(state)
+    }
+
+    /// Reads object `index`/`subindex` of `node`, expedited or segmented as the node
+    /// chooses.
+    pub fn sdo_upload(&mut self, node: u8, index: u16, subindex: u8) -> Result<Vec<u8>> {
+        let response = self.sdo_request(node, sdo_frame(0x40, index, subindex, &[]))?;
+        check_initiate_response(&response, 0x40, index, subindex)?;
+        let command = response[0];
+        let expedited = command & 0x02 != 0;
+        let size_indicated = command & 0x01 != 0;
+        if expedited {
+            let unused = match size_indicated {
+                true => usize::from((command >> 2) & 3),
+                false => 0,
+            };
+            return Ok(response[4..8 - unused].to_vec());
+        }
+
+        let size = u32::from_le_bytes(response[4..8].try_into().unwrap()) as usize;
+        let mut data = Vec::with_capacity(size);
+        let mut toggle = 0;
+        loop {
+            let response = self.sdo_request(node, [0x60 | toggle, 0, 0, 0, 0, 0, 0, 0])?;
+            if response[0] >> 5 != 0 {
+                return Err(self.sdo_abort(node, index, subindex, SDO_ABORT_INVALID_COMMAND));
+            }
+            if response[0] & 0x10 != toggle {
+                return Err(self.sdo_abort(node, index, subindex, SDO_ABORT_TOGGLE));
+            }
+            let unused = usize::from((response[0] >> 1) & 7);
+            data.extend_from_slice(&response[1..8 - unused]);
+            if response[0] & 0x01 != 0 {
+                break;
+            }
+            toggle ^= 0x10;
+        }
+        if size_indicated && data.len() != size {
+            return Err(Error::ProtocolError(format!(
+                "SDO upload of {} bytes, {size} indicated",
+                data.len()
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to object `index`/`subindex` of `node`, expedited if it fits 4 bytes.
+    pub fn sdo_download(&mut self, node: u8, index: u16, subindex: u8, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "Empty SDO download to {index:#06x}/{subindex}"
+            )));
+        }
+        if data.len() <= 4 {
+            let command = 0x23 | ((4 - data.len() as u8) << 2);
+            let response = self.sdo_request(node, sdo_frame(command, index, subindex, data))?;
+            return check_initiate_response(&response, 0x60, index, subindex);
+        }
+
+        let size = (data.len() as u32).to_le_bytes();
+        let response = self.sdo_request(node, sdo_frame(0x21, index, subindex, &size))?;
+        check_initiate_response(&response, 0x60, index, subindex)?;
+        let mut toggle = 0;
+        let segments = data.chunks(7);
+        let count = segments.len();
+        for (i, segment) in segments.enumerate() {
+            let last = u8::from(i + 1 == count);
+            let mut request = [0; 8];
+            request[0] = toggle | ((7 - segment.len() as u8) << 1) | last;
+            request[1..1 + segment.len()].copy_from_slice(segment);
+            let response = self.sdo_request(node, request)?;
+            if response[0] & 0xe0 != 0x20 {
+                return Err(self.sdo_abort(node, index, subindex, SDO_ABORT_INVALID_COMMAND));
+            }
+            if response[0] & 0x10 != toggle {
+                return Err(self.sdo_abort(node, index, subindex, SDO_ABORT_TOGGLE));
+            }
+            toggle ^= 0x10;
+        }
+        Ok(())
+    }
+
+    /// Reads the communication and mapping parameters of transmit PDO `number` (1 based)
+    /// from `node`.
+    pub fn read_tpdo_mapping(&mut self, node: u8, number: u16) -> Result<PdoMapping> {
+        self.read_pdo_mapping(node, 0x1800, number)
+    }
+
+    /// Reads the communication and mapping parameters of receive PDO `number` (1 based)
+    /// from `node`.
+    pub fn read_rpdo_mapping(&mut self, node: u8, number: u16) -> Result<PdoMapping> {
+        self.read_pdo_mapping(node, 0x1400, number)
+    }
+
+    fn read_pdo_mapping(&mut self, node: u8, base: u16, number: u16) -> Result<PdoMapping> {
+        if !(1..=512).contains(&number) {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid PDO number {number}"
+            )));
+        }
+        let communication = base + number - 1;
+        let cob_id = self.sdo_upload_u32(node, communication, 1)? & 0x1fff_ffff;
+        let mapping = communication + 0x200;
+        let count = self
+            .sdo_upload(node, mapping, 0)?
+            .first()
+            .copied()
+            .unwrap_or(0);
+        let objects = (1..=count)
+            .map(|subindex| {
+                Ok(MappedObject::from_u32(
+                    self.sdo_upload_u32(node, mapping, subindex)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(PdoMapping { cob_id, objects })
+    }
+
+    fn sdo_upload_u32(&mut self, node: u8, index: u16, subindex: u8) -> Result<u32> {
+        let data = self.sdo_upload(node, index, subindex)?;
+        let mut bytes = [0; 4];
+        let length = data.len().min(4);
+        bytes[..length].copy_from_slice(&data[..length]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Decodes PDOs received on `mapping`'s COB-ID from now on, replacing a previous
+    /// mapping for it.
+    pub fn add_pdo(&mut self, mapping: PdoMapping) {
+        self.pdos.insert(mapping.cob_id, mapping);
+    }
+
+    pub fn remove_pdo(&mut self, cob_id: u32) {
+        self.pdos.remove(&cob_id);
+    }
+
+    /// Returns the events received since the last call. Waits up to `timeout` if there are
+    /// none yet, returning an empty list if none arrive.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<CanOpenEvent>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            // Once there are events, only the frames received already are processed.
+            let until = match self.events.is_empty() {
+                true => deadline,
+                false => Duration::ZERO,
+            };
+            match self.next_frame(until)? {
+                Some((id, data)) => self.process(id, &data),
+                None => return Ok(std::mem::take(&mut self.events)),
+            }
+        }
+    }
+
+    /// Sends an SDO request and returns the response, turning an abort into an error.
+    fn sdo_request(&mut self, node: u8, request: [u8; 8]) -> Result<[u8; 8]> {
+        check_node(node)?;
+        self.transmit_frame(COB_SDO_REQUEST + u32::from(node), &request, false)?;
+        let deadline = self.clock.now() + self.sdo_timeout;
+        let id = COB_SDO_RESPONSE + u32::from(node);
+        let Some(response) = self.wait_for(id, 8, deadline)? else {
+            let index = u16::from_le_bytes([request[1], request[2]]);
+            return Err(self.sdo_abort(node, index, request[3], SDO_ABORT_TIMEOUT));
+        };
+        let response: [u8; 8] = response[..8].try_into().unwrap();
+        if response[0] == 0x80 {
+            return Err(Error::SdoAbort(u32::from_le_bytes(
+                response[4..8].try_into().unwrap(),
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Aborts the transfer with `node` and returns the error for it. The result of sending
+    /// the abort is ignored, the transfer failed either way.
+    fn sdo_abort(&mut self, node: u8, index: u16, subindex: u8, code: u32) -> Error {
+        let frame = sdo_frame(0x80, index, subindex, &code.to_le_bytes());
+        let _ = self.transmit_frame(COB_SDO_REQUEST + u32::from(node), &frame, false);
+        match code {
+            SDO_ABORT_TIMEOUT => Error::Timeout(format!("No SDO response from node {node}")),
+            _ => Error::SdoAbort(code),
+        }
+    }
+
+    /// Waits for a data frame with `id` of at least `length` bytes, processing everything
+    /// else received meanwhile. Returns `None` at `deadline`.
+    fn wait_for(&mut self, id: u32, length: usize, deadline: Duration) -> Result<Option<Vec<u8>>> {
+        while let Some((received, data)) = self.next_frame(deadline)? {
+            if received == id && data.len() >= length {
+                return Ok(Some(data));
+            }
+            self.process(received, &data);
+        }
+        Ok(None)
+    }
+
+    /// Returns the next data frame with an 11 bit ID, or `None` at `deadline`.
+    fn next_frame(&mut self, deadline: Duration) -> Result<Option<(u32, Vec<u8>)>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                let Message::Can(m) = message else {
+                    continue;
+                };
+                if m.netid == self.netid
+                    && !m.is_extended()
+                    && !m.is_transmit()
+                    && !m.is_remote()
+                    && !m.is_error_frame()
+                {
+                    return Ok(Some((m.arbid, m.data().to_vec())));
+                }
+            }
+            // Checks the bus once even if the deadline passed, so polling without a timeout
+            // sees what was received.
+            let now = self.clock.now();
+            let messages = self.bus.receive(deadline.saturating_sub(now))?;
+            if messages.is_empty() && now >= deadline {
+                return Ok(None);
+            }
+            self.pending.extend(messages);
+        }
+    }
+
+    fn process(&mut self, id: u32, data: &[u8]) {
+        if let Some(mapping) = self.pdos.get(&id) {
+            self.events.push(CanOpenEvent::Pdo {
+                cob_id: id,
+                values: mapping.decode(data),
+            });
+            return;
+        }
+        let node = (id & 0x7f) as u8;
+        match (id & 0x780, data) {
+            (COB_HEARTBEAT, [state]) if node != 0 => {
+                let Some(state) = NmtState::from_byte(*state & 0x7f) else {
+                    return;
+                };
+                self.states.insert(node, state);
+                if let Some(monitor) = self.monitors.get_mut(&node) {
+                    monitor.last_seen = self.clock.now();
+                }
+                // A node starts node guarding over after booting.
+                if state == NmtState::BootUp {
+                    self.guard_toggles.remove(&node);
+                }
+                self.events.push(CanOpenEvent::Heartbeat { node, state });
+            }
+            (COB_EMERGENCY, [low, high, error_register, data @ ..]) if node != 0 => {
+                if let Ok(data) = data.try_into() {
+                    self.events.push(CanOpenEvent::Emergency {
+                        node,
+                        code: u16::from_le_bytes([*low, *high]),
+                        error_register: *error_register,
+                        data,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn transmit_frame(&mut self, id: u32, data: &[u8], remote: bool) -> Result<()> {
+        let mut message = CanMessage::new(self.netid, id, data);
+        message.set_remote(remote);
+        // Remote frames request one byte of node guarding response.
+        message.set_dlc_on_wire(match remote {
+            true => 1,
+            false => data.len() as u8,
+        });
+        self.bus.transmit(&Message::Can(message))
+    }
+}
+
+/// Checks that `node` is a node id, 1 to 127.
+fn check_node(node: u8) -> Result<()> {
+    match node {
+        1..=127 => Ok(()),
+        _ => Err(Error::InvalidArgument(format!("Invalid node id {node}"))),
+    }
+}
+
+fn sdo_frame(command: u8, index: u16, subindex: u8, data: &[u8]) -> [u8; 8] {
+    let [index_low, index_high] = index.to_le_bytes();
+    let mut frame = [command, index_low, index_high, subindex, 0, 0, 0, 0];
+    frame[4..4 + data.len()].copy_from_slice(data);
+    frame
+}
+
+/// Checks the command specifier and multiplexer of an initiate upload or download response.
+fn check_initiate_response(
+    response: &[u8; 8],
+    command: u8,
+    index: u16,
+    subindex: u8,
+) -> Result<()> {
+    if response[0] & 0xe0 != command || response[1..4] != sdo_frame(0, index, subindex, &[])[1..4] {
+        return Err(Error::ProtocolError(format!(
+            "Unexpected SDO response {response:02x?} for {index:#06x}/{subindex}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{VirtualBus, VirtualEndpoint};
+    use crate::clock::ManualClock;
+    use std::thread::JoinHandle;
+
+    const NODE: u8 = 5;
+
+    /// A node with an object dictionary answering SDO requests, node guarding and NMT
+    /// commands, until nothing is received for a while. Returns the final dictionary.
+    fn node(
+        bus: &VirtualBus,
+        mut dictionary: HashMap<(u16, u8), Vec<u8>>,
+    ) -> JoinHandle<HashMap<(u16, u8), Vec<u8>>> {
+        let mut endpoint = bus.endpoint();
+        std::thread::spawn(move || {
+            let mut state = NmtState::PreOperational;
+            let mut guard_toggle = 0;
+            // Object and data of a segmented transfer in progress.
+            let mut transfer: Option<((u16, u8), Vec<u8>)> = None;
+            let send = |endpoint: &mut VirtualEndpoint, id: u32, data: &[u8]| {
+                let frame = CanMessage::new(1, id, data);
+                endpoint.transmit(&Message::Can(frame)).unwrap();
+            };
+            loop {
+                let messages = endpoint.receive(Duration::from_millis(300)).unwrap();
+                if messages.is_empty() {
+                    return dictionary;
+                }
+                for message in messages {
+                    let Message::Can(m) = message else { continue };
+                    let data = m.data();
+                    match m.arbid {
+                        0x000 if data[1] == NODE || data[1] == 0 => {
+                            state = match data[0] {
+                                0x01 => NmtState::Operational,
+                                0x02 => NmtState::Stopped,
+                                _ => NmtState::PreOperational,
+                            };
+                            send(&mut endpoint, 0x700 + u32::from(NODE), &[state as u8]);
+                            if state == NmtState::Operational {
+                                // TPDO 1: 16 bit and 8 bit value.
+                                send(&mut endpoint, 0x185, &[0x34, 0x12, 0x56]);
+                                send(&mut endpoint, 0x085, &[0x10, 0x81, 0x01, 1, 2, 3, 4, 5]);
+                            }
+                        }
+                        0x705 if m.is_remote() => {
+                            send(&mut endpoint, 0x705, &[guard_toggle | state as u8]);
+                            guard_toggle ^= 0x80;
+                        }
+                        0x605 => {
+                            let object = (u16::from_le_bytes([data[1], data[2]]), data[3]);
+                            let response = match (data[0], &mut transfer) {
+                                // Initiate upload.
+                                (0x40, _) => match dictionary.get(&object) {
+                                    Some(value) if value.len() <= 4 => {
+                                        let command = 0x43 | ((4 - value.len() as u8) << 2);
+                                        sdo_frame(command, object.0, object.1, value)
+                                    }
+                                    Some(value) => {
+                                        transfer = Some((object, value.clone()));
+                                        let size = (value.len() as u32).to_le_bytes();
+                                        sdo_frame(0x41, object.0, object.1, &size)
+                                    }
+                                    None => sdo_frame(
+                                        0x80,
+                                        object.0,
+                                        object.1,
+                                        &0x0602_0000u32.to_le_bytes(),
+                                    ),
+                                },
+                                // Upload segment.
+                                (command, Some((_, remaining))) if command & 0xe0 == 0x60 => {
+                                    let length = remaining.len().min(7);
+                                    let segment: Vec<u8> = remaining.drain(..length).collect();
+                                    let last = u8::from(remaining.is_empty());
+                                    let mut response = [0; 8];
+                                    response[0] =
+                                        (command & 0x10) | ((7 - length as u8) << 1) | last;
+                                    response[1..1 + length].copy_from_slice(&segment);
+                                    if remaining.is_empty() {
+                                        transfer = None;
+                                    }
+                                    response
+                                }
+                                // Expedited download.
+                                (command, _) if command & 0xe3 == 0x23 => {
+                                    let length = 4 - usize::from((command >> 2) & 3);
+                                    dictionary.insert(object, data[4..4 + length].to_vec());
+                                    sdo_frame(0x60, object.0, object.1, &[])
+                                }
+                                // Initiate segmented download.
+                                (0x21, _) => {
+                                    transfer = Some((object, Vec::new()));
+                                    sdo_frame(0x60, object.0, object.1, &[])
+                                }
+                                // Download segment.
+                                (command, Some((object, received))) if command & 0xe0 == 0 => {
+                                    let length = 7 - usize::from((command >> 1) & 7);
+                                    received.extend_from_slice(&data[1..1 + length]);
+                                    if command & 1 != 0 {
+                                        dictionary.insert(*object, received.clone());
+                                        transfer = None;
+                                    }
+                                    [0x20 | (command & 0x10), 0, 0, 0, 0, 0, 0, 0]
+                                }
+                                // Abort from the client.
+                                (0x80, _) => {
+                                    transfer = None;
+                                    continue;
+                                }
+                                _ => sdo_frame(
+                                    0x80,
+                                    object.0,
+                                    object.1,
+                                    &0x0504_0001u32.to_le_bytes(),
+                                ),
+                            };
+                            send(&mut endpoint, 0x585, &response);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        })
+    }
+
+    fn dictionary() -> HashMap<(u16, u8), Vec<u8>> {
+        HashMap::from([
+            ((0x1000, 0), vec![0x91, 0x01, 0x0f, 0x00]),
+            ((0x1008, 0), b"Test fixture IO module".to_vec()),
+            ((0x1800, 1), 0x185u32.to_le_bytes().to_vec()),
+            ((0x1a00, 0), vec![2]),
+            ((0x1a00, 1), 0x6401_0110u32.to_le_bytes().to_vec()),
+            ((0x1a00, 2), 0x6000_0108u32.to_le_bytes().to_vec()),
+        ])
+    }
+
+    #[test]
+    fn test_sdo() {
+        let bus = VirtualBus::new();
+        let node = node(&bus, dictionary());
+        let mut master = CanOpenMaster::new(bus.endpoint(), NetworkId::HSCAN);
+
+        assert_eq!(
+            master.sdo_upload(NODE, 0x1000, 0).unwrap(),
+            [0x91, 0x01, 0x0f, 0x00]
+        );
+        assert_eq!(
+            master.sdo_upload(NODE, 0x1008, 0).unwrap(),
+            b"Test fixture IO module"
+        );
+        assert!(matches!(
+            master.sdo_upload(NODE, 0x2000, 0),
+            Err(Error::SdoAbort(0x0602_0000))
+        ));
+        master.sdo_download(NODE, 0x2000, 1, &[0xaa, 0xbb]).unwrap();
+        master
+            .sdo_download(NODE, 0x2000, 2, &(0..20).collect::<Vec<u8>>())
+            .unwrap();
+        assert_eq!(master.sdo_upload(NODE, 0x2000, 1).unwrap(), [0xaa, 0xbb]);
+        assert!(matches!(
+            master.sdo_download(NODE, 0x2000, 1, &[]),
+            Err(Error::InvalidArgument(_))
+        ));
+        for node in [0, 128] {
+            assert!(matches!(
+                master.sdo_upload(node, 0x1000, 0),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        // Nobody answers for node 6.
+        master.set_sdo_timeout(Duration::from_millis(20));
+        assert!(matches!(
+            master.sdo_upload(6, 0x1000, 0),
+            Err(Error::Timeout(_))
+        ));
+
+        let dictionary = node.join().unwrap();
+        assert_eq!(dictionary[&(0x2000, 1)], [0xaa, 0xbb]);
+        assert_eq!(dictionary[&(0x2000, 2)], (0..20).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_nmt_and_pdo() {
+        let bus = VirtualBus::new();
+        let node = node(&bus, dictionary());
+        let mut master = CanOpenMaster::new(bus.endpoint(), NetworkId::HSCAN);
+
+        let mapping = master.read_tpdo_mapping(NODE, 1).unwrap();
+        assert_eq!(mapping.cob_id, 0x185);
+        assert_eq!(
+            mapping.objects,
+            [
+                MappedObject {
+                    index: 0x6401,
+                    subindex: 1,
+                    bits: 16
+                },
+                MappedObject {
+                    index: 0x6000,
+                    subindex: 1,
+                    bits: 8
+                },
+            ]
+        );
+        master.add_pdo(mapping);
+        assert!(matches!(
+            master.read_tpdo_mapping(NODE, 0),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert_eq!(master.node_guard(NODE).unwrap(), NmtState::PreOperational);
+        assert_eq!(master.node_guard(NODE).unwrap(), NmtState::PreOperational);
+
+        master.nmt(NmtCommand::Start, NODE).unwrap();
+        assert!(matches!(
+            master.nmt(NmtCommand::Start, 128),
+            Err(Error::InvalidArgument(_))
+        ));
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            events.extend(master.poll(Duration::from_secs(1)).unwrap());
+        }
+        assert_eq!(
+            events[0],
+            CanOpenEvent::Heartbeat {
+                node: NODE,
+                state: NmtState::Operational
+            }
+        );
+        let CanOpenEvent::Pdo { cob_id, values } = &events[1] else {
+            panic!("{:?}", events[1])
+        };
+        assert_eq!(*cob_id, 0x185);
+        assert_eq!(values[0].raw, 0x1234);
+        assert_eq!(values[1].object.index, 0x6000);
+        assert_eq!(values[1].raw, 0x56);
+        assert_eq!(
+            events[2],
+            CanOpenEvent::Emergency {
+                node: NODE,
+                code: 0x8110,
+                error_register: 0x01,
+                data: [1, 2, 3, 4, 5],
+            }
+        );
+        assert_eq!(master.node_state(NODE), Some(NmtState::Operational));
+        assert_eq!(master.node_guard(NODE).unwrap(), NmtState::Operational);
+        assert!(master.poll(Duration::from_millis(10)).unwrap().is_empty());
+
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn test_heartbeat_monitoring() {
+        let bus = VirtualBus::new();
+        let clock = ManualClock::new();
+        let mut node = bus.endpoint();
+        let mut master = CanOpenMaster::with_clock(bus.endpoint(), NetworkId::HSCAN, clock.clone());
+        master.monitor_heartbeat(NODE, Duration::from_millis(100));
+        master.monitor_heartbeat(6, Duration::from_millis(100));
+
+        clock.advance(Duration::from_millis(80));
+        node.transmit(&Message::Can(CanMessage::new(1, 0x705, &[0x05])))
+            .unwrap();
+        master.poll(Duration::ZERO).unwrap();
+        assert!(master.missing_nodes().is_empty());
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(master.missing_nodes(), [6]);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(master.missing_nodes(), [NODE, 6]);
+        master.stop_monitoring(6);
+        assert_eq!(master.missing_nodes(), [NODE]);
+    }
+}
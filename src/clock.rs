@@ -0,0 +1,114 @@
+//! Time sources for code that paces or times out bus traffic.
+//!
+//! Everything that waits takes a [Clock] so it can run against the wall clock on a bench and
+//! against a [ManualClock] in tests, where sleeping returns immediately.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A monotonic time source. Times are offsets from an arbitrary, fixed epoch.
+pub trait Clock {
+    fn now(&self) -> Duration;
+
+    /// Blocks until [now](Clock::now) is at least `deadline`. Returns immediately if the
+    /// deadline has passed.
+    fn sleep_until(&self, deadline: Duration);
+}
+
+/// [Instant] based clock whose epoch is the time it was created.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        let now = self.now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+    }
+}
+
+/// A clock that only moves when told to. Sleeping advances it to the deadline instantly.
+///
+/// Clones share the same time, so a test can hold one while the code under test sleeps on
+/// another.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(now.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        self.nanos
+            .fetch_max(deadline.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        (**self).sleep_until(deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(shared.now(), Duration::from_millis(5));
+        shared.sleep_until(Duration::from_millis(8));
+        assert_eq!(clock.now(), Duration::from_millis(8));
+        // Deadlines in the past don't move the clock back.
+        clock.sleep_until(Duration::from_millis(1));
+        assert_eq!(clock.now(), Duration::from_millis(8));
+        clock.set(Duration::from_secs(1));
+        assert_eq!(shared.now(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,368 @@
+//! Transmit confirmation.
+//!
+//! Devices report every transmitted message back as a receipt with the transmit flag set,
+//! the time it went on the wire and the `description` it was transmitted with.
+//! [Confirming] tags each transmit with a unique description and waits for its receipt, to
+//! confirm delivery and measure latency:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::confirm::Confirming;
+//! use icsneo::message::CanMessage;
+//! use icsneo::network::NetworkId;
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut bus = Confirming::new(device);
+//! let message = CanMessage::new(NetworkId::HSCAN.0, 0x123, &[1, 2, 3]).into();
+//! let confirmation = bus
+//!     .transmit_confirmed(&message, Duration::from_millis(100))
+//!     .unwrap();
+//! println!("On the wire at {} after {:?}", confirmation.timestamp, confirmation.latency);
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The receipt of a transmitted message.
+#[derive(Debug, Clone)]
+pub struct Confirmation {
+    pub description: u16,
+    /// Timestamp of the receipt, when the message was on the wire.
+    pub timestamp: u64,
+    /// Time from handing the message to the bus until the receipt was received.
+    pub latency: Duration,
+    pub receipt: Message,
+}
+
+/// Wraps a bus to confirm transmits.
+///
+/// Receipts of transmits awaiting confirmation are taken out of the received messages, all
+/// other messages, including receipts of untagged transmits, are returned by
+/// [receive](Receive::receive) as usual.
+pub struct Confirming<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    next: u16,
+    /// Transmit times of the descriptions awaiting a receipt.
+    in_flight: HashMap<u16, Duration>,
+    confirmed: HashMap<u16, Confirmation>,
+    received: VecDeque<Message>,
+}
+
+impl<B: Transmit + Receive> Confirming<B> {
+    pub fn new(bus: B) -> Self {
+        Self::with_clock(bus, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> Confirming<B, C> {
+    pub fn with_clock(bus: B, clock: C) -> Self {
+        Self {
+            bus,
+            clock,
+            next: 1,
+            in_flight: HashMap::new(),
+            confirmed: HashMap::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// The bus. Received messages not returned yet are dropped.
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Number of tagged transmits whose receipt wasn't taken yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len() + self.confirmed.len()
+    }
+
+    /// Transmits `message` and waits for its receipt. Fails with [Error::TransmitFailed] if
+    /// the receipt reports an error and with [Error::Timeout] if none arrives in `timeout`.
+    pub fn transmit_confirmed(
+        &mut self,
+        message: &Message,
+        timeout: Duration,
+    ) -> Result<Confirmation> {
+        let description = self.transmit_tagged(message)?;
+        self.wait_confirmation(description, timeout)
+    }
+
+    /// Transmits `message` tagged with an unused description and returns it without waiting,
+    /// to confirm several transmits with [wait_confirmation](Self::wait_confirmation).
+    pub fn transmit_tagged(&mut self, message: &Message) -> Result<u16> {
+        let description = self.allocate()?;
+        let mut message = message.clone();
+        message.set_description(description);
+        let sent = self.clock.now();
+        self.bus.transmit(&message)?;
+        self.in_flight.insert(description, sent);
+        Ok(description)
+    }
+
+    /// Waits for the receipt of a [tagged](Self::transmit_tagged) transmit, like
+    /// [transmit_confirmed](Self::transmit_confirmed). The description is free again
+    /// afterwards, also on timeout; a receipt arriving later is received like any other
+    /// message.
+    pub fn wait_confirmation(
+        &mut self,
+        description: u16,
+        timeout: Duration,
+    ) -> Result<Confirmation> {
+        if !self.in_flight.contains_key(&description) && !self.confirmed.contains_key(&description)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "No transmit awaiting confirmation with description {description}"
+            )));
+        }
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if let Some(confirmation) = self.confirmed.remove(&description) {
+                return match confirmation.receipt.status().global_error() {
+                    true => Err(Error::TransmitFailed(format!(
+                        "Receipt of description {description} reports an error"
+                    ))),
+                    false => Ok(confirmation),
+                };
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                self.in_flight.remove(&description);
+                return Err(Error::Timeout(format!(
+                    "No receipt for description {description}"
+                )));
+            }
+            let messages = self.bus.receive(deadline - now)?;
+            self.sort(messages);
+        }
+    }
+
+    /// The next description that isn't awaiting confirmation. 0 means untagged and is skipped.
+    fn allocate(&mut self) -> Result<u16> {
+        for _ in 0..u16::MAX {
+            let description = self.next;
+            self.next = self.next.checked_add(1).unwrap_or(1);
+            if !self.in_flight.contains_key(&description)
+                && !self.confirmed.contains_key(&description)
+            {
+                return Ok(description);
+            }
+        }
+        Err(Error::InvalidArgument(
+            "All descriptions are awaiting confirmation".to_string(),
+        ))
+    }
+
+    /// Takes the receipts of transmits awaiting confirmation and keeps everything else to be
+    /// received.
+    fn sort(&mut self, messages: Vec<Message>) {
+        let now = self.clock.now();
+        for message in messages {
+            let description = message.description();
+            match self.in_flight.get(&description) {
+                Some(&sent) if message.is_transmit() => {
+                    self.in_flight.remove(&description);
+                    self.confirmed.insert(
+                        description,
+                        Confirmation {
+                            description,
+                            timestamp: message.timestamp(),
+                            latency: now.saturating_sub(sent),
+                            receipt: message,
+                        },
+                    );
+                }
+                _ => self.received.push_back(message),
+            }
+        }
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> Receive for Confirming<B, C> {
+    /// Keeps waiting until `timeout` if only receipts awaiting confirmation arrive.
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if !self.received.is_empty() {
+                return Ok(self.received.drain(..).collect());
+            }
+            let messages = self
+                .bus
+                .receive(deadline.saturating_sub(self.clock.now()))?;
+            self.sort(messages);
+            if self.received.is_empty() && self.clock.now() >= deadline {
+                return Ok(Vec::new());
+            }
+        }
+    }
+}
+
+/// Transmits untagged, without confirmation.
+impl<B: Transmit + Receive, C: Clock> Transmit for Confirming<B, C> {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        self.bus.transmit(message)
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        self.bus.transmit_messages(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::VirtualBus;
+    use crate::clock::ManualClock;
+    use crate::network::NetworkId;
+
+    fn can(arbid: u32) -> Message {
+        CanMessage::new(NetworkId::HSCAN.0, arbid, &[1, 2]).into()
+    }
+
+    fn arbid(message: &Message) -> u32 {
+        match message {
+            Message::Can(m) => m.arbid,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns receipts of transmits after a delay in clock time, failed ones for arbid 0x666,
+    /// and none for 0x777.
+    struct Device {
+        clock: ManualClock,
+        receipts: Vec<(Duration, Message)>,
+    }
+
+    impl Transmit for Device {
+        fn transmit(&mut self, message: &Message) -> Result<()> {
+            let mut receipt = message.clone();
+            if let Message::Can(m) = &mut receipt {
+                m.set_transmit(true);
+                let mut status = m.status();
+                status.set_global_error(m.arbid == 0x666);
+                m.set_status(status);
+            }
+            let at = self.clock.now() + Duration::from_millis(2);
+            receipt.set_timestamp(at.as_nanos() as u64);
+            if arbid(message) != 0x777 {
+                self.receipts.push((at, receipt));
+            }
+            Ok(())
+        }
+    }
+
+    impl Receive for Device {
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+            let deadline = self.clock.now() + timeout;
+            let ready = |receipts: &Vec<(Duration, Message)>, now| {
+                receipts.iter().any(|(at, _)| *at <= now)
+            };
+            while !ready(&self.receipts, self.clock.now()) && self.clock.now() < deadline {
+                self.clock.advance(Duration::from_millis(1));
+            }
+            let now = self.clock.now();
+            let (ready, waiting) = self.receipts.drain(..).partition(|(at, _)| *at <= now);
+            self.receipts = waiting;
+            Ok(ready.into_iter().map(|(_, message)| message).collect())
+        }
+    }
+
+    fn device() -> (ManualClock, Confirming<Device, ManualClock>) {
+        let clock = ManualClock::new();
+        let device = Device {
+            clock: clock.clone(),
+            receipts: Vec::new(),
+        };
+        (clock.clone(), Confirming::with_clock(device, clock))
+    }
+
+    #[test]
+    fn test_transmit_confirmed() {
+        let (clock, mut bus) = device();
+        clock.set(Duration::from_millis(10));
+        let confirmation = bus
+            .transmit_confirmed(&can(0x123), Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(confirmation.description, 1);
+        assert_eq!(confirmation.timestamp, 12_000_000);
+        assert_eq!(confirmation.latency, Duration::from_millis(2));
+        assert_eq!(confirmation.receipt.description(), 1);
+        assert!(confirmation.receipt.is_transmit());
+        assert_eq!(bus.in_flight(), 0);
+
+        assert!(matches!(
+            bus.transmit_confirmed(&can(0x666), Duration::from_millis(100)),
+            Err(Error::TransmitFailed(_))
+        ));
+        assert!(matches!(
+            bus.transmit_confirmed(&can(0x777), Duration::from_millis(100)),
+            Err(Error::Timeout(_))
+        ));
+        assert_eq!(bus.in_flight(), 0);
+        assert!(matches!(
+            bus.wait_confirmation(3, Duration::ZERO),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_pipelined_and_untagged() {
+        let (_, mut bus) = device();
+        let first = bus.transmit_tagged(&can(0x100)).unwrap();
+        bus.transmit(&can(0x200)).unwrap();
+        let second = bus.transmit_tagged(&can(0x300)).unwrap();
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(bus.in_flight(), 2);
+
+        // Waiting for the second receipt keeps the first one and the untagged receipt.
+        let confirmation = bus
+            .wait_confirmation(second, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(arbid(&confirmation.receipt), 0x300);
+        let received = bus.receive(Duration::ZERO).unwrap();
+        assert_eq!(received.iter().map(arbid).collect::<Vec<_>>(), [0x200]);
+        assert_eq!(received[0].description(), 0);
+        let confirmation = bus.wait_confirmation(first, Duration::ZERO).unwrap();
+        assert_eq!(arbid(&confirmation.receipt), 0x100);
+
+        // Descriptions wrap around and skip 0.
+        bus.next = u16::MAX;
+        assert_eq!(bus.transmit_tagged(&can(0x100)).unwrap(), u16::MAX);
+        assert_eq!(bus.transmit_tagged(&can(0x100)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_virtual_bus_echo() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        a.set_echo(true);
+        let mut b = bus.endpoint();
+        let mut a = Confirming::new(a);
+
+        b.transmit(&can(0x7e8)).unwrap();
+        let confirmation = a
+            .transmit_confirmed(&can(0x7e0), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(arbid(&confirmation.receipt), 0x7e0);
+        let received = b.receive(Duration::from_secs(1)).unwrap();
+        assert_eq!(confirmation.timestamp, received[0].timestamp());
+        assert_eq!(received[0].description(), confirmation.description);
+        // Traffic received while waiting is kept.
+        let received = a.receive(Duration::from_secs(1)).unwrap();
+        assert_eq!(received.iter().map(arbid).collect::<Vec<_>>(), [0x7e8]);
+    }
+}
@@ -0,0 +1,1800 @@
+//! Vector DBC databases and signal decoding.
+//!
+//! [Database] parses the message and signal definitions of a DBC file, including value tables,
+//! comments, attributes and (extended) multiplexing, and decodes received CAN and CAN FD
+//! frames into physical values:
+//! ```
+//! use icsneo::dbc::Database;
+//! use icsneo::message::CanMessage;
+//!
+//! let db: Database = r#"
+//! BO_ 256 EngineStatus: 8 ECU
+//!  SG_ RPM : 0|16@1+ (0.25,0) [0|16383.75] "rpm" Vector__XXX
+//!  SG_ Gear : 16|4@1+ (1,0) [0|15] "" Vector__XXX
+//! VAL_ 256 Gear 0 "Neutral" 1 "First" 2 "Second" 3 "Third" ;
+//! "#
+//! .parse()
+//! .unwrap();
+//!
+//! let frame = CanMessage::new(1, 0x100, &[0x10, 0x27, 0x03, 0, 0, 0, 0, 0]);
+//! let decoded = db.decode(&frame).unwrap();
+//! assert_eq!(decoded.message.name, "EngineStatus");
+//! assert_eq!(decoded.value("RPM"), Some(2500.0));
+//! assert_eq!(decoded.signal("Gear").unwrap().to_string(), "Gear = 3 (Third)");
+//! ```
+//! It also builds frames from physical values for transmitting, checking them against the
+//! signal ranges:
+//! ```
+//! # let db: icsneo::dbc::Database = r#"
+//! # BO_ 256 EngineStatus: 8 ECU
+//! #  SG_ RPM : 0|16@1+ (0.25,0) [0|16383.75] "rpm" Vector__XXX
+//! #  SG_ Gear : 16|4@1+ (1,0) [0|15] "" Vector__XXX
+//! # "#.parse().unwrap();
+//! let frame = db.encode("EngineStatus", &[("RPM", 2500.0), ("Gear", 3.0)]).unwrap();
+//! assert_eq!(frame.data(), &[0x10, 0x27, 0x03, 0, 0, 0, 0, 0]);
+//! assert!(db.encode("EngineStatus", &[("RPM", 20000.0)]).is_err());
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Bit 31 of a DBC message id marks an extended (29 bit) identifier.
+const EXTENDED_ID_FLAG: u32 = 0x8000_0000;
+const EXTENDED_ID_MASK: u32 = 0x1fff_ffff;
+/// Nesting limit for multiplexer switches that are themselves multiplexed.
+const MAX_MULTIPLEX_DEPTH: usize = 8;
+
+/// Value descriptions by raw value.
+pub type ValueTable = BTreeMap<i64, String>;
+
+/// An attribute value. Enum attributes are stored as the name of their value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl AttributeValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(value) => Some(*value as f64),
+            Self::Float(value) => Some(*value),
+            Self::String(value) => value.parse().ok(),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::String(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Intel, `@1`. The start bit is the least significant bit.
+    LittleEndian,
+    /// Motorola, `@0`. The start bit is the most significant bit.
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Unsigned,
+    Signed,
+    /// IEEE float, set with `SIG_VALTYPE_ ... : 1`.
+    Float32,
+    /// IEEE double, set with `SIG_VALTYPE_ ... : 2`.
+    Float64,
+}
+
+/// Marks a signal as only present while its multiplexer switch has one of `values`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multiplexing {
+    /// Name of the multiplexer switch signal.
+    pub switch: String,
+    pub values: Vec<RangeInclusive<u64>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub start_bit: u32,
+    pub size: u32,
+    pub byte_order: ByteOrder,
+    pub value_type: ValueType,
+    pub factor: f64,
+    pub offset: f64,
+    pub minimum: f64,
+    pub maximum: f64,
+    pub unit: String,
+    pub receivers: Vec<String>,
+    /// True for multiplexer switches (`M`).
+    pub multiplexer: bool,
+    /// Set for multiplexed signals (`m<n>` or `SG_MUL_VAL_`).
+    pub multiplexed: Option<Multiplexing>,
+    /// Value descriptions from `VAL_`.
+    pub values: ValueTable,
+    pub comment: Option<String>,
+    /// Signal attributes, including the defaults of attributes that aren't set.
+    pub attributes: HashMap<String, AttributeValue>,
+}
+
+impl Signal {
+    /// Returns the raw value, sign extended for signed signals and the bit pattern for float
+    /// signals. `None` if the signal doesn't fit inside `data`.
+    pub fn raw(&self, data: &[u8]) -> Option<i64> {
+        let raw = extract(data, self.start_bit, self.size, self.byte_order)?;
+        Some(match self.value_type {
+            ValueType::Signed if self.size < 64 && raw >> (self.size - 1) & 1 == 1 => {
+                (raw | !0 << self.size) as i64
+            }
+            _ => raw as i64,
+        })
+    }
+
+    /// Converts a raw value into the physical value.
+    pub fn physical(&self, raw: i64) -> f64 {
+        let value = match self.value_type {
+            ValueType::Unsigned => raw as u64 as f64,
+            ValueType::Signed => raw as f64,
+            ValueType::Float32 => f64::from(f32::from_bits(raw as u32)),
+            ValueType::Float64 => f64::from_bits(raw as u64),
+        };
+        value * self.factor + self.offset
+    }
+
+    /// Returns the physical value inside `data`, see [raw](Signal::raw).
+    pub fn decode(&self, data: &[u8]) -> Option<f64> {
+        self.raw(data).map(|raw| self.physical(raw))
+    }
+
+    /// Returns the value description for `raw`.
+    pub fn description(&self, raw: i64) -> Option<&str> {
+        self.values.get(&raw).map(String::as_str)
+    }
+
+    /// Converts a physical value into the raw value, rounding to the nearest step. Fails if
+    /// the value is outside the signal's `[minimum|maximum]` range (unless both are 0, which
+    /// DBC files use for "unlimited") or doesn't fit the signal's bits.
+    pub fn to_raw(&self, value: f64) -> Result<i64> {
+        let out_of_range = |range: String| {
+            Error::InvalidArgument(format!(
+                "{} = {value}{}{} is out of range {range}",
+                self.name,
+                if self.unit.is_empty() { "" } else { " " },
+                self.unit
+            ))
+        };
+        let tolerance = self.factor.abs() * 1e-6;
+        let limited = self.minimum != 0.0 || self.maximum != 0.0;
+        if value.is_nan()
+            || limited && (value < self.minimum - tolerance || value > self.maximum + tolerance)
+        {
+            return Err(out_of_range(format!(
+                "[{}, {}]",
+                self.minimum, self.maximum
+            )));
+        }
+        let scaled = (value - self.offset) / self.factor;
+        match self.value_type {
+            ValueType::Float32 => Ok(i64::from((scaled as f32).to_bits())),
+            ValueType::Float64 => Ok(scaled.to_bits() as i64),
+            ValueType::Unsigned | ValueType::Signed => {
+                let (low, high) = self.raw_range();
+                let raw = scaled.round();
+                if raw < low as f64 || raw > high as f64 {
+                    let low = self.physical(low as i64);
+                    let high = self.physical(high as i64);
+                    return Err(out_of_range(format!(
+                        "[{}, {}] of its {} bits",
+                        low.min(high),
+                        low.max(high),
+                        self.size
+                    )));
+                }
+                Ok(raw as i128 as i64)
+            }
+        }
+    }
+
+    /// Writes the physical value `value` into `data`, see [to_raw](Signal::to_raw).
+    pub fn encode(&self, data: &mut [u8], value: f64) -> Result<()> {
+        let raw = self.to_raw(value)?;
+        self.encode_raw(data, raw)
+    }
+
+    /// Writes a raw value into `data`, truncated to the signal's size.
+    pub fn encode_raw(&self, data: &mut [u8], raw: i64) -> Result<()> {
+        insert(data, self.start_bit, self.size, self.byte_order, raw as u64).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "signal {} doesn't fit {} bytes",
+                self.name,
+                data.len()
+            ))
+        })
+    }
+
+    /// Smallest and largest raw integer value.
+    fn raw_range(&self) -> (i128, i128) {
+        match self.value_type {
+            ValueType::Signed => (-(1 << (self.size - 1)), (1 << (self.size - 1)) - 1),
+            _ => (0, (1 << self.size) - 1),
+        }
+    }
+
+    /// The raw `GenSigStartValue` attribute, 0 if the database doesn't define it.
+    fn start_value(&self) -> i64 {
+        self.attributes
+            .get("GenSigStartValue")
+            .and_then(AttributeValue::as_f64)
+            .map_or(0, |value| value as i64)
+    }
+}
+
+/// A message (`BO_`) definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageDefinition {
+    /// Arbitration ID without the extended flag.
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    /// Payload length in bytes.
+    pub size: usize,
+    pub transmitter: Option<String>,
+    pub signals: Vec<Signal>,
+    pub comment: Option<String>,
+    /// Message attributes, including the defaults of attributes that aren't set.
+    pub attributes: HashMap<String, AttributeValue>,
+    /// Sent as CAN FD, from the `VFrameFormat` attribute or a size over 8 bytes.
+    pub fd: bool,
+    /// Sent with bit rate switching, from the `CANFD_BRS` attribute. Only set for CAN FD.
+    pub brs: bool,
+}
+
+impl MessageDefinition {
+    pub fn signal(&self, name: &str) -> Option<&Signal> {
+        self.signals.iter().find(|signal| signal.name == name)
+    }
+
+    /// Decodes the signals present in `data`. Signals that don't fit a short payload and
+    /// multiplexed signals whose switch has a different value are left out.
+    pub fn decode<'a>(&'a self, data: &[u8]) -> DecodedMessage<'a> {
+        let signals = self
+            .signals
+            .iter()
+            .filter(|signal| self.is_active(signal, data, 0))
+            .filter_map(|signal| {
+                let raw = signal.raw(data)?;
+                Some(DecodedSignal {
+                    signal,
+                    raw,
+                    value: signal.physical(raw),
+                })
+            })
+            .collect();
+        DecodedMessage {
+            message: self,
+            signals,
+        }
+    }
+
+    /// Builds the payload from physical signal values. Signals that aren't given are set to
+    /// their `GenSigStartValue`, or 0.
+    ///
+    /// Multiplexed signals are only written while their switch, given or from its start
+    /// value, selects them. Giving a signal that isn't selected is an error, as are unknown
+    /// signals and values outside a signal's range.
+    pub fn encode(&self, values: &[(&str, f64)]) -> Result<Vec<u8>> {
+        if let Some((name, _)) = values.iter().find(|(name, _)| self.signal(name).is_none()) {
+            return Err(Error::InvalidArgument(format!(
+                "{} has no signal {name}",
+                self.name
+            )));
+        }
+        let value = |name: &str| {
+            values
+                .iter()
+                .rev()
+                .find(|(given, _)| *given == name)
+                .map(|(_, value)| *value)
+        };
+        // Switches are written before the signals they select.
+        let mut signals: Vec<&Signal> = self.signals.iter().collect();
+        signals.sort_by_key(|signal| self.multiplex_depth(signal));
+        let mut data = vec![0u8; self.size];
+        for signal in signals {
+            let given = value(&signal.name);
+            if !self.is_active(signal, &data, 0) {
+                if given.is_some() {
+                    let multiplexing = signal.multiplexed.as_ref().unwrap();
+                    return Err(Error::InvalidArgument(format!(
+                        "{} isn't present in {} while {} is {}",
+                        signal.name,
+                        self.name,
+                        multiplexing.switch,
+                        self.signal(&multiplexing.switch)
+                            .and_then(|switch| switch.raw(&data))
+                            .unwrap_or_default()
+                    )));
+                }
+                continue;
+            }
+            match given {
+                Some(value) => signal.encode(&mut data, value)?,
+                None => signal.encode_raw(&mut data, signal.start_value())?,
+            }
+        }
+        Ok(data)
+    }
+
+    /// Number of switches above `signal`.
+    fn multiplex_depth(&self, signal: &Signal) -> usize {
+        let mut depth = 0;
+        let mut signal = signal;
+        while let Some(switch) = signal
+            .multiplexed
+            .as_ref()
+            .and_then(|multiplexing| self.signal(&multiplexing.switch))
+        {
+            depth += 1;
+            if depth >= MAX_MULTIPLEX_DEPTH {
+                break;
+            }
+            signal = switch;
+        }
+        depth
+    }
+
+    fn is_active(&self, signal: &Signal, data: &[u8], depth: usize) -> bool {
+        let Some(multiplexing) = &signal.multiplexed else {
+            return true;
+        };
+        let Some(switch) = self.signal(&multiplexing.switch) else {
+            return false;
+        };
+        depth < MAX_MULTIPLEX_DEPTH
+            && self.is_active(switch, data, depth + 1)
+            && switch.raw(data).is_some_and(|raw| {
+                multiplexing
+                    .values
+                    .iter()
+                    .any(|values| values.contains(&(raw as u64)))
+            })
+    }
+}
+
+/// The decoded signals of a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedMessage<'a> {
+    pub message: &'a MessageDefinition,
+    pub signals: Vec<DecodedSignal<'a>>,
+}
+
+impl<'a> DecodedMessage<'a> {
+    pub fn signal(&self, name: &str) -> Option<&DecodedSignal<'a>> {
+        self.signals.iter().find(|signal| signal.name() == name)
+    }
+
+    /// Returns the physical value of signal `name`.
+    pub fn value(&self, name: &str) -> Option<f64> {
+        self.signal(name).map(|signal| signal.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedSignal<'a> {
+    pub signal: &'a Signal,
+    /// See [Signal::raw](Signal::raw).
+    pub raw: i64,
+    /// Physical value.
+    pub value: f64,
+}
+
+impl DecodedSignal<'_> {
+    pub fn name(&self) -> &str {
+        &self.signal.name
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.signal.unit
+    }
+
+    /// The value description of the raw value, if the signal has one.
+    pub fn description(&self) -> Option<&str> {
+        self.signal.description(self.raw)
+    }
+}
+
+/// Formats as `name = value unit` or `name = value (description)`.
+impl fmt::Display for DecodedSignal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}", self.name(), self.value)?;
+        if !self.unit().is_empty() {
+            write!(f, " {}", self.unit())?;
+        }
+        if let Some(description) = self.description() {
+            write!(f, " ({description})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed DBC file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Database {
+    /// Network the messages of this database are [encoded](Database::encode) for.
+    netid: neonetid_t,
+    pub version: String,
+    pub nodes: Vec<String>,
+    messages: Vec<MessageDefinition>,
+    /// Message index by ID and extended flag.
+    ids: HashMap<(u32, bool), usize>,
+    /// Value tables defined with `VAL_TABLE_`.
+    pub value_tables: HashMap<String, ValueTable>,
+    /// Network attributes, including the defaults of attributes that aren't set.
+    pub attributes: HashMap<String, AttributeValue>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self {
+            netid: NetworkId::HSCAN.0,
+            version: String::new(),
+            nodes: Vec::new(),
+            messages: Vec::new(),
+            ids: HashMap::new(),
+            value_tables: HashMap::new(),
+            attributes: HashMap::new(),
+        }
+    }
+}
+
+impl Database {
+    /// Reads a DBC file. Files that aren't valid UTF-8 are read as Windows-1252, the usual
+    /// encoding of DBC files, approximated by Latin-1.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        match String::from_utf8(bytes) {
+            Ok(text) => text.parse(),
+            Err(e) => e
+                .into_bytes()
+                .iter()
+                .map(|&b| char::from(b))
+                .collect::<String>()
+                .parse(),
+        }
+    }
+
+    pub fn messages(&self) -> &[MessageDefinition] {
+        &self.messages
+    }
+
+    /// Looks up a message by name.
+    pub fn message(&self, name: &str) -> Option<&MessageDefinition> {
+        self.messages.iter().find(|message| message.name == name)
+    }
+
+    pub fn message_by_id(&self, id: u32, extended: bool) -> Option<&MessageDefinition> {
+        self.ids
+            .get(&(id, extended))
+            .map(|&index| &self.messages[index])
+    }
+
+    /// Decodes a received frame. Returns `None` for IDs not in the database, remote frames
+    /// and error frames.
+    pub fn decode<'a>(&'a self, message: &CanMessage) -> Option<DecodedMessage<'a>> {
+        let status = message.status;
+        if status::get(status, status::REMOTE) || status::get(status, status::ERROR_FRAME) {
+            return None;
+        }
+        let extended = status::get(status, status::EXTENDED);
+        let definition = self.message_by_id(message.arbid, extended)?;
+        Some(definition.decode(message.data()))
+    }
+
+    /// Sets the network [encode](Database::encode) creates frames for. Defaults to HSCAN.
+    pub fn set_netid(&mut self, netid: impl Into<NetworkId>) {
+        self.netid = netid.into().0;
+    }
+
+    pub fn netid(&self) -> NetworkId {
+        NetworkId(self.netid)
+    }
+
+    /// Builds a frame of message `name` from physical signal values, ready to
+    /// [transmit](crate::bus::Transmit::transmit). ID type, CAN FD and bit rate switch follow
+    /// the message definition. See [MessageDefinition::encode](MessageDefinition::encode).
+    pub fn encode(&self, name: &str, values: &[(&str, f64)]) -> Result<CanMessage> {
+        let definition = self
+            .message(name)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown message {name}")))?;
+        let mut data = definition.encode(values)?;
+        let max_length = if definition.fd { 64 } else { 8 };
+        if data.len() > max_length {
+            return Err(Error::InvalidArgument(format!(
+                "{name} has {} bytes, more than a {} frame holds",
+                data.len(),
+                if definition.fd { "CAN FD" } else { "CAN" }
+            )));
+        }
+        let dlc = len_to_dlc(data.len());
+        // CAN FD only has some payload lengths, the rest is padded.
+        data.resize(dlc_to_len(dlc).max(data.len()), 0);
+        let mut frame = CanMessage::new(self.netid, definition.id, &data);
+        frame.set_extended(definition.extended);
+        frame.set_fd(definition.fd);
+        frame.set_brs(definition.brs);
+        frame.set_dlc_on_wire(dlc);
+        Ok(frame)
+    }
+}
+
+impl std::str::FromStr for Database {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Parser::new(s)?.parse()
+    }
+}
+
+/// Reads `size` bits starting at `start` in DBC bit numbering.
+pub(crate) fn extract(data: &[u8], start: u32, size: u32, byte_order: ByteOrder) -> Option<u64> {
+    let mut value = 0u64;
+    let mut bit = start as usize;
+    for i in 0..size {
+        let byte = *data.get(bit / 8)?;
+        let set = u64::from(byte >> (bit % 8) & 1);
+        match byte_order {
+            ByteOrder::LittleEndian => {
+                value |= set << i;
+                bit += 1;
+            }
+            ByteOrder::BigEndian => {
+                value = value << 1 | set;
+                // Continue with the most significant bit of the next byte.
+                bit = if bit & 7 == 0 { bit + 15 } else { bit - 1 };
+            }
+        }
+    }
+    Some(value)
+}
+
+/// Writes the low `size` bits of `value` at `start`, the counterpart of [extract].
+fn insert(data: &mut [u8], start: u32, size: u32, byte_order: ByteOrder, value: u64) -> Option<()> {
+    let mut bit = start as usize;
+    for i in 0..size {
+        let shift = match byte_order {
+            ByteOrder::LittleEndian => i,
+            ByteOrder::BigEndian => size - 1 - i,
+        };
+        let byte = data.get_mut(bit / 8)?;
+        let mask = 1 << (bit % 8);
+        if value >> shift & 1 == 1 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+        bit = match byte_order {
+            ByteOrder::LittleEndian => bit + 1,
+            ByteOrder::BigEndian if bit & 7 == 0 => bit + 15,
+            ByteOrder::BigEndian => bit - 1,
+        };
+    }
+    Some(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Punct(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ident(s) | Self::Number(s) => write!(f, "{s}"),
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::Punct(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+/// Splits DBC text into tokens with their line numbers.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut previous = ' ';
+    while let Some(c) = chars.next() {
+        let start_line = line;
+        let token = match c {
+            '\n' => {
+                line += 1;
+                None
+            }
+            c if c.is_whitespace() => None,
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => s.push(c),
+                            None => break,
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            s.push(c);
+                        }
+                        None => {
+                            return Err(Error::ParseError(format!(
+                                "line {start_line}: unterminated string"
+                            )))
+                        }
+                    }
+                }
+                Some(Token::Str(s))
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut s = String::from(c);
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    s.push(c);
+                    chars.next();
+                }
+                Some(Token::Ident(s))
+            }
+            // A sign only starts a number after a separator, so `1-` in `@1-` and the
+            // range `0-3` in `SG_MUL_VAL_` stay punctuation.
+            c if c.is_ascii_digit()
+                || ((c == '-' || c == '+')
+                    && !previous.is_ascii_alphanumeric()
+                    && chars
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_digit() || *c == '.')) =>
+            {
+                let mut s = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    let exponent_sign = (c == '-' || c == '+') && s.ends_with(['e', 'E']);
+                    if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                        s.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Some(Token::Number(s))
+            }
+            c => Some(Token::Punct(c)),
+        };
+        if let Some(token) = token {
+            previous = match &token {
+                Token::Ident(_) | Token::Number(_) => 'a',
+                _ => c,
+            };
+            tokens.push((start_line, token));
+        } else {
+            previous = ' ';
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Object {
+    Network,
+    Node,
+    Message,
+    Signal,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttributeKind {
+    Int,
+    Float,
+    String,
+    Enum,
+}
+
+struct AttributeDefinition {
+    object: Object,
+    kind: AttributeKind,
+    values: Vec<String>,
+    default: Option<AttributeValue>,
+}
+
+/// A `BA_` statement. The target is the message id and, for signals, the signal name.
+struct AttributeAssignment {
+    name: String,
+    object: Object,
+    target: Option<(u32, Option<String>)>,
+    value: Token,
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    database: Database,
+    definitions: HashMap<String, AttributeDefinition>,
+    /// Attribute assignments, resolved once all definitions are known.
+    assignments: Vec<AttributeAssignment>,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(text)?,
+            position: 0,
+            database: Database::default(),
+            definitions: HashMap::new(),
+            assignments: Vec::new(),
+        })
+    }
+
+    fn parse(mut self) -> Result<Database> {
+        while let Some(token) = self.next() {
+            let Token::Ident(keyword) = token else {
+                return Err(self.error(format!("unexpected {token}")));
+            };
+            match keyword.as_str() {
+                "VERSION" => self.database.version = self.string()?,
+                "NS_" => self.skip_new_symbols(),
+                "BS_" => self.skip_line(),
+                "BU_" => self.parse_nodes()?,
+                "BO_" => self.parse_message()?,
+                "SG_" => self.parse_signal()?,
+                "CM_" => self.parse_comment()?,
+                "BA_DEF_" => self.parse_attribute_definition()?,
+                "BA_DEF_DEF_" => self.parse_attribute_default()?,
+                "BA_" => self.parse_attribute()?,
+                "VAL_TABLE_" => self.parse_value_table()?,
+                "VAL_" => self.parse_values()?,
+                "SIG_VALTYPE_" => self.parse_value_type()?,
+                "SG_MUL_VAL_" => self.parse_multiplexing()?,
+                _ => self.skip_statement(),
+            }
+        }
+        self.finish()
+    }
+
+    fn finish(mut self) -> Result<Database> {
+        for assignment in std::mem::take(&mut self.assignments) {
+            let AttributeAssignment {
+                name,
+                object,
+                target,
+                value,
+            } = assignment;
+            let Some(definition) = self.definitions.get(&name) else {
+                continue;
+            };
+            let value = resolve(definition, &value)
+                .ok_or_else(|| self.error(format!("invalid value {value} for attribute {name}")))?;
+            let attributes = match (object, target) {
+                (Object::Network, _) => &mut self.database.attributes,
+                (Object::Message, Some((id, None))) => match self.message_mut(id) {
+                    Some(message) => &mut message.attributes,
+                    None => continue,
+                },
+                (Object::Signal, Some((id, Some(signal)))) => match self.signal_mut(id, &signal) {
+                    Some(signal) => &mut signal.attributes,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            attributes.insert(name, value);
+        }
+
+        let database = &mut self.database;
+        for (name, definition) in &self.definitions {
+            let Some(default) = &definition.default else {
+                continue;
+            };
+            let defaults = |attributes: &mut HashMap<String, AttributeValue>| {
+                attributes
+                    .entry(name.clone())
+                    .or_insert_with(|| default.clone());
+            };
+            match definition.object {
+                Object::Network => defaults(&mut database.attributes),
+                Object::Message => database
+                    .messages
+                    .iter_mut()
+                    .for_each(|message| defaults(&mut message.attributes)),
+                Object::Signal => database
+                    .messages
+                    .iter_mut()
+                    .flat_map(|message| &mut message.signals)
+                    .for_each(|signal| defaults(&mut signal.attributes)),
+                _ => {}
+            }
+        }
+
+        for message in &mut database.messages {
+            let format = message.attributes.get("VFrameFormat");
+            message.fd = message.size > 8
+                || format
+                    .and_then(AttributeValue::as_str)
+                    .is_some_and(|f| f.contains("FD"));
+            message.brs = message.fd
+                && message
+                    .attributes
+                    .get("CANFD_BRS")
+                    .and_then(AttributeValue::as_f64)
+                    .is_none_or(|brs| brs != 0.0);
+
+            let switch = message
+                .signals
+                .iter()
+                .find(|signal| signal.multiplexer)
+                .map(|signal| signal.name.clone());
+            for signal in &mut message.signals {
+                let Some(multiplexing) = &mut signal.multiplexed else {
+                    continue;
+                };
+                if multiplexing.switch.is_empty() {
+                    multiplexing.switch = switch.clone().ok_or_else(|| {
+                        Error::ParseError(format!(
+                            "multiplexed signal {} of {} without a multiplexer",
+                            signal.name, message.name
+                        ))
+                    })?;
+                }
+            }
+        }
+        Ok(self.database)
+    }
+
+    fn parse_nodes(&mut self) -> Result<()> {
+        self.expect(':')?;
+        let line = self.line();
+        while self.peek_line() == Some(line) {
+            let node = self.ident()?;
+            self.database.nodes.push(node);
+        }
+        Ok(())
+    }
+
+    fn parse_message(&mut self) -> Result<()> {
+        let raw_id: u32 = self.number()?;
+        let name = self.ident()?;
+        self.expect(':')?;
+        let size: usize = self.number()?;
+        let line = self.line();
+        let transmitter = match self.peek_line() == Some(line) {
+            true => Some(self.ident()?).filter(|node| node != "Vector__XXX"),
+            false => None,
+        };
+        let extended = raw_id & EXTENDED_ID_FLAG != 0;
+        let id = if extended {
+            raw_id & EXTENDED_ID_MASK
+        } else {
+            raw_id
+        };
+        self.database
+            .ids
+            .insert((id, extended), self.database.messages.len());
+        self.database.messages.push(MessageDefinition {
+            id,
+            extended,
+            name,
+            size,
+            transmitter,
+            signals: Vec::new(),
+            comment: None,
+            attributes: HashMap::new(),
+            fd: false,
+            brs: false,
+        });
+        Ok(())
+    }
+
+    fn parse_signal(&mut self) -> Result<()> {
+        let name = self.ident()?;
+        let (mut multiplexer, mut multiplexed) = (false, None);
+        if let Some(Token::Ident(mux)) = self.peek() {
+            let mux = mux.clone();
+            self.next();
+            if mux == "M" {
+                multiplexer = true;
+            } else if let Some(value) = mux.strip_prefix('m') {
+                let value = value.strip_suffix('M').map_or(value, |value| {
+                    multiplexer = true;
+                    value
+                });
+                let value = value
+                    .parse::<u64>()
+                    .map_err(|_| self.error(format!("invalid multiplexer {mux}")))?;
+                multiplexed = Some(Multiplexing {
+                    switch: String::new(),
+                    values: vec![value..=value],
+                });
+            } else {
+                return Err(self.error(format!("invalid multiplexer {mux}")));
+            }
+        }
+        self.expect(':')?;
+        let start_bit = self.number()?;
+        self.expect('|')?;
+        let size: u32 = self.number()?;
+        self.expect('@')?;
+        let byte_order = match self.number::<u8>()? {
+            0 => ByteOrder::BigEndian,
+            1 => ByteOrder::LittleEndian,
+            order => return Err(self.error(format!("invalid byte order {order}"))),
+        };
+        let value_type = match self.next() {
+            Some(Token::Punct('+')) => ValueType::Unsigned,
+            Some(Token::Punct('-')) => ValueType::Signed,
+            _ => return Err(self.error("expected + or -".to_string())),
+        };
+        self.expect('(')?;
+        let factor = self.number()?;
+        self.expect(',')?;
+        let offset = self.number()?;
+        self.expect(')')?;
+        self.expect('[')?;
+        let minimum = self.number()?;
+        self.expect('|')?;
+        let maximum = self.number()?;
+        self.expect(']')?;
+        let unit = self.string()?;
+        let line = self.line();
+        let mut receivers = Vec::new();
+        while self.peek_line() == Some(line) {
+            match self.next() {
+                Some(Token::Ident(node)) if node != "Vector__XXX" => receivers.push(node),
+                Some(Token::Ident(_) | Token::Punct(',')) => {}
+                _ => return Err(self.error("expected receiver".to_string())),
+            }
+        }
+        if !(1..=64).contains(&size) {
+            return Err(self.error(format!("invalid size {size} of signal {name}")));
+        }
+        let message = self
+            .database
+            .messages
+            .last_mut()
+            .ok_or_else(|| Error::ParseError(format!("line {line}: signal outside of BO_")))?;
+        message.signals.push(Signal {
+            name,
+            start_bit,
+            size,
+            byte_order,
+            value_type,
+            factor,
+            offset,
+            minimum,
+            maximum,
+            unit,
+            receivers,
+            multiplexer,
+            multiplexed,
+            values: ValueTable::new(),
+            comment: None,
+            attributes: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn parse_comment(&mut self) -> Result<()> {
+        match self.next() {
+            Some(Token::Ident(object)) if object == "BO_" => {
+                let id = self.number()?;
+                let comment = self.string()?;
+                if let Some(message) = self.message_mut(id) {
+                    message.comment = Some(comment);
+                }
+            }
+            Some(Token::Ident(object)) if object == "SG_" => {
+                let id = self.number()?;
+                let name = self.ident()?;
+                let comment = self.string()?;
+                if let Some(signal) = self.signal_mut(id, &name) {
+                    signal.comment = Some(comment);
+                }
+            }
+            _ => {}
+        }
+        self.skip_statement();
+        Ok(())
+    }
+
+    fn parse_attribute_definition(&mut self) -> Result<()> {
+        let object = self.object();
+        let name = self.string()?;
+        let kind = match self.ident()?.as_str() {
+            "INT" | "HEX" => AttributeKind::Int,
+            "FLOAT" => AttributeKind::Float,
+            "STRING" => AttributeKind::String,
+            "ENUM" => AttributeKind::Enum,
+            kind => return Err(self.error(format!("unknown attribute type {kind}"))),
+        };
+        let mut values = Vec::new();
+        while let Some(token) = self.next() {
+            match token {
+                Token::Punct(';') => break,
+                Token::Str(value) if kind == AttributeKind::Enum => values.push(value),
+                _ => {}
+            }
+        }
+        self.definitions.insert(
+            name,
+            AttributeDefinition {
+                object,
+                kind,
+                values,
+                default: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn parse_attribute_default(&mut self) -> Result<()> {
+        let name = self.string()?;
+        let value = self
+            .next()
+            .ok_or_else(|| self.error("expected attribute value".to_string()))?;
+        self.skip_statement();
+        if let Some(definition) = self.definitions.get(&name) {
+            let default = resolve(definition, &value)
+                .ok_or_else(|| self.error(format!("invalid default {value} for {name}")))?;
+            self.definitions.get_mut(&name).unwrap().default = Some(default);
+        }
+        Ok(())
+    }
+
+    fn parse_attribute(&mut self) -> Result<()> {
+        let name = self.string()?;
+        let object = self.object();
+        let target = match object {
+            Object::Message => Some((self.number()?, None)),
+            Object::Signal => Some((self.number()?, Some(self.ident()?))),
+            Object::Node | Object::Other => {
+                self.next();
+                None
+            }
+            Object::Network => None,
+        };
+        let value = self
+            .next()
+            .ok_or_else(|| self.error("expected attribute value".to_string()))?;
+        self.skip_statement();
+        self.assignments.push(AttributeAssignment {
+            name,
+            object,
+            target,
+            value,
+        });
+        Ok(())
+    }
+
+    fn parse_value_table(&mut self) -> Result<()> {
+        let name = self.ident()?;
+        let values = self.value_descriptions()?;
+        self.database.value_tables.insert(name, values);
+        Ok(())
+    }
+
+    fn parse_values(&mut self) -> Result<()> {
+        // Environment variables have value descriptions too, they are skipped.
+        if !matches!(self.peek(), Some(Token::Number(_))) {
+            self.skip_statement();
+            return Ok(());
+        }
+        let id = self.number()?;
+        let name = self.ident()?;
+        let values = self.value_descriptions()?;
+        if let Some(signal) = self.signal_mut(id, &name) {
+            signal.values = values;
+        }
+        Ok(())
+    }
+
+    fn value_descriptions(&mut self) -> Result<ValueTable> {
+        let mut values = ValueTable::new();
+        loop {
+            match self.next() {
+                Some(Token::Punct(';')) => return Ok(values),
+                Some(Token::Number(value)) => {
+                    // Some tools write the values of unsigned signals as doubles.
+                    let value = value
+                        .parse::<i64>()
+                        .ok()
+                        .or_else(|| value.parse::<f64>().ok().map(|value| value as i64))
+                        .ok_or_else(|| self.error(format!("invalid value {value}")))?;
+                    let description = self.string()?;
+                    values.insert(value, description);
+                }
+                _ => return Err(self.error("expected value description".to_string())),
+            }
+        }
+    }
+
+    fn parse_value_type(&mut self) -> Result<()> {
+        let id = self.number()?;
+        let name = self.ident()?;
+        if self.peek() == Some(&Token::Punct(':')) {
+            self.next();
+        }
+        let value_type = match self.number::<u8>()? {
+            1 => ValueType::Float32,
+            2 => ValueType::Float64,
+            _ => {
+                self.skip_statement();
+                return Ok(());
+            }
+        };
+        self.skip_statement();
+        if let Some(signal) = self.signal_mut(id, &name) {
+            signal.value_type = value_type;
+        }
+        Ok(())
+    }
+
+    fn parse_multiplexing(&mut self) -> Result<()> {
+        let id = self.number()?;
+        let name = self.ident()?;
+        let switch = self.ident()?;
+        let mut values = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Punct(';')) => break,
+                Some(Token::Punct(',')) => {}
+                Some(Token::Number(low)) => {
+                    self.expect('-')?;
+                    let high: u64 = self.number()?;
+                    let low = low
+                        .parse::<u64>()
+                        .map_err(|_| self.error(format!("invalid multiplexer value {low}")))?;
+                    values.push(low..=high);
+                }
+                _ => return Err(self.error("expected multiplexer value range".to_string())),
+            }
+        }
+        if let Some(signal) = self.signal_mut(id, &name) {
+            signal.multiplexed = Some(Multiplexing { switch, values });
+        }
+        Ok(())
+    }
+
+    fn message_mut(&mut self, raw_id: u32) -> Option<&mut MessageDefinition> {
+        let key = if raw_id & EXTENDED_ID_FLAG != 0 {
+            (raw_id & EXTENDED_ID_MASK, true)
+        } else {
+            (raw_id, false)
+        };
+        let index = *self.database.ids.get(&key)?;
+        self.database.messages.get_mut(index)
+    }
+
+    fn signal_mut(&mut self, raw_id: u32, name: &str) -> Option<&mut Signal> {
+        let message = self.message_mut(raw_id)?;
+        message
+            .signals
+            .iter_mut()
+            .find(|signal| signal.name == name)
+    }
+
+    fn object(&mut self) -> Object {
+        let object = match self.peek() {
+            Some(Token::Ident(object)) => match object.as_str() {
+                "BU_" => Object::Node,
+                "BO_" => Object::Message,
+                "SG_" => Object::Signal,
+                "EV_" => Object::Other,
+                _ => return Object::Network,
+            },
+            _ => return Object::Network,
+        };
+        self.next();
+        object
+    }
+
+    /// Skips the `NS_` section, a list of keywords ending at `BS_`.
+    fn skip_new_symbols(&mut self) {
+        while let Some(token) = self.peek() {
+            if let Token::Ident(keyword) = token {
+                if matches!(keyword.as_str(), "BS_" | "BU_" | "BO_") {
+                    return;
+                }
+            }
+            self.next();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        let line = self.line();
+        while self.peek_line() == Some(line) {
+            self.next();
+        }
+    }
+
+    fn skip_statement(&mut self) {
+        while let Some(token) = self.next() {
+            if token == Token::Punct(';') {
+                return;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn peek_line(&self) -> Option<usize> {
+        self.tokens.get(self.position).map(|(line, _)| *line)
+    }
+
+    /// Line of the last consumed token.
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position.saturating_sub(1))
+            .map_or(0, |(line, _)| *line)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(_, token)| token.clone());
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        match self.next() {
+            Some(Token::Punct(c)) if c == punct => Ok(()),
+            Some(token) => Err(self.error(format!("expected {punct}, found {token}"))),
+            None => Err(self.error(format!("expected {punct}"))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(s)) => Ok(s),
+            Some(token) => Err(self.error(format!("expected identifier, found {token}"))),
+            None => Err(self.error("expected identifier".to_string())),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            Some(token) => Err(self.error(format!("expected string, found {token}"))),
+            None => Err(self.error("expected string".to_string())),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T> {
+        match self.next() {
+            Some(Token::Number(s)) => s
+                .parse()
+                .map_err(|_| self.error(format!("invalid number {s}"))),
+            Some(token) => Err(self.error(format!("expected number, found {token}"))),
+            None => Err(self.error("expected number".to_string())),
+        }
+    }
+
+    fn error(&self, message: String) -> Error {
+        Error::ParseError(format!("DBC line {}: {message}", self.line()))
+    }
+}
+
+/// Converts an attribute value token according to its definition.
+fn resolve(definition: &AttributeDefinition, value: &Token) -> Option<AttributeValue> {
+    match (definition.kind, value) {
+        (AttributeKind::Enum, Token::Number(index)) => {
+            let index: usize = index.parse().ok()?;
+            Some(AttributeValue::String(
+                definition.values.get(index)?.clone(),
+            ))
+        }
+        (AttributeKind::Enum | AttributeKind::String, Token::Str(s)) => {
+            Some(AttributeValue::String(s.clone()))
+        }
+        (AttributeKind::Int, Token::Number(n)) => n
+            .parse()
+            .ok()
+            .or_else(|| n.parse::<f64>().ok().map(|n| n as i64))
+            .map(AttributeValue::Int),
+        (AttributeKind::Float, Token::Number(n)) => n.parse().ok().map(AttributeValue::Float),
+        (AttributeKind::Int | AttributeKind::Float, Token::Str(s)) => match s.parse::<i64>() {
+            Ok(n) if definition.kind == AttributeKind::Int => Some(AttributeValue::Int(n)),
+            _ => s.parse().ok().map(AttributeValue::Float),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DBC: &str = r#"VERSION "1.2"
+
+NS_ :
+	NS_DESC_
+	CM_
+	BA_DEF_
+	BA_
+	VAL_
+	SIG_VALTYPE_
+	SG_MUL_VAL_
+
+BS_:
+
+BU_: ECU Tester
+
+VAL_TABLE_ GearTable 0 "Neutral" 1 "First" 2 "Second" 3 "Third" ;
+
+BO_ 256 EngineStatus: 8 ECU
+ SG_ RPM : 0|16@1+ (0.25,0) [0|16383.75] "rpm" Tester
+ SG_ Gear : 16|4@1+ (1,0) [0|15] "" Tester,ECU
+ SG_ Temp : 31|12@0- (0.1,-40) [-244.8|164.7] "degC" Vector__XXX
+ SG_ Nibble : 20|8@1+ (1,0) [0|255] "" Vector__XXX
+
+BO_ 2566844926 Pressure: 8 Vector__XXX
+ SG_ Pressure : 0|32@1- (1,0) [-1E+038|1E+038] "kPa" Vector__XXX
+ SG_ Counter : 39|4@0+ (1,0) [0|15] "" Vector__XXX
+
+BO_ 512 Muxed: 8 ECU
+ SG_ Selector M : 0|8@1+ (1,0) [0|255] "" Vector__XXX
+ SG_ A m0 : 8|16@1+ (1,0) [0|65535] "" Vector__XXX
+ SG_ B m1 : 8|16@1- (0.5,0) [-16384|16383.5] "V" Vector__XXX
+ SG_ Sub m1M : 24|8@1+ (1,0) [0|255] "" Vector__XXX
+ SG_ C m2 : 32|8@1+ (1,0) [0|255] "" Vector__XXX
+
+BO_ 768 FdData: 64 ECU
+ SG_ Last : 504|8@1+ (1,0) [0|255] "" Vector__XXX
+ SG_ Double : 0|64@1+ (1,0) [0|0] "" Vector__XXX
+
+BO_ 769 FdShort: 8 ECU
+ SG_ Value : 0|8@1+ (1,0) [0|255] "" Vector__XXX
+
+CM_ "Network comment";
+CM_ BU_ ECU "The engine controller";
+CM_ BO_ 256 "Engine state
+on two lines";
+CM_ SG_ 256 RPM "Engine \"speed\"";
+BA_DEF_ BO_  "GenMsgCycleTime" INT 0 65535;
+BA_DEF_ BO_  "VFrameFormat" ENUM  "StandardCAN","ExtendedCAN","reserved","J1939PG","reserved","reserved","reserved","reserved","reserved","reserved","reserved","reserved","reserved","reserved","StandardCAN_FD","ExtendedCAN_FD";
+BA_DEF_ BO_  "CANFD_BRS" ENUM  "0","1";
+BA_DEF_ SG_  "GenSigStartValue" FLOAT -3.4E+038 3.4E+038;
+BA_DEF_  "BusType" STRING ;
+BA_DEF_ BU_  "NodeLayerModules" STRING ;
+BA_DEF_DEF_  "GenMsgCycleTime" 0;
+BA_DEF_DEF_  "VFrameFormat" "StandardCAN";
+BA_DEF_DEF_  "CANFD_BRS" "1";
+BA_DEF_DEF_  "GenSigStartValue" 0;
+BA_DEF_DEF_  "BusType" "";
+BA_ "BusType" "CAN FD";
+BA_ "NodeLayerModules" BU_ ECU "CANoeILNVector.dll";
+BA_ "GenMsgCycleTime" BO_ 256 100;
+BA_ "VFrameFormat" BO_ 2566844926 3;
+BA_ "VFrameFormat" BO_ 768 14;
+BA_ "VFrameFormat" BO_ 769 14;
+BA_ "CANFD_BRS" BO_ 769 0;
+BA_ "GenSigStartValue" SG_ 256 RPM 3200;
+VAL_ 256 Gear 0 "Neutral" 1 "First" 2 "Second" 3 "Third" ;
+VAL_ 512 Selector 0 "Plain" 1 "Nested" ;
+SIG_VALTYPE_ 2566844926 Pressure : 1;
+SIG_VALTYPE_ 768 Double : 2;
+SG_MUL_VAL_ 512 C Sub 2-3, 5-5;
+"#;
+
+    fn database() -> Database {
+        DBC.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let db = database();
+        assert_eq!(db.version, "1.2");
+        assert_eq!(db.nodes, ["ECU", "Tester"]);
+        assert_eq!(db.messages().len(), 5);
+        assert_eq!(db.value_tables["GearTable"][&3], "Third");
+        assert_eq!(
+            db.attributes["BusType"],
+            AttributeValue::String("CAN FD".to_string())
+        );
+
+        let engine = db.message("EngineStatus").unwrap();
+        assert_eq!((engine.id, engine.extended, engine.size), (0x100, false, 8));
+        assert_eq!(engine.transmitter.as_deref(), Some("ECU"));
+        assert_eq!(
+            engine.comment.as_deref(),
+            Some("Engine state\non two lines")
+        );
+        assert_eq!(
+            engine.attributes["GenMsgCycleTime"],
+            AttributeValue::Int(100)
+        );
+        assert_eq!(
+            engine.attributes["VFrameFormat"],
+            AttributeValue::String("StandardCAN".to_string())
+        );
+        assert!(!engine.fd && !engine.brs);
+
+        let rpm = engine.signal("RPM").unwrap();
+        assert_eq!(rpm.comment.as_deref(), Some("Engine \"speed\""));
+        assert_eq!((rpm.factor, rpm.maximum), (0.25, 16383.75));
+        assert_eq!(rpm.unit, "rpm");
+        assert_eq!(rpm.receivers, ["Tester"]);
+        assert_eq!(rpm.attributes["GenSigStartValue"].as_f64(), Some(3200.0));
+        let gear = engine.signal("Gear").unwrap();
+        assert_eq!(gear.receivers, ["Tester", "ECU"]);
+        assert_eq!(gear.attributes["GenSigStartValue"].as_f64(), Some(0.0));
+        let temp = engine.signal("Temp").unwrap();
+        assert_eq!(temp.byte_order, ByteOrder::BigEndian);
+        assert_eq!(temp.value_type, ValueType::Signed);
+        assert_eq!((temp.offset, temp.minimum), (-40.0, -244.8));
+
+        let pressure = db.message_by_id(0x18FEF1FE, true).unwrap();
+        assert_eq!(pressure.name, "Pressure");
+        assert_eq!(pressure.transmitter, None);
+        assert!(db.message_by_id(0x18FEF1FE, false).is_none());
+        assert_eq!(
+            pressure.signal("Pressure").unwrap().value_type,
+            ValueType::Float32
+        );
+
+        let muxed = db.message("Muxed").unwrap();
+        assert!(muxed.signal("Selector").unwrap().multiplexer);
+        let sub = muxed.signal("Sub").unwrap();
+        assert!(sub.multiplexer);
+        assert_eq!(
+            sub.multiplexed,
+            Some(Multiplexing {
+                switch: "Selector".to_string(),
+                values: vec![1..=1],
+            })
+        );
+        assert_eq!(
+            muxed.signal("C").unwrap().multiplexed,
+            Some(Multiplexing {
+                switch: "Sub".to_string(),
+                values: vec![2..=3, 5..=5],
+            })
+        );
+
+        let fd = db.message("FdData").unwrap();
+        assert!(fd.fd && fd.brs);
+        let fd = db.message("FdShort").unwrap();
+        assert!(fd.fd && !fd.brs);
+    }
+
+    #[test]
+    fn test_bit_layout() {
+        let data = [0x12, 0x34, 0xF0, 0x0A];
+        // Intel: the start bit is the LSB, bytes in increasing order.
+        assert_eq!(extract(&data, 0, 16, ByteOrder::LittleEndian), Some(0x3412));
+        assert_eq!(extract(&data, 20, 8, ByteOrder::LittleEndian), Some(0xAF));
+        assert_eq!(extract(&data, 1, 3, ByteOrder::LittleEndian), Some(0b001));
+        // Motorola: the start bit is the MSB, continuing at bit 7 of the next byte.
+        assert_eq!(extract(&data, 7, 16, ByteOrder::BigEndian), Some(0x1234));
+        assert_eq!(extract(&data, 3, 8, ByteOrder::BigEndian), Some(0x23));
+        assert_eq!(extract(&data, 23, 12, ByteOrder::BigEndian), Some(0xF00));
+        assert_eq!(extract(&data, 31, 9, ByteOrder::BigEndian), None);
+        assert_eq!(extract(&data, 28, 8, ByteOrder::LittleEndian), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        let db = database();
+        // RPM 2500, gear 3, nibble 0xC0 over bytes 2 and 3, temp 0x9C4 (-1596) in Motorola
+        // order starting at bit 31.
+        let mut frame = CanMessage::new(1, 0x100, &[0x10, 0x27, 0x03, 0x9C, 0x40, 0, 0, 0]);
+        frame.set_transmit(true);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.message.name, "EngineStatus");
+        assert_eq!(decoded.value("RPM"), Some(2500.0));
+        assert_eq!(decoded.value("Gear"), Some(3.0));
+        assert_eq!(decoded.value("Nibble"), Some(0xC0 as f64));
+        let temp = decoded.signal("Temp").unwrap();
+        assert_eq!(temp.raw, 0x9C4 - 0x1000);
+        assert!((temp.value - (-1596.0 * 0.1 - 40.0)).abs() < 1e-9);
+        assert_eq!(decoded.signal("RPM").unwrap().to_string(), "RPM = 2500 rpm");
+        assert_eq!(decoded.signal("Gear").unwrap().description(), Some("Third"));
+        assert_eq!(
+            decoded.signal("Gear").unwrap().to_string(),
+            "Gear = 3 (Third)"
+        );
+
+        // Short frames only decode the signals that fit.
+        let frame = CanMessage::new(1, 0x100, &[0x10, 0x27]);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.signals.len(), 1);
+
+        // Unknown IDs, ID type mismatches, remote and error frames.
+        assert!(db.decode(&CanMessage::new(1, 0x101, &[0; 8])).is_none());
+        let mut frame = CanMessage::new(1, 0x100, &[0; 8]);
+        frame.set_extended(true);
+        assert!(db.decode(&frame).is_none());
+        let mut frame = CanMessage::new(1, 0x100, &[]);
+        frame.set_remote(true);
+        assert!(db.decode(&frame).is_none());
+
+        let mut frame = CanMessage::new(1, 0x18FEF1FE, &[0; 8]);
+        frame.set_extended(true);
+        let pressure = (-1013.25f32).to_bits().to_le_bytes();
+        frame.set_data(&[
+            pressure[0],
+            pressure[1],
+            pressure[2],
+            pressure[3],
+            0x50,
+            0,
+            0,
+            0,
+        ]);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.value("Pressure"), Some(-1013.25));
+        assert_eq!(decoded.value("Counter"), Some(5.0));
+    }
+
+    #[test]
+    fn test_decode_multiplexed() {
+        let db = database();
+        let names = |data: &[u8]| -> Vec<(String, f64)> {
+            let frame = CanMessage::new(1, 0x200, data);
+            let decoded = db.decode(&frame).unwrap();
+            decoded
+                .signals
+                .iter()
+                .map(|signal| (signal.name().to_string(), signal.value))
+                .collect()
+        };
+        let signals = |list: &[(&str, f64)]| -> Vec<(String, f64)> {
+            list.iter().map(|(n, v)| (n.to_string(), *v)).collect()
+        };
+        assert_eq!(
+            names(&[0, 0xff, 0xff, 2, 7, 0, 0, 0]),
+            signals(&[("Selector", 0.0), ("A", 65535.0)])
+        );
+        assert_eq!(
+            names(&[1, 0xff, 0xff, 4, 7, 0, 0, 0]),
+            signals(&[("Selector", 1.0), ("B", -0.5), ("Sub", 4.0)])
+        );
+        assert_eq!(
+            names(&[1, 0x10, 0, 3, 7, 0, 0, 0]),
+            signals(&[("Selector", 1.0), ("B", 8.0), ("Sub", 3.0), ("C", 7.0)])
+        );
+        assert_eq!(
+            names(&[1, 0x10, 0, 5, 7, 0, 0, 0]),
+            signals(&[("Selector", 1.0), ("B", 8.0), ("Sub", 5.0), ("C", 7.0)])
+        );
+        // Sub has the right value but is inactive itself.
+        assert_eq!(
+            names(&[2, 0, 0, 3, 7, 0, 0, 0]),
+            signals(&[("Selector", 2.0)])
+        );
+    }
+
+    #[test]
+    fn test_decode_fd() {
+        let db = database();
+        let mut data = vec![0u8; 64];
+        data[..8].copy_from_slice(&1234.5f64.to_bits().to_le_bytes());
+        data[63] = 0x42;
+        let mut frame = CanMessage::new(1, 0x300, &data);
+        frame.set_fd(true);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.value("Double"), Some(1234.5));
+        assert_eq!(decoded.value("Last"), Some(66.0));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match text.parse::<Database>() {
+            Err(Error::ParseError(message)) => message,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(error("BO_ 1 M: 8 ECU\n SG_ S : 0|8@2+ (1,0) [0|0] \"\" X\n").contains("line 2"));
+        assert!(error("BO_ 1 M: 8 ECU\n SG_ S : 0|0@1+ (1,0) [0|0] \"\" X\n").contains("size"));
+        assert!(error(" SG_ S : 0|8@1+ (1,0) [0|0] \"\" X\n").contains("outside"));
+        assert!(
+            error("BO_ 1 M: 8 ECU\n SG_ S m1 : 0|8@1+ (1,0) [0|0] \"\" X\n")
+                .contains("without a multiplexer")
+        );
+        assert!(error("CM_ \"unterminated").contains("unterminated"));
+        assert!(error("BA_DEF_ BO_ \"X\" ENUM \"a\";\nBA_DEF_DEF_ \"X\" 3;").contains("line 2"));
+        // Unknown statements are skipped.
+        let db: Database = "EV_ Env: 0 [0|1] \"\" 0 1 DUMMY_NODE_VECTOR0 Vector__XXX;\n\
+                            VAL_ Env 0 \"Off\" 1 \"On\" ;\n\
+                            BO_TX_BU_ 1 : ECU,Tester;"
+            .parse()
+            .unwrap();
+        assert!(db.messages().is_empty());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut db = database();
+        let frame = db
+            .encode(
+                "EngineStatus",
+                &[
+                    ("RPM", 2500.0),
+                    ("Gear", 3.0),
+                    ("Temp", -199.6),
+                    ("Nibble", 192.0),
+                ],
+            )
+            .unwrap();
+        assert_eq!({ frame.netid }, NetworkId::HSCAN.0);
+        assert_eq!({ frame.arbid }, 0x100);
+        assert_eq!({ frame.dlcOnWire }, 8);
+        assert!(!frame.is_extended() && !frame.is_fd());
+        assert_eq!(frame.data(), &[0x10, 0x27, 0x03, 0x9C, 0x40, 0, 0, 0]);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.value("Gear"), Some(3.0));
+        assert!((decoded.value("Temp").unwrap() - -199.6).abs() < 1e-9);
+
+        // Unspecified signals use their start value, the last value given wins.
+        db.set_netid(NetworkId::HSCAN2);
+        let frame = db
+            .encode("EngineStatus", &[("Gear", 1.0), ("Gear", 2.0)])
+            .unwrap();
+        assert_eq!({ frame.netid }, NetworkId::HSCAN2.0);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.value("RPM"), Some(800.0));
+        assert_eq!(decoded.value("Gear"), Some(2.0));
+
+        let frame = db
+            .encode("Pressure", &[("Pressure", -1013.25), ("Counter", 5.0)])
+            .unwrap();
+        assert!(frame.is_extended());
+        assert_eq!({ frame.arbid }, 0x18FEF1FE);
+        let decoded = db.decode(&frame).unwrap();
+        assert_eq!(decoded.value("Pressure"), Some(-1013.25));
+        assert_eq!(decoded.value("Counter"), Some(5.0));
+    }
+
+    #[test]
+    fn test_encode_fd() {
+        let db = database();
+        let frame = db
+            .encode("FdData", &[("Double", 1234.5), ("Last", 66.0)])
+            .unwrap();
+        assert!(frame.is_fd() && frame.is_brs());
+        assert_eq!({ frame.dlcOnWire }, 15);
+        assert_eq!(frame.data().len(), 64);
+        assert_eq!(frame.data()[63], 66);
+        assert_eq!(db.decode(&frame).unwrap().value("Double"), Some(1234.5));
+
+        let frame = db.encode("FdShort", &[("Value", 1.0)]).unwrap();
+        assert!(frame.is_fd() && !frame.is_brs());
+        assert_eq!(frame.data(), &[1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // CAN FD lengths in between DLCs are padded.
+        let mut db: Database = "BO_ 1 Odd: 10 ECU\n SG_ S : 0|8@1+ (1,0) [0|0] \"\" X\n"
+            .parse()
+            .unwrap();
+        db.set_netid(NetworkId::HSCAN3);
+        let frame = db.encode("Odd", &[]).unwrap();
+        assert!(frame.is_fd());
+        assert_eq!({ frame.dlcOnWire }, 9);
+        assert_eq!(frame.data().len(), 12);
+
+        // Payloads that don't fit a frame.
+        let mut db: Database = "BO_ 1 Huge: 72 ECU\nBO_ 2 Classic: 10 ECU\n"
+            .parse()
+            .unwrap();
+        db.messages[1].fd = false;
+        for name in ["Huge", "Classic"] {
+            assert!(matches!(
+                db.encode(name, &[]),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_encode_multiplexed() {
+        let db = database();
+        let encode = |values: &[(&str, f64)]| db.encode("Muxed", values).map(|f| f.data().to_vec());
+        assert_eq!(
+            encode(&[("A", 0x1234 as f64)]).unwrap(),
+            [0, 0x34, 0x12, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(&[("Selector", 1.0), ("B", -0.5), ("Sub", 5.0), ("C", 7.0)]).unwrap(),
+            [1, 0xff, 0xff, 5, 7, 0, 0, 0]
+        );
+        let error = encode(&[("Selector", 1.0), ("A", 1.0)]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid Argument: A isn't present in Muxed while Selector is 1"
+        );
+        assert!(encode(&[("Selector", 1.0), ("Sub", 4.0), ("C", 7.0)]).is_err());
+        assert!(encode(&[("Selector", 0.0), ("C", 7.0)]).is_err());
+    }
+
+    #[test]
+    fn test_encode_range() {
+        let db = database();
+        let error = |message: &str, values: &[(&str, f64)]| match db.encode(message, values) {
+            Err(Error::InvalidArgument(error)) => error,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(
+            error("EngineStatus", &[("RPM", 16384.0)]),
+            "RPM = 16384 rpm is out of range [0, 16383.75]"
+        );
+        assert_eq!(
+            error("EngineStatus", &[("RPM", -1.0)]),
+            "RPM = -1 rpm is out of range [0, 16383.75]"
+        );
+        assert!(db.encode("EngineStatus", &[("RPM", 16383.75)]).is_ok());
+        assert!(db.encode("EngineStatus", &[("Gear", f64::NAN)]).is_err());
+        assert_eq!(
+            error("EngineStatus", &[("Speed", 1.0)]),
+            "EngineStatus has no signal Speed"
+        );
+        assert_eq!(error("Engine", &[]), "unknown message Engine");
+
+        // Unlimited ranges still have to fit the signal's bits.
+        let db: Database = "BO_ 1 M: 8 ECU\n SG_ S : 0|8@1- (0.5,10) [0|0] \"V\" X\n"
+            .parse()
+            .unwrap();
+        let signal = db.message("M").unwrap().signal("S").unwrap();
+        assert_eq!(signal.to_raw(73.5).unwrap(), 127);
+        assert_eq!(signal.to_raw(-54.0).unwrap(), -128);
+        assert_eq!(signal.to_raw(10.2).unwrap(), 0);
+        assert_eq!(
+            signal.to_raw(74.0).unwrap_err().to_string(),
+            "Invalid Argument: S = 74 V is out of range [-54, 73.5] of its 8 bits"
+        );
+    }
+
+    #[test]
+    fn test_insert_extract() {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            // The whole payload starts at bit 0 in Intel and bit 7 in Motorola order.
+            let all = match byte_order {
+                ByteOrder::LittleEndian => (0, 64),
+                ByteOrder::BigEndian => (7, 64),
+            };
+            for (start, size) in [(0, 1), (7, 8), (3, 13), (12, 20), (39, 25), all] {
+                let value = 0x5a5a_5a5a_5a5a_5a5au64 & (u64::MAX >> (64 - size));
+                let mut data = [0xffu8; 8];
+                insert(&mut data, start, size, byte_order, value).unwrap();
+                assert_eq!(extract(&data, start, size, byte_order), Some(value));
+                // Bits outside the signal are untouched.
+                insert(&mut data, start, size, byte_order, u64::MAX).unwrap();
+                assert_eq!(data, [0xff; 8]);
+            }
+        }
+        assert!(insert(&mut [0u8; 2], 12, 8, ByteOrder::LittleEndian, 0).is_none());
+    }
+}
@@ -0,0 +1,623 @@
+//! Devices whose state is part of their type.
+//!
+//! The functions in [native](crate::native) take any [NeoDevice], so nothing stops transmitting
+//! on a device that was never opened. A [Device] moves from [Closed] to [Open] to [Online] by
+//! value, and [transmit](Device::transmit) and [get_messages](Device::get_messages) only exist
+//! once it's online:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::device::Device;
+//! use icsneo::message::CanMessage;
+//! use icsneo::network::NetworkId;
+//!
+//! let device = Device::find_all().unwrap().remove(0);
+//! let device = device.open().unwrap();
+//! device.enable_message_polling();
+//! let device = device.go_online().unwrap();
+//! device
+//!     .transmit(&CanMessage::new(NetworkId::HSCAN.0, 0x123, &[1, 2, 3]).into())
+//!     .unwrap();
+//! let messages = device.get_messages(Duration::from_millis(100)).unwrap();
+//! device.go_offline().unwrap().close().unwrap();
+//! ```
+//! A device that isn't online can't transmit:
+//! ```compile_fail
+//! use icsneo::device::Device;
+//! use icsneo::message::{CanMessage, Message};
+//!
+//! let device = Device::find_all().unwrap().remove(0).open().unwrap();
+//! let message: Message = CanMessage::new(1, 0x123, &[]).into();
+//! device.transmit(&message).unwrap();
+//! ```
+//! The dynamic functions remain available through [neo_device](Device::neo_device) for
+//! anything the typed API doesn't cover.
+//!
+//! Instead of searching the found devices by hand, a device can be opened by
+//! [serial](Device::open_by_serial), by [type](Device::open_first) or by a [Selector] string
+//! as it would appear in a configuration file:
+//! ```no_run
+//! use icsneo::device::{Device, DeviceType, Selector};
+//!
+//! let vcan = Device::open_first(DeviceType::VCAN4_2).unwrap();
+//! let fire = Device::open_by_serial("CY1234").unwrap();
+//! let selector: Selector = "type=ValueCAN4-2,serial=V21234".parse().unwrap();
+//! let device = Device::open_selected(&selector).unwrap();
+//! ```
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use libicsneo_sys::devicetype_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A libicsneo device type with its product name.
+///
+/// The `ICSNEO_DEVICETYPE_*` values are preprocessor macros in `devicetype.h` and are not
+/// exported by [libicsneo_sys](libicsneo_sys), so the current products are defined here:
+/// ```
+/// use icsneo::device::DeviceType;
+///
+/// assert_eq!(DeviceType::VCAN4_2.to_string(), "ValueCAN 4-2");
+/// assert_eq!("valuecan4-2".parse::<DeviceType>().unwrap(), DeviceType::VCAN4_2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceType(pub devicetype_t);
+
+impl DeviceType {
+    pub const UNKNOWN: Self = Self(0x0000_0000);
+    pub const RADMOON2: Self = Self(0x0000_0005);
+    pub const RADMARS: Self = Self(0x0000_0006);
+    pub const VCAN4_1: Self = Self(0x0000_0007);
+    pub const FIRE: Self = Self(0x0000_0008);
+    pub const RADPLUTO: Self = Self(0x0000_0009);
+    pub const VCAN4_2EL: Self = Self(0x0000_000a);
+    pub const RADMOONDUO: Self = Self(0x0000_000e);
+    pub const FIRE3: Self = Self(0x0000_000f);
+    pub const VCAN3: Self = Self(0x0000_0010);
+    pub const RADJUPITER: Self = Self(0x0000_0011);
+    pub const VCAN4_IND: Self = Self(0x0000_0012);
+    pub const RADGIGASTAR: Self = Self(0x0000_0013);
+    pub const RED2: Self = Self(0x0000_0014);
+    pub const ETHERBADGE: Self = Self(0x0000_0016);
+    pub const RAD_A2B: Self = Self(0x0000_0017);
+    pub const RADEPSILON: Self = Self(0x0000_0018);
+    pub const RADMOON3: Self = Self(0x0000_0023);
+    pub const RADCOMET: Self = Self(0x0000_0024);
+    pub const FIRE3_FLEXRAY: Self = Self(0x0000_0025);
+    pub const RED: Self = Self(0x0000_0040);
+    pub const PLASMA: Self = Self(0x0000_1000);
+    pub const ION: Self = Self(0x0004_0000);
+    pub const RADSTAR: Self = Self(0x0008_0000);
+    pub const VCAN4_4: Self = Self(0x0020_0000);
+    pub const VCAN4_2: Self = Self(0x0040_0000);
+    pub const VCANRF: Self = Self(0x0200_0000);
+    pub const FIRE2: Self = Self(0x0400_0000);
+    pub const FLEX: Self = Self(0x0800_0000);
+    pub const RADGALAXY: Self = Self(0x1000_0000);
+    pub const RADSTAR2: Self = Self(0x2000_0000);
+    pub const VIVIDCAN: Self = Self(0x4000_0000);
+    pub const OBD2_SIM: Self = Self(0x8000_0000);
+
+    const NAMES: [(Self, &'static str); 33] = [
+        (Self::UNKNOWN, "Unknown"),
+        (Self::RADMOON2, "RAD-Moon 2"),
+        (Self::RADMARS, "RAD-Mars"),
+        (Self::VCAN4_1, "ValueCAN 4-1"),
+        (Self::FIRE, "neoVI FIRE"),
+        (Self::RADPLUTO, "RAD-Pluto"),
+        (Self::VCAN4_2EL, "ValueCAN 4-2EL"),
+        (Self::RADMOONDUO, "RAD-Moon Duo"),
+        (Self::FIRE3, "neoVI FIRE 3"),
+        (Self::VCAN3, "ValueCAN 3"),
+        (Self::RADJUPITER, "RAD-Jupiter"),
+        (Self::VCAN4_IND, "ValueCAN 4 Industrial"),
+        (Self::RADGIGASTAR, "RAD-Gigastar"),
+        (Self::RED2, "neoVI RED 2"),
+        (Self::ETHERBADGE, "EtherBADGE"),
+        (Self::RAD_A2B, "RAD-A2B"),
+        (Self::RADEPSILON, "RAD-Epsilon"),
+        (Self::RADMOON3, "RAD-Moon 3"),
+        (Self::RADCOMET, "RAD-Comet"),
+        (Self::FIRE3_FLEXRAY, "neoVI FIRE3 FlexRay"),
+        (Self::RED, "neoVI RED"),
+        (Self::PLASMA, "neoVI PLASMA"),
+        (Self::ION, "neoVI ION"),
+        (Self::RADSTAR, "RAD-Star"),
+        (Self::VCAN4_4, "ValueCAN 4-4"),
+        (Self::VCAN4_2, "ValueCAN 4-2"),
+        (Self::VCANRF, "ValueCAN.rf"),
+        (Self::FIRE2, "neoVI FIRE 2"),
+        (Self::FLEX, "neoVI Flex"),
+        (Self::RADGALAXY, "RAD-Galaxy"),
+        (Self::RADSTAR2, "RAD-Star 2"),
+        (Self::VIVIDCAN, "VividCAN"),
+        (Self::OBD2_SIM, "neoOBD2 SIM"),
+    ];
+
+    /// The product name libicsneo uses for this type, `None` for types not listed here. See
+    /// [get_product_name_for_type] for all types libicsneo knows.
+    pub fn name(&self) -> Option<&'static str> {
+        Self::NAMES
+            .iter()
+            .find(|(device_type, _)| device_type == self)
+            .map(|(_, name)| *name)
+    }
+
+    /// A lowercase name without spaces or punctuation, e.g. `valuecan42`. Types without a
+    /// name become `type<number>`.
+    pub fn short_name(&self) -> String {
+        match self.name() {
+            Some(name) => name
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect(),
+            None => format!("type{}", self.0),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "Device Type {:#x}", self.0),
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceType {
+    type Err = Error;
+
+    /// Accepts the [name](DeviceType::name), the name without spaces or punctuation or a
+    /// number, case insensitively.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let number = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => devicetype_t::from_str_radix(hex, 16).ok(),
+            None => s
+                .strip_prefix("type")
+                .unwrap_or(s)
+                .parse::<devicetype_t>()
+                .ok(),
+        };
+        if let Some(number) = number {
+            return Ok(Self(number));
+        }
+        let short: String = s
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        Self::NAMES
+            .iter()
+            .map(|(device_type, _)| *device_type)
+            .find(|device_type| {
+                device_type
+                    .name()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(s))
+                    || device_type.short_name() == short
+            })
+            .ok_or_else(|| Error::ParseError(format!("unknown device type {s:?}")))
+    }
+}
+
+impl From<devicetype_t> for DeviceType {
+    fn from(device_type: devicetype_t) -> Self {
+        Self(device_type)
+    }
+}
+
+impl From<DeviceType> for devicetype_t {
+    fn from(device_type: DeviceType) -> Self {
+        device_type.0
+    }
+}
+
+/// Which device to open, parsed from comma separated `key=value` pairs:
+/// ```
+/// use icsneo::device::{DeviceType, Selector};
+///
+/// let selector: Selector = "type=ValueCAN4-2,serial=V21234".parse().unwrap();
+/// assert_eq!(selector.device_type, Some(DeviceType::VCAN4_2));
+/// assert_eq!(selector.serial.as_deref(), Some("V21234"));
+/// assert_eq!(selector.to_string(), "type=valuecan42,serial=V21234");
+/// ```
+/// The keys are `type`, a [DeviceType], and `serial`. A value without a key is a serial. An
+/// empty selector matches every device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    pub device_type: Option<DeviceType>,
+    pub serial: Option<String>,
+}
+
+impl Selector {
+    /// Selects the device with this serial.
+    pub fn serial(serial: &str) -> Self {
+        Self {
+            device_type: None,
+            serial: Some(serial.to_string()),
+        }
+    }
+
+    /// Selects devices of this type.
+    pub fn device_type(device_type: DeviceType) -> Self {
+        Self {
+            device_type: Some(device_type),
+            serial: None,
+        }
+    }
+
+    /// Whether `device` is selected. Serials are compared case insensitively.
+    pub fn matches(&self, device: &NeoDevice) -> bool {
+        self.device_type
+            .is_none_or(|device_type| device_type.0 == device.type_)
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|serial| serial.eq_ignore_ascii_case(&device.serial()))
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut separator = "";
+        if let Some(device_type) = self.device_type {
+            write!(f, "type={}", device_type.short_name())?;
+            separator = ",";
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, "{separator}serial={serial}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut selector = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => ("serial", pair),
+            };
+            if value.is_empty() {
+                return Err(Error::ParseError(format!("empty value in {pair:?}")));
+            }
+            let duplicate = match key.to_ascii_lowercase().as_str() {
+                "type" => selector.device_type.replace(value.parse()?).is_some(),
+                "serial" => selector.serial.replace(value.to_string()).is_some(),
+                _ => return Err(Error::ParseError(format!("unknown key {key:?} in {s:?}"))),
+            };
+            if duplicate {
+                return Err(Error::ParseError(format!("{key} given twice in {s:?}")));
+            }
+        }
+        Ok(selector)
+    }
+}
+
+/// Found, but not opened.
+#[derive(Debug)]
+pub struct Closed;
+
+/// Opened, but not online.
+#[derive(Debug)]
+pub struct Open;
+
+/// Opened and online.
+#[derive(Debug)]
+pub struct Online;
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Closed {}
+    impl Sealed for super::Open {}
+    impl Sealed for super::Online {}
+}
+
+/// A state of a [Device].
+pub trait State: sealed::Sealed {}
+
+impl State for Closed {}
+impl State for Open {}
+impl State for Online {}
+
+/// States of an opened device.
+pub trait Opened: State {}
+
+impl Opened for Open {}
+impl Opened for Online {}
+
+/// A [NeoDevice] in state `S`.
+///
+/// Transitions consume the device, a failed transition drops it. Dropping a device doesn't
+/// close it, like dropping a [NeoDevice].
+#[derive(Debug)]
+pub struct Device<S: State = Closed> {
+    device: NeoDevice,
+    state: PhantomData<S>,
+}
+
+impl<S: State> Device<S> {
+    fn into_state<T: State>(self) -> Device<T> {
+        Device {
+            device: self.device,
+            state: PhantomData,
+        }
+    }
+
+    /// The underlying device, for the functions in [native](crate::native).
+    pub fn neo_device(&self) -> &NeoDevice {
+        &self.device
+    }
+
+    /// The underlying device, leaving the state to the caller.
+    pub fn into_inner(self) -> NeoDevice {
+        self.device
+    }
+
+    pub fn serial(&self) -> String {
+        self.device.serial()
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType(self.device.type_)
+    }
+}
+
+impl Device<Closed> {
+    /// Takes a found device that isn't open.
+    pub fn new(device: NeoDevice) -> Self {
+        Self {
+            device,
+            state: PhantomData,
+        }
+    }
+
+    /// See [find_all_devices].
+    pub fn find_all() -> Result<Vec<Self>> {
+        Ok(find_all_devices()?.into_iter().map(Self::new).collect())
+    }
+
+    /// See [open_device].
+    pub fn open(self) -> Result<Device<Open>> {
+        open_device(&self.device)?;
+        Ok(self.into_state())
+    }
+
+    /// Opens the first found device `selector` matches, [NoDevicesFound](Error::NoDevicesFound)
+    /// if there is none.
+    ///
+    /// All other unopened devices are freed with [free_unconnected_devices], including ones
+    /// found earlier and not opened yet, which become invalid.
+    pub fn open_selected(selector: &Selector) -> Result<Device<Open>> {
+        let opened = Self::find_all().and_then(|devices| {
+            devices
+                .into_iter()
+                .find(|device| selector.matches(&device.device))
+                .ok_or(Error::NoDevicesFound)?
+                .open()
+        });
+        free_unconnected_devices()?;
+        opened
+    }
+
+    /// Opens the device with this serial, see [open_selected](Self::open_selected).
+    pub fn open_by_serial(serial: &str) -> Result<Device<Open>> {
+        Self::open_selected(&Selector::serial(serial))
+    }
+
+    /// Opens the first device of this type, see [open_selected](Self::open_selected).
+    pub fn open_first(device_type: DeviceType) -> Result<Device<Open>> {
+        Self::open_selected(&Selector::device_type(device_type))
+    }
+}
+
+impl<S: Opened> Device<S> {
+    /// See [describe_device].
+    pub fn describe(&self) -> Result<String> {
+        describe_device(&self.device)
+    }
+
+    /// See [enable_message_polling].
+    pub fn enable_message_polling(&self) -> bool {
+        enable_message_polling(&self.device)
+    }
+
+    /// See [disable_message_polling].
+    pub fn disable_message_polling(&self) -> bool {
+        disable_message_polling(&self.device)
+    }
+
+    /// See [is_message_polling_enabled].
+    pub fn is_message_polling_enabled(&self) -> bool {
+        is_message_polling_enabled(&self.device)
+    }
+
+    /// See [get_timestamp_resolution].
+    pub fn timestamp_resolution(&self) -> Result<u16> {
+        get_timestamp_resolution(&self.device)
+    }
+
+    /// See [get_device_events].
+    pub fn events(&self) -> Result<Vec<NeoEvent>> {
+        get_device_events(&self.device)
+    }
+}
+
+impl Device<Open> {
+    /// See [go_online].
+    pub fn go_online(self) -> Result<Device<Online>> {
+        go_online(&self.device)?;
+        Ok(self.into_state())
+    }
+
+    /// See [close_device]. The device is no longer valid afterwards.
+    pub fn close(self) -> Result<()> {
+        close_device(&self.device)
+    }
+}
+
+impl Device<Online> {
+    /// See [go_offline].
+    pub fn go_offline(self) -> Result<Device<Open>> {
+        go_offline(&self.device)?;
+        Ok(self.into_state())
+    }
+
+    /// See [transmit].
+    pub fn transmit(&self, message: &Message) -> Result<()> {
+        transmit(&self.device, &message.to_neo_message())
+    }
+
+    /// See [transmit_messages].
+    pub fn transmit_messages(&self, messages: &[Message]) -> Result<()> {
+        transmit_messages(
+            &self.device,
+            messages.iter().map(Message::to_neo_message).collect(),
+        )
+    }
+
+    /// See [get_messages]. Requires message polling. Message types other than CAN, CAN error
+    /// counts and Ethernet are dropped.
+    pub fn get_messages(&self, timeout: Duration) -> Result<Vec<Message>> {
+        let messages = get_messages(&self.device, timeout.as_millis() as u64)?;
+        // The payloads stay valid until the next get_messages call on this device.
+        Ok(messages
+            .iter()
+            .filter_map(|message| unsafe { Message::from_neo(message) })
+            .collect())
+    }
+}
+
+impl Transmit for Device<Online> {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        Device::transmit(self, message)
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        Device::transmit_messages(self, messages)
+    }
+}
+
+impl Receive for Device<Online> {
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        self.get_messages(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_online_close() {
+        let devices = match Device::find_all() {
+            Ok(devices) => devices,
+            Err(Error::NoDevicesFound) => return,
+            Err(e) => panic!("ERROR: {e:#?}"),
+        };
+        for device in devices {
+            let serial = device.serial();
+            let device = device.open().unwrap();
+            assert!(is_open(device.neo_device()).unwrap());
+            assert!(device.enable_message_polling());
+            let device = device.go_online().unwrap();
+            assert!(is_online(device.neo_device()).unwrap());
+            assert_eq!(device.serial(), serial);
+            let device = device.go_offline().unwrap();
+            assert!(!is_online(device.neo_device()).unwrap());
+            device.close().unwrap();
+        }
+        free_unconnected_devices().unwrap();
+    }
+
+    fn neo_device(serial: &str, device_type: DeviceType) -> NeoDevice {
+        let mut device = NeoDevice::new();
+        for (c, b) in device.serial.iter_mut().zip(serial.bytes()) {
+            *c = b as _;
+        }
+        device.type_ = device_type.0;
+        device
+    }
+
+    #[test]
+    fn test_device_type_names() {
+        assert_eq!(DeviceType::FIRE3.to_string(), "neoVI FIRE 3");
+        assert_eq!(DeviceType::VCAN4_2EL.short_name(), "valuecan42el");
+        assert_eq!(DeviceType(0x2000).to_string(), "Device Type 0x2000");
+        assert_eq!(DeviceType(0x2000).short_name(), "type8192");
+        for (device_type, _) in DeviceType::NAMES {
+            assert_eq!(
+                device_type.to_string().parse::<DeviceType>().unwrap(),
+                device_type
+            );
+            assert_eq!(
+                device_type.short_name().parse::<DeviceType>().unwrap(),
+                device_type
+            );
+        }
+        assert_eq!(
+            "ValueCAN4-2".parse::<DeviceType>().unwrap(),
+            DeviceType::VCAN4_2
+        );
+        assert_eq!(
+            "0x400000".parse::<DeviceType>().unwrap(),
+            DeviceType::VCAN4_2
+        );
+        assert_eq!(
+            "type8192".parse::<DeviceType>().unwrap(),
+            DeviceType(0x2000)
+        );
+        assert!(matches!(
+            "ValueCAN 5".parse::<DeviceType>(),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_selector() {
+        let selector: Selector = " type = ValueCAN 4-2 , serial = V21234 ".parse().unwrap();
+        assert_eq!(
+            selector,
+            Selector {
+                device_type: Some(DeviceType::VCAN4_2),
+                serial: Some("V21234".to_string()),
+            }
+        );
+        assert_eq!(selector.to_string().parse::<Selector>().unwrap(), selector);
+        assert!(selector.matches(&neo_device("V21234", DeviceType::VCAN4_2)));
+        assert!(selector.matches(&neo_device("v21234", DeviceType::VCAN4_2)));
+        assert!(!selector.matches(&neo_device("V21235", DeviceType::VCAN4_2)));
+        assert!(!selector.matches(&neo_device("V21234", DeviceType::VCAN4_4)));
+
+        assert_eq!(
+            "CY1234".parse::<Selector>().unwrap(),
+            Selector::serial("CY1234")
+        );
+        assert_eq!(
+            "type=neoVI FIRE 3".parse::<Selector>().unwrap().to_string(),
+            "type=neovifire3"
+        );
+        let any: Selector = "".parse().unwrap();
+        assert_eq!(any, Selector::default());
+        assert_eq!(any.to_string(), "");
+        assert!(any.matches(&neo_device("CY1234", DeviceType::FIRE3)));
+
+        for invalid in ["port=3", "serial=", "serial=A,serial=B", "type=ValueCAN 5"] {
+            assert!(
+                matches!(invalid.parse::<Selector>(), Err(Error::ParseError(_))),
+                "{invalid}"
+            );
+        }
+    }
+}
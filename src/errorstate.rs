@@ -0,0 +1,456 @@
+//! CAN controller error states.
+//!
+//! Devices report the transmit and receive error counters of their CAN controllers as
+//! [Message::CanError]. [ErrorStateMonitor] turns them into the error state of each network,
+//! calls back on every transition and can bring a network back from bus-off by restarting the
+//! device:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::bus::Receive;
+//! use icsneo::errorstate::ErrorStateMonitor;
+//!
+//! let mut device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut monitor = ErrorStateMonitor::new();
+//! monitor.on_transition(|transition| {
+//!     println!("{}: {:?} -> {:?}", transition.netid, transition.from, transition.to)
+//! });
+//! monitor.set_recovery(Some(Duration::from_millis(500)));
+//! loop {
+//!     let messages = device.receive(Duration::from_millis(100)).unwrap();
+//!     monitor.update_all(&messages);
+//!     monitor.recover(&mut device).unwrap();
+//! }
+//! ```
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+type Callback = Box<dyn FnMut(&Transition) + Send>;
+
+/// Error counter a controller becomes error-warning at.
+pub const WARNING_LIMIT: u8 = 96;
+/// Error counter a controller becomes error-passive at.
+pub const PASSIVE_LIMIT: u8 = 128;
+
+/// Error state of a CAN controller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorState {
+    /// Both error counters are below [WARNING_LIMIT].
+    #[default]
+    Active,
+    /// An error counter reached [WARNING_LIMIT]. The controller still sends active error flags.
+    Warning,
+    /// An error counter reached [PASSIVE_LIMIT]. The controller only sends passive error flags.
+    Passive,
+    /// The transmit error counter overflowed and the controller left the bus.
+    BusOff,
+}
+
+impl ErrorState {
+    /// State of a controller that isn't bus-off with the given error counters.
+    pub fn from_counters(transmit_error_count: u8, receive_error_count: u8) -> Self {
+        match transmit_error_count.max(receive_error_count) {
+            count if count >= PASSIVE_LIMIT => Self::Passive,
+            count if count >= WARNING_LIMIT => Self::Warning,
+            _ => Self::Active,
+        }
+    }
+
+    /// State reported by an error counter message. The counters are only 8 bits, so bus-off
+    /// comes from the status flag.
+    pub fn of(message: &NeoMessageCanError) -> Self {
+        if MessageStatus::from(message.status).can_bus_off() {
+            Self::BusOff
+        } else {
+            Self::from_counters(message.transmitErrorCount, message.receiveErrorCount)
+        }
+    }
+}
+
+/// A change of the error state of a network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub netid: NetworkId,
+    pub from: ErrorState,
+    pub to: ErrorState,
+    /// Timestamp of the message that reported the new state.
+    pub timestamp: u64,
+    pub transmit_error_count: u8,
+    pub receive_error_count: u8,
+}
+
+/// Something that can restart its networks, which resets their CAN controllers.
+pub trait Restart {
+    fn restart(&mut self) -> Result<()>;
+}
+
+impl Restart for NeoDevice {
+    /// Goes offline and online again.
+    fn restart(&mut self) -> Result<()> {
+        go_offline(self)?;
+        go_online(self)
+    }
+}
+
+impl<T: Restart + ?Sized> Restart for &mut T {
+    fn restart(&mut self) -> Result<()> {
+        (**self).restart()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Network {
+    state: ErrorState,
+    timestamp: u64,
+    /// When the network went bus-off.
+    bus_off_since: Option<Duration>,
+}
+
+/// Tracks the error state of every CAN network error counters are received for.
+pub struct ErrorStateMonitor<C: Clock = SystemClock> {
+    clock: C,
+    networks: BTreeMap<neonetid_t, Network>,
+    callbacks: Vec<Callback>,
+    recovery: Option<Duration>,
+    restarts: u32,
+}
+
+impl ErrorStateMonitor {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock::new())
+    }
+}
+
+impl Default for ErrorStateMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> ErrorStateMonitor<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            networks: BTreeMap::new(),
+            callbacks: Vec::new(),
+            recovery: None,
+            restarts: 0,
+        }
+    }
+
+    /// Calls `callback` on every transition, in the order callbacks were added.
+    pub fn on_transition<F: FnMut(&Transition) + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Restarts the device from [recover](Self::recover) once a network has been bus-off for
+    /// `delay`. `None`, the default, leaves networks bus-off.
+    pub fn set_recovery(&mut self, delay: Option<Duration>) {
+        self.recovery = delay;
+    }
+
+    pub fn recovery(&self) -> Option<Duration> {
+        self.recovery
+    }
+
+    /// Number of restarts [recover](Self::recover) made.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// State of a network. Networks nothing was received for are error-active.
+    pub fn state(&self, netid: impl Into<NetworkId>) -> ErrorState {
+        self.networks
+            .get(&netid.into().0)
+            .map(|network| network.state)
+            .unwrap_or_default()
+    }
+
+    /// States of every network error counters were received for.
+    pub fn states(&self) -> Vec<(NetworkId, ErrorState)> {
+        self.networks
+            .iter()
+            .map(|(&netid, network)| (NetworkId(netid), network.state))
+            .collect()
+    }
+
+    /// Updates the state of the message's network if it's an error counter message. Returns
+    /// the transition, if any, after the callbacks were called with it.
+    pub fn update(&mut self, message: &Message) -> Option<Transition> {
+        let Message::CanError(message) = message else {
+            return None;
+        };
+        let now = self.clock.now();
+        let network = self.networks.entry(message.netid).or_default();
+        network.timestamp = message.timestamp;
+        let state = ErrorState::of(message);
+        if state == network.state {
+            return None;
+        }
+        let transition = Transition {
+            netid: NetworkId(message.netid),
+            from: network.state,
+            to: state,
+            timestamp: message.timestamp,
+            transmit_error_count: message.transmitErrorCount,
+            receive_error_count: message.receiveErrorCount,
+        };
+        network.state = state;
+        network.bus_off_since = (state == ErrorState::BusOff).then_some(now);
+        self.notify(&transition);
+        Some(transition)
+    }
+
+    /// Updates the states with every error counter message. Returns the transitions in order.
+    pub fn update_all(&mut self, messages: &[Message]) -> Vec<Transition> {
+        messages
+            .iter()
+            .filter_map(|message| self.update(message))
+            .collect()
+    }
+
+    /// Restarts `device` if recovery is enabled and a network has been bus-off for the
+    /// recovery delay. Restarting resets the controllers, so bus-off networks become
+    /// error-active again with a transition timestamped like their last message. Networks
+    /// still bus-off afterwards are restarted again after another delay. Returns whether the
+    /// device was restarted.
+    pub fn recover<D: Restart>(&mut self, mut device: D) -> Result<bool> {
+        let Some(delay) = self.recovery else {
+            return Ok(false);
+        };
+        let now = self.clock.now();
+        let due = self.networks.values().any(|network| {
+            network
+                .bus_off_since
+                .is_some_and(|since| now.saturating_sub(since) >= delay)
+        });
+        if !due {
+            return Ok(false);
+        }
+        device.restart()?;
+        self.restarts += 1;
+        let mut transitions = Vec::new();
+        for (&netid, network) in self.networks.iter_mut() {
+            if network.state != ErrorState::BusOff {
+                continue;
+            }
+            transitions.push(Transition {
+                netid: NetworkId(netid),
+                from: ErrorState::BusOff,
+                to: ErrorState::Active,
+                timestamp: network.timestamp,
+                transmit_error_count: 0,
+                receive_error_count: 0,
+            });
+            network.state = ErrorState::Active;
+            network.bus_off_since = None;
+        }
+        transitions
+            .iter()
+            .for_each(|transition| self.notify(transition));
+        Ok(true)
+    }
+
+    fn notify(&mut self, transition: &Transition) {
+        self.callbacks
+            .iter_mut()
+            .for_each(|callback| callback(transition));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn counters(netid: NetworkId, timestamp: u64, tec: u8, rec: u8, bus_off: bool) -> Message {
+        let mut m = NeoMessageCanError::new();
+        m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+        m.netid = netid.0;
+        m.timestamp = timestamp;
+        m.transmitErrorCount = tec;
+        m.receiveErrorCount = rec;
+        let mut status = MessageStatus::new();
+        status.set_can_bus_off(bus_off);
+        m.status = status.into();
+        Message::CanError(m)
+    }
+
+    #[derive(Default)]
+    struct Device {
+        restarts: u32,
+        fail: bool,
+    }
+
+    impl Restart for Device {
+        fn restart(&mut self) -> Result<()> {
+            if self.fail {
+                return Err(Error::CriticalError("Couldn't go offline".to_string()));
+            }
+            self.restarts += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_states_from_counters() {
+        assert_eq!(ErrorState::from_counters(0, 0), ErrorState::Active);
+        assert_eq!(ErrorState::from_counters(95, 95), ErrorState::Active);
+        assert_eq!(ErrorState::from_counters(96, 0), ErrorState::Warning);
+        assert_eq!(ErrorState::from_counters(0, 127), ErrorState::Warning);
+        assert_eq!(ErrorState::from_counters(0, 128), ErrorState::Passive);
+        assert_eq!(ErrorState::from_counters(255, 0), ErrorState::Passive);
+        match counters(NetworkId::HSCAN, 0, 0, 0, true) {
+            Message::CanError(m) => assert_eq!(ErrorState::of(&m), ErrorState::BusOff),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_transitions_and_callbacks() {
+        let mut monitor = ErrorStateMonitor::with_clock(ManualClock::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let callback_seen = seen.clone();
+        monitor.on_transition(move |transition| {
+            callback_seen
+                .lock()
+                .unwrap()
+                .push((transition.netid, transition.to))
+        });
+
+        let transitions = monitor.update_all(&[
+            counters(NetworkId::HSCAN, 1, 8, 0, false),
+            Message::Can(CanMessage::new(NetworkId::HSCAN.0, 0x100, &[])),
+            counters(NetworkId::HSCAN, 2, 100, 0, false),
+            counters(NetworkId::HSCAN, 3, 120, 0, false),
+            counters(NetworkId::HSCAN2, 4, 0, 130, false),
+            counters(NetworkId::HSCAN, 5, 136, 0, false),
+            counters(NetworkId::HSCAN, 6, 255, 0, true),
+            counters(NetworkId::HSCAN2, 7, 0, 10, false),
+        ]);
+        assert_eq!(
+            transitions,
+            [
+                Transition {
+                    netid: NetworkId::HSCAN,
+                    from: ErrorState::Active,
+                    to: ErrorState::Warning,
+                    timestamp: 2,
+                    transmit_error_count: 100,
+                    receive_error_count: 0,
+                },
+                Transition {
+                    netid: NetworkId::HSCAN2,
+                    from: ErrorState::Active,
+                    to: ErrorState::Passive,
+                    timestamp: 4,
+                    transmit_error_count: 0,
+                    receive_error_count: 130,
+                },
+                Transition {
+                    netid: NetworkId::HSCAN,
+                    from: ErrorState::Warning,
+                    to: ErrorState::Passive,
+                    timestamp: 5,
+                    transmit_error_count: 136,
+                    receive_error_count: 0,
+                },
+                Transition {
+                    netid: NetworkId::HSCAN,
+                    from: ErrorState::Passive,
+                    to: ErrorState::BusOff,
+                    timestamp: 6,
+                    transmit_error_count: 255,
+                    receive_error_count: 0,
+                },
+                Transition {
+                    netid: NetworkId::HSCAN2,
+                    from: ErrorState::Passive,
+                    to: ErrorState::Active,
+                    timestamp: 7,
+                    transmit_error_count: 0,
+                    receive_error_count: 10,
+                },
+            ]
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            transitions
+                .iter()
+                .map(|transition| (transition.netid, transition.to))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(monitor.state(NetworkId::HSCAN), ErrorState::BusOff);
+        assert_eq!(monitor.state(NetworkId::HSCAN3), ErrorState::Active);
+        assert_eq!(
+            monitor.states(),
+            [
+                (NetworkId::HSCAN, ErrorState::BusOff),
+                (NetworkId::HSCAN2, ErrorState::Active)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bus_off_recovery() {
+        let clock = ManualClock::new();
+        let mut monitor = ErrorStateMonitor::with_clock(clock.clone());
+        let mut device = Device::default();
+        monitor.update(&counters(NetworkId::HSCAN, 1, 255, 0, true));
+
+        // Without a recovery policy networks stay bus-off.
+        clock.advance(Duration::from_secs(10));
+        assert!(!monitor.recover(&mut device).unwrap());
+        assert_eq!(device.restarts, 0);
+
+        monitor.set_recovery(Some(Duration::from_millis(100)));
+        monitor.update(&counters(NetworkId::HSCAN, 2, 0, 0, false));
+        monitor.update(&counters(NetworkId::HSCAN, 3, 255, 0, true));
+        clock.advance(Duration::from_millis(99));
+        assert!(!monitor.recover(&mut device).unwrap());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let callback_seen = seen.clone();
+        monitor.on_transition(move |transition| callback_seen.lock().unwrap().push(*transition));
+        clock.advance(Duration::from_millis(1));
+        assert!(monitor.recover(&mut device).unwrap());
+        assert_eq!((device.restarts, monitor.restarts()), (1, 1));
+        assert_eq!(monitor.state(NetworkId::HSCAN), ErrorState::Active);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [Transition {
+                netid: NetworkId::HSCAN,
+                from: ErrorState::BusOff,
+                to: ErrorState::Active,
+                timestamp: 3,
+                transmit_error_count: 0,
+                receive_error_count: 0,
+            }]
+        );
+        assert!(!monitor.recover(&mut device).unwrap());
+
+        // A failed restart leaves the network bus-off to be retried.
+        monitor.update(&counters(NetworkId::HSCAN, 4, 255, 0, true));
+        clock.advance(Duration::from_millis(100));
+        device.fail = true;
+        assert!(monitor.recover(&mut device).is_err());
+        assert_eq!(monitor.state(NetworkId::HSCAN), ErrorState::BusOff);
+        device.fail = false;
+        assert!(monitor.recover(&mut device).unwrap());
+        assert_eq!(device.restarts, 2);
+    }
+}
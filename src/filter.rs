@@ -0,0 +1,404 @@
+//! Receive filters, evaluated before messages reach user code.
+//!
+//! A [Filter] is built from simple conditions combined with [and](Filter::and),
+//! [or](Filter::or) and `!`. It can wrap any [Receive] with [Filtered], or a callback with
+//! [callback](Filter::callback):
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::bus::Receive;
+//! use icsneo::filter::{Filter, Filtered};
+//! use icsneo::network::NetworkId;
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! // Diagnostic responses on either bus, but no error frames.
+//! let filter = Filter::netids([NetworkId::HSCAN, NetworkId::HSCAN2])
+//!     .and(Filter::id_mask(0x7e8, 0x7f8))
+//!     .and(!Filter::ErrorFrame);
+//! let mut receiver = Filtered::new(device, filter);
+//! for message in receiver.receive(Duration::from_secs(1)).unwrap() {
+//!     println!("{message:?}");
+//! }
+//! ```
+use std::ops::Not;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// EtherType of IEEE 802.1Q VLAN tags.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// A condition on received messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Every message.
+    Any,
+    /// Messages on one of the networks.
+    Netids(Vec<neonetid_t>),
+    /// CAN frames with `arbid & mask == id & mask`.
+    IdMask {
+        id: u32,
+        mask: u32,
+    },
+    /// CAN frames with 11 bit identifiers.
+    Standard,
+    /// CAN frames with 29 bit identifiers.
+    Extended,
+    /// CAN frames, including error frames, but not error counters.
+    Can,
+    /// CAN FD frames.
+    Fd,
+    /// CAN error frames.
+    ErrorFrame,
+    /// CAN error counter updates.
+    ErrorCounts,
+    /// Messages received, not the echo of own transmits.
+    Received,
+    /// Ethernet frames.
+    Ethernet,
+    /// Ethernet frames of an EtherType, after a VLAN tag if there is one.
+    EtherType(u16),
+    /// Ethernet frames with a destination MAC address.
+    Destination([u8; 6]),
+    /// Ethernet frames with a source MAC address.
+    Source([u8; 6]),
+    /// Messages matching every filter.
+    All(Vec<Filter>),
+    /// Messages matching at least one filter.
+    AnyOf(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn netids<N: Into<NetworkId>>(netids: impl IntoIterator<Item = N>) -> Self {
+        Self::Netids(netids.into_iter().map(|netid| netid.into().0).collect())
+    }
+
+    /// CAN frames with exactly this identifier.
+    pub fn id(id: u32) -> Self {
+        Self::IdMask { id, mask: u32::MAX }
+    }
+
+    /// CAN frames whose identifier matches `id` in the bits set in `mask`.
+    pub fn id_mask(id: u32, mask: u32) -> Self {
+        Self::IdMask { id, mask }
+    }
+
+    /// Messages matching both filters.
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Self::All(mut filters) => {
+                filters.push(other);
+                Self::All(filters)
+            }
+            Self::Any => other,
+            filter => Self::All(vec![filter, other]),
+        }
+    }
+
+    /// Messages matching either filter.
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Self::AnyOf(mut filters) => {
+                filters.push(other);
+                Self::AnyOf(filters)
+            }
+            filter => Self::AnyOf(vec![filter, other]),
+        }
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Netids(netids) => netids.contains(&message.netid()),
+            Self::IdMask { id, mask } => {
+                matches!(message, Message::Can(m) if m.arbid & mask == id & mask)
+            }
+            Self::Standard => matches!(message, Message::Can(m) if !m.is_extended()),
+            Self::Extended => matches!(message, Message::Can(m) if m.is_extended()),
+            Self::Can => matches!(message, Message::Can(_)),
+            Self::Fd => matches!(message, Message::Can(m) if m.is_fd()),
+            Self::ErrorFrame => matches!(message, Message::Can(m) if m.is_error_frame()),
+            Self::ErrorCounts => matches!(message, Message::CanError(_)),
+            Self::Received => match message {
+                Message::Can(m) => !m.is_transmit(),
+                Message::Eth(m) => !m.is_transmit(),
+                Message::CanError(_) => true,
+            },
+            Self::Ethernet => matches!(message, Message::Eth(_)),
+            Self::EtherType(ethertype) => {
+                matches!(message, Message::Eth(m) if ethertype_of(m.data()) == Some(*ethertype))
+            }
+            Self::Destination(mac) => {
+                matches!(message, Message::Eth(m) if m.data().get(..6) == Some(mac))
+            }
+            Self::Source(mac) => {
+                matches!(message, Message::Eth(m) if m.data().get(6..12) == Some(mac))
+            }
+            Self::All(filters) => filters.iter().all(|filter| filter.matches(message)),
+            Self::AnyOf(filters) => filters.iter().any(|filter| filter.matches(message)),
+            Self::Not(filter) => !filter.matches(message),
+        }
+    }
+
+    /// Removes the messages that don't match.
+    pub fn retain(&self, messages: &mut Vec<Message>) {
+        messages.retain(|message| self.matches(message));
+    }
+
+    /// Wraps a callback so it's only called with matching messages.
+    pub fn callback<F: FnMut(&Message)>(self, mut callback: F) -> impl FnMut(&Message) {
+        move |message| {
+            if self.matches(message) {
+                callback(message)
+            }
+        }
+    }
+}
+
+impl Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        match self {
+            Self::Not(filter) => *filter,
+            filter => Self::Not(Box::new(filter)),
+        }
+    }
+}
+
+/// EtherType of a frame starting with the destination MAC address.
+fn ethertype_of(frame: &[u8]) -> Option<u16> {
+    let ethertype = |offset: usize| {
+        let bytes = frame.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    match ethertype(12)? {
+        ETHERTYPE_VLAN => ethertype(16),
+        ethertype => Some(ethertype),
+    }
+}
+
+/// A [Receive] that only returns messages matching a [Filter]. Transmits are passed through.
+pub struct Filtered<R, C: Clock = SystemClock> {
+    inner: R,
+    filter: Filter,
+    clock: C,
+}
+
+impl<R: Receive> Filtered<R> {
+    pub fn new(inner: R, filter: Filter) -> Self {
+        Self::with_clock(inner, filter, SystemClock::new())
+    }
+}
+
+impl<R: Receive, C: Clock> Filtered<R, C> {
+    pub fn with_clock(inner: R, filter: Filter, clock: C) -> Self {
+        Self {
+            inner,
+            filter,
+            clock,
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Replaces the filter from the next [receive](Receive::receive) on.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Receive, C: Clock> Receive for Filtered<R, C> {
+    /// Keeps waiting until `timeout` if only messages that don't match arrive.
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let mut messages = self
+                .inner
+                .receive(deadline.saturating_sub(self.clock.now()))?;
+            self.filter.retain(&mut messages);
+            if !messages.is_empty() || self.clock.now() >= deadline {
+                return Ok(messages);
+            }
+        }
+    }
+}
+
+impl<R: Transmit, C: Clock> Transmit for Filtered<R, C> {
+    fn transmit(&mut self, message: &Message) -> Result<()> {
+        self.inner.transmit(message)
+    }
+
+    fn transmit_messages(&mut self, messages: &[Message]) -> Result<()> {
+        self.inner.transmit_messages(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::VirtualBus;
+
+    const MAC_A: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0a];
+    const MAC_B: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0b];
+
+    fn can(netid: NetworkId, arbid: u32, configure: impl FnOnce(&mut CanMessage)) -> Message {
+        let mut m = CanMessage::new(netid.0, arbid, &[0; 8]);
+        configure(&mut m);
+        Message::Can(m)
+    }
+
+    fn eth(destination: [u8; 6], source: [u8; 6], ethertype: &[u8]) -> Message {
+        let mut data = destination.to_vec();
+        data.extend(source);
+        data.extend(ethertype);
+        data.extend([0; 46]);
+        Message::Eth(EthMessage::new(NetworkId::ETHERNET.0, &data))
+    }
+
+    /// Messages of every kind, and the indices of the ones `filter` matches.
+    fn matching(filter: &Filter) -> Vec<usize> {
+        let messages = [
+            can(NetworkId::HSCAN, 0x7e8, |_| {}),
+            can(NetworkId::HSCAN2, 0x7e0, |_| {}),
+            can(NetworkId::HSCAN, 0x18da_f100, |m| m.set_extended(true)),
+            can(NetworkId::HSCAN2, 0x123, |m| m.set_fd(true)),
+            can(NetworkId::HSCAN, 0, |m| m.set_error_frame(true)),
+            can(NetworkId::HSCAN, 0x7e8, |m| m.set_transmit(true)),
+            Message::CanError(NeoMessageCanError::new()),
+            eth(MAC_A, MAC_B, &[0x08, 0x00]),
+            eth(MAC_B, MAC_A, &[0x81, 0x00, 0x00, 0x05, 0x88, 0xf7]),
+        ];
+        (0..messages.len())
+            .filter(|&i| filter.matches(&messages[i]))
+            .collect()
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(matching(&Filter::Any), (0..9).collect::<Vec<_>>());
+        assert_eq!(matching(&Filter::netids([NetworkId::HSCAN2])), [1, 3]);
+        assert_eq!(
+            matching(&Filter::netids([NetworkId::HSCAN2, NetworkId::ETHERNET])),
+            [1, 3, 7, 8]
+        );
+        assert_eq!(matching(&Filter::id(0x7e8)), [0, 5]);
+        assert_eq!(matching(&Filter::id_mask(0x7e0, 0x7f0)), [0, 1, 5]);
+        assert_eq!(matching(&Filter::id_mask(0x18da_0000, 0x1fff_0000)), [2]);
+        assert_eq!(matching(&Filter::Standard), [0, 1, 3, 4, 5]);
+        assert_eq!(matching(&Filter::Extended), [2]);
+        assert_eq!(matching(&Filter::Can), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(matching(&Filter::Fd), [3]);
+        assert_eq!(matching(&Filter::ErrorFrame), [4]);
+        assert_eq!(matching(&Filter::ErrorCounts), [6]);
+        assert_eq!(matching(&Filter::Received), [0, 1, 2, 3, 4, 6, 7, 8]);
+        assert_eq!(matching(&Filter::Ethernet), [7, 8]);
+        assert_eq!(matching(&Filter::EtherType(0x0800)), [7]);
+        // After the VLAN tag.
+        assert_eq!(matching(&Filter::EtherType(0x88f7)), [8]);
+        assert_eq!(matching(&Filter::Destination(MAC_A)), [7]);
+        assert_eq!(matching(&Filter::Source(MAC_A)), [8]);
+    }
+
+    #[test]
+    fn test_composition() {
+        let filter = Filter::netids([NetworkId::HSCAN])
+            .and(Filter::Standard)
+            .and(!Filter::ErrorFrame);
+        assert!(matches!(&filter, Filter::All(filters) if filters.len() == 3));
+        assert_eq!(matching(&filter), [0, 5]);
+        assert_eq!(matching(&filter.clone().and(Filter::Received)), [0]);
+        assert_eq!(
+            matching(&Filter::Fd.or(Filter::Extended).or(Filter::ErrorCounts)),
+            [2, 3, 6]
+        );
+        assert_eq!(matching(&!Filter::Can), [6, 7, 8]);
+        assert_eq!(!!Filter::Fd, Filter::Fd);
+        assert_eq!(Filter::Any.and(Filter::Fd), Filter::Fd);
+        assert_eq!(matching(&Filter::All(vec![])), (0..9).collect::<Vec<_>>());
+        assert_eq!(matching(&Filter::AnyOf(vec![])), []);
+        assert_eq!(matching(&Filter::Netids(vec![])), []);
+    }
+
+    #[test]
+    fn test_callback_and_retain() {
+        let mut arbids = Vec::new();
+        let mut callback = Filter::Extended.callback(|message| {
+            if let Message::Can(m) = message {
+                arbids.push(m.arbid)
+            }
+        });
+        callback(&can(NetworkId::HSCAN, 0x100, |_| {}));
+        callback(&can(NetworkId::HSCAN, 0x1234_5678, |m| {
+            m.set_extended(true)
+        }));
+        drop(callback);
+        assert_eq!(arbids, [0x1234_5678]);
+
+        let mut messages = vec![
+            can(NetworkId::HSCAN, 0x100, |_| {}),
+            eth(MAC_A, MAC_B, &[0x08, 0x00]),
+        ];
+        Filter::Ethernet.retain(&mut messages);
+        assert!(matches!(messages[..], [Message::Eth(_)]));
+    }
+
+    #[test]
+    fn test_filtered_receiver() {
+        let bus = VirtualBus::new();
+        let mut sender = bus.endpoint();
+        let mut receiver = Filtered::new(bus.endpoint(), Filter::id(0x200));
+
+        let thread = std::thread::spawn(move || {
+            for arbid in [0x100, 0x101, 0x200] {
+                std::thread::sleep(Duration::from_millis(10));
+                let frame = CanMessage::new(1, arbid, &[0]);
+                sender.transmit(&Message::Can(frame)).unwrap();
+            }
+            sender
+        });
+        // Frames that don't match don't end the wait.
+        let messages = receiver.receive(Duration::from_secs(1)).unwrap();
+        assert!(matches!(&messages[..], [Message::Can(m)] if m.arbid == 0x200));
+        let mut sender = thread.join().unwrap();
+
+        sender
+            .transmit(&can(NetworkId::HSCAN, 0x100, |_| {}))
+            .unwrap();
+        assert!(receiver
+            .receive(Duration::from_millis(20))
+            .unwrap()
+            .is_empty());
+        receiver.set_filter(Filter::Any);
+        sender
+            .transmit(&can(NetworkId::HSCAN, 0x100, |_| {}))
+            .unwrap();
+        assert_eq!(
+            receiver.receive(Duration::from_millis(20)).unwrap().len(),
+            1
+        );
+    }
+}
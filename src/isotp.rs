@@ -0,0 +1,834 @@
+//! ISO 15765-2 (ISO-TP) transport protocol over CAN and CAN FD.
+//!
+//! [IsoTp] segments messages of up to 4 GiB into single, first and consecutive frames and
+//! reassembles them on the receiving side, with flow control in both directions:
+//! ```
+//! use std::time::Duration;
+//!
+//! use icsneo::bus::VirtualBus;
+//! use icsneo::isotp::{IsoTp, IsoTpConfig};
+//! use icsneo::network::NetworkId;
+//!
+//! let bus = VirtualBus::new();
+//! let tester = IsoTpConfig::new(NetworkId::HSCAN, 0x7E0, 0x7E8);
+//! let mut tester = IsoTp::new(bus.endpoint(), tester).unwrap();
+//! let ecu = IsoTpConfig::new(NetworkId::HSCAN, 0x7E8, 0x7E0);
+//! let mut ecu = IsoTp::new(bus.endpoint(), ecu).unwrap();
+//!
+//! std::thread::scope(|s| {
+//!     s.spawn(|| tester.send(&[0x22; 100]).unwrap());
+//!     assert_eq!(ecu.receive(Duration::from_secs(1)).unwrap(), [0x22; 100]);
+//! });
+//! ```
+//! Frames are sent and received through the [bus](crate::bus) traits, so a
+//! [NeoDevice](crate::native::NeoDevice) with message polling enabled works the same way.
+//! Received frames with the transmit flag set (transmit receipts) are ignored.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const SINGLE_FRAME: u8 = 0x0;
+const FIRST_FRAME: u8 = 0x1;
+const CONSECUTIVE_FRAME: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+const FLOW_CONTINUE: u8 = 0x0;
+const FLOW_WAIT: u8 = 0x1;
+const FLOW_OVERFLOW: u8 = 0x2;
+
+/// Largest length of a first frame without the 32 bit escape sequence.
+const MAX_SHORT_LENGTH: usize = 0xfff;
+/// Padding of CAN FD frames when [padding](IsoTpConfig::padding) is off, since their
+/// length has to match a DLC anyway.
+const DEFAULT_PADDING: u8 = 0xcc;
+
+/// How frames address the peer, in addition to the arbitration ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    /// The IDs alone identify sender and receiver.
+    Normal,
+    /// The first data byte of every frame is the target address. Frames of the peer are
+    /// expected to start with `source`.
+    Extended { target: u8, source: u8 },
+    /// The first data byte of every frame in both directions is the address extension.
+    Mixed { address_extension: u8 },
+}
+
+/// Settings of one ISO-TP connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsoTpConfig {
+    pub netid: neonetid_t,
+    /// Arbitration ID of frames sent.
+    pub tx_id: u32,
+    /// Arbitration ID of frames received.
+    pub rx_id: u32,
+    /// Both IDs are 29 bit.
+    pub extended_id: bool,
+    pub addressing: Addressing,
+    /// Data length of the frames sent (TX_DL): 8 for CAN, a CAN FD length up to 64 to send
+    /// CAN FD frames. Received frames may be of any length. Defaults to 8.
+    pub frame_size: usize,
+    /// Bit rate switching for CAN FD frames. Defaults to true.
+    pub brs: bool,
+    /// Pads frames to 8 bytes with this value. CAN FD frames over 8 bytes are always padded
+    /// to the next DLC length, with 0xCC if this is `None`. Defaults to `Some(0xCC)`.
+    pub padding: Option<u8>,
+    /// Consecutive frames the peer may send before waiting for the next flow control
+    /// frame, 0 for no limit. Defaults to 0.
+    pub block_size: u8,
+    /// Minimum gap the peer has to leave between consecutive frames (STmin), between 100 µs
+    /// and 127 ms. Defaults to 0.
+    pub st_min: Duration,
+    /// Time a transmit call may take. Defaults to 1 s.
+    pub n_as: Duration,
+    /// Time to wait for a flow control frame after a first frame or block. Defaults to 1 s.
+    pub n_bs: Duration,
+    /// Time to wait for the next consecutive frame. Defaults to 1 s.
+    pub n_cr: Duration,
+    /// Flow control WAIT frames accepted in a row (N_WFTmax). Defaults to 10.
+    pub max_wait_frames: u32,
+    /// Longest message accepted, longer ones are rejected with an overflow flow control
+    /// frame. Defaults to 4095.
+    pub max_length: usize,
+}
+
+impl IsoTpConfig {
+    /// Normal addressing on CAN with the defaults listed on the fields.
+    pub fn new(netid: impl Into<NetworkId>, tx_id: u32, rx_id: u32) -> Self {
+        Self {
+            netid: netid.into().0,
+            tx_id,
+            rx_id,
+            extended_id: tx_id > 0x7ff || rx_id > 0x7ff,
+            addressing: Addressing::Normal,
+            frame_size: 8,
+            brs: true,
+            padding: Some(DEFAULT_PADDING),
+            block_size: 0,
+            st_min: Duration::ZERO,
+            n_as: Duration::from_secs(1),
+            n_bs: Duration::from_secs(1),
+            n_cr: Duration::from_secs(1),
+            max_wait_frames: 10,
+            max_length: MAX_SHORT_LENGTH,
+        }
+    }
+
+    /// Length of the address byte in front of the protocol control information.
+    fn address_length(&self) -> usize {
+        match self.addressing {
+            Addressing::Normal => 0,
+            _ => 1,
+        }
+    }
+
+    /// Longest payload that fits in a single frame.
+    fn max_single_frame_length(&self) -> usize {
+        if self.frame_size > 8 {
+            self.frame_size - 2 - self.address_length()
+        } else {
+            7 - self.address_length()
+        }
+    }
+}
+
+/// Encodes an STmin duration, rounding up to the next supported value.
+fn encode_st_min(st_min: Duration) -> u8 {
+    let micros = st_min.as_micros();
+    match micros {
+        0 => 0,
+        1..=900 => 0xf0 + micros.div_ceil(100) as u8,
+        _ => micros.div_ceil(1000).min(0x7f) as u8,
+    }
+}
+
+/// Decodes an STmin byte. Reserved values mean the maximum of 127 ms.
+fn decode_st_min(st_min: u8) -> Duration {
+    match st_min {
+        0..=0x7f => Duration::from_millis(u64::from(st_min)),
+        0xf1..=0xf9 => Duration::from_micros(u64::from(st_min - 0xf0) * 100),
+        _ => Duration::from_millis(0x7f),
+    }
+}
+
+/// One ISO-TP connection over a bus, sending to [tx_id](IsoTpConfig::tx_id) and receiving
+/// from [rx_id](IsoTpConfig::rx_id).
+pub struct IsoTp<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    config: IsoTpConfig,
+    /// Received messages not looked at yet.
+    pending: VecDeque<Message>,
+}
+
+impl<B: Transmit + Receive> IsoTp<B> {
+    pub fn new(bus: B, config: IsoTpConfig) -> Result<Self> {
+        Self::with_clock(bus, config, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> IsoTp<B, C> {
+    pub fn with_clock(bus: B, config: IsoTpConfig, clock: C) -> Result<Self> {
+        let size = config.frame_size;
+        if !(8..=64).contains(&size) || dlc_to_len(len_to_dlc(size)) != size {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid ISO-TP frame size {size}"
+            )));
+        }
+        Ok(Self {
+            bus,
+            clock,
+            config,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn config(&self) -> &IsoTpConfig {
+        &self.config
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Sends `data`, waiting for the peer's flow control if it doesn't fit a single frame.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        let address_length = self.config.address_length();
+        let size = self.config.frame_size;
+        if data.is_empty() || data.len() > u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid ISO-TP message length {}",
+                data.len()
+            )));
+        }
+
+        // Single frame, with the escaped length for CAN FD.
+        if data.len() <= 7 - address_length {
+            let mut frame = vec![SINGLE_FRAME << 4 | data.len() as u8];
+            frame.extend_from_slice(data);
+            return self.transmit_frame(frame);
+        }
+        if size > 8 && data.len() <= size - 2 - address_length {
+            let mut frame = vec![SINGLE_FRAME << 4, data.len() as u8];
+            frame.extend_from_slice(data);
+            return self.transmit_frame(frame);
+        }
+
+        let mut frame = if data.len() <= MAX_SHORT_LENGTH {
+            vec![FIRST_FRAME << 4 | (data.len() >> 8) as u8, data.len() as u8]
+        } else {
+            let mut frame = vec![FIRST_FRAME << 4, 0];
+            frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            frame
+        };
+        let mut offset = size - address_length - frame.len();
+        frame.extend_from_slice(&data[..offset]);
+        self.transmit_frame(frame)?;
+
+        let mut sequence = 1u8;
+        while offset < data.len() {
+            let (block_size, st_min) = self.wait_flow_control()?;
+            let mut next = self.clock.now();
+            let mut sent = 0usize;
+            while offset < data.len() && (block_size == 0 || sent < usize::from(block_size)) {
+                self.clock.sleep_until(next);
+                let end = data.len().min(offset + size - 1 - address_length);
+                let mut frame = vec![CONSECUTIVE_FRAME << 4 | sequence];
+                frame.extend_from_slice(&data[offset..end]);
+                self.transmit_frame(frame)?;
+                next = self.clock.now() + st_min;
+                offset = end;
+                sequence = (sequence + 1) & 0xf;
+                sent += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the start of a message and returns it once complete.
+    ///
+    /// A new single or first frame from the peer aborts a message in progress and starts
+    /// over, like ISO 15765-2 requires.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let mut deadline = self.clock.now() + timeout;
+        let mut message: Option<Reception> = None;
+        loop {
+            let Some(frame) = self.next_frame(deadline)? else {
+                return Err(match message {
+                    Some(_) => Error::Timeout("ISO-TP N_Cr expired".to_string()),
+                    None => Error::Timeout("No ISO-TP message received".to_string()),
+                });
+            };
+            match frame[0] >> 4 {
+                SINGLE_FRAME => {
+                    let (length, start) = match frame[0] & 0xf {
+                        0 if frame.len() > 1 => (usize::from(frame[1]), 2),
+                        length => (usize::from(length), 1),
+                    };
+                    // Invalid single frames are ignored.
+                    if length > 0 && start + length <= frame.len() {
+                        return Ok(frame[start..start + length].to_vec());
+                    }
+                }
+                FIRST_FRAME if frame.len() >= 2 => {
+                    let (length, start) =
+                        match (usize::from(frame[0] & 0xf) << 8) | usize::from(frame[1]) {
+                            0 if frame.len() >= 6 => {
+                                let length = u32::from_be_bytes(frame[2..6].try_into().unwrap());
+                                (length as usize, 6)
+                            }
+                            0 => continue,
+                            length => (length, 2),
+                        };
+                    // Like invalid single frames, first frames are ignored if their length fits
+                    // a single frame, or their escaped length fits in 12 bits.
+                    if length <= self.config.max_single_frame_length()
+                        || (start == 6 && length <= MAX_SHORT_LENGTH)
+                    {
+                        continue;
+                    }
+                    if length > self.config.max_length {
+                        self.transmit_flow_control(FLOW_OVERFLOW)?;
+                        return Err(Error::ProtocolError(format!(
+                            "ISO-TP message of {length} bytes exceeds the maximum of {}",
+                            self.config.max_length
+                        )));
+                    }
+                    let mut data = frame[start..].to_vec();
+                    data.truncate(length);
+                    message = Some(Reception {
+                        data,
+                        length,
+                        sequence: 1,
+                        block: 0,
+                    });
+                    self.transmit_flow_control(FLOW_CONTINUE)?;
+                    deadline = self.clock.now() + self.config.n_cr;
+                }
+                CONSECUTIVE_FRAME => {
+                    let Some(reception) = &mut message else {
+                        continue;
+                    };
+                    if frame[0] & 0xf != reception.sequence {
+                        return Err(Error::ProtocolError(format!(
+                            "ISO-TP consecutive frame {} received, expected {}",
+                            frame[0] & 0xf,
+                            reception.sequence
+                        )));
+                    }
+                    let remaining = reception.length - reception.data.len();
+                    let payload = &frame[1..];
+                    reception
+                        .data
+                        .extend_from_slice(&payload[..payload.len().min(remaining)]);
+                    if reception.data.len() == reception.length {
+                        return Ok(std::mem::take(&mut reception.data));
+                    }
+                    reception.sequence = (reception.sequence + 1) & 0xf;
+                    // A block size of 0 sends the whole message without further flow control.
+                    if self.config.block_size != 0 {
+                        reception.block += 1;
+                        if reception.block == self.config.block_size {
+                            reception.block = 0;
+                            self.transmit_flow_control(FLOW_CONTINUE)?;
+                        }
+                    }
+                    deadline = self.clock.now() + self.config.n_cr;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the block size and STmin of the next clear-to-send flow control frame.
+    fn wait_flow_control(&mut self) -> Result<(u8, Duration)> {
+        let mut deadline = self.clock.now() + self.config.n_bs;
+        let mut wait_frames = 0;
+        loop {
+            let frame = self
+                .next_frame(deadline)?
+                .ok_or_else(|| Error::Timeout("ISO-TP N_Bs expired".to_string()))?;
+            if frame[0] >> 4 != FLOW_CONTROL {
+                continue;
+            }
+            if frame.len() < 3 {
+                return Err(Error::ProtocolError(
+                    "ISO-TP flow control frame too short".to_string(),
+                ));
+            }
+            match frame[0] & 0xf {
+                FLOW_CONTINUE => return Ok((frame[1], decode_st_min(frame[2]))),
+                FLOW_WAIT => {
+                    wait_frames += 1;
+                    if wait_frames > self.config.max_wait_frames {
+                        return Err(Error::ProtocolError(format!(
+                            "ISO-TP peer sent more than {} WAIT frames",
+                            self.config.max_wait_frames
+                        )));
+                    }
+                    deadline = self.clock.now() + self.config.n_bs;
+                }
+                FLOW_OVERFLOW => {
+                    return Err(Error::ProtocolError(
+                        "ISO-TP message too long for the peer".to_string(),
+                    ))
+                }
+                status => {
+                    return Err(Error::ProtocolError(format!(
+                        "Invalid ISO-TP flow status {status}"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Returns the next frame from the peer without the address byte, or `None` at
+    /// `deadline`.
+    fn next_frame(&mut self, deadline: Duration) -> Result<Option<Vec<u8>>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                if let Some(frame) = self.accept(&message) {
+                    return Ok(Some(frame));
+                }
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let messages = self.bus.receive(deadline - now)?;
+            self.pending.extend(messages);
+        }
+    }
+
+    fn accept(&self, message: &Message) -> Option<Vec<u8>> {
+        let Message::Can(m) = message else {
+            return None;
+        };
+        if m.netid != self.config.netid
+            || m.arbid != self.config.rx_id
+            || m.is_extended() != self.config.extended_id
+            || m.is_transmit()
+            || m.is_remote()
+            || m.is_error_frame()
+        {
+            return None;
+        }
+        let data = m.data();
+        let data = match self.config.addressing {
+            Addressing::Normal => data,
+            Addressing::Extended {
+                source: address, ..
+            }
+            | Addressing::Mixed {
+                address_extension: address,
+            } => match data.split_first() {
+                Some((first, data)) if *first == address => data,
+                _ => return None,
+            },
+        };
+        (!data.is_empty()).then(|| data.to_vec())
+    }
+
+    fn transmit_flow_control(&mut self, status: u8) -> Result<()> {
+        let frame = vec![
+            FLOW_CONTROL << 4 | status,
+            self.config.block_size,
+            encode_st_min(self.config.st_min),
+        ];
+        self.transmit_frame(frame)
+    }
+
+    /// Adds the address byte and padding and transmits.
+    fn transmit_frame(&mut self, frame: Vec<u8>) -> Result<()> {
+        let mut data = match self.config.addressing {
+            Addressing::Normal => Vec::with_capacity(64),
+            Addressing::Extended { target, .. } => vec![target],
+            Addressing::Mixed { address_extension } => vec![address_extension],
+        };
+        data.extend_from_slice(&frame);
+        let length = match data.len() {
+            length if length > 8 => dlc_to_len(len_to_dlc(length)),
+            _ if self.config.padding.is_some() => 8,
+            length => length,
+        };
+        data.resize(length, self.config.padding.unwrap_or(DEFAULT_PADDING));
+
+        let mut message = CanMessage::new(self.config.netid, self.config.tx_id, &data);
+        message.set_extended(self.config.extended_id);
+        if self.config.frame_size > 8 {
+            message.set_fd(true);
+            message.set_brs(self.config.brs);
+        }
+        message.set_dlc_on_wire(len_to_dlc(data.len()));
+        let start = self.clock.now();
+        self.bus.transmit(&Message::Can(message))?;
+        if self.clock.now() - start > self.config.n_as {
+            return Err(Error::Timeout("ISO-TP N_As expired".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A segmented message being received.
+struct Reception {
+    data: Vec<u8>,
+    length: usize,
+    /// Expected sequence number of the next consecutive frame.
+    sequence: u8,
+    /// Consecutive frames received since the last flow control frame.
+    block: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{VirtualBus, VirtualEndpoint};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn config(tx_id: u32, rx_id: u32) -> IsoTpConfig {
+        let mut config = IsoTpConfig::new(NetworkId::HSCAN, tx_id, rx_id);
+        config.n_bs = Duration::from_millis(50);
+        config.n_cr = Duration::from_millis(50);
+        config
+    }
+
+    /// Sends `data` from `sender` to `receiver` and returns what was received.
+    fn transfer(
+        bus: &VirtualBus,
+        sender: IsoTpConfig,
+        receiver: IsoTpConfig,
+        data: &[u8],
+    ) -> (Result<()>, Result<Vec<u8>>) {
+        let mut sender = IsoTp::new(bus.endpoint(), sender).unwrap();
+        let mut receiver = IsoTp::new(bus.endpoint(), receiver).unwrap();
+        std::thread::scope(|s| {
+            let received = s.spawn(move || receiver.receive(TIMEOUT));
+            let sent = sender.send(data);
+            (sent, received.join().unwrap())
+        })
+    }
+
+    fn sniffed(sniffer: &mut VirtualEndpoint) -> Vec<CanMessage> {
+        sniffer
+            .receive(Duration::ZERO)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Can(m) => Some(m),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn payload(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_st_min() {
+        for (code, st_min) in [
+            (0x00, Duration::ZERO),
+            (0x7f, Duration::from_millis(127)),
+            (0x14, Duration::from_millis(20)),
+            (0xf1, Duration::from_micros(100)),
+            (0xf9, Duration::from_micros(900)),
+        ] {
+            assert_eq!(decode_st_min(code), st_min);
+            assert_eq!(encode_st_min(st_min), code);
+        }
+        for reserved in [0x80, 0xf0, 0xfa, 0xff] {
+            assert_eq!(decode_st_min(reserved), Duration::from_millis(127));
+        }
+        assert_eq!(encode_st_min(Duration::from_micros(150)), 0xf2);
+        assert_eq!(encode_st_min(Duration::from_micros(1500)), 0x02);
+        assert_eq!(encode_st_min(Duration::from_secs(1)), 0x7f);
+    }
+
+    #[test]
+    fn test_single_frame() {
+        let bus = VirtualBus::new();
+        let mut sniffer = bus.endpoint();
+        let (sent, received) = transfer(&bus, config(0x7e0, 0x7e8), config(0x7e8, 0x7e0), &[1, 2]);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), [1, 2]);
+        let frames = sniffed(&mut sniffer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!({ frames[0].arbid }, 0x7e0);
+        assert_eq!(frames[0].data(), [0x02, 1, 2, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc]);
+
+        // Without padding frames are only as long as needed.
+        let mut sender = config(0x7e0, 0x7e8);
+        sender.padding = None;
+        let (sent, received) = transfer(&bus, sender, config(0x7e8, 0x7e0), &[1, 2, 3, 4, 5, 6, 7]);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(sniffed(&mut sniffer)[0].data(), [0x07, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_multi_frame() {
+        let bus = VirtualBus::new();
+        let mut sniffer = bus.endpoint();
+        let mut receiver = config(0x7e8, 0x7e0);
+        receiver.block_size = 4;
+        receiver.st_min = Duration::from_millis(2);
+        let data = payload(100);
+        let (sent, received) = transfer(&bus, config(0x7e0, 0x7e8), receiver, &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+
+        let frames = sniffed(&mut sniffer);
+        let (flow_control, data_frames): (Vec<_>, Vec<_>) =
+            frames.iter().partition(|m| m.arbid == 0x7e8);
+        assert_eq!(data_frames[0].data(), [0x10, 100, 0, 1, 2, 3, 4, 5]);
+        // 94 bytes in consecutive frames of 7, in blocks of 4.
+        assert_eq!(data_frames.len(), 1 + 14);
+        assert_eq!(flow_control.len(), 4);
+        assert_eq!(
+            flow_control[0].data(),
+            [0x30, 4, 2, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc]
+        );
+        for (i, frame) in data_frames[1..].iter().enumerate() {
+            assert_eq!(frame.data()[0], 0x20 | ((i + 1) & 0xf) as u8);
+        }
+        let last = data_frames.last().unwrap().data();
+        assert_eq!(last[..4], [0x2e, 97, 98, 99]);
+        assert_eq!(last[4..], [0xcc; 4]);
+        // STmin is kept between consecutive frames of a block.
+        for pair in data_frames[1..5].windows(2) {
+            assert!(pair[1].timestamp - pair[0].timestamp >= 2_000_000);
+        }
+
+        // The sequence number wraps after 15.
+        let data = payload(200);
+        let (sent, received) = transfer(&bus, config(0x7e0, 0x7e8), config(0x7e8, 0x7e0), &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+
+        // More than 255 consecutive frames without a block size need a single flow control.
+        sniffed(&mut sniffer);
+        let data = payload(2000);
+        let (sent, received) = transfer(&bus, config(0x7e0, 0x7e8), config(0x7e8, 0x7e0), &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+        let frames = sniffed(&mut sniffer);
+        assert_eq!(frames.iter().filter(|m| m.arbid == 0x7e8).count(), 1);
+        assert_eq!(frames.len(), 1 + 1 + 285);
+    }
+
+    #[test]
+    fn test_addressing() {
+        let bus = VirtualBus::new();
+        let mut sniffer = bus.endpoint();
+        let mut sender = config(0x18da10f1, 0x18daf110);
+        sender.addressing = Addressing::Extended {
+            target: 0x10,
+            source: 0xf1,
+        };
+        let mut receiver = config(0x18daf110, 0x18da10f1);
+        receiver.addressing = Addressing::Extended {
+            target: 0xf1,
+            source: 0x10,
+        };
+        assert!(sender.extended_id);
+        let data = payload(20);
+        let (sent, received) = transfer(&bus, sender.clone(), receiver.clone(), &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+        let frames = sniffed(&mut sniffer);
+        assert!(frames.iter().all(|m| m.is_extended()));
+        assert_eq!(frames[0].data(), [0x10, 0x10, 20, 0, 1, 2, 3, 4]);
+        assert_eq!(frames[1].data()[..4], [0xf1, 0x30, 0, 0]);
+        assert_eq!(frames[2].data()[..3], [0x10, 0x21, 5]);
+
+        // Frames for another target address are ignored.
+        receiver.addressing = Addressing::Extended {
+            target: 0xf1,
+            source: 0x11,
+        };
+        let (sent, received) = transfer(&bus, sender, receiver, &[1]);
+        sent.unwrap();
+        assert!(matches!(received, Err(Error::Timeout(_))));
+        sniffed(&mut sniffer);
+
+        let mut sender = config(0x7e0, 0x7e8);
+        sender.addressing = Addressing::Mixed {
+            address_extension: 0x55,
+        };
+        let mut receiver = config(0x7e8, 0x7e0);
+        receiver.addressing = sender.addressing;
+        let (sent, received) = transfer(&bus, sender, receiver, &[1, 2, 3, 4, 5, 6]);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), [1, 2, 3, 4, 5, 6]);
+        // A single frame only fits 6 bytes next to the address extension.
+        let frames = sniffed(&mut sniffer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data(), [0x55, 0x06, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_fd() {
+        let bus = VirtualBus::new();
+        let mut sniffer = bus.endpoint();
+        let mut sender = config(0x7e0, 0x7e8);
+        sender.frame_size = 64;
+        let mut receiver = config(0x7e8, 0x7e0);
+        receiver.max_length = 10_000;
+
+        // Escaped single frame length.
+        let data = payload(30);
+        let (sent, received) = transfer(&bus, sender.clone(), receiver.clone(), &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+        let frames_sent = sniffed(&mut sniffer);
+        assert_eq!(frames_sent.len(), 1);
+        let frame = &frames_sent[0];
+        assert!(frame.is_fd() && frame.is_brs());
+        assert_eq!(frame.data().len(), 32);
+        assert_eq!({ frame.dlcOnWire }, len_to_dlc(32));
+        assert_eq!(frame.data()[..3], [0x00, 30, 0]);
+
+        let data = payload(200);
+        let (sent, received) = transfer(&bus, sender.clone(), receiver.clone(), &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+        let frames_sent = sniffed(&mut sniffer);
+        // 62 bytes in the first frame, 63 in each consecutive frame.
+        let data_frames: Vec<_> = frames_sent.iter().filter(|m| m.arbid == 0x7e0).collect();
+        assert_eq!(data_frames.len(), 4);
+        assert_eq!(data_frames[0].data().len(), 64);
+        assert_eq!(data_frames[3].data().len(), 16);
+
+        // Escaped first frame length.
+        let data = payload(5000);
+        let (sent, received) = transfer(&bus, sender, receiver, &data);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+        assert_eq!(
+            sniffed(&mut sniffer)[0].data()[..6],
+            [0x10, 0, 0, 0, 0x13, 0x88]
+        );
+
+        let mut invalid = config(0x7e0, 0x7e8);
+        invalid.frame_size = 10;
+        assert!(matches!(
+            IsoTp::new(bus.endpoint(), invalid),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let bus = VirtualBus::new();
+        let mut peer = bus.endpoint();
+        let mut receiver = IsoTp::new(bus.endpoint(), config(0x7e8, 0x7e0)).unwrap();
+        assert!(matches!(
+            receiver.receive(Duration::from_millis(10)),
+            Err(Error::Timeout(_))
+        ));
+
+        // No consecutive frame after the first.
+        let first = CanMessage::new(1, 0x7e0, &[0x10, 20, 0, 1, 2, 3, 4, 5]);
+        peer.transmit(&Message::Can(first)).unwrap();
+        assert!(matches!(receiver.receive(TIMEOUT), Err(Error::Timeout(_))));
+        assert_eq!(peer.receive(Duration::ZERO).unwrap().len(), 1);
+
+        // No flow control after the first frame.
+        let mut sender = IsoTp::new(bus.endpoint(), config(0x7e0, 0x7e8)).unwrap();
+        assert!(matches!(sender.send(&payload(20)), Err(Error::Timeout(_))));
+
+        // WAIT frames restart N_Bs up to the limit.
+        let mut sender = config(0x7e0, 0x7e8);
+        sender.max_wait_frames = 2;
+        let mut sender = IsoTp::new(bus.endpoint(), sender).unwrap();
+        let wait = Message::Can(CanMessage::new(1, 0x7e8, &[0x31, 0, 0]));
+        for _ in 0..3 {
+            peer.transmit(&wait).unwrap();
+        }
+        assert!(matches!(
+            sender.send(&payload(20)),
+            Err(Error::ProtocolError(_))
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let bus = VirtualBus::new();
+        let mut peer = bus.endpoint();
+        let mut receiver = config(0x7e8, 0x7e0);
+        receiver.max_length = 100;
+        let mut receiver = IsoTp::new(bus.endpoint(), receiver).unwrap();
+
+        let first = CanMessage::new(1, 0x7e0, &[0x10, 20, 0, 1, 2, 3, 4, 5]);
+        peer.transmit(&Message::Can(first)).unwrap();
+        peer.transmit(&Message::Can(CanMessage::new(1, 0x7e0, &[0x22, 6])))
+            .unwrap();
+        assert!(matches!(
+            receiver.receive(TIMEOUT),
+            Err(Error::ProtocolError(_))
+        ));
+        peer.receive(Duration::ZERO).unwrap();
+
+        // Too long for the receiver, which answers with an overflow.
+        let first = CanMessage::new(1, 0x7e0, &[0x1f, 0xff, 0, 1, 2, 3, 4, 5]);
+        peer.transmit(&Message::Can(first)).unwrap();
+        assert!(matches!(
+            receiver.receive(TIMEOUT),
+            Err(Error::ProtocolError(_))
+        ));
+        let received = peer.receive(Duration::ZERO).unwrap();
+        let Message::Can(overflow) = &received[0] else {
+            panic!("{:?}", received[0])
+        };
+        assert_eq!(overflow.data()[..3], [0x32, 0, 0]);
+
+        let mut receiver = config(0x7e8, 0x7e0);
+        receiver.max_length = 10;
+        let (sent, received) = transfer(&bus, config(0x7e0, 0x7e8), receiver, &payload(20));
+        assert!(matches!(sent, Err(Error::ProtocolError(_))));
+        assert!(matches!(received, Err(Error::ProtocolError(_))));
+
+        let mut sender = IsoTp::new(bus.endpoint(), config(0x7e0, 0x7e8)).unwrap();
+        assert!(matches!(sender.send(&[]), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_invalid_first_frames() {
+        let bus = VirtualBus::new();
+        let mut peer = bus.endpoint();
+        let mut fd = config(0x7e8, 0x7e0);
+        fd.frame_size = 64;
+        for (receiver, first) in [
+            // Escaped length cut short.
+            (config(0x7e8, 0x7e0), vec![0x10, 0, 0, 0, 0]),
+            // Fits a single frame.
+            (config(0x7e8, 0x7e0), vec![0x10, 7, 1, 2, 3, 4, 5, 6]),
+            (fd, vec![0x10, 62, 1, 2, 3, 4, 5, 6]),
+            // Escaped length that fits 12 bits.
+            (config(0x7e8, 0x7e0), vec![0x10, 0, 0, 0, 0x0f, 0xff, 1, 2]),
+        ] {
+            let mut receiver = IsoTp::new(bus.endpoint(), receiver).unwrap();
+            peer.transmit(&Message::Can(CanMessage::new(1, 0x7e0, &first)))
+                .unwrap();
+            peer.transmit(&Message::Can(CanMessage::new(1, 0x7e0, &[0x02, 9, 9])))
+                .unwrap();
+            assert_eq!(receiver.receive(TIMEOUT).unwrap(), [9, 9]);
+            // No flow control was sent for the first frame.
+            assert!(peer.receive(Duration::ZERO).unwrap().is_empty());
+        }
+    }
+}
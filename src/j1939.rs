@@ -0,0 +1,955 @@
+//! SAE J1939 on CAN: identifiers, address claiming (J1939-81), the transport protocol
+//! (J1939-21) and DM1 diagnostics (J1939-73).
+//!
+//! A [J1939] node claims an address and then sends and receives parameter groups of up to
+//! 1785 bytes. Longer than 8 bytes are sent with BAM to the global address and with
+//! RTS/CTS to a specific one:
+//! ```no_run
+//! use icsneo::j1939::{Dm1, Name, J1939, PGN_DM1};
+//! use icsneo::network::NetworkId;
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut node = J1939::new(device, NetworkId::HSCAN, Name(0x8000_0000_0012_3456), 0xf9);
+//! node.claim_address().unwrap();
+//! loop {
+//!     let message = node.receive(std::time::Duration::from_secs(1)).unwrap();
+//!     if message.pgn == PGN_DM1 {
+//!         println!("{:02X}: {:?}", message.source, Dm1::decode(&message.data).unwrap());
+//!     }
+//! }
+//! ```
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub const PGN_REQUEST: u32 = 0xea00;
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xee00;
+pub const PGN_TP_CM: u32 = 0xec00;
+pub const PGN_TP_DT: u32 = 0xeb00;
+pub const PGN_DM1: u32 = 0xfeca;
+
+pub const ADDRESS_GLOBAL: u8 = 0xff;
+pub const ADDRESS_NULL: u8 = 0xfe;
+
+/// Largest parameter group the transport protocol carries, 255 packets of 7 bytes.
+pub const MAX_LENGTH: usize = 1785;
+
+const CONTROL_RTS: u8 = 16;
+const CONTROL_CTS: u8 = 17;
+const CONTROL_ACK: u8 = 19;
+const CONTROL_BAM: u8 = 32;
+const CONTROL_ABORT: u8 = 255;
+
+const ABORT_TIMEOUT: u8 = 3;
+const ABORT_BAD_SEQUENCE: u8 = 7;
+
+/// Time to wait for contending address claims.
+const CLAIM_TIMEOUT: Duration = Duration::from_millis(250);
+/// Time between data packets of a receiving transfer (T1).
+const T1: Duration = Duration::from_millis(750);
+/// Time between a CTS and its first data packet (T2).
+const T2: Duration = Duration::from_millis(1250);
+/// Time for the receiver to answer with a CTS or acknowledgement (T3).
+const T3: Duration = Duration::from_millis(1250);
+/// Time to wait for the next CTS after a hold (T4).
+const T4: Duration = Duration::from_millis(1050);
+
+const PADDING: u8 = 0xff;
+
+/// The fields of a 29 bit J1939 identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Identifier {
+    pub priority: u8,
+    /// Parameter group number, with the PDU specific byte cleared for destination specific
+    /// (PDU1) groups.
+    pub pgn: u32,
+    pub source: u8,
+    /// [ADDRESS_GLOBAL] for PDU2 groups, which are always broadcast.
+    pub destination: u8,
+}
+
+/// Whether `pgn` is destination specific (PDU format below 240).
+pub fn is_pdu1(pgn: u32) -> bool {
+    (pgn >> 8) & 0xff < 240
+}
+
+impl Identifier {
+    pub fn from_arbid(arbid: u32) -> Self {
+        let pgn = (arbid >> 8) & 0x3ffff;
+        let (pgn, destination) = match is_pdu1(pgn) {
+            true => (pgn & 0x3ff00, pgn as u8),
+            false => (pgn, ADDRESS_GLOBAL),
+        };
+        Self {
+            priority: (arbid >> 26) as u8 & 7,
+            pgn,
+            source: arbid as u8,
+            destination,
+        }
+    }
+
+    pub fn to_arbid(&self) -> u32 {
+        let pgn = match is_pdu1(self.pgn) {
+            true => (self.pgn & 0x3ff00) | u32::from(self.destination),
+            false => self.pgn & 0x3ffff,
+        };
+        u32::from(self.priority & 7) << 26 | pgn << 8 | u32::from(self.source)
+    }
+}
+
+/// The 64 bit NAME a node claims its address with. Lower values win contended addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Name(pub u64);
+
+impl Name {
+    pub fn identity_number(&self) -> u32 {
+        self.0 as u32 & 0x1f_ffff
+    }
+
+    pub fn manufacturer_code(&self) -> u16 {
+        (self.0 >> 21) as u16 & 0x7ff
+    }
+
+    pub fn ecu_instance(&self) -> u8 {
+        (self.0 >> 32) as u8 & 0x7
+    }
+
+    pub fn function_instance(&self) -> u8 {
+        (self.0 >> 35) as u8 & 0x1f
+    }
+
+    pub fn function(&self) -> u8 {
+        (self.0 >> 40) as u8
+    }
+
+    pub fn vehicle_system(&self) -> u8 {
+        (self.0 >> 49) as u8 & 0x7f
+    }
+
+    pub fn vehicle_system_instance(&self) -> u8 {
+        (self.0 >> 56) as u8 & 0xf
+    }
+
+    pub fn industry_group(&self) -> u8 {
+        (self.0 >> 60) as u8 & 0x7
+    }
+
+    /// Whether the node may pick another address when it loses its preferred one.
+    pub fn is_arbitrary_address_capable(&self) -> bool {
+        self.0 >> 63 != 0
+    }
+}
+
+/// A complete parameter group, received in one frame or reassembled from a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct J1939Message {
+    pub priority: u8,
+    pub pgn: u32,
+    pub source: u8,
+    pub destination: u8,
+    pub data: Vec<u8>,
+    /// Timestamp of the last frame.
+    pub timestamp: u64,
+}
+
+/// Status of the four DM1 lamps: 0 off, 1 on, 3 not available. For the flash status 0 is
+/// slow flash, 1 fast flash and 3 no flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Lamps {
+    pub malfunction: u8,
+    pub red_stop: u8,
+    pub amber_warning: u8,
+    pub protect: u8,
+}
+
+impl Lamps {
+    fn from_byte(byte: u8) -> Self {
+        Self {
+            malfunction: byte >> 6,
+            red_stop: (byte >> 4) & 3,
+            amber_warning: (byte >> 2) & 3,
+            protect: byte & 3,
+        }
+    }
+}
+
+/// A J1939 diagnostic trouble code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dtc {
+    /// Suspect parameter number, 19 bits.
+    pub spn: u32,
+    /// Failure mode identifier, 5 bits.
+    pub fmi: u8,
+    pub occurrence_count: u8,
+    /// SPN conversion method bit, set for the obsolete byte orders of J1939-73 versions 1 to 3.
+    pub conversion_method: bool,
+}
+
+impl Dtc {
+    pub fn decode(data: [u8; 4]) -> Self {
+        Self {
+            spn: u32::from(data[0]) | u32::from(data[1]) << 8 | u32::from(data[2] >> 5) << 16,
+            fmi: data[2] & 0x1f,
+            occurrence_count: data[3] & 0x7f,
+            conversion_method: data[3] & 0x80 != 0,
+        }
+    }
+}
+
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SPN {} FMI {} (occurred {} times)",
+            self.spn, self.fmi, self.occurrence_count
+        )
+    }
+}
+
+/// DM1, the active diagnostic trouble codes of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dm1 {
+    pub lamps: Lamps,
+    pub flash: Lamps,
+    pub dtcs: Vec<Dtc>,
+}
+
+impl Dm1 {
+    /// Decodes a DM1 parameter group. The all zero DTC nodes send when none are active is
+    /// left out.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let [lamps, flash, dtcs @ ..] = data else {
+            return Err(Error::ParseError(format!("DM1 of {} bytes", data.len())));
+        };
+        Ok(Self {
+            lamps: Lamps::from_byte(*lamps),
+            flash: Lamps::from_byte(*flash),
+            dtcs: dtcs
+                .chunks_exact(4)
+                .map(|dtc| Dtc::decode(dtc.try_into().unwrap()))
+                .filter(|dtc| dtc.spn != 0 || dtc.fmi != 0)
+                .collect(),
+        })
+    }
+}
+
+/// A transport protocol transfer being received.
+struct Transfer {
+    priority: u8,
+    pgn: u32,
+    /// Sent with RTS/CTS instead of BAM.
+    connected: bool,
+    size: usize,
+    packets: u8,
+    /// Largest number of packets the sender accepts per CTS.
+    max_per_cts: u8,
+    /// Packets until the next CTS.
+    window: u8,
+    data: Vec<u8>,
+    deadline: Duration,
+}
+
+/// A J1939 node on one network.
+pub struct J1939<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    netid: neonetid_t,
+    name: Name,
+    preferred_address: u8,
+    address: Option<u8>,
+    /// NAMEs of the other nodes by the address they claimed.
+    addresses: BTreeMap<u8, Name>,
+    bam_interval: Duration,
+    /// Transfers being received, by source and destination.
+    transfers: HashMap<(u8, u8), Transfer>,
+    pending: VecDeque<Message>,
+    completed: VecDeque<J1939Message>,
+}
+
+impl<B: Transmit + Receive> J1939<B> {
+    pub fn new(bus: B, netid: impl Into<NetworkId>, name: Name, preferred_address: u8) -> Self {
+        Self::with_clock(bus, netid, name, preferred_address, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> J1939<B, C> {
+    pub fn with_clock(
+        bus: B,
+        netid: impl Into<NetworkId>,
+        name: Name,
+        preferred_address: u8,
+        clock: C,
+    ) -> Self {
+        Self {
+            bus,
+            clock,
+            netid: netid.into().0,
+            name,
+            preferred_address,
+            address: None,
+            addresses: BTreeMap::new(),
+            bam_interval: Duration::from_millis(50),
+            transfers: HashMap::new(),
+            pending: VecDeque::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    pub fn name(&self) -> Name {
+        self.name
+    }
+
+    /// The claimed address, `None` before claiming or after losing it.
+    pub fn address(&self) -> Option<u8> {
+        self.address
+    }
+
+    /// The other nodes seen claiming an address.
+    pub fn addresses(&self) -> &BTreeMap<u8, Name> {
+        &self.addresses
+    }
+
+    /// Gap between BAM data packets, 50 to 200 ms per J1939-21. Defaults to 50 ms.
+    pub fn set_bam_interval(&mut self, interval: Duration) {
+        self.bam_interval = interval;
+    }
+
+    /// Claims the preferred address and waits for contending claims. A node with an
+    /// arbitrary address capable NAME moves on to a free address in 128-247 when it loses,
+    /// otherwise it announces that it cannot claim an address and returns an error.
+    ///
+    /// The node keeps defending its address in [receive](Self::receive) and answers requests
+    /// for address claimed.
+    pub fn claim_address(&mut self) -> Result<u8> {
+        self.address = Some(self.preferred_address);
+        self.transmit_claim()?;
+        loop {
+            let claimed = self.address;
+            let deadline = self.clock.now() + CLAIM_TIMEOUT;
+            while let Some((id, data, timestamp)) = self.next_frame(deadline)? {
+                self.process(id, &data, timestamp)?;
+                if self.address != claimed {
+                    break;
+                }
+            }
+            match self.address {
+                None => {
+                    return Err(Error::ProtocolError(
+                        "Cannot claim a J1939 address".to_string(),
+                    ))
+                }
+                Some(address) if self.address == claimed => return Ok(address),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Sends a parameter group to `destination`, with the transport protocol if longer than
+    /// 8 bytes. `destination` is ignored for PDU2 groups.
+    pub fn send(&mut self, pgn: u32, priority: u8, destination: u8, data: &[u8]) -> Result<()> {
+        let source = self
+            .address
+            .ok_or_else(|| Error::InvalidArgument("No J1939 address claimed".to_string()))?;
+        let destination = match is_pdu1(pgn) {
+            true => destination,
+            false => ADDRESS_GLOBAL,
+        };
+        if data.len() > MAX_LENGTH {
+            return Err(Error::InvalidArgument(format!(
+                "J1939 message of {} bytes exceeds {MAX_LENGTH}",
+                data.len()
+            )));
+        }
+        let id = Identifier {
+            priority,
+            pgn,
+            source,
+            destination,
+        };
+        if data.len() <= 8 {
+            return self.transmit_frame(id, data);
+        }
+
+        let packets = data.len().div_ceil(7) as u8;
+        let [size_low, size_high] = (data.len() as u16).to_le_bytes();
+        let [pgn_low, pgn_mid, pgn_high, _] = pgn.to_le_bytes();
+        let connection = Identifier {
+            priority: 7,
+            pgn: PGN_TP_CM,
+            source,
+            destination,
+        };
+        let transfer = Identifier {
+            pgn: PGN_TP_DT,
+            ..connection
+        };
+        if destination == ADDRESS_GLOBAL {
+            let announce = [
+                CONTROL_BAM,
+                size_low,
+                size_high,
+                packets,
+                0xff,
+                pgn_low,
+                pgn_mid,
+                pgn_high,
+            ];
+            self.transmit_frame(connection, &announce)?;
+            for sequence in 1..=packets {
+                self.clock.sleep_until(self.clock.now() + self.bam_interval);
+                self.transmit_packet(transfer, data, sequence)?;
+            }
+            return Ok(());
+        }
+
+        let request = [
+            CONTROL_RTS,
+            size_low,
+            size_high,
+            packets,
+            0xff,
+            pgn_low,
+            pgn_mid,
+            pgn_high,
+        ];
+        self.transmit_frame(connection, &request)?;
+        let mut deadline = self.clock.now() + T3;
+        loop {
+            let Some((id, frame, timestamp)) = self.next_frame(deadline)? else {
+                return Err(Error::Timeout(format!(
+                    "No response from J1939 node {destination:#04x} to RTS"
+                )));
+            };
+            let response = id.pgn == PGN_TP_CM
+                && id.source == destination
+                && id.destination == source
+                && frame.len() == 8
+                && frame[5..8] == [pgn_low, pgn_mid, pgn_high];
+            if !response {
+                self.process(id, &frame, timestamp)?;
+                continue;
+            }
+            match frame[0] {
+                // A CTS for 0 packets holds the transfer.
+                CONTROL_CTS if frame[1] == 0 => deadline = self.clock.now() + T4,
+                CONTROL_CTS => {
+                    let first = frame[2].max(1);
+                    let last = first.saturating_add(frame[1] - 1).min(packets);
+                    for sequence in first..=last {
+                        self.transmit_packet(transfer, data, sequence)?;
+                    }
+                    deadline = self.clock.now() + T3;
+                }
+                CONTROL_ACK => return Ok(()),
+                CONTROL_ABORT => {
+                    return Err(Error::ProtocolError(format!(
+                        "J1939 node {destination:#04x} aborted the transfer, reason {}",
+                        frame[1]
+                    )))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the next parameter group addressed to this node or broadcast, waiting up to
+    /// `timeout` for it.
+    pub fn receive(&mut self, timeout: Duration) -> Result<J1939Message> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if let Some(message) = self.completed.pop_front() {
+                return Ok(message);
+            }
+            self.expire_transfers()?;
+            let next = self
+                .transfers
+                .values()
+                .map(|transfer| transfer.deadline)
+                .fold(deadline, Duration::min);
+            match self.next_frame(next)? {
+                Some((id, data, timestamp)) => self.process(id, &data, timestamp)?,
+                None if self.clock.now() >= deadline => {
+                    return Err(Error::Timeout("No J1939 message received".to_string()))
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Handles a received frame: address claiming, transport protocol and completed
+    /// messages for the caller.
+    fn process(&mut self, id: Identifier, data: &[u8], timestamp: u64) -> Result<()> {
+        if id.pgn == PGN_ADDRESS_CLAIMED && data.len() == 8 {
+            let name = Name(u64::from_le_bytes(data.try_into().unwrap()));
+            if id.source != ADDRESS_NULL {
+                self.addresses.insert(id.source, name);
+            }
+            if Some(id.source) == self.address && name != self.name {
+                match self.name < name {
+                    true => self.transmit_claim()?,
+                    false => self.lose_address()?,
+                }
+            }
+        }
+        let for_us = id.destination == ADDRESS_GLOBAL || Some(id.destination) == self.address;
+        if !for_us {
+            return Ok(());
+        }
+        match id.pgn {
+            PGN_REQUEST if data.len() >= 3 => {
+                let requested = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+                if requested == PGN_ADDRESS_CLAIMED && self.address.is_some() {
+                    self.transmit_claim()?;
+                }
+            }
+            PGN_TP_CM if data.len() == 8 => return self.process_connection(id, data),
+            PGN_TP_DT if !data.is_empty() => return self.process_packet(id, data, timestamp),
+            _ => {}
+        }
+        self.completed.push_back(J1939Message {
+            priority: id.priority,
+            pgn: id.pgn,
+            source: id.source,
+            destination: id.destination,
+            data: data.to_vec(),
+            timestamp,
+        });
+        Ok(())
+    }
+
+    fn process_connection(&mut self, id: Identifier, data: &[u8]) -> Result<()> {
+        let connected = match data[0] {
+            CONTROL_BAM if id.destination == ADDRESS_GLOBAL => false,
+            CONTROL_RTS if id.destination != ADDRESS_GLOBAL => true,
+            CONTROL_ABORT => {
+                self.transfers.remove(&(id.source, id.destination));
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let size = usize::from(u16::from_le_bytes([data[1], data[2]]));
+        let packets = data[3];
+        let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        if size <= 8 || size > MAX_LENGTH || usize::from(packets) != size.div_ceil(7) {
+            return Ok(());
+        }
+        let max_per_cts = match data[4] {
+            0 => 0xff,
+            max => max,
+        };
+        let mut transfer = Transfer {
+            priority: id.priority,
+            pgn,
+            connected,
+            size,
+            packets,
+            max_per_cts,
+            window: 0,
+            data: Vec::with_capacity(size + 7),
+            deadline: self.clock.now() + T1,
+        };
+        if connected {
+            self.transmit_cts(id, &mut transfer)?;
+        }
+        self.transfers.insert((id.source, id.destination), transfer);
+        Ok(())
+    }
+
+    fn process_packet(&mut self, id: Identifier, data: &[u8], timestamp: u64) -> Result<()> {
+        let key = (id.source, id.destination);
+        let Some(mut transfer) = self.transfers.remove(&key) else {
+            return Ok(());
+        };
+        let expected = transfer.data.len() / 7 + 1;
+        if usize::from(data[0]) != expected {
+            if transfer.connected {
+                self.transmit_abort(id, transfer.pgn, ABORT_BAD_SEQUENCE)?;
+            }
+            return Ok(());
+        }
+        transfer.data.extend_from_slice(&data[1..data.len().min(8)]);
+        transfer.data.resize(expected * 7, PADDING);
+        if transfer.data.len() >= transfer.size {
+            transfer.data.truncate(transfer.size);
+            if transfer.connected {
+                let [size_low, size_high] = (transfer.size as u16).to_le_bytes();
+                let [pgn_low, pgn_mid, pgn_high, _] = transfer.pgn.to_le_bytes();
+                let ack = [
+                    CONTROL_ACK,
+                    size_low,
+                    size_high,
+                    transfer.packets,
+                    0xff,
+                    pgn_low,
+                    pgn_mid,
+                    pgn_high,
+                ];
+                self.transmit_frame(reply(id, PGN_TP_CM), &ack)?;
+            }
+            self.completed.push_back(J1939Message {
+                priority: transfer.priority,
+                pgn: transfer.pgn,
+                source: id.source,
+                destination: id.destination,
+                data: transfer.data,
+                timestamp,
+            });
+            return Ok(());
+        }
+        transfer.deadline = self.clock.now() + T1;
+        if transfer.connected {
+            transfer.window -= 1;
+            if transfer.window == 0 {
+                self.transmit_cts(id, &mut transfer)?;
+            }
+        }
+        self.transfers.insert(key, transfer);
+        Ok(())
+    }
+
+    /// Drops transfers whose sender went quiet, aborting connected ones.
+    fn expire_transfers(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let expired: Vec<(u8, u8)> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (source, destination) in expired {
+            let transfer = self.transfers.remove(&(source, destination)).unwrap();
+            if transfer.connected {
+                let id = Identifier {
+                    priority: 7,
+                    pgn: PGN_TP_CM,
+                    source,
+                    destination,
+                };
+                self.transmit_abort(id, transfer.pgn, ABORT_TIMEOUT)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the sender of `id` for the next window of packets.
+    fn transmit_cts(&mut self, id: Identifier, transfer: &mut Transfer) -> Result<()> {
+        let next = (transfer.data.len() / 7 + 1) as u8;
+        transfer.window = (transfer.packets - next + 1).min(transfer.max_per_cts);
+        transfer.deadline = self.clock.now() + T2;
+        let [pgn_low, pgn_mid, pgn_high, _] = transfer.pgn.to_le_bytes();
+        let cts = [
+            CONTROL_CTS,
+            transfer.window,
+            next,
+            0xff,
+            0xff,
+            pgn_low,
+            pgn_mid,
+            pgn_high,
+        ];
+        self.transmit_frame(reply(id, PGN_TP_CM), &cts)
+    }
+
+    fn transmit_abort(&mut self, id: Identifier, pgn: u32, reason: u8) -> Result<()> {
+        let [pgn_low, pgn_mid, pgn_high, _] = pgn.to_le_bytes();
+        let abort = [
+            CONTROL_ABORT,
+            reason,
+            0xff,
+            0xff,
+            0xff,
+            pgn_low,
+            pgn_mid,
+            pgn_high,
+        ];
+        self.transmit_frame(reply(id, PGN_TP_CM), &abort)
+    }
+
+    /// Claims the current address, or announces that no address could be claimed.
+    fn transmit_claim(&mut self) -> Result<()> {
+        let id = Identifier {
+            priority: 6,
+            pgn: PGN_ADDRESS_CLAIMED,
+            source: self.address.unwrap_or(ADDRESS_NULL),
+            destination: ADDRESS_GLOBAL,
+        };
+        self.transmit_frame(id, &self.name.0.to_le_bytes())
+    }
+
+    /// Gives up the current address to a node with a lower NAME and claims a free one if
+    /// the NAME allows it.
+    fn lose_address(&mut self) -> Result<()> {
+        self.address = match self.name.is_arbitrary_address_capable() {
+            true => (128..=247).find(|address| !self.addresses.contains_key(address)),
+            false => None,
+        };
+        self.transmit_claim()
+    }
+
+    /// Returns the next frame and its timestamp, or `None` at `deadline`.
+    fn next_frame(&mut self, deadline: Duration) -> Result<Option<(Identifier, Vec<u8>, u64)>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                let Message::Can(m) = message else {
+                    continue;
+                };
+                if m.netid == self.netid
+                    && m.is_extended()
+                    && !m.is_transmit()
+                    && !m.is_remote()
+                    && !m.is_error_frame()
+                {
+                    let id = Identifier::from_arbid(m.arbid);
+                    return Ok(Some((id, m.data().to_vec(), m.timestamp)));
+                }
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let messages = self.bus.receive(deadline - now)?;
+            self.pending.extend(messages);
+        }
+    }
+
+    /// Sends packet `sequence` (1 based) of `data`.
+    fn transmit_packet(&mut self, id: Identifier, data: &[u8], sequence: u8) -> Result<()> {
+        let start = (usize::from(sequence) - 1) * 7;
+        let mut packet = vec![sequence];
+        packet.extend_from_slice(&data[start..data.len().min(start + 7)]);
+        self.transmit_frame(id, &packet)
+    }
+
+    fn transmit_frame(&mut self, id: Identifier, data: &[u8]) -> Result<()> {
+        let mut data = data.to_vec();
+        data.resize(8, PADDING);
+        let mut message = CanMessage::new(self.netid, id.to_arbid(), &data);
+        message.set_extended(true);
+        message.set_dlc_on_wire(8);
+        self.bus.transmit(&Message::Can(message))
+    }
+}
+
+/// The identifier of a transport protocol reply to the sender of `id`.
+fn reply(id: Identifier, pgn: u32) -> Identifier {
+    Identifier {
+        priority: 7,
+        pgn,
+        source: id.destination,
+        destination: id.source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{VirtualBus, VirtualEndpoint};
+
+    fn node(bus: &VirtualBus, name: u64, address: u8) -> J1939<VirtualEndpoint> {
+        let mut node = J1939::new(bus.endpoint(), NetworkId::HSCAN, Name(name), address);
+        node.set_bam_interval(Duration::from_millis(5));
+        node
+    }
+
+    #[test]
+    fn test_identifier() {
+        let broadcast = Identifier::from_arbid(0x18feca00);
+        assert_eq!(
+            broadcast,
+            Identifier {
+                priority: 6,
+                pgn: PGN_DM1,
+                source: 0x00,
+                destination: ADDRESS_GLOBAL,
+            }
+        );
+        let request = Identifier::from_arbid(0x18ea00f9);
+        assert_eq!(request.pgn, PGN_REQUEST);
+        assert_eq!(request.destination, 0x00);
+        assert_eq!(request.source, 0xf9);
+        for arbid in [0x18feca00, 0x18ea00f9, 0x0cf00400, 0x1cebff80, 0x03ef1122] {
+            assert_eq!(Identifier::from_arbid(arbid).to_arbid(), arbid);
+        }
+        // The data page is part of the PGN.
+        assert_eq!(Identifier::from_arbid(0x19fe0000).pgn, 0x1fe00);
+
+        let name = Name(0xa00a_8100_4ca1_2345);
+        assert!(name.is_arbitrary_address_capable());
+        assert_eq!(name.industry_group(), 2);
+        assert_eq!(name.vehicle_system_instance(), 0);
+        assert_eq!(name.vehicle_system(), 5);
+        assert_eq!(name.function(), 0x81);
+        assert_eq!(name.function_instance(), 0);
+        assert_eq!(name.ecu_instance(), 0);
+        assert_eq!(name.manufacturer_code(), 0x265);
+        assert_eq!(name.identity_number(), 0x12345);
+    }
+
+    #[test]
+    fn test_dm1() {
+        let dm1 = Dm1::decode(&[
+            0x44, 0xff, 0x6e, 0x00, 0x00, 0x03, 0xff, 0xff, 0xff, 0x81, 0x00, 0x00, 0x00, 0x00,
+        ])
+        .unwrap();
+        assert_eq!(dm1.lamps.malfunction, 1);
+        assert_eq!(dm1.lamps.red_stop, 0);
+        assert_eq!(dm1.lamps.amber_warning, 1);
+        assert_eq!(dm1.flash.protect, 3);
+        assert_eq!(dm1.dtcs.len(), 2);
+        assert_eq!(dm1.dtcs[0].to_string(), "SPN 110 FMI 0 (occurred 3 times)");
+        assert_eq!(
+            dm1.dtcs[1],
+            Dtc {
+                spn: 0x7ffff,
+                fmi: 31,
+                occurrence_count: 1,
+                conversion_method: true,
+            }
+        );
+        // No active DTCs.
+        let dm1 = Dm1::decode(&[0x00, 0xff, 0, 0, 0, 0, 0xff, 0xff]).unwrap();
+        assert!(dm1.dtcs.is_empty());
+        assert!(matches!(Dm1::decode(&[0x00]), Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn test_address_claim() {
+        let bus = VirtualBus::new();
+        let mut winner = node(&bus, 0x100, 0x80);
+        let mut arbitrary = node(&bus, 0x8000_0000_0000_0200, 0x80);
+        let mut loser = node(&bus, 0x300, 0x80);
+        let mut observer = bus.endpoint();
+
+        assert_eq!(winner.claim_address().unwrap(), 0x80);
+        let defender = std::thread::spawn(move || {
+            while winner.receive(Duration::from_millis(400)).is_ok() {}
+            winner
+        });
+        assert_eq!(arbitrary.claim_address().unwrap(), 0x81);
+        assert_eq!(arbitrary.addresses()[&0x80], Name(0x100));
+        assert!(matches!(
+            loser.claim_address(),
+            Err(Error::ProtocolError(_))
+        ));
+        assert_eq!(loser.address(), None);
+        let winner = defender.join().unwrap();
+        assert_eq!(winner.address(), Some(0x80));
+        assert_eq!(winner.addresses()[&0x81], Name(0x8000_0000_0000_0200));
+
+        // The loser announced it has no address.
+        let cannot_claim = observer
+            .receive(Duration::ZERO)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Can(m) => Some(m),
+                _ => None,
+            })
+            .rfind(|m| m.data() == 0x300u64.to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            Identifier::from_arbid(cannot_claim.arbid).source,
+            ADDRESS_NULL
+        );
+    }
+
+    #[test]
+    fn test_transport_protocol() {
+        let bus = VirtualBus::new();
+        let mut sender = node(&bus, 0x100, 0x80);
+        let mut receiver = node(&bus, 0x200, 0x90);
+        sender.claim_address().unwrap();
+        receiver.claim_address().unwrap();
+
+        let dm1: Vec<u8> = [0x44, 0xff]
+            .into_iter()
+            .chain((0..5u8).flat_map(|spn| [spn + 1, 0, 0x03, 0x01]))
+            .collect();
+        let proprietary: Vec<u8> = (0..100).collect();
+        std::thread::scope(|s| {
+            let received = s.spawn(|| {
+                let mut messages = Vec::new();
+                while let Ok(message) = receiver.receive(Duration::from_millis(500)) {
+                    // Address claims are passed on too.
+                    if message.pgn != PGN_ADDRESS_CLAIMED {
+                        messages.push(message);
+                    }
+                }
+                messages
+            });
+            // BAM, then RTS/CTS.
+            sender.send(PGN_DM1, 6, ADDRESS_GLOBAL, &dm1).unwrap();
+            sender.send(0xef00, 6, 0x90, &proprietary).unwrap();
+            sender.send(0xef00, 6, 0x90, &[1, 2, 3]).unwrap();
+            // Not for the receiver.
+            sender.send(0xef00, 6, 0x91, &[4, 5, 6]).unwrap();
+
+            let messages = received.join().unwrap();
+            assert_eq!(messages.len(), 3);
+            assert_eq!(messages[0].pgn, PGN_DM1);
+            assert_eq!(messages[0].source, 0x80);
+            assert_eq!(messages[0].data, dm1);
+            assert_eq!(Dm1::decode(&messages[0].data).unwrap().dtcs.len(), 5);
+            assert_eq!(messages[1].pgn, 0xef00);
+            assert_eq!(messages[1].destination, 0x90);
+            assert_eq!(messages[1].data, proprietary);
+            assert_eq!(messages[2].data, [1, 2, 3, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        });
+    }
+
+    #[test]
+    fn test_transfer_abort() {
+        let bus = VirtualBus::new();
+        let mut sender = node(&bus, 0x100, 0x80);
+        let mut peer = bus.endpoint();
+        sender.claim_address().unwrap();
+        peer.receive(Duration::ZERO).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let rts = peer.receive(Duration::from_secs(1)).unwrap();
+                let Message::Can(rts) = &rts[0] else { panic!() };
+                let pgn = &rts.data()[5..8];
+                let hold = [&[CONTROL_CTS, 0, 1, 0xff, 0xff][..], pgn].concat();
+                let abort = [&[CONTROL_ABORT, 2, 0xff, 0xff, 0xff][..], pgn].concat();
+                for data in [hold, abort] {
+                    let mut frame = CanMessage::new(1, 0x1cec8090, &data);
+                    frame.set_extended(true);
+                    peer.transmit(&Message::Can(frame)).unwrap();
+                }
+            });
+            assert!(matches!(
+                sender.send(0xef00, 6, 0x90, &[0; 20]),
+                Err(Error::ProtocolError(_))
+            ));
+        });
+
+        let mut unclaimed = node(&bus, 0x300, 0x70);
+        assert!(matches!(
+            unclaimed.send(0xef00, 6, 0x90, &[0]),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
@@ -9,7 +9,29 @@
 //! 
 //! [GitHub libicsneo-rs](https://github.com/intrepidcs/libicsneo-rs)
 
+pub mod a2l;
+pub mod bus;
+pub mod canopen;
+pub mod clock;
+pub mod confirm;
+pub mod dbc;
+pub mod device;
+pub mod errorstate;
+pub mod filter;
+pub mod isotp;
+pub mod j1939;
+pub mod log;
+pub mod merge;
+pub mod message;
 pub mod native;
+pub mod network;
+pub mod obd;
+pub mod replay;
+pub mod scheduler;
+pub mod stats;
+pub mod timebase;
+pub mod uds;
+pub mod xcp;
 
 #[cfg(feature = "python")]
 mod python;
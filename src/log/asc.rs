@@ -0,0 +1,645 @@
+//! Vector ASCII log (ASC) writer and reader.
+//!
+//! Written files use `base hex` and absolute timestamps in seconds since the first message (or
+//! the origin set with [AscWriter::set_time_origin](AscWriter::set_time_origin)). Timestamps of
+//! received messages are already nanoseconds, see [Timestamps](super#timestamps), so they can
+//! be written as they are:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::bus::Receive;
+//! use icsneo::log::asc::AscWriter;
+//! use icsneo::native::*;
+//!
+//! let mut device = find_all_devices().unwrap().remove(0);
+//! open_device(&device).unwrap();
+//! go_online(&device).unwrap();
+//! enable_message_polling(&device);
+//!
+//! let mut writer = AscWriter::new(std::fs::File::create("capture.asc").unwrap());
+//! for message in device.receive(Duration::from_millis(100)).unwrap() {
+//!     writer.write(&message).unwrap();
+//! }
+//! writer.finish().unwrap();
+//! ```
+//!
+//! The header date is written and read as UTC.
+use std::io::{BufRead, Write};
+use std::time::SystemTime;
+
+use super::{ticks_to_nanos, ChannelMap, CivilTime};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const CANFD_EDL: u32 = 0x1000;
+const CANFD_BRS: u32 = 0x2000;
+const CANFD_ESI: u32 = 0x4000;
+
+/// Writes messages as an ASC text log. Call [finish](AscWriter::finish) to close the trigger
+/// block.
+pub struct AscWriter<W: Write> {
+    inner: W,
+    channels: ChannelMap,
+    resolution: u64,
+    start_time: SystemTime,
+    origin: Option<u64>,
+    header_written: bool,
+}
+
+impl<W: Write> AscWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            start_time: SystemTime::now(),
+            origin: None,
+            header_written: false,
+        }
+    }
+
+    /// Maps netids to ASC channel numbers. Unmapped netids use their own value as channel.
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per message timestamp tick, see [Timestamps](super#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Wall-clock time written to the `date` header. Defaults to the time the writer was
+    /// created.
+    pub fn set_start_time(&mut self, start_time: SystemTime) {
+        self.start_time = start_time;
+    }
+
+    /// Message timestamp (in ticks) that corresponds to time zero in the file. Defaults to the
+    /// timestamp of the first message written.
+    pub fn set_time_origin(&mut self, timestamp: u64) {
+        self.origin = Some(timestamp);
+    }
+
+    /// Writes any message kind this format supports, other kinds are skipped.
+    ///
+    /// # Safety
+    ///
+    /// See [Message::from_neo].
+    pub unsafe fn write_message(&mut self, message: &NeoMessage) -> Result<()> {
+        match Message::from_neo(message) {
+            Some(message) => self.write(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Can(m) => self.write_can(m),
+            Message::CanError(m) => self.write_can_error(m),
+            Message::Eth(m) => self.write_eth(m),
+        }
+    }
+
+    pub fn write_can(&mut self, message: &CanMessage) -> Result<()> {
+        let time = self.event_time(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let status = message.status;
+        let data = message.data();
+        let dir = direction(status::get(status, status::TRANSMIT));
+        let mut id = format!("{:X}", { message.arbid });
+        if status::get(status, status::EXTENDED) {
+            id.push('x');
+        }
+        let remote = status::get(status, status::REMOTE);
+        let dlc = if remote {
+            message.dlcOnWire.max(len_to_dlc(data.len()))
+        } else {
+            len_to_dlc(data.len())
+        };
+
+        let line = if status::get(status, status::CANFD_FDF) {
+            let brs = status::get(status, status::CANFD_BRS);
+            let esi = status::get(status, status::CANFD_ESI);
+            let mut flags = CANFD_EDL;
+            if brs {
+                flags |= CANFD_BRS;
+            }
+            if esi {
+                flags |= CANFD_ESI;
+            }
+            if status::get(status, status::ERROR_FRAME) {
+                format!("CANFD {channel:>3} {dir:<4} ErrorFrame")
+            } else {
+                format!(
+                    "CANFD {channel:>3} {dir:<4} {id:>8}  {:>32} {} {} {dlc:x} {:>2} {} {:>8} {:>4} {flags:>8X} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                    "",
+                    u8::from(brs),
+                    u8::from(esi),
+                    data.len(),
+                    hex_bytes(data),
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                )
+            }
+        } else if status::get(status, status::ERROR_FRAME) {
+            format!("{channel}  ErrorFrame")
+        } else if remote {
+            format!("{channel}  {id:<15} {dir:<4} r {dlc:x}")
+        } else {
+            format!("{channel}  {id:<15} {dir:<4} d {dlc:x} {}", hex_bytes(data))
+        };
+        self.write_event(time, line.trim_end())
+    }
+
+    pub fn write_can_error(&mut self, message: &NeoMessageCanError) -> Result<()> {
+        let time = self.event_time(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let (tec, rec) = (message.transmitErrorCount, message.receiveErrorCount);
+        let state = if tec >= 128 || rec >= 128 {
+            "error passive"
+        } else if tec >= 96 || rec >= 96 {
+            "warning level"
+        } else {
+            "error active"
+        };
+        let line = format!("CAN {channel} Status:chip status {state} - TxErr: {tec} RxErr: {rec}");
+        self.write_event(time, &line)
+    }
+
+    pub fn write_eth(&mut self, message: &EthMessage) -> Result<()> {
+        let time = self.event_time(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let data = message.data();
+        let dir = direction(status::get(message.status, status::TRANSMIT));
+        let hex: String = data.iter().map(|b| format!("{b:02X}")).collect();
+        let line = format!("ETH {channel} {dir:<4} {:x}:{hex}", data.len());
+        self.write_event(time, &line)
+    }
+
+    /// Closes the trigger block and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header()?;
+        writeln!(self.inner, "End TriggerBlock")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.header_written = true;
+        let date = format_date(CivilTime::from_system_time(self.start_time));
+        writeln!(self.inner, "date {date}")?;
+        writeln!(self.inner, "base hex  timestamps absolute")?;
+        writeln!(self.inner, "internal events logged")?;
+        writeln!(self.inner, "// version 9.0.0")?;
+        writeln!(self.inner, "Begin Triggerblock {date}")?;
+        writeln!(self.inner, "{:>11.6} Start of measurement", 0.0)?;
+        Ok(())
+    }
+
+    /// Writes the header if needed and returns the seconds since the origin.
+    fn event_time(&mut self, timestamp: u64) -> Result<f64> {
+        self.write_header()?;
+        let origin = *self.origin.get_or_insert(timestamp);
+        let ns = ticks_to_nanos(timestamp.saturating_sub(origin), self.resolution)?;
+        Ok(ns as f64 / 1e9)
+    }
+
+    fn write_event(&mut self, time: f64, line: &str) -> Result<()> {
+        writeln!(self.inner, "{time:>11.6} {line}")?;
+        Ok(())
+    }
+}
+
+/// Reads messages back out of an ASC log. Lines that aren't CAN, CAN FD, error frame, error
+/// counter or Ethernet events are skipped.
+pub struct AscReader<R: BufRead> {
+    inner: R,
+    channels: ChannelMap,
+    resolution: u64,
+    start_time: Option<SystemTime>,
+    hex: bool,
+    relative: bool,
+    last_time: f64,
+    pending: Option<String>,
+    line_number: usize,
+}
+
+impl<R: BufRead> AscReader<R> {
+    /// Reads the header lines up to the first event.
+    pub fn new(inner: R) -> Result<Self> {
+        let mut reader = Self {
+            inner,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            start_time: None,
+            hex: true,
+            relative: false,
+            last_time: 0.0,
+            pending: None,
+            line_number: 0,
+        };
+        while let Some(line) = reader.next_line()? {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("date") => reader.start_time = parse_date(tokens),
+                Some("base") => {
+                    let tokens: Vec<&str> = tokens.collect();
+                    reader.hex = tokens.first() != Some(&"dec");
+                    reader.relative = tokens.get(2) == Some(&"relative");
+                }
+                Some("Begin") | Some("//") | Some("internal") | Some("no") | None => {}
+                Some(_) => {
+                    reader.pending = Some(line);
+                    break;
+                }
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Maps ASC channel numbers back to netids. Unmapped channels become the netid with the
+    /// same value.
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per tick for the timestamps of returned messages. Defaults to 1.
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// The `date` header, if present.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start_time
+    }
+
+    /// Returns the next supported message or `None` at the end of the file.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        while let Some(line) = self.next_line()? {
+            if let Some(message) = self.parse_line(&line)? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        Ok(Some(line.trim().to_string()))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<Option<Message>> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some(time) = tokens.first().and_then(|t| t.parse::<f64>().ok()) else {
+            return Ok(None);
+        };
+        let time = if self.relative {
+            self.last_time + time
+        } else {
+            time
+        };
+        self.last_time = time;
+        let mut message = match self.parse_event(&tokens[1..]) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                return Err(Error::ParseError(format!(
+                    "ASC line {}: {e}: {line}",
+                    self.line_number
+                )))
+            }
+        };
+        let ns = (time * 1e9).round() as u64;
+        message.set_timestamp(ns / self.resolution);
+        Ok(Some(message))
+    }
+
+    fn parse_event(&self, tokens: &[&str]) -> std::result::Result<Option<Message>, String> {
+        let token = |i: usize| tokens.get(i).copied().ok_or("missing field");
+        match tokens {
+            ["CANFD", ..] => self.parse_can_fd(&tokens[1..]).map(Some),
+            ["ETH", ..] => {
+                let channel = parse_channel(token(1)?)?;
+                let (len, hex) = token(3)?.split_once(':').ok_or("missing frame data")?;
+                if !hex.is_ascii() || hex.len() % 2 != 0 {
+                    return Err("invalid frame data".to_string());
+                }
+                let len = usize::from_str_radix(len, 16).map_err(|e| e.to_string())?;
+                let data = (0..hex.len() / 2)
+                    .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16))
+                    .collect::<std::result::Result<Vec<u8>, _>>()
+                    .map_err(|e| e.to_string())?;
+                if data.len() != len {
+                    return Err(format!("expected {len} bytes, found {}", data.len()));
+                }
+                let mut m = EthMessage::new(self.channels.netid(channel), &data);
+                m.set_transmit(token(2)? == "Tx");
+                Ok(Some(Message::Eth(m)))
+            }
+            ["CAN", channel, status, ..] if status.starts_with("Status:") => {
+                let channel = parse_channel(channel)?;
+                let count = |name: &str| -> std::result::Result<u8, String> {
+                    let i = tokens
+                        .iter()
+                        .position(|t| *t == name)
+                        .ok_or("missing count")?;
+                    token(i + 1)?.parse::<u8>().map_err(|e| e.to_string())
+                };
+                let mut m = NeoMessageCanError::new();
+                m.netid = self.channels.netid(channel);
+                m.type_ = NETWORK_TYPE_CAN;
+                m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+                m.transmitErrorCount = count("TxErr:")?;
+                m.receiveErrorCount = count("RxErr:")?;
+                Ok(Some(Message::CanError(m)))
+            }
+            [channel, "ErrorFrame", ..] => {
+                let mut m = CanMessage::new(self.channels.netid(parse_channel(channel)?), 0, &[]);
+                m.set_error_frame(true);
+                Ok(Some(Message::Can(m)))
+            }
+            [channel, id, dir, kind @ ("d" | "r"), ..] => {
+                let Ok(channel) = channel.parse::<u16>() else {
+                    return Ok(None);
+                };
+                let (arbid, extended) = self.parse_id(id)?;
+                let dlc = match tokens.get(4) {
+                    Some(dlc) => self.parse_byte(dlc)?,
+                    None => 0,
+                };
+                let mut m = CanMessage::new(self.channels.netid(channel), arbid, &[]);
+                m.set_extended(extended);
+                m.set_transmit(*dir == "Tx");
+                m.set_dlc_on_wire(dlc);
+                if *kind == "r" {
+                    m.set_remote(true);
+                } else {
+                    let len = usize::from(dlc.min(8));
+                    let data = tokens
+                        .get(5..5 + len)
+                        .ok_or("missing data bytes")?
+                        .iter()
+                        .map(|b| self.parse_byte(b))
+                        .collect::<std::result::Result<Vec<u8>, String>>()?;
+                    m.set_data(&data);
+                }
+                Ok(Some(Message::Can(m)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_can_fd(&self, tokens: &[&str]) -> std::result::Result<Message, String> {
+        let token = |i: usize| tokens.get(i).copied().ok_or("missing field");
+        let channel = parse_channel(token(0)?)?;
+        let mut m = CanMessage::new(self.channels.netid(channel), 0, &[]);
+        m.set_fd(true);
+        m.set_transmit(token(1)? == "Tx");
+        if token(2)? == "ErrorFrame" {
+            m.set_error_frame(true);
+            return Ok(Message::Can(m));
+        }
+        let (arbid, extended) = self.parse_id(token(2)?)?;
+        m.set_arbid(arbid);
+        m.set_extended(extended);
+        // An optional symbolic name sits between the ID and the BRS flag.
+        let mut i = 3;
+        if !matches!(token(i)?, "0" | "1") {
+            i += 1;
+        }
+        let brs = token(i)? == "1";
+        let esi = token(i + 1)? == "1";
+        let dlc = u8::from_str_radix(token(i + 2)?, 16).map_err(|e| e.to_string())?;
+        let len: usize = token(i + 3)?
+            .parse()
+            .map_err(|e| format!("data length: {e}"))?;
+        let data = tokens
+            .get(i + 4..i + 4 + len)
+            .ok_or("missing data bytes")?
+            .iter()
+            .map(|b| self.parse_byte(b))
+            .collect::<std::result::Result<Vec<u8>, String>>()?;
+        // Newer files carry EDL in the flags column, classic frames logged as CANFD have it clear.
+        if let Some(flags) = tokens.get(i + 6 + len) {
+            let flags = u32::from_str_radix(flags, 16).map_err(|e| e.to_string())?;
+            m.set_fd(flags & CANFD_EDL != 0 || brs || esi || len > 8);
+        }
+        m.set_brs(brs);
+        m.set_esi(esi);
+        m.set_dlc_on_wire(dlc);
+        m.set_data(&data);
+        Ok(Message::Can(m))
+    }
+
+    fn parse_id(&self, id: &str) -> std::result::Result<(u32, bool), String> {
+        let (id, extended) = match id.strip_suffix('x') {
+            Some(id) => (id, true),
+            None => (id, false),
+        };
+        let radix = if self.hex { 16 } else { 10 };
+        let id = u32::from_str_radix(id, radix).map_err(|e| format!("id {id}: {e}"))?;
+        Ok((id, extended))
+    }
+
+    fn parse_byte(&self, byte: &str) -> std::result::Result<u8, String> {
+        let radix = if self.hex { 16 } else { 10 };
+        u8::from_str_radix(byte, radix).map_err(|e| format!("byte {byte}: {e}"))
+    }
+}
+
+impl<R: BufRead> Iterator for AscReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn direction(transmit: bool) -> &'static str {
+    if transmit {
+        "Tx"
+    } else {
+        "Rx"
+    }
+}
+
+fn hex_bytes(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn parse_channel(channel: &str) -> std::result::Result<u16, String> {
+    channel
+        .parse::<u16>()
+        .map_err(|e| format!("channel {channel}: {e}"))
+}
+
+/// Formats a date the way CANoe does, e.g. `Fri Jul 10 06:17:32.249 am 2015`.
+fn format_date(time: CivilTime) -> String {
+    let hour = match time.hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    let am_pm = if time.hour < 12 { "am" } else { "pm" };
+    format!(
+        "{} {} {:02} {:02}:{:02}:{:02}.{:03} {} {}",
+        WEEKDAYS[usize::from(time.weekday % 7)],
+        MONTHS[usize::from(time.month.clamp(1, 12) - 1)],
+        time.day,
+        hour,
+        time.minute,
+        time.second,
+        time.millisecond,
+        am_pm,
+        time.year
+    )
+}
+
+/// Parses the fields after `date`, with or without milliseconds and am/pm.
+fn parse_date<'a>(tokens: impl Iterator<Item = &'a str>) -> Option<SystemTime> {
+    let tokens: Vec<&str> = tokens.collect();
+    let month = MONTHS.iter().position(|m| Some(m) == tokens.get(1))? as u16 + 1;
+    let day = tokens.get(2)?.parse().ok()?;
+    let (clock, millisecond) = match tokens.get(3)?.split_once('.') {
+        Some((clock, ms)) => (clock, ms.get(..3).unwrap_or(ms).parse().ok()?),
+        None => (*tokens.get(3)?, 0),
+    };
+    let mut clock = clock.split(':').map(|v| v.parse::<u16>());
+    let (hour, minute, second) = (
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+    );
+    let (hour, year) = match tokens.get(4).copied() {
+        Some("am") => (hour % 12, tokens.get(5)?),
+        Some("pm") => (hour % 12 + 12, tokens.get(5)?),
+        _ => (hour, tokens.get(4)?),
+    };
+    let time = CivilTime {
+        year: year.parse().ok()?,
+        month,
+        weekday: 0,
+        day,
+        hour,
+        minute,
+        second,
+        millisecond,
+    };
+    Some(time.to_system_time())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::golden::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn write_golden(resolution: u16) -> String {
+        let mut writer = AscWriter::new(Vec::new());
+        writer.set_channel_map(channel_map());
+        writer.set_timestamp_resolution(resolution);
+        writer.set_start_time(start_time());
+        for mut message in golden_messages() {
+            message.set_timestamp(message.timestamp() / u64::from(resolution));
+            writer.write(&message).unwrap();
+        }
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_write_golden() {
+        assert_eq!(write_golden(1), include_str!("testdata/golden.asc"));
+        // Timestamps in other units are scaled by the resolution.
+        assert_eq!(write_golden(25), include_str!("testdata/golden.asc"));
+    }
+
+    #[test]
+    fn test_read_golden() {
+        let mut reader = AscReader::new(include_str!("testdata/golden.asc").as_bytes()).unwrap();
+        reader.set_channel_map(channel_map());
+        assert_eq!(reader.start_time(), Some(start_time()));
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        let written = golden_messages();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            assert_same(read, written, written.timestamp() - ORIGIN);
+        }
+    }
+
+    #[test]
+    fn test_read_canoe() {
+        let mut reader = AscReader::new(include_str!("testdata/canoe.asc").as_bytes()).unwrap();
+        reader.set_timestamp_resolution(10);
+        assert_eq!(
+            reader.start_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1664460310))
+        );
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), 4);
+
+        let Message::Can(m) = &read[0] else {
+            panic!("{:?}", read[0])
+        };
+        assert_eq!(
+            ({ m.netid }, { m.arbid }, { m.timestamp }),
+            (1, 291, 1_000_000)
+        );
+        assert_eq!(m.data(), &[1, 2]);
+
+        // Relative timestamps keep counting across skipped events.
+        let Message::Can(m) = &read[1] else {
+            panic!("{:?}", read[1])
+        };
+        assert_eq!(({ m.arbid }, { m.timestamp }), (2016, 2_500_000));
+        assert!(m.is_fd() && m.is_transmit() && m.is_esi() && !m.is_brs());
+        assert_eq!(m.data(), (0..12).collect::<Vec<u8>>());
+
+        let Message::Can(m) = &read[2] else {
+            panic!("{:?}", read[2])
+        };
+        assert_eq!({ m.arbid }, 100);
+        assert!(m.is_remote() && m.is_extended());
+        assert_eq!({ m.dlcOnWire }, 4);
+
+        let Message::Can(m) = &read[3] else {
+            panic!("{:?}", read[3])
+        };
+        assert_eq!({ m.netid }, 2);
+        assert!(m.is_fd() && m.is_error_frame());
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let input = "base hex  timestamps absolute\n   0.000000 1  12G             Rx   d 1 00\n";
+        let mut reader = AscReader::new(input.as_bytes()).unwrap();
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+        let input = "   0.000000 1  123             Rx   d 8 00 01\n";
+        let mut reader = AscReader::new(input.as_bytes()).unwrap();
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+    }
+}
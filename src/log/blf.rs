@@ -0,0 +1,853 @@
+//! Vector Binary Logging Format (BLF) writer and reader.
+//!
+//! CAN frames are stored as `CAN_MESSAGE` objects, CAN FD frames as `CAN_FD_MESSAGE`, error
+//! frames as `CAN_ERROR_EXT`, error counters from [NeoMessageCanError](NeoMessageCanError) as
+//! `CAN_DRIVER_ERROR` and Ethernet frames as `ETHERNET_FRAME_EX`. Objects are packed into zlib
+//! compressed `LOG_CONTAINER`s like CANoe does. The reader additionally understands
+//! `CAN_MESSAGE2`, `CAN_FD_MESSAGE_64`, `CAN_ERROR` and `ETHERNET_FRAME`.
+//!
+//! Object timestamps are nanoseconds relative to the first message written, or to the origin
+//! set with [BlfWriter::set_time_origin](BlfWriter::set_time_origin).
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use super::{read_fully, ticks_to_nanos, ChannelMap, CivilTime};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const FILE_SIGNATURE: &[u8; 4] = b"LOGG";
+const OBJECT_SIGNATURE: &[u8; 4] = b"LOBJ";
+const FILE_HEADER_SIZE: usize = 144;
+const OBJECT_HEADER_BASE_SIZE: usize = 16;
+const OBJECT_HEADER_V1_SIZE: usize = 32;
+const LOG_CONTAINER_HEADER_SIZE: usize = 16;
+const MAX_CONTAINER_SIZE: usize = 128 * 1024;
+const APPLICATION_ID: u8 = 0;
+
+const CAN_MESSAGE: u32 = 1;
+const CAN_ERROR: u32 = 2;
+const LOG_CONTAINER: u32 = 10;
+const CAN_DRIVER_ERROR: u32 = 31;
+const ETHERNET_FRAME: u32 = 71;
+const CAN_ERROR_EXT: u32 = 73;
+const CAN_MESSAGE2: u32 = 86;
+const CAN_FD_MESSAGE: u32 = 100;
+const CAN_FD_MESSAGE_64: u32 = 101;
+const ETHERNET_FRAME_EX: u32 = 120;
+
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+
+const TIME_TEN_MICS: u32 = 0x1;
+const TIME_ONE_NANS: u32 = 0x2;
+
+const CAN_MSG_EXT: u32 = 0x8000_0000;
+const CAN_DIR_TX: u8 = 0x1;
+const CAN_REMOTE: u8 = 0x80;
+const CANFD_EDL: u8 = 0x1;
+const CANFD_BRS: u8 = 0x2;
+const CANFD_ESI: u8 = 0x4;
+const CANFD64_REMOTE: u32 = 0x0010;
+const CANFD64_EDL: u32 = 0x1000;
+const CANFD64_BRS: u32 = 0x2000;
+const CANFD64_ESI: u32 = 0x4000;
+const ETH_STRUCT_LENGTH: u16 = 32;
+const ETH_DIR_TX: u16 = 1;
+
+/// Writes messages into a BLF file. Call [finish](BlfWriter::finish) to flush the last
+/// container and fill in the file header.
+pub struct BlfWriter<W: Write + Seek> {
+    inner: W,
+    start_position: u64,
+    channels: ChannelMap,
+    resolution: u64,
+    compress: bool,
+    start_time: SystemTime,
+    origin: Option<u64>,
+    last_timestamp: u64,
+    buffer: Vec<u8>,
+    object_count: u32,
+    uncompressed_size: u64,
+}
+
+impl<W: Write + Seek> BlfWriter<W> {
+    /// Reserves space for the file header at the current position of `inner`.
+    pub fn new(mut inner: W) -> Result<Self> {
+        let start_position = inner.stream_position()?;
+        inner.write_all(&[0u8; FILE_HEADER_SIZE])?;
+        Ok(Self {
+            inner,
+            start_position,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            compress: true,
+            start_time: SystemTime::now(),
+            origin: None,
+            last_timestamp: 0,
+            buffer: Vec::new(),
+            object_count: 0,
+            uncompressed_size: FILE_HEADER_SIZE as u64,
+        })
+    }
+
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per message timestamp tick, see [Timestamps](super#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Enables zlib compression of log containers. Enabled by default.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Wall-clock time of the measurement start stored in the file header. Defaults to the
+    /// time the writer was created.
+    pub fn set_start_time(&mut self, start_time: SystemTime) {
+        self.start_time = start_time;
+    }
+
+    /// Message timestamp (in ticks) that corresponds to time zero in the file. Defaults to the
+    /// timestamp of the first message written.
+    pub fn set_time_origin(&mut self, timestamp: u64) {
+        self.origin = Some(timestamp);
+    }
+
+    /// Writes any message kind this format supports, other kinds are skipped.
+    ///
+    /// # Safety
+    ///
+    /// See [Message::from_neo].
+    pub unsafe fn write_message(&mut self, message: &NeoMessage) -> Result<()> {
+        match Message::from_neo(message) {
+            Some(message) => self.write(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Can(m) => self.write_can(m),
+            Message::CanError(m) => self.write_can_error(m),
+            Message::Eth(m) => self.write_eth(m),
+        }
+    }
+
+    pub fn write_can(&mut self, message: &CanMessage) -> Result<()> {
+        let timestamp = self.relative_timestamp(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let status = message.status;
+        let data = message.data();
+        let mut id = message.arbid;
+        if status::get(status, status::EXTENDED) {
+            id |= CAN_MSG_EXT;
+        }
+        let mut flags = 0u8;
+        if status::get(status, status::TRANSMIT) {
+            flags |= CAN_DIR_TX;
+        }
+        let remote = status::get(status, status::REMOTE);
+        if remote {
+            flags |= CAN_REMOTE;
+        }
+        let dlc = if remote {
+            message.dlcOnWire.max(len_to_dlc(data.len()))
+        } else {
+            len_to_dlc(data.len())
+        };
+
+        let mut payload = Vec::with_capacity(84);
+        if status::get(status, status::ERROR_FRAME) {
+            payload.extend_from_slice(&channel.to_le_bytes());
+            payload.extend_from_slice(&0u16.to_le_bytes()); // length
+            payload.extend_from_slice(&0u32.to_le_bytes()); // flags
+            payload.extend_from_slice(&[0, 0, dlc, 0]); // ecc, position, dlc, reserved
+            payload.extend_from_slice(&0u32.to_le_bytes()); // frame length
+            payload.extend_from_slice(&id.to_le_bytes());
+            payload.extend_from_slice(&0u16.to_le_bytes()); // extended flags
+            payload.extend_from_slice(&0u16.to_le_bytes()); // reserved
+            payload.extend_from_slice(&padded::<8>(data));
+            self.write_object(CAN_ERROR_EXT, timestamp, &payload)
+        } else if status::get(status, status::CANFD_FDF) {
+            let mut fd_flags = CANFD_EDL;
+            if status::get(status, status::CANFD_BRS) {
+                fd_flags |= CANFD_BRS;
+            }
+            if status::get(status, status::CANFD_ESI) {
+                fd_flags |= CANFD_ESI;
+            }
+            let data = &data[..data.len().min(64)];
+            payload.extend_from_slice(&channel.to_le_bytes());
+            payload.extend_from_slice(&[flags, dlc]);
+            payload.extend_from_slice(&id.to_le_bytes());
+            payload.extend_from_slice(&0u32.to_le_bytes()); // frame length
+            payload.extend_from_slice(&[0, fd_flags, data.len() as u8]); // bit count, fd flags, valid bytes
+            payload.extend_from_slice(&[0u8; 5]);
+            payload.extend_from_slice(&padded::<64>(data));
+            self.write_object(CAN_FD_MESSAGE, timestamp, &payload)
+        } else {
+            payload.extend_from_slice(&channel.to_le_bytes());
+            payload.extend_from_slice(&[flags, dlc.min(8)]);
+            payload.extend_from_slice(&id.to_le_bytes());
+            payload.extend_from_slice(&padded::<8>(data));
+            self.write_object(CAN_MESSAGE, timestamp, &payload)
+        }
+    }
+
+    pub fn write_can_error(&mut self, message: &NeoMessageCanError) -> Result<()> {
+        let timestamp = self.relative_timestamp(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&channel.to_le_bytes());
+        payload.extend_from_slice(&[message.transmitErrorCount, message.receiveErrorCount]);
+        payload.extend_from_slice(&0u32.to_le_bytes()); // error code
+        self.write_object(CAN_DRIVER_ERROR, timestamp, &payload)
+    }
+
+    pub fn write_eth(&mut self, message: &EthMessage) -> Result<()> {
+        let timestamp = self.relative_timestamp(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let data = message.data();
+        let frame_length = u16::try_from(data.len()).map_err(|_| {
+            Error::CriticalError(format!("Ethernet frame too long: {} bytes", data.len()))
+        })?;
+        let dir = if status::get(message.status, status::TRANSMIT) {
+            ETH_DIR_TX
+        } else {
+            0
+        };
+        let mut payload = Vec::with_capacity(32 + data.len());
+        payload.extend_from_slice(&ETH_STRUCT_LENGTH.to_le_bytes());
+        payload.extend_from_slice(&0u16.to_le_bytes()); // flags
+        payload.extend_from_slice(&channel.to_le_bytes());
+        payload.extend_from_slice(&0u16.to_le_bytes()); // hardware channel
+        payload.extend_from_slice(&0u64.to_le_bytes()); // frame duration
+        payload.extend_from_slice(&0u32.to_le_bytes()); // frame checksum
+        payload.extend_from_slice(&dir.to_le_bytes());
+        payload.extend_from_slice(&frame_length.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes()); // frame handle
+        payload.extend_from_slice(&0u32.to_le_bytes()); // reserved
+        payload.extend_from_slice(data);
+        self.write_object(ETHERNET_FRAME_EX, timestamp, &payload)
+    }
+
+    /// Flushes buffered objects, writes the file header and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        while !self.buffer.is_empty() {
+            let len = self.buffer.len().min(MAX_CONTAINER_SIZE);
+            self.write_container(len)?;
+        }
+        let end = self.inner.stream_position()?;
+        let stop_time = self.start_time + Duration::from_nanos(self.last_timestamp);
+
+        let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+        header.extend_from_slice(FILE_SIGNATURE);
+        header.extend_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        // application id/major/minor/build, binlog major/minor/build/patch
+        header.extend_from_slice(&[APPLICATION_ID, 0, 0, 0, 2, 6, 8, 1]);
+        header.extend_from_slice(&(end - self.start_position).to_le_bytes());
+        header.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        header.extend_from_slice(&self.object_count.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // objects read
+        write_system_time(&mut header, CivilTime::from_system_time(self.start_time));
+        write_system_time(&mut header, CivilTime::from_system_time(stop_time));
+        header.resize(FILE_HEADER_SIZE, 0);
+
+        self.inner.seek(SeekFrom::Start(self.start_position))?;
+        self.inner.write_all(&header)?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn relative_timestamp(&mut self, timestamp: u64) -> Result<u64> {
+        let origin = *self.origin.get_or_insert(timestamp);
+        let relative = ticks_to_nanos(timestamp.saturating_sub(origin), self.resolution)?;
+        self.last_timestamp = self.last_timestamp.max(relative);
+        Ok(relative)
+    }
+
+    fn write_object(&mut self, object_type: u32, timestamp: u64, payload: &[u8]) -> Result<()> {
+        let size = OBJECT_HEADER_V1_SIZE + payload.len();
+        let buffer = &mut self.buffer;
+        buffer.extend_from_slice(OBJECT_SIGNATURE);
+        buffer.extend_from_slice(&(OBJECT_HEADER_V1_SIZE as u16).to_le_bytes());
+        buffer.extend_from_slice(&1u16.to_le_bytes()); // header version
+        buffer.extend_from_slice(&(size as u32).to_le_bytes());
+        buffer.extend_from_slice(&object_type.to_le_bytes());
+        buffer.extend_from_slice(&TIME_ONE_NANS.to_le_bytes());
+        buffer.extend_from_slice(&0u16.to_le_bytes()); // client index
+        buffer.extend_from_slice(&0u16.to_le_bytes()); // object version
+        buffer.extend_from_slice(&timestamp.to_le_bytes());
+        buffer.extend_from_slice(payload);
+        buffer.resize(buffer.len() + size % 4, 0);
+        self.object_count += 1;
+        while self.buffer.len() >= MAX_CONTAINER_SIZE {
+            self.write_container(MAX_CONTAINER_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn write_container(&mut self, len: usize) -> Result<()> {
+        let uncompressed: Vec<u8> = self.buffer.drain(..len).collect();
+        let (method, data) = if self.compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&uncompressed)?;
+            (ZLIB_DEFLATE, encoder.finish()?)
+        } else {
+            (NO_COMPRESSION, uncompressed.clone())
+        };
+        let size = OBJECT_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE + data.len();
+        let mut header = Vec::with_capacity(OBJECT_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE);
+        header.extend_from_slice(OBJECT_SIGNATURE);
+        header.extend_from_slice(&(OBJECT_HEADER_BASE_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // header version
+        header.extend_from_slice(&(size as u32).to_le_bytes());
+        header.extend_from_slice(&LOG_CONTAINER.to_le_bytes());
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&[0u8; 6]);
+        header.extend_from_slice(&(uncompressed.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[0u8; 4]);
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&data)?;
+        self.inner.write_all(&[0u8; 3][..size % 4])?;
+        self.uncompressed_size += (size - data.len() + uncompressed.len()) as u64;
+        Ok(())
+    }
+}
+
+/// Reads messages back out of a BLF file. Objects of unsupported types are skipped.
+pub struct BlfReader<R: Read> {
+    inner: R,
+    channels: ChannelMap,
+    resolution: u64,
+    object_count: u32,
+    start_time: SystemTime,
+    pending: Vec<u8>,
+    skip: usize,
+}
+
+impl<R: Read> BlfReader<R> {
+    /// Reads and validates the file header.
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut header = [0u8; 8];
+        inner.read_exact(&mut header)?;
+        if &header[..4] != FILE_SIGNATURE {
+            return Err(Error::ParseError("Not a BLF file".to_string()));
+        }
+        let header_size = u32_at(&header, 4) as usize;
+        if header_size < 72 {
+            return Err(Error::ParseError(format!(
+                "BLF file header too small: {header_size} bytes"
+            )));
+        }
+        let mut rest = vec![0u8; header_size - header.len()];
+        inner.read_exact(&mut rest)?;
+        let header = [&header[..], &rest].concat();
+        Ok(Self {
+            inner,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            object_count: u32_at(&header, 32),
+            start_time: read_system_time(&header[40..56]).to_system_time(),
+            pending: Vec::new(),
+            skip: 0,
+        })
+    }
+
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per tick for the timestamps of returned messages. Defaults to 1.
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Number of objects the file header claims the file contains.
+    pub fn object_count(&self) -> u32 {
+        self.object_count
+    }
+
+    /// Wall-clock time of the measurement start from the file header.
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+
+    /// Returns the next supported message or `None` at the end of the file.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            while let Some(object) = self.take_object()? {
+                if let Some(message) = self.decode_object(&object)? {
+                    return Ok(Some(message));
+                }
+            }
+            if !self.read_top_level_object()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Removes the next complete object from the decompressed buffer.
+    fn take_object(&mut self) -> Result<Option<Vec<u8>>> {
+        let skip = self.skip.min(self.pending.len());
+        self.pending.drain(..skip);
+        self.skip -= skip;
+        if self.pending.len() < OBJECT_HEADER_BASE_SIZE {
+            return Ok(None);
+        }
+        if &self.pending[..4] != OBJECT_SIGNATURE {
+            return Err(Error::ParseError(
+                "Invalid BLF object signature".to_string(),
+            ));
+        }
+        let size = u32_at(&self.pending, 8) as usize;
+        if size < OBJECT_HEADER_BASE_SIZE {
+            return Err(Error::ParseError(format!("Invalid BLF object size {size}")));
+        }
+        if self.pending.len() < size {
+            return Ok(None);
+        }
+        let object = self.pending.drain(..size).collect();
+        self.skip = size % 4;
+        Ok(Some(object))
+    }
+
+    /// Reads the next object from the file into the buffer, decompressing containers.
+    /// Returns false at the end of the file.
+    fn read_top_level_object(&mut self) -> Result<bool> {
+        let mut header = [0u8; OBJECT_HEADER_BASE_SIZE];
+        let read = read_fully(&mut self.inner, &mut header)?;
+        if read == 0 {
+            return Ok(false);
+        }
+        if read < header.len() || &header[..4] != OBJECT_SIGNATURE {
+            return Err(Error::ParseError("Truncated BLF object".to_string()));
+        }
+        let size = u32_at(&header, 8) as usize;
+        if size < OBJECT_HEADER_BASE_SIZE {
+            return Err(Error::ParseError(format!("Invalid BLF object size {size}")));
+        }
+        // Grows with what is actually read, so a corrupt size can't allocate gigabytes.
+        let body_size = size - OBJECT_HEADER_BASE_SIZE;
+        let mut body = Vec::new();
+        (&mut self.inner)
+            .take(body_size as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < body_size {
+            return Err(Error::ParseError("Truncated BLF object".to_string()));
+        }
+        // Trailing padding may be missing after the last object.
+        read_fully(&mut self.inner, &mut [0u8; 3][..size % 4])?;
+
+        if u32_at(&header, 12) != LOG_CONTAINER {
+            self.pending.extend_from_slice(&header);
+            self.pending.extend_from_slice(&body);
+            self.pending.resize(self.pending.len() + size % 4, 0);
+            return Ok(true);
+        }
+        if body.len() < LOG_CONTAINER_HEADER_SIZE {
+            return Err(Error::ParseError("Truncated BLF log container".to_string()));
+        }
+        let method = u16_at(&body, 0);
+        let uncompressed_size = u32_at(&body, 8) as usize;
+        let data = &body[LOG_CONTAINER_HEADER_SIZE..];
+        match method {
+            NO_COMPRESSION => self.pending.extend_from_slice(data),
+            ZLIB_DEFLATE => {
+                // The size is only a hint, a corrupt one mustn't allocate gigabytes.
+                let mut decompressed =
+                    Vec::with_capacity(uncompressed_size.min(MAX_CONTAINER_SIZE));
+                ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+                self.pending.extend_from_slice(&decompressed);
+            }
+            _ => {
+                return Err(Error::ParseError(format!(
+                    "Unsupported BLF compression method {method}"
+                )))
+            }
+        }
+        Ok(true)
+    }
+
+    fn decode_object(&self, object: &[u8]) -> Result<Option<Message>> {
+        let header_size = usize::from(u16_at(object, 4));
+        let object_type = u32_at(object, 12);
+        if header_size < OBJECT_HEADER_V1_SIZE || object.len() < header_size {
+            // Unknown header layout, nothing we can decode.
+            return Ok(None);
+        }
+        let flags = u32_at(object, 16);
+        let timestamp = u64_at(object, 24);
+        let timestamp = match flags {
+            TIME_TEN_MICS => timestamp
+                .checked_mul(10_000)
+                .ok_or_else(|| Error::ParseError(format!("BLF timestamp {timestamp} overflows")))?,
+            _ => timestamp,
+        } / self.resolution;
+        let payload = &object[header_size..];
+        let require = |len: usize| {
+            if payload.len() < len {
+                Err(Error::ParseError(format!(
+                    "BLF object type {object_type} too short: {} bytes",
+                    payload.len()
+                )))
+            } else {
+                Ok(())
+            }
+        };
+
+        let message = match object_type {
+            CAN_MESSAGE | CAN_MESSAGE2 => {
+                require(16)?;
+                let flags = payload[2];
+                let dlc = payload[3];
+                let remote = flags & CAN_REMOTE != 0;
+                let len = if remote { 0 } else { usize::from(dlc.min(8)) };
+                let mut m =
+                    self.can_message(u16_at(payload, 0), u32_at(payload, 4), &payload[8..8 + len]);
+                m.set_dlc_on_wire(dlc);
+                m.set_transmit(flags & CAN_DIR_TX != 0);
+                m.set_remote(remote);
+                Message::Can(m)
+            }
+            CAN_FD_MESSAGE => {
+                require(20)?;
+                let flags = payload[2];
+                let fd_flags = payload[13];
+                let len = usize::from(payload[14]).min(64).min(payload.len() - 20);
+                let mut m = self.can_message(
+                    u16_at(payload, 0),
+                    u32_at(payload, 4),
+                    &payload[20..20 + len],
+                );
+                m.set_dlc_on_wire(payload[3]);
+                m.set_transmit(flags & CAN_DIR_TX != 0);
+                m.set_remote(flags & CAN_REMOTE != 0);
+                m.set_fd(fd_flags & CANFD_EDL != 0);
+                m.set_brs(fd_flags & CANFD_BRS != 0);
+                m.set_esi(fd_flags & CANFD_ESI != 0);
+                Message::Can(m)
+            }
+            CAN_FD_MESSAGE_64 => {
+                require(40)?;
+                let flags = u32_at(payload, 12);
+                let len = usize::from(payload[2]).min(payload.len() - 40);
+                let mut m = self.can_message(
+                    u16::from(payload[0]),
+                    u32_at(payload, 4),
+                    &payload[40..40 + len],
+                );
+                m.set_dlc_on_wire(payload[1]);
+                m.set_transmit(payload[34] == 1);
+                m.set_remote(flags & CANFD64_REMOTE != 0);
+                m.set_fd(flags & CANFD64_EDL != 0);
+                m.set_brs(flags & CANFD64_BRS != 0);
+                m.set_esi(flags & CANFD64_ESI != 0);
+                Message::Can(m)
+            }
+            CAN_ERROR => {
+                require(2)?;
+                let mut m = self.can_message(u16_at(payload, 0), 0, &[]);
+                m.set_error_frame(true);
+                Message::Can(m)
+            }
+            CAN_ERROR_EXT => {
+                require(32)?;
+                let len = usize::from(payload[10].min(8));
+                let mut m = self.can_message(
+                    u16_at(payload, 0),
+                    u32_at(payload, 16),
+                    &payload[24..24 + len],
+                );
+                m.set_dlc_on_wire(payload[10]);
+                m.set_error_frame(true);
+                Message::Can(m)
+            }
+            CAN_DRIVER_ERROR => {
+                require(4)?;
+                let mut m = NeoMessageCanError::new();
+                m.netid = self.channels.netid(u16_at(payload, 0));
+                m.type_ = NETWORK_TYPE_CAN;
+                m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+                m.transmitErrorCount = payload[2];
+                m.receiveErrorCount = payload[3];
+                Message::CanError(m)
+            }
+            ETHERNET_FRAME_EX => {
+                require(32)?;
+                let len = usize::from(u16_at(payload, 22)).min(payload.len() - 32);
+                let mut m = EthMessage::new(
+                    self.channels.netid(u16_at(payload, 4)),
+                    &payload[32..32 + len],
+                );
+                m.set_transmit(u16_at(payload, 20) == ETH_DIR_TX);
+                Message::Eth(m)
+            }
+            ETHERNET_FRAME => {
+                require(32)?;
+                let len = usize::from(u16_at(payload, 22)).min(payload.len() - 32);
+                let tpid = u16_at(payload, 18);
+                let mut frame = Vec::with_capacity(18 + len);
+                frame.extend_from_slice(&payload[8..14]); // destination
+                frame.extend_from_slice(&payload[0..6]); // source
+                if tpid != 0 {
+                    frame.extend_from_slice(&tpid.to_be_bytes());
+                    frame.extend_from_slice(&u16_at(payload, 20).to_be_bytes());
+                }
+                frame.extend_from_slice(&u16_at(payload, 16).to_be_bytes());
+                frame.extend_from_slice(&payload[32..32 + len]);
+                let mut m = EthMessage::new(self.channels.netid(u16_at(payload, 6)), &frame);
+                m.set_transmit(u16_at(payload, 14) == ETH_DIR_TX);
+                Message::Eth(m)
+            }
+            _ => return Ok(None),
+        };
+        let mut message = message;
+        message.set_timestamp(timestamp);
+        Ok(Some(message))
+    }
+
+    fn can_message(&self, channel: u16, id: u32, data: &[u8]) -> CanMessage {
+        let mut m = CanMessage::new(self.channels.netid(channel), id & !CAN_MSG_EXT, data);
+        m.set_extended(id & CAN_MSG_EXT != 0);
+        m
+    }
+}
+
+impl<R: Read> Iterator for BlfReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn padded<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut buffer = [0u8; N];
+    let len = data.len().min(N);
+    buffer[..len].copy_from_slice(&data[..len]);
+    buffer
+}
+
+fn write_system_time(buffer: &mut Vec<u8>, time: CivilTime) {
+    for value in [
+        time.year,
+        time.month,
+        time.weekday,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+        time.millisecond,
+    ] {
+        buffer.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn read_system_time(buffer: &[u8]) -> CivilTime {
+    CivilTime {
+        year: u16_at(buffer, 0),
+        month: u16_at(buffer, 2),
+        weekday: u16_at(buffer, 4),
+        day: u16_at(buffer, 6),
+        hour: u16_at(buffer, 8),
+        minute: u16_at(buffer, 10),
+        second: u16_at(buffer, 12),
+        millisecond: u16_at(buffer, 14),
+    }
+}
+
+fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::golden::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_golden() {
+        let mut writer = BlfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_channel_map(channel_map());
+        writer.set_compression(false);
+        writer.set_start_time(start_time());
+        for message in golden_messages() {
+            writer.write(&message).unwrap();
+        }
+        let written = writer.finish().unwrap().into_inner();
+        assert_eq!(written, include_bytes!("testdata/golden.blf"));
+    }
+
+    #[test]
+    fn test_read_golden() {
+        let mut reader = BlfReader::new(&include_bytes!("testdata/golden.blf")[..]).unwrap();
+        reader.set_channel_map(channel_map());
+        assert_eq!(reader.object_count(), 6);
+        assert_eq!(reader.start_time(), start_time());
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        let written = golden_messages();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            assert_same(read, written, written.timestamp() - ORIGIN);
+        }
+    }
+
+    #[test]
+    fn test_read_golden_compressed() {
+        let reader = BlfReader::new(&include_bytes!("testdata/golden_compressed.blf")[..]).unwrap();
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), 4);
+
+        let Message::Can(m) = &read[0] else {
+            panic!("{:?}", read[0])
+        };
+        assert_eq!(
+            ({ m.netid }, { m.arbid }, m.data()),
+            (1, 0x100, &[1u8, 2][..])
+        );
+        assert_eq!({ m.timestamp }, 10_000);
+
+        let Message::Can(m) = &read[1] else {
+            panic!("{:?}", read[1])
+        };
+        assert_eq!(({ m.netid }, { m.arbid }), (2, 0x80000));
+        assert_eq!(m.data(), (0..12).collect::<Vec<u8>>());
+        assert!(m.is_extended() && m.is_fd() && m.is_brs() && m.is_transmit());
+        assert!(!m.is_esi());
+        assert_eq!({ m.timestamp }, 20_000);
+
+        let Message::Eth(m) = &read[2] else {
+            panic!("{:?}", read[2])
+        };
+        assert_eq!({ m.netid }, 3);
+        assert_eq!(
+            m.data(),
+            &[
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 2, 0, 0, 0, 0, 1, 0x81, 0x00, 0x00, 0x05, 0x08,
+                0x00, 0xde, 0xad, 0xbe, 0xef
+            ]
+        );
+
+        let Message::Can(m) = &read[3] else {
+            panic!("{:?}", read[3])
+        };
+        assert!(m.is_error_frame());
+        assert_eq!({ m.timestamp }, 40_000);
+    }
+
+    #[test]
+    fn test_round_trip_compressed() {
+        let mut writer = BlfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_timestamp_resolution(25);
+        let mut written = Vec::new();
+        // Enough traffic to spill over several containers.
+        for i in 0..10_000u32 {
+            let data: Vec<u8> = (0..(i % 65) as u8).collect();
+            let mut m = CanMessage::new(1 + (i % 3) as u16, i & 0x7ff, &data[..data.len().min(8)]);
+            if data.len() > 8 {
+                m.set_fd(true);
+                m.set_data(&data);
+            }
+            m.set_timestamp(40_000 + u64::from(i) * 4);
+            let message = Message::Can(m);
+            writer.write(&message).unwrap();
+            written.push(message);
+        }
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let mut reader = BlfReader::new(Cursor::new(bytes)).unwrap();
+        reader.set_timestamp_resolution(25);
+        assert_eq!(reader.object_count(), 10_000);
+        let mut count = 0;
+        for (read, written) in reader.zip(&written) {
+            assert_same(&read.unwrap(), written, written.timestamp() - 40_000);
+            count += 1;
+        }
+        assert_eq!(count, written.len());
+    }
+
+    #[test]
+    fn test_timestamp_resolution() {
+        let mut writer = BlfWriter::new(Cursor::new(Vec::new())).unwrap();
+        // The origin is in ticks, whenever the resolution is set.
+        writer.set_time_origin(1_000);
+        writer.set_timestamp_resolution(25);
+        let mut m = CanMessage::new(1, 0x123, &[1]);
+        m.set_timestamp(1_040);
+        writer.write(&Message::Can(m)).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let reader = BlfReader::new(Cursor::new(bytes)).unwrap();
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].timestamp(), 1_000);
+    }
+
+    #[test]
+    fn test_timestamp_overflow() {
+        let mut writer = BlfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_compression(false);
+        writer
+            .write(&Message::Can(CanMessage::new(1, 0x123, &[1])))
+            .unwrap();
+        let mut bytes = writer.finish().unwrap().into_inner();
+        // The CAN object inside the container, in 10 us ticks.
+        let object = bytes
+            .windows(4)
+            .enumerate()
+            .filter(|(_, signature)| signature == OBJECT_SIGNATURE)
+            .nth(1)
+            .unwrap()
+            .0;
+        bytes[object + 16..object + 20].copy_from_slice(&TIME_TEN_MICS.to_le_bytes());
+        bytes[object + 24..object + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut reader = BlfReader::new(Cursor::new(bytes)).unwrap();
+        assert!(matches!(reader.next(), Some(Err(Error::ParseError(_)))));
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        assert!(matches!(
+            BlfReader::new(&b"not a blf file at all"[..]),
+            Err(Error::ParseError(_))
+        ));
+        assert!(matches!(
+            BlfReader::new(&b"LOGG"[..]),
+            Err(Error::IoError(_))
+        ));
+
+        // An object claiming 4 GiB is truncated, not allocated.
+        let mut bytes = include_bytes!("testdata/golden.blf").to_vec();
+        let object = bytes
+            .windows(4)
+            .position(|signature| signature == OBJECT_SIGNATURE)
+            .unwrap();
+        bytes[object + 8..object + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = BlfReader::new(Cursor::new(bytes)).unwrap();
+        assert!(matches!(reader.next(), Some(Err(Error::ParseError(_)))));
+    }
+}
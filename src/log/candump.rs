@@ -0,0 +1,502 @@
+//! Linux `candump -l` log format, as read and written by can-utils and cantools.
+//!
+//! Every line holds one frame: `(1436509052.249713) hscan 123#112233`. CAN FD frames use the
+//! `##` separator followed by a flags nibble (`1` BRS, `2` ESI), error frames carry
+//! `CAN_ERR_FLAG` in the ID like SocketCAN does. Interface names default to the
+//! [short name](crate::network::NetworkId::short_name) of the netid, e.g. `hscan2`, and can
+//! be overridden to match existing tooling:
+//!
+//! ```
+//! use icsneo::log::candump::CandumpWriter;
+//! use icsneo::message::{CanMessage, Message};
+//! use icsneo::network::NetworkId;
+//!
+//! let mut writer = CandumpWriter::new(Vec::new());
+//! writer.set_interface(NetworkId::HSCAN, "can0");
+//! writer.write(&Message::Can(CanMessage::new(1, 0x44C, &[0x12, 0x34]))).unwrap();
+//! let log = String::from_utf8(writer.finish().unwrap()).unwrap();
+//! assert!(log.ends_with(" can0 44C#1234\n"));
+//! ```
+//!
+//! Ethernet frames have no candump representation and are skipped. The format also has no
+//! direction, so transmitted frames read back as received ones.
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libicsneo_sys::neonetid_t;
+
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+use super::socketcan::*;
+use super::ticks_to_nanos;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Writes messages as `candump -l` lines.
+pub struct CandumpWriter<W: Write> {
+    inner: W,
+    interfaces: HashMap<neonetid_t, String>,
+    resolution: u64,
+    start_time: SystemTime,
+    origin: Option<u64>,
+}
+
+impl<W: Write> CandumpWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            interfaces: HashMap::new(),
+            resolution: 1,
+            start_time: SystemTime::now(),
+            origin: None,
+        }
+    }
+
+    /// Uses `name` instead of the netid's short name for frames on `netid`.
+    pub fn set_interface(&mut self, netid: impl Into<NetworkId>, name: &str) {
+        self.interfaces.insert(netid.into().0, name.to_string());
+    }
+
+    /// Nanoseconds per message timestamp tick, see [Timestamps](super#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Wall-clock time of the time origin. Defaults to the time the writer was created.
+    pub fn set_start_time(&mut self, start_time: SystemTime) {
+        self.start_time = start_time;
+    }
+
+    /// Message timestamp (in ticks) that corresponds to the start time. Defaults to the
+    /// timestamp of the first message written.
+    pub fn set_time_origin(&mut self, timestamp: u64) {
+        self.origin = Some(timestamp);
+    }
+
+    /// Writes any message kind this format supports, other kinds are skipped.
+    ///
+    /// # Safety
+    ///
+    /// See [Message::from_neo].
+    pub unsafe fn write_message(&mut self, message: &NeoMessage) -> Result<()> {
+        match Message::from_neo(message) {
+            Some(message) => self.write(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Can(m) => self.write_can(m),
+            Message::CanError(m) => self.write_can_error(m),
+            Message::Eth(_) => Ok(()),
+        }
+    }
+
+    pub fn write_can(&mut self, message: &CanMessage) -> Result<()> {
+        let frame = format_frame(&Frame::from_can(message));
+        self.write_line(message.timestamp, message.netid, &frame)
+    }
+
+    /// Writes the error counters as a controller error frame with `CAN_ERR_CNT` set, the
+    /// counters go into data bytes 6 and 7 as on SocketCAN.
+    pub fn write_can_error(&mut self, message: &NeoMessageCanError) -> Result<()> {
+        let frame = format_frame(&Frame::from_can_error(message));
+        self.write_line(message.timestamp, message.netid, &frame)
+    }
+
+    /// Flushes and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_line(&mut self, timestamp: u64, netid: neonetid_t, frame: &str) -> Result<()> {
+        let origin = *self.origin.get_or_insert(timestamp);
+        let time = match timestamp.checked_sub(origin) {
+            Some(after) => {
+                let after = Duration::from_nanos(ticks_to_nanos(after, self.resolution)?);
+                self.start_time.checked_add(after)
+            }
+            None => {
+                let before = ticks_to_nanos(origin - timestamp, self.resolution)?;
+                self.start_time.checked_sub(Duration::from_nanos(before))
+            }
+        }
+        .ok_or_else(|| Error::InvalidArgument(format!("Timestamp {timestamp} is out of range")))?;
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let interface = match self.interfaces.get(&netid) {
+            Some(name) => name.clone(),
+            None => NetworkId(netid).short_name(),
+        };
+        writeln!(
+            self.inner,
+            "({}.{:06}) {interface} {frame}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros()
+        )?;
+        Ok(())
+    }
+}
+
+/// Reads messages from `candump -l` lines. Empty lines and `#` comments are skipped.
+///
+/// Interface names are looked up in the names set with
+/// [set_interface](CandumpReader::set_interface) first and then parsed as a
+/// [NetworkId](crate::network::NetworkId), so files written with the default names need no
+/// configuration. Message timestamps count from the first line, whose wall-clock time is
+/// available as [start_time](CandumpReader::start_time).
+pub struct CandumpReader<R: BufRead> {
+    inner: R,
+    interfaces: HashMap<String, neonetid_t>,
+    resolution: u64,
+    start_time: Option<Duration>,
+    line_number: usize,
+}
+
+impl<R: BufRead> CandumpReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            interfaces: HashMap::new(),
+            resolution: 1,
+            start_time: None,
+            line_number: 0,
+        }
+    }
+
+    /// Maps frames on the interface `name` to `netid`.
+    pub fn set_interface(&mut self, netid: impl Into<NetworkId>, name: &str) {
+        self.interfaces.insert(name.to_string(), netid.into().0);
+    }
+
+    /// Nanoseconds per tick for the timestamps of returned messages. Defaults to 1.
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// The time of the first line read so far.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start_time.map(|time| UNIX_EPOCH + time)
+    }
+
+    /// Returns the next message or `None` at the end of the input.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.inner.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            return match self.parse_line(trimmed) {
+                Ok(message) => Ok(Some(message)),
+                Err(e) => Err(Error::ParseError(format!(
+                    "candump line {}: {e}: {trimmed}",
+                    self.line_number
+                ))),
+            };
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> std::result::Result<Message, String> {
+        let mut tokens = line.split_whitespace();
+        let time = tokens
+            .next()
+            .and_then(|t| t.strip_prefix('('))
+            .and_then(|t| t.strip_suffix(')'))
+            .ok_or("missing timestamp")?;
+        let time = parse_time(time)?;
+        let interface = tokens.next().ok_or("missing interface")?;
+        let netid = match self.interfaces.get(interface) {
+            Some(netid) => *netid,
+            None => {
+                interface
+                    .parse::<NetworkId>()
+                    .map_err(|_| {
+                        format!("unknown interface {interface}, map it with set_interface")
+                    })?
+                    .0
+            }
+        };
+        let frame = tokens.next().ok_or("missing frame")?;
+
+        let start = *self.start_time.get_or_insert(time);
+        let ns = time.saturating_sub(start).as_nanos() as u64;
+        let mut message = parse_frame(frame)?.to_message(netid)?;
+        message.set_timestamp(ns / self.resolution);
+        Ok(message)
+    }
+}
+
+impl<R: BufRead> Iterator for CandumpReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn parse_hex(data: &str) -> std::result::Result<Vec<u8>, String> {
+    // can-utils allows dots between bytes
+    let data: String = data.chars().filter(|c| *c != '.').collect();
+    if !data.is_ascii() || data.len() % 2 == 1 {
+        return Err(format!("invalid data {data}"));
+    }
+    (0..data.len() / 2)
+        .map(|i| u8::from_str_radix(&data[2 * i..2 * i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|e| format!("data {data}: {e}"))
+}
+
+/// Parses `seconds.fraction` without going through floating point.
+fn parse_time(time: &str) -> std::result::Result<Duration, String> {
+    let (secs, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let secs = secs
+        .parse::<u64>()
+        .map_err(|e| format!("timestamp {time}: {e}"))?;
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("timestamp {time}: invalid fraction"));
+    }
+    let nanos = format!("{fraction:0<9}").parse::<u32>().unwrap_or(0);
+    Ok(Duration::new(secs, nanos))
+}
+
+fn format_frame(frame: &Frame) -> String {
+    let id = if frame.can_id & (CAN_EFF_FLAG | CAN_ERR_FLAG) != 0 {
+        format!("{:08X}", frame.can_id & (CAN_EFF_MASK | CAN_ERR_FLAG))
+    } else {
+        format!("{:03X}", frame.can_id & CAN_SFF_MASK)
+    };
+    match frame.fd_flags {
+        Some(flags) => format!("{id}##{flags:X}{}", hex(&frame.data)),
+        None if frame.can_id & CAN_RTR_FLAG != 0 => match frame.len8_dlc {
+            0 => format!("{id}#R"),
+            dlc => format!("{id}#R{dlc:X}"),
+        },
+        None if frame.len8_dlc != 0 => format!("{id}#{}_{:X}", hex(&frame.data), frame.len8_dlc),
+        None => format!("{id}#{}", hex(&frame.data)),
+    }
+}
+
+fn parse_frame(frame: &str) -> std::result::Result<Frame, String> {
+    let (id_text, rest) = frame.split_once('#').ok_or("missing #")?;
+    let mut can_id = u32::from_str_radix(id_text, 16).map_err(|e| format!("id {id_text}: {e}"))?;
+    match id_text.len() {
+        3 if can_id > CAN_SFF_MASK => return Err(format!("standard id {id_text} exceeds 7FF")),
+        3 => {}
+        // Eight digits mean an extended ID unless the error flag is set.
+        8 if can_id & CAN_ERR_FLAG == 0 => can_id |= CAN_EFF_FLAG,
+        8 => {}
+        _ => return Err(format!("id {id_text} must have 3 or 8 digits")),
+    }
+    if let Some(rest) = rest.strip_prefix('#') {
+        let flags = rest.get(..1).ok_or("missing CAN FD flags")?;
+        let flags = u8::from_str_radix(flags, 16).map_err(|e| format!("flags {flags}: {e}"))?;
+        return Ok(Frame {
+            can_id,
+            fd_flags: Some(flags),
+            len8_dlc: 0,
+            data: parse_hex(&rest[1..])?,
+        });
+    }
+    if let Some(dlc) = rest.strip_prefix('R') {
+        let len8_dlc = match dlc {
+            "" => 0,
+            dlc => u8::from_str_radix(dlc, 16).map_err(|e| format!("dlc {dlc}: {e}"))?,
+        };
+        return Ok(Frame {
+            can_id: can_id | CAN_RTR_FLAG,
+            fd_flags: None,
+            len8_dlc,
+            data: Vec::new(),
+        });
+    }
+    // Classic frames may carry a `_<dlc>` suffix for DLCs above 8.
+    let (data, len8_dlc) = match rest.split_once('_') {
+        Some((data, dlc)) => (
+            data,
+            u8::from_str_radix(dlc, 16).map_err(|e| format!("dlc {dlc}: {e}"))?,
+        ),
+        None => (rest, 0),
+    };
+    Ok(Frame {
+        can_id,
+        fd_flags: None,
+        len8_dlc,
+        data: parse_hex(data)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN: &str = "\
+(1436509052.249713) hscan 44C#1122334455667788
+(1436509052.250713) hscan2 18DAF110#0001020304
+(1436509052.252213) hscan 7E0##1000102030405060708090A0B
+(1436509052.253713) hscan 20000204#0020000000008005
+(1436509052.254713) hscan2 20000080#0000000000000000
+(1436509052.255713) hscan 123#R4
+";
+
+    fn start_time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(1436509052249713)
+    }
+
+    fn golden_messages() -> Vec<Message> {
+        let mut messages = Vec::new();
+
+        let mut m = CanMessage::new(1, 0x44C, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        m.set_timestamp(1_000);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(42, 0x18DAF110, &[0, 1, 2, 3, 4]);
+        m.set_extended(true);
+        m.set_timestamp(1_001_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..12).collect();
+        let mut m = CanMessage::new(1, 0x7E0, &data);
+        m.set_fd(true);
+        m.set_brs(true);
+        m.set_timestamp(2_501_000);
+        messages.push(Message::Can(m));
+
+        let mut m = NeoMessageCanError::new();
+        m.netid = 1;
+        m.type_ = NETWORK_TYPE_CAN;
+        m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+        m.transmitErrorCount = 128;
+        m.receiveErrorCount = 5;
+        m.timestamp = 4_001_000;
+        messages.push(Message::CanError(m));
+
+        let mut m = CanMessage::new(42, 0, &[]);
+        m.set_error_frame(true);
+        m.set_timestamp(5_001_000);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(1, 0x123, &[]);
+        m.set_remote(true);
+        m.set_dlc_on_wire(4);
+        m.set_timestamp(6_001_000);
+        messages.push(Message::Can(m));
+
+        messages
+    }
+
+    #[test]
+    fn test_write_golden() {
+        let mut writer = CandumpWriter::new(Vec::new());
+        writer.set_start_time(start_time());
+        for message in golden_messages() {
+            writer.write(&message).unwrap();
+        }
+        // Ethernet frames are skipped.
+        writer
+            .write(&Message::Eth(EthMessage::new(93, &[0; 60])))
+            .unwrap();
+        let written = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(written, GOLDEN);
+    }
+
+    #[test]
+    fn test_timestamp_resolution() {
+        let mut writer = CandumpWriter::new(Vec::new());
+        writer.set_start_time(UNIX_EPOCH + Duration::from_secs(100));
+        // The origin is in ticks, whenever the resolution is set.
+        writer.set_time_origin(1_000);
+        writer.set_timestamp_resolution(25);
+        let mut m = CanMessage::new(1, 0x123, &[1]);
+        m.set_timestamp(41_000);
+        writer.write(&Message::Can(m)).unwrap();
+        let written = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(written, "(100.001000) hscan 123#01\n");
+    }
+
+    #[test]
+    fn test_read_golden() {
+        let mut reader = CandumpReader::new(GOLDEN.as_bytes());
+        reader.set_timestamp_resolution(10);
+        let read: Vec<Message> = reader.by_ref().map(|m| m.unwrap()).collect();
+        assert_eq!(reader.start_time(), Some(start_time()));
+        let written = golden_messages();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            assert_eq!(read.netid(), written.netid());
+            assert_eq!(read.timestamp(), (written.timestamp() - 1_000) / 10);
+            match (read, written) {
+                (Message::Can(a), Message::Can(b)) => {
+                    assert_eq!({ a.arbid }, { b.arbid });
+                    assert_eq!(a.data(), b.data());
+                    assert_eq!(a.is_extended(), b.is_extended());
+                    assert_eq!(a.is_remote(), b.is_remote());
+                    assert_eq!(a.is_fd(), b.is_fd());
+                    assert_eq!(a.is_brs(), b.is_brs());
+                    assert_eq!(a.is_esi(), b.is_esi());
+                    assert_eq!(a.is_error_frame(), b.is_error_frame());
+                }
+                (Message::CanError(a), Message::CanError(b)) => {
+                    assert_eq!(a.transmitErrorCount, b.transmitErrorCount);
+                    assert_eq!(a.receiveErrorCount, b.receiveErrorCount);
+                }
+                _ => panic!("message kind mismatch: {read:?} vs {written:?}"),
+            }
+        }
+        let Message::Can(m) = &read[5] else {
+            panic!("{:?}", read[5])
+        };
+        assert_eq!({ m.dlcOnWire }, 4);
+    }
+
+    #[test]
+    fn test_read_can_utils() {
+        let input = "\
+# comment
+(1700000000.000000) vcan0 123#11.22.33
+(1700000000.000100) vcan0 1F334455##3
+(1700000000.000200) hscan7 00000123#
+(1700000000.000300) vcan0 123#1122334455667788_C
+";
+        let mut reader = CandumpReader::new(input.as_bytes());
+        reader.set_interface(NetworkId::HSCAN2, "vcan0");
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), 4);
+        assert_eq!(read[0].netid(), 42);
+        let Message::Can(m) = &read[1] else {
+            panic!("{:?}", read[1])
+        };
+        assert_eq!({ m.timestamp }, 100_000);
+        assert!(m.is_fd() && m.is_brs() && m.is_esi() && m.data().is_empty());
+        // Eight hex digits mean an extended ID even if it would fit 11 bits.
+        let Message::Can(m) = &read[2] else {
+            panic!("{:?}", read[2])
+        };
+        assert_eq!(({ m.netid }, { m.arbid }), (97, 0x123));
+        assert!(m.is_extended());
+        let Message::Can(m) = &read[3] else {
+            panic!("{:?}", read[3])
+        };
+        assert_eq!({ m.dlcOnWire }, 12);
+
+        let mut reader = CandumpReader::new("(1.0) can0 123#11\n".as_bytes());
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+        let mut reader = CandumpReader::new("(1.0) hscan 123#112\n".as_bytes());
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+        let mut reader = CandumpReader::new("(1.0) hscan 923#11\n".as_bytes());
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+    }
+}
@@ -0,0 +1,1261 @@
+//! ASAM MDF 4 (MF4) bus logging writer and a minimal reader.
+//!
+//! The writer follows the ASAM MDF bus logging conventions: CAN data frames, remote frames and
+//! error frames go into the `CAN_DataFrame`, `CAN_RemoteFrame` and `CAN_ErrorFrame` channel
+//! groups, Ethernet frames into `ETH_Frame`. Every group has a `Timestamp` master channel in
+//! seconds (stored as nanoseconds with a linear conversion) relative to the measurement start
+//! in the file header, followed by a structure channel whose components are named like
+//! `CAN_DataFrame.ID`. Ethernet frames are stored as variable length signal data.
+//!
+//! Records are streamed into a single unsorted data group as they are written, all metadata
+//! is added by [finish](MdfWriter::finish). Error counter messages have no bus logging
+//! representation and are skipped.
+//!
+//! [MdfReader](MdfReader) reads back the frames of these channel groups from uncompressed
+//! files, sorted or unsorted.
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{ticks_to_nanos, ChannelMap};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const ID_BLOCK_SIZE: u64 = 64;
+const HD_BLOCK_SIZE: u64 = 104;
+const BLOCK_HEADER_SIZE: u64 = 24;
+const VERSION: u16 = 410;
+const UNFINISHED_CYCLE_COUNTERS: u16 = 0x1;
+const UNFINISHED_DT_LENGTH: u16 = 0x4;
+
+const CN_FIXED: u8 = 0;
+const CN_VLSD: u8 = 1;
+const CN_MASTER: u8 = 2;
+const SYNC_TIME: u8 = 1;
+const DT_UINT_LE: u8 = 0;
+const DT_FLOAT_LE: u8 = 4;
+const DT_BYTE_ARRAY: u8 = 10;
+const CC_LINEAR: u8 = 1;
+
+const CG_VLSD: u16 = 0x1;
+const CG_BUS_EVENT: u16 = 0x2;
+const CG_PLAIN_BUS_EVENT: u16 = 0x4;
+
+const SI_TYPE_BUS: u8 = 2;
+const SI_BUS_CAN: u8 = 2;
+const SI_BUS_ETHERNET: u8 = 7;
+
+const CAN_DATA_FRAME: u8 = 1;
+const CAN_REMOTE_FRAME: u8 = 2;
+const CAN_ERROR_FRAME: u8 = 3;
+const ETH_FRAME: u8 = 4;
+const ETH_FRAME_DATA: u8 = 5;
+
+/// Record sizes without the record id.
+const CAN_DATA_FRAME_SIZE: u32 = 81;
+const CAN_REMOTE_FRAME_SIZE: u32 = 17;
+const CAN_ERROR_FRAME_SIZE: u32 = 18;
+const ETH_FRAME_SIZE: u32 = 21;
+
+const FLAG_IDE: u8 = 0x01;
+const FLAG_DIR: u8 = 0x02;
+const FLAG_EDL: u8 = 0x04;
+const FLAG_BRS: u8 = 0x08;
+const FLAG_ESI: u8 = 0x10;
+
+/// Streams messages into an MDF 4 file. Call [finish](MdfWriter::finish) to add the channel
+/// descriptions, without them the file only holds raw records.
+pub struct MdfWriter<W: Write + Seek> {
+    inner: W,
+    start_position: u64,
+    channels: ChannelMap,
+    resolution: u64,
+    start_time: SystemTime,
+    origin: Option<u64>,
+    data_size: u64,
+    cycle_counts: [u64; 5],
+    vlsd_size: u64,
+}
+
+impl<W: Write + Seek> MdfWriter<W> {
+    /// Writes the identification and header blocks and starts the data block at the current
+    /// position of `inner`.
+    pub fn new(mut inner: W) -> Result<Self> {
+        let start_position = inner.stream_position()?;
+        inner.write_all(&id_block(false))?;
+        inner.write_all(&[0u8; HD_BLOCK_SIZE as usize])?;
+        inner.write_all(&block_header(b"##DT", BLOCK_HEADER_SIZE, 0))?;
+        Ok(Self {
+            inner,
+            start_position,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            start_time: SystemTime::now(),
+            origin: None,
+            data_size: 0,
+            cycle_counts: [0; 5],
+            vlsd_size: 0,
+        })
+    }
+
+    /// Maps netids to the `BusChannel` values. Unmapped netids use their own value.
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per message timestamp tick, see [Timestamps](super#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Wall-clock time of the measurement start stored in the header block. Defaults to the
+    /// time the writer was created.
+    pub fn set_start_time(&mut self, start_time: SystemTime) {
+        self.start_time = start_time;
+    }
+
+    /// Message timestamp (in ticks) that corresponds to the measurement start. Defaults to
+    /// the timestamp of the first message written.
+    pub fn set_time_origin(&mut self, timestamp: u64) {
+        self.origin = Some(timestamp);
+    }
+
+    /// Writes any message kind this format supports, other kinds are skipped.
+    ///
+    /// # Safety
+    ///
+    /// See [Message::from_neo].
+    pub unsafe fn write_message(&mut self, message: &NeoMessage) -> Result<()> {
+        match Message::from_neo(message) {
+            Some(message) => self.write(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Can(m) => self.write_can(m),
+            Message::CanError(_) => Ok(()),
+            Message::Eth(m) => self.write_eth(m),
+        }
+    }
+
+    pub fn write_can(&mut self, message: &CanMessage) -> Result<()> {
+        let timestamp = self.relative_timestamp(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let status = message.status;
+        let data = message.data();
+        let data = &data[..data.len().min(64)];
+        let mut flags = 0;
+        for (bit, flag) in [
+            (status::EXTENDED, FLAG_IDE),
+            (status::TRANSMIT, FLAG_DIR),
+            (status::CANFD_FDF, FLAG_EDL),
+            (status::CANFD_BRS, FLAG_BRS),
+            (status::CANFD_ESI, FLAG_ESI),
+        ] {
+            if status::get(status, bit) {
+                flags |= flag;
+            }
+        }
+        let (record_id, dlc, length) = if status::get(status, status::ERROR_FRAME) {
+            (CAN_ERROR_FRAME, 0, 0)
+        } else if status::get(status, status::REMOTE) {
+            let dlc = message.dlcOnWire.max(len_to_dlc(data.len()));
+            (CAN_REMOTE_FRAME, dlc, dlc_to_len(dlc) as u8)
+        } else {
+            let dlc = if status::get(status, status::CANFD_FDF) || data.len() < 8 {
+                len_to_dlc(data.len())
+            } else {
+                message.dlcOnWire.clamp(8, 15)
+            };
+            (CAN_DATA_FRAME, dlc, data.len() as u8)
+        };
+
+        let mut record = Vec::with_capacity(1 + CAN_DATA_FRAME_SIZE as usize);
+        record.push(record_id);
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&channel.to_le_bytes());
+        record.extend_from_slice(&({ message.arbid } & 0x1fff_ffff).to_le_bytes());
+        record.extend_from_slice(&[flags, dlc, length]);
+        match record_id {
+            CAN_DATA_FRAME => {
+                record.extend_from_slice(data);
+                record.resize(1 + CAN_DATA_FRAME_SIZE as usize, 0);
+            }
+            CAN_ERROR_FRAME => record.push(0), // error type unknown
+            _ => {}
+        }
+        self.write_record(&record)
+    }
+
+    pub fn write_eth(&mut self, message: &EthMessage) -> Result<()> {
+        let timestamp = self.relative_timestamp(message.timestamp)?;
+        let channel = self.channels.channel(message.netid);
+        let data = message.data();
+        let length = u16::try_from(data.len()).map_err(|_| {
+            Error::CriticalError(format!("Ethernet frame too long: {} bytes", data.len()))
+        })?;
+        let mut vlsd = Vec::with_capacity(5 + data.len());
+        vlsd.push(ETH_FRAME_DATA);
+        vlsd.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        vlsd.extend_from_slice(data);
+        let offset = self.vlsd_size;
+        self.write_record(&vlsd)?;
+        self.vlsd_size += 4 + data.len() as u64;
+
+        let dir = u8::from(status::get(message.status, status::TRANSMIT));
+        let mut record = Vec::with_capacity(1 + ETH_FRAME_SIZE as usize);
+        record.push(ETH_FRAME);
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&channel.to_le_bytes());
+        record.push(dir);
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&offset.to_le_bytes());
+        self.write_record(&record)
+    }
+
+    /// Completes the data block, writes the channel descriptions and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let data_position = self.start_position + ID_BLOCK_SIZE + HD_BLOCK_SIZE;
+        let end = data_position + BLOCK_HEADER_SIZE + self.data_size;
+        // Blocks start at 8 byte boundaries.
+        let mut meta = Metadata {
+            base: end,
+            buffer: vec![0; (end.next_multiple_of(8) - end) as usize],
+        };
+
+        let xml = format!(
+            "<FHcomment><TX>Bus logging</TX><tool_id>libicsneo-rs</tool_id>\
+             <tool_vendor>Intrepid Control Systems</tool_vendor>\
+             <tool_version>{}</tool_version></FHcomment>",
+            env!("CARGO_PKG_VERSION")
+        );
+        let comment = meta.text(b"##MD", &xml);
+        let mut fh = Vec::new();
+        fh.extend_from_slice(&self.start_time_ns().to_le_bytes());
+        fh.extend_from_slice(&[0u8; 8]); // time zone, DST, time flags, reserved
+        let fh = meta.block(b"##FH", &[0, comment], &fh);
+
+        let groups = self.write_channel_groups(&mut meta);
+        let mut dg = vec![1u8]; // record id size
+        dg.resize(8, 0);
+        let dg = meta.block(b"##DG", &[0, groups, data_position, 0], &dg);
+
+        let mut hd = Vec::new();
+        hd.extend_from_slice(&self.start_time_ns().to_le_bytes());
+        hd.extend_from_slice(&[0u8; 8]); // time zone, DST, time flags, time class, flags, reserved
+        hd.extend_from_slice(&0f64.to_le_bytes()); // start angle
+        hd.extend_from_slice(&0f64.to_le_bytes()); // start distance
+        let mut header = block_header(b"##HD", HD_BLOCK_SIZE, 6);
+        for link in [dg, fh, 0, 0, 0, 0] {
+            header.extend_from_slice(&link.to_le_bytes());
+        }
+        header.extend_from_slice(&hd);
+
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.write_all(&meta.buffer)?;
+        let file_end = self.inner.stream_position()?;
+        self.inner.seek(SeekFrom::Start(self.start_position))?;
+        self.inner.write_all(&id_block(true))?;
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&block_header(
+            b"##DT",
+            BLOCK_HEADER_SIZE + self.data_size,
+            0,
+        ))?;
+        self.inner.seek(SeekFrom::Start(file_end))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_channel_groups(&self, meta: &mut Metadata) -> u64 {
+        let time = meta.text(b"##TX", "Timestamp");
+        let unit = meta.text(b"##TX", "s");
+        let mut conversion = Vec::new();
+        conversion.extend_from_slice(&[CC_LINEAR, 0]); // type, precision
+        conversion.extend_from_slice(&[0u8; 6]); // flags, reference count, value count
+        conversion[6] = 2;
+        conversion.extend_from_slice(&0f64.to_le_bytes()); // physical range
+        conversion.extend_from_slice(&0f64.to_le_bytes());
+        conversion.extend_from_slice(&0f64.to_le_bytes()); // offset
+        conversion.extend_from_slice(&1e-9f64.to_le_bytes()); // factor
+        let conversion = meta.block(b"##CC", &[0, 0, 0, 0], &conversion);
+        let master = ChannelSpec {
+            cn_type: CN_MASTER,
+            sync_type: SYNC_TIME,
+            data_type: DT_UINT_LE,
+            bit_count: 64,
+            ..ChannelSpec::new("", 0)
+        };
+        let can_source = meta.source("CAN", SI_BUS_CAN);
+        let eth_source = meta.source("ETH", SI_BUS_ETHERNET);
+
+        let vlsd_group = meta.group(GroupSpec {
+            next: 0,
+            channels: 0,
+            name: None,
+            source: 0,
+            record_id: ETH_FRAME_DATA,
+            cycle_count: self.cycle_counts[usize::from(ETH_FRAME_DATA - 1)],
+            flags: CG_VLSD,
+            data_bytes: self.vlsd_size,
+        });
+
+        let mut next = vlsd_group;
+        for (record_id, name, size, components) in [
+            (
+                ETH_FRAME,
+                "ETH_Frame",
+                ETH_FRAME_SIZE,
+                eth_components(vlsd_group),
+            ),
+            (
+                CAN_ERROR_FRAME,
+                "CAN_ErrorFrame",
+                CAN_ERROR_FRAME_SIZE,
+                can_components(CAN_ERROR_FRAME),
+            ),
+            (
+                CAN_REMOTE_FRAME,
+                "CAN_RemoteFrame",
+                CAN_REMOTE_FRAME_SIZE,
+                can_components(CAN_REMOTE_FRAME),
+            ),
+            (
+                CAN_DATA_FRAME,
+                "CAN_DataFrame",
+                CAN_DATA_FRAME_SIZE,
+                can_components(CAN_DATA_FRAME),
+            ),
+        ] {
+            let structure = ChannelSpec {
+                data_type: DT_BYTE_ARRAY,
+                bit_count: (size - 8) * 8,
+                components,
+                ..ChannelSpec::new(name, 8)
+            };
+            let channels = meta.channels(&[structure], 0);
+            let channels = meta.channel(&master, channels, time, conversion, unit);
+            let source = if record_id == ETH_FRAME {
+                eth_source
+            } else {
+                can_source
+            };
+            let name = meta.text(b"##TX", name);
+            next = meta.group(GroupSpec {
+                next,
+                channels,
+                name: Some(name),
+                source,
+                record_id,
+                cycle_count: self.cycle_counts[usize::from(record_id - 1)],
+                flags: CG_BUS_EVENT | CG_PLAIN_BUS_EVENT,
+                data_bytes: u64::from(size),
+            });
+        }
+        next
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> Result<()> {
+        self.inner.write_all(record)?;
+        self.data_size += record.len() as u64;
+        self.cycle_counts[usize::from(record[0] - 1)] += 1;
+        Ok(())
+    }
+
+    fn relative_timestamp(&mut self, timestamp: u64) -> Result<u64> {
+        let origin = *self.origin.get_or_insert(timestamp);
+        ticks_to_nanos(timestamp.saturating_sub(origin), self.resolution)
+    }
+
+    fn start_time_ns(&self) -> u64 {
+        let since_epoch = self.start_time.duration_since(UNIX_EPOCH);
+        since_epoch.unwrap_or_default().as_nanos() as u64
+    }
+}
+
+fn id_block(finished: bool) -> Vec<u8> {
+    let mut block = Vec::with_capacity(ID_BLOCK_SIZE as usize);
+    block.extend_from_slice(if finished { b"MDF     " } else { b"UnFinMF " });
+    block.extend_from_slice(b"4.10    ");
+    block.extend_from_slice(b"icsneo  ");
+    block.extend_from_slice(&[0u8; 4]);
+    block.extend_from_slice(&VERSION.to_le_bytes());
+    block.extend_from_slice(&[0u8; 30]);
+    let unfinished = if finished {
+        0
+    } else {
+        UNFINISHED_CYCLE_COUNTERS | UNFINISHED_DT_LENGTH
+    };
+    block.extend_from_slice(&unfinished.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes()); // custom flags
+    block
+}
+
+fn block_header(id: &[u8; 4], length: u64, link_count: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(BLOCK_HEADER_SIZE as usize);
+    header.extend_from_slice(id);
+    header.extend_from_slice(&[0u8; 4]);
+    header.extend_from_slice(&length.to_le_bytes());
+    header.extend_from_slice(&link_count.to_le_bytes());
+    header
+}
+
+/// A channel to describe, with its position inside the record (after the record id).
+struct ChannelSpec {
+    name: String,
+    cn_type: u8,
+    sync_type: u8,
+    data_type: u8,
+    byte_offset: u32,
+    bit_offset: u8,
+    bit_count: u32,
+    /// Block holding the signal data of VLSD channels.
+    data: u64,
+    components: Vec<ChannelSpec>,
+}
+
+impl ChannelSpec {
+    fn new(name: &str, byte_offset: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            cn_type: CN_FIXED,
+            sync_type: 0,
+            data_type: DT_UINT_LE,
+            byte_offset,
+            bit_offset: 0,
+            bit_count: 8,
+            data: 0,
+            components: Vec::new(),
+        }
+    }
+
+    fn bits(name: &str, byte_offset: u32, bit_offset: u8, bit_count: u32) -> Self {
+        Self {
+            bit_offset,
+            bit_count,
+            ..Self::new(name, byte_offset)
+        }
+    }
+}
+
+fn can_components(record_id: u8) -> Vec<ChannelSpec> {
+    let (prefix, fd) = match record_id {
+        CAN_DATA_FRAME => ("CAN_DataFrame", true),
+        CAN_REMOTE_FRAME => ("CAN_RemoteFrame", false),
+        _ => ("CAN_ErrorFrame", true),
+    };
+    let name = |field: &str| format!("{prefix}.{field}");
+    let mut components = vec![
+        ChannelSpec::bits(&name("BusChannel"), 8, 0, 16),
+        ChannelSpec::bits(&name("ID"), 10, 0, 29),
+        ChannelSpec::bits(&name("IDE"), 14, 0, 1),
+        ChannelSpec::bits(&name("Dir"), 14, 1, 1),
+    ];
+    if fd {
+        components.push(ChannelSpec::bits(&name("EDL"), 14, 2, 1));
+        components.push(ChannelSpec::bits(&name("BRS"), 14, 3, 1));
+        components.push(ChannelSpec::bits(&name("ESI"), 14, 4, 1));
+    }
+    components.push(ChannelSpec::bits(&name("DLC"), 15, 0, 4));
+    components.push(ChannelSpec::bits(&name("DataLength"), 16, 0, 8));
+    match record_id {
+        CAN_DATA_FRAME => components.push(ChannelSpec {
+            data_type: DT_BYTE_ARRAY,
+            bit_count: 64 * 8,
+            ..ChannelSpec::new(&name("DataBytes"), 17)
+        }),
+        CAN_ERROR_FRAME => components.push(ChannelSpec::bits(&name("ErrorType"), 17, 0, 8)),
+        _ => {}
+    }
+    components
+}
+
+fn eth_components(vlsd_group: u64) -> Vec<ChannelSpec> {
+    vec![
+        ChannelSpec::bits("ETH_Frame.BusChannel", 8, 0, 16),
+        ChannelSpec::bits("ETH_Frame.Dir", 10, 0, 1),
+        ChannelSpec::bits("ETH_Frame.DataLength", 11, 0, 16),
+        ChannelSpec {
+            cn_type: CN_VLSD,
+            data_type: DT_BYTE_ARRAY,
+            bit_count: 64,
+            data: vlsd_group,
+            ..ChannelSpec::new("ETH_Frame.DataBytes", 13)
+        },
+    ]
+}
+
+struct GroupSpec {
+    next: u64,
+    channels: u64,
+    name: Option<u64>,
+    source: u64,
+    record_id: u8,
+    cycle_count: u64,
+    flags: u16,
+    /// Record size, or the total signal data size of a VLSD group.
+    data_bytes: u64,
+}
+
+/// Metadata blocks collected in memory, addressed from `base`.
+struct Metadata {
+    base: u64,
+    buffer: Vec<u8>,
+}
+
+impl Metadata {
+    fn block(&mut self, id: &[u8; 4], links: &[u64], data: &[u8]) -> u64 {
+        let address = self.base + self.buffer.len() as u64;
+        let length = BLOCK_HEADER_SIZE + 8 * links.len() as u64 + data.len() as u64;
+        self.buffer
+            .extend_from_slice(&block_header(id, length, links.len() as u64));
+        for link in links {
+            self.buffer.extend_from_slice(&link.to_le_bytes());
+        }
+        self.buffer.extend_from_slice(data);
+        self.buffer.resize(self.buffer.len().next_multiple_of(8), 0);
+        address
+    }
+
+    fn text(&mut self, id: &[u8; 4], text: &str) -> u64 {
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        data.resize(data.len().next_multiple_of(8), 0);
+        self.block(id, &[], &data)
+    }
+
+    fn source(&mut self, name: &str, bus_type: u8) -> u64 {
+        let name = self.text(b"##TX", name);
+        let mut data = vec![SI_TYPE_BUS, bus_type];
+        data.resize(8, 0);
+        self.block(b"##SI", &[name, 0, 0], &data)
+    }
+
+    fn group(&mut self, group: GroupSpec) -> u64 {
+        let mut data = Vec::with_capacity(32);
+        data.extend_from_slice(&u64::from(group.record_id).to_le_bytes());
+        data.extend_from_slice(&group.cycle_count.to_le_bytes());
+        data.extend_from_slice(&group.flags.to_le_bytes());
+        data.extend_from_slice(&u16::from(b'.').to_le_bytes()); // path separator
+        data.extend_from_slice(&[0u8; 4]);
+        // For VLSD groups data and invalidation bytes form the 64 bit signal data size.
+        data.extend_from_slice(&group.data_bytes.to_le_bytes());
+        let links = [
+            group.next,
+            group.channels,
+            group.name.unwrap_or(0),
+            group.source,
+            0,
+            0,
+        ];
+        self.block(b"##CG", &links, &data)
+    }
+
+    /// Writes a list of channels with their components and returns the first one's address.
+    fn channels(&mut self, channels: &[ChannelSpec], mut next: u64) -> u64 {
+        for channel in channels.iter().rev() {
+            let composition = self.channels(&channel.components, 0);
+            let name = self.text(b"##TX", &channel.name);
+            next = self.channel(channel, next, name, 0, 0);
+            if composition != 0 {
+                // Patch the composition link of the block just written.
+                let offset = (next - self.base) as usize + BLOCK_HEADER_SIZE as usize + 8;
+                self.buffer[offset..offset + 8].copy_from_slice(&composition.to_le_bytes());
+            }
+        }
+        next
+    }
+
+    fn channel(
+        &mut self,
+        channel: &ChannelSpec,
+        next: u64,
+        name: u64,
+        conversion: u64,
+        unit: u64,
+    ) -> u64 {
+        let mut data = Vec::with_capacity(72);
+        data.extend_from_slice(&[
+            channel.cn_type,
+            channel.sync_type,
+            channel.data_type,
+            channel.bit_offset,
+        ]);
+        data.extend_from_slice(&channel.byte_offset.to_le_bytes());
+        data.extend_from_slice(&channel.bit_count.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes()); // flags
+        data.extend_from_slice(&0u32.to_le_bytes()); // invalidation bit position
+        data.extend_from_slice(&[0, 0]); // precision, reserved
+        data.extend_from_slice(&0u16.to_le_bytes()); // attachment count
+        data.extend_from_slice(&[0u8; 48]); // value range and limits
+        let links = [next, 0, name, 0, conversion, channel.data, unit, 0];
+        self.block(b"##CN", &links, &data)
+    }
+}
+
+/// Where a channel's value sits inside a record.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    cn_type: u8,
+    data_type: u8,
+    byte_offset: usize,
+    bit_offset: u8,
+    bit_count: u32,
+    data: u64,
+    /// Offset and factor of a linear conversion.
+    conversion: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    CanData,
+    CanRemote,
+    CanError,
+    Eth,
+}
+
+struct ChannelGroup {
+    address: u64,
+    record_id: u64,
+    vlsd: bool,
+    /// Record size without the record id.
+    size: u64,
+    kind: Option<Kind>,
+    master: Option<Field>,
+    /// Components by name without the structure prefix, e.g. `ID`.
+    fields: HashMap<String, Field>,
+}
+
+struct DataGroup {
+    record_id_size: u8,
+    groups: Vec<ChannelGroup>,
+    /// Start and size of the records in the data block.
+    data: u64,
+    size: u64,
+}
+
+/// Reads CAN and Ethernet frames back out of an MDF 4 bus logging file. Messages are
+/// returned in file order, which is only chronological for unsorted files like the ones
+/// [MdfWriter] creates.
+pub struct MdfReader<R: Read + Seek> {
+    inner: R,
+    channels: ChannelMap,
+    resolution: u64,
+    start_time: SystemTime,
+    groups: Vec<DataGroup>,
+    group: usize,
+    position: u64,
+    /// Signal data of VLSD channel groups by group address and offset, until it's used.
+    vlsd: HashMap<(u64, u64), Vec<u8>>,
+    vlsd_offsets: HashMap<u64, u64>,
+}
+
+impl<R: Read + Seek> MdfReader<R> {
+    /// Reads the header and channel descriptions.
+    pub fn new(mut inner: R) -> Result<Self> {
+        let start_position = inner.stream_position()?;
+        let mut id = [0u8; ID_BLOCK_SIZE as usize];
+        inner.read_exact(&mut id)?;
+        if &id[..8] != b"MDF     " {
+            return Err(Error::ParseError("Not a finished MDF file".to_string()));
+        }
+        let version = u16::from_le_bytes([id[28], id[29]]);
+        if version < 400 {
+            return Err(Error::ParseError(format!(
+                "Unsupported MDF version {version}"
+            )));
+        }
+        let hd = read_block(&mut inner, start_position + ID_BLOCK_SIZE, b"##HD")?;
+        let start_time = UNIX_EPOCH + Duration::from_nanos(u64_at(&hd.data, 0)?);
+        let mut groups = Vec::new();
+        let mut dg_address = hd.link(0);
+        while dg_address != 0 {
+            let dg = read_block(&mut inner, dg_address, b"##DG")?;
+            groups.push(read_data_group(&mut inner, &dg)?);
+            dg_address = dg.link(0);
+        }
+        let mut vlsd = HashMap::new();
+        let fields = groups
+            .iter()
+            .flat_map(|dg| &dg.groups)
+            .flat_map(|cg| cg.fields.values());
+        for field in fields.filter(|field| field.cn_type == CN_VLSD && field.data != 0) {
+            if &read_header(&mut inner, field.data)?.0 == b"##SD" {
+                for (offset, value) in read_signal_data(&mut inner, field.data)? {
+                    vlsd.insert((field.data, offset), value);
+                }
+            }
+        }
+        Ok(Self {
+            inner,
+            channels: ChannelMap::new(),
+            resolution: 1,
+            start_time,
+            position: groups.first().map_or(0, |group| group.data),
+            groups,
+            group: 0,
+            vlsd,
+            vlsd_offsets: HashMap::new(),
+        })
+    }
+
+    /// Maps `BusChannel` values back to netids. Unmapped channels become the netid with the
+    /// same value.
+    pub fn set_channel_map(&mut self, channels: ChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Nanoseconds per tick for the timestamps of returned messages. Defaults to 1.
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Wall-clock time of the measurement start from the header block.
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+
+    /// Returns the next supported message or `None` at the end of the file.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        while let Some(data_group) = self.groups.get(self.group) {
+            if self.position >= data_group.data + data_group.size {
+                self.group += 1;
+                if let Some(next) = self.groups.get(self.group) {
+                    self.position = next.data;
+                }
+                continue;
+            }
+            let record_id_size = usize::from(data_group.record_id_size);
+            let mut record_id = [0u8; 8];
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            self.inner.read_exact(&mut record_id[..record_id_size])?;
+            let record_id = u64::from_le_bytes(record_id);
+            let group = data_group
+                .groups
+                .iter()
+                .find(|group| record_id_size == 0 || group.record_id == record_id)
+                .ok_or_else(|| Error::ParseError(format!("Unknown MDF record id {record_id}")))?;
+
+            if group.vlsd {
+                let mut length = [0u8; 4];
+                self.inner.read_exact(&mut length)?;
+                let length = u32::from_le_bytes(length) as usize;
+                let mut data = vec![0u8; length];
+                self.inner.read_exact(&mut data)?;
+                let offset = self.vlsd_offsets.entry(group.address).or_insert(0);
+                self.vlsd.insert((group.address, *offset), data);
+                *offset += 4 + length as u64;
+                self.position += (record_id_size + 4 + length) as u64;
+                continue;
+            }
+
+            let mut record = vec![0u8; group.size as usize];
+            self.inner.read_exact(&mut record)?;
+            self.position += record_id_size as u64 + group.size;
+            let (Some(kind), Some(master)) = (group.kind, group.master) else {
+                continue;
+            };
+            let mut message = self.decode(kind, &group.fields, &record)?;
+            if let Some(field) = group.fields.values().find(|f| f.cn_type == CN_VLSD) {
+                let key = (field.data, uint(&record, field)?);
+                self.vlsd.remove(&key);
+            }
+            let raw = match (master.data_type, master.bit_count) {
+                (DT_FLOAT_LE, 64) => f64::from_bits(uint(&record, &master)?),
+                (DT_FLOAT_LE, 32) => f64::from(f32::from_bits(uint(&record, &master)? as u32)),
+                (DT_UINT_LE, _) => uint(&record, &master)? as f64,
+                (data_type, _) => {
+                    return Err(Error::ParseError(format!(
+                        "Unsupported MDF master data type {data_type}"
+                    )))
+                }
+            };
+            let (offset, factor) = master.conversion.unwrap_or((0.0, 1.0));
+            let ns = ((offset + factor * raw) * 1e9).round().max(0.0) as u64;
+            message.set_timestamp(ns / self.resolution);
+            return Ok(Some(message));
+        }
+        Ok(None)
+    }
+
+    fn decode(
+        &self,
+        kind: Kind,
+        fields: &HashMap<String, Field>,
+        record: &[u8],
+    ) -> Result<Message> {
+        let value = |name: &str| -> Result<u64> {
+            fields.get(name).map_or(Ok(0), |field| uint(record, field))
+        };
+        let bytes = |name: &str| -> Result<Vec<u8>> {
+            let field = fields
+                .get(name)
+                .ok_or_else(|| Error::ParseError(format!("MDF frame without {name}")))?;
+            self.bytes(record, field)
+        };
+        let netid = self.channels.netid(value("BusChannel")? as u16);
+        let message = match kind {
+            Kind::Eth => {
+                let mut m = EthMessage::new(netid, &bytes("DataBytes")?);
+                m.set_transmit(value("Dir")? != 0);
+                Message::Eth(m)
+            }
+            Kind::CanError => {
+                let mut m = CanMessage::new(netid, 0, &[]);
+                m.set_error_frame(true);
+                m.set_transmit(value("Dir")? != 0);
+                m.set_fd(value("EDL")? != 0);
+                Message::Can(m)
+            }
+            Kind::CanData | Kind::CanRemote => {
+                let mut m = CanMessage::new(netid, value("ID")? as u32, &[]);
+                m.set_extended(value("IDE")? != 0);
+                m.set_transmit(value("Dir")? != 0);
+                m.set_dlc_on_wire(value("DLC")? as u8);
+                if kind == Kind::CanRemote {
+                    m.set_remote(true);
+                } else {
+                    m.set_fd(value("EDL")? != 0);
+                    m.set_brs(value("BRS")? != 0);
+                    m.set_esi(value("ESI")? != 0);
+                    let mut data = bytes("DataBytes")?;
+                    let length = match fields.get("DataLength") {
+                        Some(field) => uint(record, field)? as usize,
+                        None => dlc_to_len(m.dlcOnWire),
+                    };
+                    data.truncate(length);
+                    m.set_data(&data);
+                }
+                Message::Can(m)
+            }
+        };
+        Ok(message)
+    }
+
+    /// Returns the value of a byte array channel. Signal data of VLSD channels comes from
+    /// the records read so far or, in sorted files, from an SD block.
+    fn bytes(&self, record: &[u8], field: &Field) -> Result<Vec<u8>> {
+        if field.cn_type != CN_VLSD {
+            let end = field.byte_offset + (field.bit_count / 8) as usize;
+            return record
+                .get(field.byte_offset..end)
+                .map(|data| data.to_vec())
+                .ok_or_else(|| Error::ParseError("MDF channel exceeds the record".to_string()));
+        }
+        let offset = uint(record, field)?;
+        if let Some(data) = self.vlsd.get(&(field.data, offset)) {
+            return Ok(data.clone());
+        }
+        Err(Error::ParseError(format!(
+            "Missing MDF signal data at offset {offset}"
+        )))
+    }
+}
+
+impl<R: Read + Seek> Iterator for MdfReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+struct Block {
+    links: Vec<u64>,
+    data: Vec<u8>,
+}
+
+impl Block {
+    fn link(&self, index: usize) -> u64 {
+        self.links.get(index).copied().unwrap_or(0)
+    }
+}
+
+/// Returns the id, length and link count of the block at `address`.
+fn read_header<R: Read + Seek>(inner: &mut R, address: u64) -> Result<([u8; 4], u64, u64)> {
+    let mut header = [0u8; BLOCK_HEADER_SIZE as usize];
+    inner.seek(SeekFrom::Start(address))?;
+    inner.read_exact(&mut header)?;
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&header[..4]);
+    Ok((id, u64_at(&header, 8)?, u64_at(&header, 16)?))
+}
+
+fn read_block<R: Read + Seek>(inner: &mut R, address: u64, expected: &[u8; 4]) -> Result<Block> {
+    let (id, length, link_count) = read_header(inner, address)?;
+    if &id != expected {
+        return Err(Error::ParseError(format!(
+            "Expected MDF block {} at {address:#x}, found {}",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&id)
+        )));
+    }
+    let (body_length, links_length) = length
+        .checked_sub(BLOCK_HEADER_SIZE)
+        .zip(link_count.checked_mul(8))
+        .filter(|(body_length, links_length)| body_length >= links_length)
+        .ok_or_else(|| Error::ParseError(format!("Invalid MDF block length {length}")))?;
+    // Grows with what is actually read, so a corrupt length can't allocate gigabytes.
+    let mut body = Vec::new();
+    inner.by_ref().take(body_length).read_to_end(&mut body)?;
+    if body.len() as u64 != body_length {
+        return Err(Error::ParseError(format!(
+            "Truncated MDF block at {address:#x}"
+        )));
+    }
+    let data = body.split_off(links_length as usize);
+    let links = body
+        .chunks_exact(8)
+        .map(|link| u64::from_le_bytes(link.try_into().unwrap()))
+        .collect();
+    Ok(Block { links, data })
+}
+
+fn read_text<R: Read + Seek>(inner: &mut R, address: u64) -> Result<String> {
+    if address == 0 {
+        return Ok(String::new());
+    }
+    let (id, _, _) = read_header(inner, address)?;
+    let block = read_block(inner, address, &id)?;
+    let text = String::from_utf8_lossy(&block.data);
+    Ok(text.trim_end_matches('\0').to_string())
+}
+
+fn read_data_group<R: Read + Seek>(inner: &mut R, dg: &Block) -> Result<DataGroup> {
+    let record_id_size = *dg.data.first().unwrap_or(&0);
+    if ![0, 1, 2, 4, 8].contains(&record_id_size) {
+        return Err(Error::ParseError(format!(
+            "Invalid MDF record id size {record_id_size}"
+        )));
+    }
+    let mut groups = Vec::new();
+    let mut cg_address = dg.link(1);
+    while cg_address != 0 {
+        let cg = read_block(inner, cg_address, b"##CG")?;
+        groups.push(read_channel_group(inner, cg_address, &cg)?);
+        cg_address = cg.link(0);
+    }
+    let (data, size) = match dg.link(2) {
+        0 => (0, 0),
+        address => match read_header(inner, address)? {
+            (id, length, _) if &id == b"##DT" => (
+                address + BLOCK_HEADER_SIZE,
+                length.saturating_sub(BLOCK_HEADER_SIZE),
+            ),
+            (id, _, _) => {
+                return Err(Error::ParseError(format!(
+                    "Unsupported MDF data block {}",
+                    String::from_utf8_lossy(&id)
+                )))
+            }
+        },
+    };
+    Ok(DataGroup {
+        record_id_size,
+        groups,
+        data,
+        size,
+    })
+}
+
+fn read_channel_group<R: Read + Seek>(
+    inner: &mut R,
+    address: u64,
+    cg: &Block,
+) -> Result<ChannelGroup> {
+    let flags = u16_at(&cg.data, 16)?;
+    let mut group = ChannelGroup {
+        address,
+        record_id: u64_at(&cg.data, 0)?,
+        vlsd: flags & CG_VLSD != 0,
+        size: u64::from(u32_at(&cg.data, 24)?) + u64::from(u32_at(&cg.data, 28)?),
+        kind: None,
+        master: None,
+        fields: HashMap::new(),
+    };
+    let mut cn_address = cg.link(1);
+    while cn_address != 0 {
+        let cn = read_block(inner, cn_address, b"##CN")?;
+        let name = read_text(inner, cn.link(2))?;
+        let field = read_field(inner, &cn)?;
+        let kind = match name.as_str() {
+            "CAN_DataFrame" => Some(Kind::CanData),
+            "CAN_RemoteFrame" => Some(Kind::CanRemote),
+            "CAN_ErrorFrame" => Some(Kind::CanError),
+            "ETH_Frame" => Some(Kind::Eth),
+            _ => None,
+        };
+        if field.cn_type == CN_MASTER {
+            group.master = Some(field);
+        } else if kind.is_some() {
+            group.kind = kind;
+            let mut component = cn.link(1);
+            while component != 0 {
+                let cn = read_block(inner, component, b"##CN")?;
+                let name = read_text(inner, cn.link(2))?;
+                let name = name.rsplit('.').next().unwrap_or_default().to_string();
+                group.fields.insert(name, read_field(inner, &cn)?);
+                component = cn.link(0);
+            }
+        }
+        cn_address = cn.link(0);
+    }
+    Ok(group)
+}
+
+fn read_field<R: Read + Seek>(inner: &mut R, cn: &Block) -> Result<Field> {
+    if cn.data.len() < 12 {
+        return Err(Error::ParseError("Truncated MDF channel".to_string()));
+    }
+    let conversion = match cn.link(4) {
+        0 => None,
+        address => {
+            let cc = read_block(inner, address, b"##CC")?;
+            match cc.data.first() {
+                Some(&CC_LINEAR) => Some((f64_at(&cc.data, 24)?, f64_at(&cc.data, 32)?)),
+                Some(0) => None,
+                _ => return Err(Error::ParseError("Unsupported MDF conversion".to_string())),
+            }
+        }
+    };
+    Ok(Field {
+        cn_type: cn.data[0],
+        data_type: cn.data[2],
+        bit_offset: cn.data[3],
+        byte_offset: u32_at(&cn.data, 4)? as usize,
+        bit_count: u32_at(&cn.data, 8)?,
+        data: cn.link(5),
+        conversion,
+    })
+}
+
+/// Splits the signal data of an SD block into its values, keyed by offset.
+fn read_signal_data<R: Read + Seek>(inner: &mut R, address: u64) -> Result<Vec<(u64, Vec<u8>)>> {
+    let sd = read_block(inner, address, b"##SD")?;
+    let mut values = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= sd.data.len() {
+        let length = u32_at(&sd.data, offset)? as usize;
+        let value = sd
+            .data
+            .get(offset + 4..offset + 4 + length)
+            .ok_or_else(|| Error::ParseError("Truncated MDF signal data".to_string()))?;
+        values.push((offset as u64, value.to_vec()));
+        offset += 4 + length;
+    }
+    Ok(values)
+}
+
+/// Reads an unsigned little-endian channel value of up to 64 bits.
+fn uint(record: &[u8], field: &Field) -> Result<u64> {
+    let bits = field.bit_count + u32::from(field.bit_offset);
+    let bytes = record
+        .get(field.byte_offset..field.byte_offset + bits.div_ceil(8) as usize)
+        .filter(|bytes| bytes.len() <= 8)
+        .ok_or_else(|| Error::ParseError("MDF channel exceeds the record".to_string()))?;
+    let mut value = [0u8; 8];
+    value[..bytes.len()].copy_from_slice(bytes);
+    let value = u64::from_le_bytes(value) >> field.bit_offset;
+    Ok(match field.bit_count {
+        64.. => value,
+        bits => value & ((1 << bits) - 1),
+    })
+}
+
+fn u16_at(buffer: &[u8], offset: usize) -> Result<u16> {
+    buffer
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::ParseError("Truncated MDF block".to_string()))
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> Result<u32> {
+    buffer
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::ParseError("Truncated MDF block".to_string()))
+}
+
+fn u64_at(buffer: &[u8], offset: usize) -> Result<u64> {
+    buffer
+        .get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| Error::ParseError("Truncated MDF block".to_string()))
+}
+
+fn f64_at(buffer: &[u8], offset: usize) -> Result<f64> {
+    u64_at(buffer, offset).map(f64::from_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        let mut messages = Vec::new();
+
+        let mut m = CanMessage::new(1, 0x123, &[0x11, 0x22, 0x33]);
+        m.set_dlc_on_wire(3);
+        m.set_timestamp(40_000);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(42, 0x18DAF110, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        m.set_extended(true);
+        m.set_transmit(true);
+        m.set_dlc_on_wire(8);
+        m.set_timestamp(80_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..12).collect();
+        let mut m = CanMessage::new(1, 0x7E0, &data);
+        m.set_fd(true);
+        m.set_brs(true);
+        m.set_dlc_on_wire(9);
+        m.set_timestamp(120_000);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(1, 0x321, &[]);
+        m.set_remote(true);
+        m.set_dlc_on_wire(4);
+        m.set_timestamp(160_000);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(42, 0, &[]);
+        m.set_error_frame(true);
+        m.set_timestamp(200_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..60).collect();
+        let mut m = EthMessage::new(93, &data);
+        m.set_transmit(true);
+        m.set_timestamp(240_000);
+        messages.push(Message::Eth(m));
+
+        let mut m = CanMessage::new(42, 0x100, &[0xff; 8]);
+        m.set_dlc_on_wire(8);
+        m.set_timestamp(280_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..100).rev().collect();
+        let mut m = EthMessage::new(93, &data);
+        m.set_timestamp(320_000);
+        messages.push(Message::Eth(m));
+
+        messages
+    }
+
+    fn write(messages: &[Message]) -> Vec<u8> {
+        let mut writer = MdfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.set_channel_map([(1, 1), (42, 2), (93, 3)].into_iter().collect());
+        writer.set_timestamp_resolution(25);
+        writer.set_start_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        writer.set_time_origin(0);
+        for message in messages {
+            writer.write(message).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let written = messages();
+        let bytes = write(&written);
+
+        assert_eq!(&bytes[..8], b"MDF     ");
+        assert_eq!(&bytes[8..12], b"4.10");
+        assert_eq!(u16::from_le_bytes([bytes[28], bytes[29]]), VERSION);
+        assert_eq!(&bytes[64..68], b"##HD");
+
+        let mut reader = MdfReader::new(Cursor::new(bytes)).unwrap();
+        reader.set_channel_map([(1, 1), (42, 2), (93, 3)].into_iter().collect());
+        reader.set_timestamp_resolution(25);
+        assert_eq!(
+            reader.start_time(),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            assert_eq!(read.timestamp(), written.timestamp());
+            match (read, written) {
+                (Message::Can(a), Message::Can(b)) => {
+                    assert_eq!({ a.netid }, { b.netid });
+                    assert_eq!({ a.arbid }, { b.arbid });
+                    assert_eq!(a.data(), b.data());
+                    assert_eq!({ a.dlcOnWire }, { b.dlcOnWire });
+                    assert_eq!(a.is_extended(), b.is_extended());
+                    assert_eq!(a.is_transmit(), b.is_transmit());
+                    assert_eq!(a.is_remote(), b.is_remote());
+                    assert_eq!(a.is_fd(), b.is_fd());
+                    assert_eq!(a.is_brs(), b.is_brs());
+                    assert_eq!(a.is_error_frame(), b.is_error_frame());
+                }
+                (Message::Eth(a), Message::Eth(b)) => {
+                    assert_eq!({ a.netid }, { b.netid });
+                    assert_eq!(a.data(), b.data());
+                    assert_eq!(a.is_transmit(), b.is_transmit());
+                }
+                _ => panic!("message kind mismatch: {read:?} vs {written:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_skip_error_counts() {
+        let mut m = NeoMessageCanError::new();
+        m.netid = 1;
+        m.type_ = NETWORK_TYPE_CAN;
+        m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+        let mut writer = MdfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.write(&Message::CanError(m)).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        let reader = MdfReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.count(), 0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(MdfReader::new(Cursor::new(vec![0u8; 200])).is_err());
+
+        // Without finish the file is marked unfinished.
+        let mut writer = MdfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.write(&messages()[0]).unwrap();
+        let bytes = writer.inner.into_inner();
+        assert_eq!(&bytes[..8], b"UnFinMF ");
+        assert!(MdfReader::new(Cursor::new(bytes)).is_err());
+
+        // A truncated data block.
+        let mut bytes = write(&messages());
+        let data = (ID_BLOCK_SIZE + HD_BLOCK_SIZE) as usize;
+        bytes[data + 8..data + 16].copy_from_slice(&(BLOCK_HEADER_SIZE + 1000).to_le_bytes());
+        let reader = MdfReader::new(Cursor::new(bytes)).unwrap();
+        assert!(reader
+            .map(|m| m.map(|_| ()))
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+
+        // Corrupt channel group headers are parse errors rather than panics or huge
+        // allocations.
+        let bytes = write(&messages());
+        let cg = bytes.windows(4).position(|id| id == b"##CG").unwrap();
+        let link_count = u64_at(&bytes, cg + 16).unwrap();
+        for (offset, value) in [
+            (16, u64::MAX / 4),
+            (8, 1 << 40),
+            (8, BLOCK_HEADER_SIZE + link_count * 8 + 4),
+        ] {
+            let mut bytes = bytes.clone();
+            bytes[cg + offset..cg + offset + 8].copy_from_slice(&value.to_le_bytes());
+            assert!(matches!(
+                MdfReader::new(Cursor::new(bytes)),
+                Err(Error::ParseError(_))
+            ));
+        }
+    }
+}
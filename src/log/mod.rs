@@ -0,0 +1,305 @@
+//! Capture file formats for messages received with [get_messages](crate::native::get_messages).
+//!
+//! Writers accept the crate's message types directly and readers yield
+//! [Message](crate::message::Message) values that own their payload.
+//!
+//! # Timestamps
+//!
+//! libicsneo multiplies device timestamps by the device's
+//! [timestamp resolution](crate::native::get_timestamp_resolution) before returning them, so
+//! message timestamps are nanoseconds since 2007-01-01. Writers and readers count timestamps in
+//! nanoseconds by default, `set_timestamp_resolution` is only needed for other units.
+//! Writers return [InvalidArgument](crate::native::Error::InvalidArgument) for a timestamp
+//! that overflows 64-bit nanoseconds at the set resolution.
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libicsneo_sys::neonetid_t;
+
+pub mod asc;
+pub mod blf;
+pub mod candump;
+pub mod mdf;
+pub mod pcapng;
+mod socketcan;
+
+/// Maps libicsneo netids to the channel numbers used by a log format.
+///
+/// Netids without an explicit entry map to the channel with the same number and back, so
+/// the default map round-trips every netid.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelMap {
+    channels: HashMap<neonetid_t, u16>,
+    netids: HashMap<u16, neonetid_t>,
+}
+
+impl ChannelMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `netid` to `channel`, replacing any previous entry for either side.
+    pub fn insert(&mut self, netid: neonetid_t, channel: u16) {
+        if let Some(old) = self.channels.insert(netid, channel) {
+            self.netids.remove(&old);
+        }
+        if let Some(old) = self.netids.insert(channel, netid) {
+            if old != netid {
+                self.channels.remove(&old);
+            }
+        }
+    }
+
+    pub fn channel(&self, netid: neonetid_t) -> u16 {
+        self.channels.get(&netid).copied().unwrap_or(netid)
+    }
+
+    pub fn netid(&self, channel: u16) -> neonetid_t {
+        self.netids.get(&channel).copied().unwrap_or(channel)
+    }
+}
+
+impl FromIterator<(neonetid_t, u16)> for ChannelMap {
+    fn from_iter<I: IntoIterator<Item = (neonetid_t, u16)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (netid, channel) in iter {
+            map.insert(netid, channel);
+        }
+        map
+    }
+}
+
+/// A UTC calendar time with millisecond precision, as stored in log file headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CivilTime {
+    pub year: u16,
+    pub month: u16,
+    /// 0 is Sunday.
+    pub weekday: u16,
+    pub day: u16,
+    pub hour: u16,
+    pub minute: u16,
+    pub second: u16,
+    pub millisecond: u16,
+}
+
+impl CivilTime {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // Howard Hinnant's civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self {
+            year: year as u16,
+            month: month as u16,
+            weekday: ((days + 4).rem_euclid(7)) as u16,
+            day: day as u16,
+            hour: (rem / 3600) as u16,
+            minute: (rem / 60 % 60) as u16,
+            second: (rem % 60) as u16,
+            millisecond: since_epoch.subsec_millis() as u16,
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        // Howard Hinnant's days_from_civil
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = i64::from(self.month);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let secs = days * 86400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second);
+        UNIX_EPOCH
+            + Duration::from_secs(secs.max(0) as u64)
+            + Duration::from_millis(u64::from(self.millisecond))
+    }
+}
+
+/// Nanoseconds in `ticks` timestamp ticks of `resolution` nanoseconds.
+pub(crate) fn ticks_to_nanos(ticks: u64, resolution: u64) -> Result<u64, crate::native::Error> {
+    ticks.checked_mul(resolution).ok_or_else(|| {
+        crate::native::Error::InvalidArgument(format!(
+            "Timestamp {ticks} overflows at {resolution} ns per tick"
+        ))
+    })
+}
+
+/// Like `read_exact` but returns how many bytes were read before the end of the stream.
+pub(crate) fn read_fully<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> Result<usize, crate::native::Error> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// Messages and checks shared by the golden file tests of the formats.
+#[cfg(test)]
+pub(crate) mod golden {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::ChannelMap;
+    use crate::message::*;
+    use crate::native::*;
+
+    /// Timestamp of the first golden message.
+    pub const ORIGIN: u64 = 1_000_000;
+
+    pub fn start_time() -> SystemTime {
+        // 2015-07-10 06:17:32.249 UTC
+        UNIX_EPOCH + Duration::from_millis(1436509052249)
+    }
+
+    pub fn channel_map() -> ChannelMap {
+        [(1, 1), (42, 2), (93, 3)].into_iter().collect()
+    }
+
+    pub fn golden_messages() -> Vec<Message> {
+        let mut messages = Vec::new();
+
+        let mut m = CanMessage::new(1, 0x123, &[0x11, 0x22, 0x33]);
+        m.set_timestamp(ORIGIN);
+        messages.push(Message::Can(m));
+
+        let mut m = CanMessage::new(42, 0x18DAF110, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        m.set_extended(true);
+        m.set_transmit(true);
+        m.set_timestamp(ORIGIN + 1_000_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..12).collect();
+        let mut m = CanMessage::new(1, 0x7E0, &data);
+        m.set_fd(true);
+        m.set_brs(true);
+        m.set_timestamp(ORIGIN + 2_500_000);
+        messages.push(Message::Can(m));
+
+        let mut m = NeoMessageCanError::new();
+        m.netid = 1;
+        m.type_ = NETWORK_TYPE_CAN;
+        m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+        m.transmitErrorCount = 128;
+        m.receiveErrorCount = 5;
+        m.timestamp = ORIGIN + 3_000_000;
+        messages.push(Message::CanError(m));
+
+        let mut m = CanMessage::new(42, 0, &[]);
+        m.set_error_frame(true);
+        m.set_timestamp(ORIGIN + 4_000_000);
+        messages.push(Message::Can(m));
+
+        let data: Vec<u8> = (0..60).collect();
+        let mut m = EthMessage::new(93, &data);
+        m.set_transmit(true);
+        m.set_timestamp(ORIGIN + 5_000_000);
+        messages.push(Message::Eth(m));
+
+        messages
+    }
+
+    /// Asserts that `read` is `written`, read back with `timestamp`.
+    pub fn assert_same(read: &Message, written: &Message, timestamp: u64) {
+        assert_eq!(read.netid(), written.netid());
+        assert_eq!(read.timestamp(), timestamp);
+        match (read, written) {
+            (Message::Can(a), Message::Can(b)) => {
+                assert_eq!({ a.arbid }, { b.arbid });
+                assert_eq!(a.data(), b.data());
+                assert_eq!(a.is_extended(), b.is_extended());
+                assert_eq!(a.is_transmit(), b.is_transmit());
+                assert_eq!(a.is_remote(), b.is_remote());
+                assert_eq!(a.is_fd(), b.is_fd());
+                assert_eq!(a.is_brs(), b.is_brs());
+                assert_eq!(a.is_error_frame(), b.is_error_frame());
+            }
+            (Message::CanError(a), Message::CanError(b)) => {
+                assert_eq!(a.transmitErrorCount, b.transmitErrorCount);
+                assert_eq!(a.receiveErrorCount, b.receiveErrorCount);
+            }
+            (Message::Eth(a), Message::Eth(b)) => {
+                assert_eq!(a.data(), b.data());
+                assert_eq!(a.is_transmit(), b.is_transmit());
+            }
+            _ => panic!("message kind mismatch: {read:?} vs {written:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_map() {
+        let mut map: ChannelMap = [(1, 1), (42, 2)].into_iter().collect();
+        assert_eq!(map.channel(1), 1);
+        assert_eq!(map.channel(42), 2);
+        assert_eq!(map.netid(2), 42);
+        // Unmapped values fall through unchanged.
+        assert_eq!(map.channel(61), 61);
+        assert_eq!(map.netid(7), 7);
+        // Remapping drops the stale reverse entry.
+        map.insert(42, 3);
+        assert_eq!(map.netid(3), 42);
+        assert_eq!(map.netid(2), 2);
+    }
+
+    #[test]
+    fn test_ticks_to_nanos() {
+        assert_eq!(ticks_to_nanos(40, 25).unwrap(), 1_000);
+        assert_eq!(ticks_to_nanos(u64::MAX, 1).unwrap(), u64::MAX);
+        assert!(matches!(
+            ticks_to_nanos(u64::MAX / 10, 25),
+            Err(crate::native::Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_civil_time() {
+        // 2015-07-10 06:17:32.249 UTC, a Friday
+        let time = UNIX_EPOCH + Duration::from_millis(1436509052249);
+        let civil = CivilTime::from_system_time(time);
+        assert_eq!(
+            civil,
+            CivilTime {
+                year: 2015,
+                month: 7,
+                weekday: 5,
+                day: 10,
+                hour: 6,
+                minute: 17,
+                second: 32,
+                millisecond: 249,
+            }
+        );
+        assert_eq!(civil.to_system_time(), time);
+        let leap = CivilTime::from_system_time(UNIX_EPOCH + Duration::from_secs(951782400));
+        assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+        assert_eq!(leap.weekday, 2);
+    }
+}
@@ -0,0 +1,678 @@
+//! PCAP Next Generation (pcapng) writer and reader, for opening captures in Wireshark.
+//!
+//! Every netid gets its own interface description block named after the netid's
+//! [short name](crate::network::NetworkId::short_name). Ethernet frames are written with
+//! `LINKTYPE_ETHERNET`, CAN and CAN FD frames as SocketCAN frames with
+//! `LINKTYPE_CAN_SOCKETCAN`. Error frames and error counters use the SocketCAN error frame
+//! encoding, see [candump](super::candump).
+//!
+//! Packet timestamps are the device timestamps in nanoseconds, optionally shifted with
+//! [set_time_offset](PcapngWriter::set_time_offset) so Wireshark shows wall-clock times.
+//! Direction is stored in the `epb_flags` option.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use super::socketcan::*;
+use super::{read_fully, ticks_to_nanos};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x1;
+const ENHANCED_PACKET_BLOCK: u32 = 0x6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+const EPB_INBOUND: u32 = 0x1;
+const EPB_OUTBOUND: u32 = 0x2;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const CAN_MTU: usize = 16;
+const CANFD_MTU: usize = 72;
+
+/// Writes messages into a pcapng stream. The section header is written by
+/// [new](PcapngWriter::new), interface blocks are added as new netids show up.
+pub struct PcapngWriter<W: Write> {
+    inner: W,
+    interfaces: HashMap<(neonetid_t, u16), u32>,
+    names: HashMap<neonetid_t, String>,
+    resolution: u64,
+    offset: u64,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header block.
+    pub fn new(mut inner: W) -> Result<Self> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // section length not specified
+        push_option(&mut body, SHB_USERAPPL, b"libicsneo-rs");
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        write_block(&mut inner, SECTION_HEADER_BLOCK, &body)?;
+        Ok(Self {
+            inner,
+            interfaces: HashMap::new(),
+            names: HashMap::new(),
+            resolution: 1,
+            offset: 0,
+        })
+    }
+
+    /// Uses `name` instead of the netid's short name for the interface of `netid`. Only
+    /// affects interfaces that haven't been written yet.
+    pub fn set_interface(&mut self, netid: impl Into<NetworkId>, name: &str) {
+        self.names.insert(netid.into().0, name.to_string());
+    }
+
+    /// Nanoseconds per message timestamp tick, see [Timestamps](super#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Added to every message timestamp, e.g. [DEVICE_EPOCH](crate::timebase::DEVICE_EPOCH)
+    /// to store messages from libicsneo with Unix timestamps. Defaults to zero.
+    pub fn set_time_offset(&mut self, offset: Duration) {
+        self.offset = u64::try_from(offset.as_nanos()).unwrap_or(u64::MAX);
+    }
+
+    /// Writes any message kind this format supports, other kinds are skipped.
+    ///
+    /// # Safety
+    ///
+    /// See [Message::from_neo].
+    pub unsafe fn write_message(&mut self, message: &NeoMessage) -> Result<()> {
+        match Message::from_neo(message) {
+            Some(message) => self.write(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Can(m) => self.write_can(m),
+            Message::CanError(m) => self.write_can_error(m),
+            Message::Eth(m) => self.write_eth(m),
+        }
+    }
+
+    pub fn write_can(&mut self, message: &CanMessage) -> Result<()> {
+        let packet = encode_can(&Frame::from_can(message));
+        let flags = direction(status::get(message.status, status::TRANSMIT));
+        self.write_packet(
+            message.netid,
+            LINKTYPE_CAN_SOCKETCAN,
+            message.timestamp,
+            &packet,
+            Some(flags),
+        )
+    }
+
+    pub fn write_can_error(&mut self, message: &NeoMessageCanError) -> Result<()> {
+        let packet = encode_can(&Frame::from_can_error(message));
+        self.write_packet(
+            message.netid,
+            LINKTYPE_CAN_SOCKETCAN,
+            message.timestamp,
+            &packet,
+            None,
+        )
+    }
+
+    pub fn write_eth(&mut self, message: &EthMessage) -> Result<()> {
+        let flags = direction(status::get(message.status, status::TRANSMIT));
+        self.write_packet(
+            message.netid,
+            LINKTYPE_ETHERNET,
+            message.timestamp,
+            message.data(),
+            Some(flags),
+        )
+    }
+
+    /// Flushes and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_packet(
+        &mut self,
+        netid: neonetid_t,
+        linktype: u16,
+        timestamp: u64,
+        packet: &[u8],
+        flags: Option<u32>,
+    ) -> Result<()> {
+        let interface = self.interface(netid, linktype)?;
+        let ns = ticks_to_nanos(timestamp, self.resolution)?
+            .checked_add(self.offset)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Timestamp {timestamp} overflows with the offset"))
+            })?;
+        let mut body = Vec::with_capacity(packet.len() + 32);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((ns >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ns as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        body.extend_from_slice(packet);
+        body.resize(body.len().next_multiple_of(4), 0);
+        if let Some(flags) = flags {
+            push_option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+            push_option(&mut body, OPT_ENDOFOPT, &[]);
+        }
+        write_block(&mut self.inner, ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    /// Returns the interface id for `netid`, writing its description block first if needed.
+    fn interface(&mut self, netid: neonetid_t, linktype: u16) -> Result<u32> {
+        if let Some(id) = self.interfaces.get(&(netid, linktype)) {
+            return Ok(*id);
+        }
+        let id = self.interfaces.len() as u32;
+        let name = match self.names.get(&netid) {
+            Some(name) => name.clone(),
+            None => NetworkId(netid).short_name(),
+        };
+        let mut body = Vec::new();
+        body.extend_from_slice(&linktype.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        body.extend_from_slice(&0u32.to_le_bytes()); // no snapshot length limit
+        push_option(&mut body, IF_NAME, name.as_bytes());
+        push_option(&mut body, IF_TSRESOL, &[9]); // nanoseconds
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        write_block(&mut self.inner, INTERFACE_DESCRIPTION_BLOCK, &body)?;
+        self.interfaces.insert((netid, linktype), id);
+        Ok(id)
+    }
+}
+
+struct Interface {
+    linktype: u16,
+    name: Option<String>,
+    /// Timestamp units per second.
+    units: u64,
+}
+
+/// Reads messages back out of a pcapng stream. Packets on interfaces with other link types and
+/// blocks other than interface descriptions and enhanced packets are skipped.
+///
+/// Interface names are looked up in the names set with
+/// [set_interface](PcapngReader::set_interface) first and then parsed as a
+/// [NetworkId](crate::network::NetworkId), so files written by [PcapngWriter] need no
+/// configuration.
+pub struct PcapngReader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+    names: HashMap<String, neonetid_t>,
+    resolution: u64,
+    offset: u64,
+}
+
+impl<R: Read> PcapngReader<R> {
+    /// Reads and validates the section header block.
+    pub fn new(inner: R) -> Result<Self> {
+        let mut reader = Self {
+            inner,
+            big_endian: false,
+            interfaces: Vec::new(),
+            names: HashMap::new(),
+            resolution: 1,
+            offset: 0,
+        };
+        match reader.read_block()? {
+            Some((SECTION_HEADER_BLOCK, _)) => Ok(reader),
+            _ => Err(Error::ParseError("Not a pcapng file".to_string())),
+        }
+    }
+
+    /// Maps packets on the interface `name` to `netid`.
+    pub fn set_interface(&mut self, netid: impl Into<NetworkId>, name: &str) {
+        self.names.insert(name.to_string(), netid.into().0);
+    }
+
+    /// Nanoseconds per tick for the timestamps of returned messages. Defaults to 1.
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Subtracted from every packet timestamp, the counterpart of
+    /// [PcapngWriter::set_time_offset](PcapngWriter::set_time_offset).
+    pub fn set_time_offset(&mut self, offset: Duration) {
+        self.offset = u64::try_from(offset.as_nanos()).unwrap_or(u64::MAX);
+    }
+
+    /// Returns the next supported message or `None` at the end of the stream.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        while let Some((block_type, body)) = self.read_block()? {
+            match block_type {
+                SECTION_HEADER_BLOCK => self.interfaces.clear(),
+                INTERFACE_DESCRIPTION_BLOCK => self.read_interface(&body)?,
+                ENHANCED_PACKET_BLOCK => {
+                    if let Some(message) = self.read_packet(&body)? {
+                        return Ok(Some(message));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the next block and returns its type and body, without the trailing length.
+    fn read_block(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        let mut header = [0u8; 8];
+        let read = read_fully(&mut self.inner, &mut header)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if read < header.len() {
+            return Err(Error::ParseError("Truncated pcapng block".to_string()));
+        }
+        let mut prefix = Vec::new();
+        if header[..4] == SECTION_HEADER_BLOCK.to_le_bytes() {
+            // The byte order magic after the length decides how the section is encoded.
+            let mut magic = [0u8; 4];
+            self.inner.read_exact(&mut magic)?;
+            self.big_endian = match magic {
+                m if m == BYTE_ORDER_MAGIC.to_le_bytes() => false,
+                m if m == BYTE_ORDER_MAGIC.to_be_bytes() => true,
+                _ => return Err(Error::ParseError("Invalid pcapng byte order".to_string())),
+            };
+            prefix.extend_from_slice(&magic);
+        }
+        let block_type = self.u32_at(&header, 0);
+        let length = self.u32_at(&header, 4) as usize;
+        if length < 12 + prefix.len() || length & 3 != 0 {
+            return Err(Error::ParseError(format!(
+                "Invalid pcapng block length {length}"
+            )));
+        }
+        // Grows with what is actually read, so a corrupt length can't allocate gigabytes.
+        let mut body = prefix;
+        let remaining = length - 8 - body.len();
+        (&mut self.inner)
+            .take(remaining as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < length - 8 {
+            return Err(Error::ParseError("Truncated pcapng block".to_string()));
+        }
+        body.truncate(length - 12);
+        Ok(Some((block_type, body)))
+    }
+
+    fn read_interface(&mut self, body: &[u8]) -> Result<()> {
+        if body.len() < 8 {
+            return Err(Error::ParseError(
+                "Truncated pcapng interface description".to_string(),
+            ));
+        }
+        let mut interface = Interface {
+            linktype: self.u16_at(body, 0),
+            name: None,
+            units: 1_000_000,
+        };
+        for (code, value) in self.options(&body[8..]) {
+            match (code, value) {
+                (IF_NAME, name) => {
+                    let name = String::from_utf8_lossy(name);
+                    interface.name = Some(name.trim_end_matches('\0').to_string());
+                }
+                (IF_TSRESOL, [resolution, ..]) => {
+                    let exponent = u32::from(resolution & 0x7f);
+                    let units = if resolution & 0x80 != 0 {
+                        2u64.checked_pow(exponent)
+                    } else {
+                        10u64.checked_pow(exponent)
+                    };
+                    interface.units = units.ok_or_else(|| {
+                        Error::ParseError(format!("Invalid pcapng if_tsresol {resolution}"))
+                    })?;
+                }
+                _ => {}
+            }
+        }
+        self.interfaces.push(interface);
+        Ok(())
+    }
+
+    fn read_packet(&self, body: &[u8]) -> Result<Option<Message>> {
+        if body.len() < 20 {
+            return Err(Error::ParseError(
+                "Truncated pcapng enhanced packet".to_string(),
+            ));
+        }
+        let index = self.u32_at(body, 0) as usize;
+        let interface = self.interfaces.get(index).ok_or_else(|| {
+            Error::ParseError(format!("pcapng packet on undeclared interface {index}"))
+        })?;
+        let units = u64::from(self.u32_at(body, 4)) << 32 | u64::from(self.u32_at(body, 8));
+        let captured = self.u32_at(body, 12) as usize;
+        let packet = body
+            .get(20..20 + captured)
+            .ok_or_else(|| Error::ParseError("Truncated pcapng packet data".to_string()))?;
+        let options = body.get(20 + captured.next_multiple_of(4)..).unwrap_or(&[]);
+        let flags = self
+            .options(options)
+            .find(|(code, value)| *code == EPB_FLAGS && value.len() == 4)
+            .map(|(_, value)| self.u32_at(value, 0));
+        let transmit = flags.is_some_and(|flags| flags & 0x3 == EPB_OUTBOUND);
+
+        let mut message = match interface.linktype {
+            LINKTYPE_ETHERNET => {
+                let mut m = EthMessage::new(self.netid(index)?, packet);
+                m.set_transmit(transmit);
+                Message::Eth(m)
+            }
+            LINKTYPE_CAN_SOCKETCAN => {
+                let netid = self.netid(index)?;
+                let mut message = decode_can(packet)
+                    .and_then(|frame| frame.to_message(netid))
+                    .map_err(|e| Error::ParseError(format!("pcapng CAN packet: {e}")))?;
+                if let Message::Can(m) = &mut message {
+                    m.set_transmit(transmit);
+                }
+                message
+            }
+            _ => return Ok(None),
+        };
+        let ns = (u128::from(units) * 1_000_000_000 / u128::from(interface.units)) as u64;
+        message.set_timestamp(ns.saturating_sub(self.offset) / self.resolution);
+        Ok(Some(message))
+    }
+
+    fn netid(&self, index: usize) -> Result<neonetid_t> {
+        let Some(name) = &self.interfaces[index].name else {
+            return Err(Error::ParseError(format!(
+                "pcapng interface {index} has no name"
+            )));
+        };
+        if let Some(netid) = self.names.get(name) {
+            return Ok(*netid);
+        }
+        name.parse::<NetworkId>().map(|id| id.0).map_err(|_| {
+            Error::ParseError(format!(
+                "Unknown pcapng interface {name}, map it with set_interface"
+            ))
+        })
+    }
+
+    /// Iterates over the `(code, value)` pairs of an options list.
+    fn options<'a>(&self, mut options: &'a [u8]) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+        let big_endian = self.big_endian;
+        std::iter::from_fn(move || {
+            if options.len() < 4 {
+                return None;
+            }
+            let word = |offset: usize| {
+                let bytes = [options[offset], options[offset + 1]];
+                if big_endian {
+                    u16::from_be_bytes(bytes)
+                } else {
+                    u16::from_le_bytes(bytes)
+                }
+            };
+            let (code, length) = (word(0), usize::from(word(2)));
+            if code == OPT_ENDOFOPT {
+                return None;
+            }
+            let value = options.get(4..4 + length)?;
+            options = options.get(4 + length.next_multiple_of(4)..).unwrap_or(&[]);
+            Some((code, value))
+        })
+    }
+
+    fn u16_at(&self, buffer: &[u8], offset: usize) -> u16 {
+        let bytes = [buffer[offset], buffer[offset + 1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32_at(&self, buffer: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buffer[offset..offset + 4]);
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapngReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn direction(transmit: bool) -> u32 {
+    if transmit {
+        EPB_OUTBOUND
+    } else {
+        EPB_INBOUND
+    }
+}
+
+/// Serializes a frame as a `can_frame` or `canfd_frame`. The ID is big-endian as
+/// `LINKTYPE_CAN_SOCKETCAN` requires.
+fn encode_can(frame: &Frame) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(CANFD_MTU);
+    packet.extend_from_slice(&frame.can_id.to_be_bytes());
+    match frame.fd_flags {
+        Some(flags) => {
+            packet.extend_from_slice(&[frame.data.len() as u8, flags | CANFD_FDF, 0, 0]);
+            packet.extend_from_slice(&frame.data);
+            packet.resize(CANFD_MTU, 0);
+        }
+        None if frame.can_id & CAN_RTR_FLAG != 0 => {
+            packet.extend_from_slice(&[frame.len8_dlc, 0, 0, 0]);
+            packet.resize(CAN_MTU, 0);
+        }
+        None => {
+            packet.extend_from_slice(&[frame.data.len() as u8, 0, 0, frame.len8_dlc]);
+            packet.extend_from_slice(&frame.data);
+            packet.resize(CAN_MTU, 0);
+        }
+    }
+    packet
+}
+
+fn decode_can(packet: &[u8]) -> std::result::Result<Frame, String> {
+    if packet.len() < 8 {
+        return Err(format!("{} bytes is too short", packet.len()));
+    }
+    let can_id = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+    let len = usize::from(packet[4]);
+    // Older captures don't set CANFD_FDF, their frames are only told apart by size.
+    let fd = packet[5] & CANFD_FDF != 0 || packet.len() == CANFD_MTU;
+    if !fd && can_id & CAN_RTR_FLAG != 0 {
+        return Ok(Frame {
+            can_id,
+            fd_flags: None,
+            len8_dlc: packet[4],
+            data: Vec::new(),
+        });
+    }
+    let data = packet
+        .get(8..8 + len)
+        .ok_or_else(|| format!("length {len} exceeds the packet"))?
+        .to_vec();
+    Ok(Frame {
+        can_id,
+        fd_flags: fd.then_some(packet[5] & (CANFD_BRS | CANFD_ESI)),
+        len8_dlc: if fd { 0 } else { packet[7] },
+        data,
+    })
+}
+
+fn push_option(buffer: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buffer.extend_from_slice(&code.to_le_bytes());
+    buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(value);
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+}
+
+fn write_block<W: Write>(inner: &mut W, block_type: u32, body: &[u8]) -> Result<()> {
+    let length = (body.len() + 12) as u32;
+    inner.write_all(&block_type.to_le_bytes())?;
+    inner.write_all(&length.to_le_bytes())?;
+    inner.write_all(body)?;
+    inner.write_all(&length.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::golden::{golden_messages, ORIGIN};
+
+    /// Also compares the DLC on the wire, which pcapng keeps.
+    fn assert_same(read: &Message, written: &Message) {
+        crate::log::golden::assert_same(read, written, written.timestamp());
+        if let (Message::Can(a), Message::Can(b)) = (read, written) {
+            assert_eq!({ a.dlcOnWire }, { b.dlcOnWire });
+        }
+    }
+
+    #[test]
+    fn test_write_golden() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        for message in golden_messages() {
+            writer.write(&message).unwrap();
+        }
+        let written = writer.finish().unwrap();
+        assert_eq!(written, include_bytes!("testdata/golden.pcapng"));
+    }
+
+    #[test]
+    fn test_read_golden() {
+        let reader = PcapngReader::new(&include_bytes!("testdata/golden.pcapng")[..]).unwrap();
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        let written = golden_messages();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            let Message::Can(m) = written else {
+                assert_same(read, written);
+                continue;
+            };
+            // Error frames carry no length on the wire.
+            let mut m = m.clone();
+            if !m.is_error_frame() {
+                m.set_dlc_on_wire(len_to_dlc(m.data().len()));
+            }
+            assert_same(read, &Message::Can(m));
+        }
+    }
+
+    #[test]
+    fn test_read_big_endian() {
+        let mut reader =
+            PcapngReader::new(&include_bytes!("testdata/big_endian.pcapng")[..]).unwrap();
+        reader.set_interface(NetworkId::HSCAN3, "vcan0");
+        reader.set_timestamp_resolution(25);
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), 2);
+
+        let Message::Can(m) = &read[0] else {
+            panic!("{:?}", read[0])
+        };
+        // Microsecond timestamps
+        assert_eq!(
+            ({ m.netid }, { m.arbid }, { m.timestamp }),
+            (44, 0x7FF, 60_000_000)
+        );
+        assert_eq!(m.data(), &[0xAA]);
+        assert!(m.is_transmit() && !m.is_fd());
+
+        let Message::Can(m) = &read[1] else {
+            panic!("{:?}", read[1])
+        };
+        assert_eq!(m.data(), (0..16).collect::<Vec<u8>>());
+        assert!(m.is_fd() && m.is_esi() && !m.is_brs() && !m.is_transmit());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut written = golden_messages();
+        let mut m = CanMessage::new(61, 0x321, &[]);
+        m.set_remote(true);
+        m.set_dlc_on_wire(6);
+        m.set_timestamp(ORIGIN + 6_000_000);
+        written.push(Message::Can(m));
+        let mut m = CanMessage::new(61, 0x322, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        m.set_dlc_on_wire(13);
+        m.set_timestamp(ORIGIN + 7_000_000);
+        written.push(Message::Can(m));
+
+        let offset = Duration::from_secs(1_700_000_000);
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        writer.set_interface(NetworkId::HSCAN4, "can4");
+        writer.set_timestamp_resolution(25);
+        writer.set_time_offset(offset);
+        for message in &written {
+            writer.write(message).unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        let mut reader = PcapngReader::new(&file[..]).unwrap();
+        reader.set_interface(NetworkId::HSCAN4, "can4");
+        reader.set_timestamp_resolution(25);
+        reader.set_time_offset(offset);
+        let read: Vec<Message> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(read.len(), written.len());
+        assert_same(&read[6], &written[6]);
+        assert_same(&read[7], &written[7]);
+
+        let mut reader = PcapngReader::new(&file[..]).unwrap();
+        reader.set_timestamp_resolution(25);
+        assert!(matches!(
+            reader.nth(6),
+            Some(Err(Error::ParseError(e))) if e.contains("can4")
+        ));
+    }
+
+    #[test]
+    fn test_time_offset_overflow() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        let mut m = CanMessage::new(1, 0x123, &[1]);
+        m.set_timestamp(u64::MAX - 1);
+        writer.set_time_offset(Duration::from_secs(1));
+        assert!(matches!(
+            writer.write(&Message::Can(m)),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        assert!(matches!(
+            PcapngReader::new(&b"\xd4\xc3\xb2\xa1"[..]),
+            Err(Error::ParseError(_))
+        ));
+        let golden = include_bytes!("testdata/golden.pcapng");
+        let mut reader = PcapngReader::new(&golden[..golden.len() - 8]).unwrap();
+        assert!((&mut reader).take(5).all(|m| m.is_ok()));
+        assert!(matches!(reader.read_message(), Err(Error::ParseError(_))));
+    }
+}
@@ -0,0 +1,173 @@
+//! Linux SocketCAN frame layout (`linux/can.h`, `linux/can/error.h`), shared by the candump
+//! and pcapng formats.
+use libicsneo_sys::neonetid_t;
+
+use crate::message::*;
+use crate::native::*;
+
+pub const CAN_EFF_FLAG: u32 = 0x8000_0000;
+pub const CAN_RTR_FLAG: u32 = 0x4000_0000;
+pub const CAN_ERR_FLAG: u32 = 0x2000_0000;
+pub const CAN_SFF_MASK: u32 = 0x7ff;
+pub const CAN_EFF_MASK: u32 = 0x1fff_ffff;
+
+pub const CANFD_BRS: u8 = 0x01;
+pub const CANFD_ESI: u8 = 0x02;
+pub const CANFD_FDF: u8 = 0x04;
+
+const CAN_ERR_CRTL: u32 = 0x0004;
+const CAN_ERR_BUSERROR: u32 = 0x0080;
+const CAN_ERR_CNT: u32 = 0x0200;
+const CAN_ERR_CRTL_RX_WARNING: u8 = 0x04;
+const CAN_ERR_CRTL_TX_WARNING: u8 = 0x08;
+const CAN_ERR_CRTL_RX_PASSIVE: u8 = 0x10;
+const CAN_ERR_CRTL_TX_PASSIVE: u8 = 0x20;
+
+/// A `can_frame` or `canfd_frame`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Identifier including the EFF/RTR/ERR flags.
+    pub can_id: u32,
+    /// `None` for classic frames, the `CANFD_*` flags for CAN FD frames.
+    pub fd_flags: Option<u8>,
+    /// DLC of a classic frame when it differs from the payload length, i.e. the requested
+    /// DLC of remote frames or DLCs 9 to 15 of 8 byte frames. 0 otherwise.
+    pub len8_dlc: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Error frames become a `CAN_ERR_BUSERROR` frame, since libicsneo doesn't report the
+    /// error class.
+    pub fn from_can(message: &CanMessage) -> Self {
+        let status = message.status;
+        let data = message.data();
+        if status::get(status, status::ERROR_FRAME) {
+            return Self {
+                can_id: CAN_ERR_FLAG | CAN_ERR_BUSERROR,
+                fd_flags: None,
+                len8_dlc: 0,
+                data: vec![0; 8],
+            };
+        }
+        let arbid = message.arbid;
+        let can_id = if status::get(status, status::EXTENDED) {
+            CAN_EFF_FLAG | (arbid & CAN_EFF_MASK)
+        } else {
+            arbid & CAN_SFF_MASK
+        };
+        if status::get(status, status::CANFD_FDF) {
+            let mut flags = 0;
+            if status::get(status, status::CANFD_BRS) {
+                flags |= CANFD_BRS;
+            }
+            if status::get(status, status::CANFD_ESI) {
+                flags |= CANFD_ESI;
+            }
+            return Self {
+                can_id,
+                fd_flags: Some(flags),
+                len8_dlc: 0,
+                data: data.to_vec(),
+            };
+        }
+        if status::get(status, status::REMOTE) {
+            return Self {
+                can_id: can_id | CAN_RTR_FLAG,
+                fd_flags: None,
+                len8_dlc: message.dlcOnWire.max(len_to_dlc(data.len())),
+                data: Vec::new(),
+            };
+        }
+        let dlc = message.dlcOnWire;
+        Self {
+            can_id,
+            fd_flags: None,
+            len8_dlc: if data.len() == 8 && dlc > 8 { dlc } else { 0 },
+            data: data.to_vec(),
+        }
+    }
+
+    /// Error counters become a controller error frame with `CAN_ERR_CNT` set and the counters
+    /// in data bytes 6 and 7.
+    pub fn from_can_error(message: &NeoMessageCanError) -> Self {
+        let (tec, rec) = (message.transmitErrorCount, message.receiveErrorCount);
+        let mut data = vec![0u8; 8];
+        data[1] = if tec >= 128 {
+            CAN_ERR_CRTL_TX_PASSIVE
+        } else if tec >= 96 {
+            CAN_ERR_CRTL_TX_WARNING
+        } else {
+            0
+        };
+        data[1] |= if rec >= 128 {
+            CAN_ERR_CRTL_RX_PASSIVE
+        } else if rec >= 96 {
+            CAN_ERR_CRTL_RX_WARNING
+        } else {
+            0
+        };
+        data[6] = tec;
+        data[7] = rec;
+        let mut can_id = CAN_ERR_FLAG | CAN_ERR_CNT;
+        if data[1] != 0 {
+            can_id |= CAN_ERR_CRTL;
+        }
+        Self {
+            can_id,
+            fd_flags: None,
+            len8_dlc: 0,
+            data,
+        }
+    }
+
+    pub fn to_message(&self, netid: neonetid_t) -> std::result::Result<Message, String> {
+        if self.can_id & CAN_ERR_FLAG != 0 {
+            let class = self.can_id & CAN_EFF_MASK;
+            if class & (CAN_ERR_CNT | CAN_ERR_CRTL) != 0 && self.data.len() == 8 {
+                let mut m = NeoMessageCanError::new();
+                m.netid = netid;
+                m.type_ = NETWORK_TYPE_CAN;
+                m.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+                m.transmitErrorCount = self.data[6];
+                m.receiveErrorCount = self.data[7];
+                return Ok(Message::CanError(m));
+            }
+            let mut m = CanMessage::new(netid, 0, &[]);
+            m.set_error_frame(true);
+            return Ok(Message::Can(m));
+        }
+
+        let extended = self.can_id & CAN_EFF_FLAG != 0;
+        let arbid = self.can_id & if extended { CAN_EFF_MASK } else { CAN_SFF_MASK };
+        let mut m = CanMessage::new(netid, arbid, &[]);
+        m.set_extended(extended);
+        if let Some(flags) = self.fd_flags {
+            if self.data.len() > 64 {
+                return Err(format!(
+                    "{} bytes don't fit a CAN FD frame",
+                    self.data.len()
+                ));
+            }
+            m.set_fd(true);
+            m.set_brs(flags & CANFD_BRS != 0);
+            m.set_esi(flags & CANFD_ESI != 0);
+            m.set_dlc_on_wire(len_to_dlc(self.data.len()));
+            m.set_data(&self.data);
+        } else if self.can_id & CAN_RTR_FLAG != 0 {
+            m.set_remote(true);
+            m.set_dlc_on_wire(self.len8_dlc);
+        } else {
+            if self.data.len() > 8 {
+                return Err(format!("{} bytes don't fit a CAN frame", self.data.len()));
+            }
+            m.set_dlc_on_wire(if self.data.len() == 8 && self.len8_dlc > 8 {
+                self.len8_dlc
+            } else {
+                self.data.len() as u8
+            });
+            m.set_data(&self.data);
+        }
+        Ok(Message::Can(m))
+    }
+}
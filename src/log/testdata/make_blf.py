@@ -0,0 +1,83 @@
+"""Builds the BLF golden files used by the tests in src/log/blf.rs.
+
+Written directly from the BLF object layouts with `struct`, independent of the Rust writer.
+Run from this directory: python3 make_blf.py
+"""
+import struct
+import zlib
+
+FILE_HEADER_SIZE = 144
+# 2015-07-10 06:17:32.249 UTC, Friday
+START = (2015, 7, 5, 10, 6, 17, 32, 249)
+STOP = (2015, 7, 5, 10, 6, 17, 32, 254)
+
+
+def obj_v1(obj_type, timestamp, payload, flags=2):
+    size = 32 + len(payload)
+    data = struct.pack("<4sHHLLLHHQ", b"LOBJ", 32, 1, size, obj_type, flags, 0, 0, timestamp) + payload
+    return data + b"\x00" * (size % 4)
+
+
+def obj_v2(obj_type, timestamp, payload, flags=2):
+    size = 32 + len(payload)
+    data = struct.pack("<4sHHLLLBxHQ", b"LOBJ", 32, 2, size, obj_type, flags, 0, 0, timestamp) + payload
+    return data + b"\x00" * (size % 4)
+
+
+def container(data, compress):
+    payload = zlib.compress(data, 6) if compress else data
+    size = 16 + 16 + len(payload)
+    out = struct.pack("<4sHHLL", b"LOBJ", 16, 1, size, 10)
+    out += struct.pack("<H6xL4x", 2 if compress else 0, len(data)) + payload
+    return out + b"\x00" * (size % 4)
+
+
+def blf(objects, chunks, compress, stop=STOP):
+    stream = b"".join(objects)
+    containers = []
+    offset = 0
+    for chunk in chunks + [len(stream)]:
+        containers.append(container(stream[offset:chunk], compress))
+        offset = chunk
+    body = b"".join(c for c in containers if c)
+    uncompressed = FILE_HEADER_SIZE + sum(32 + (len(stream[a:b])) for a, b in zip([0] + chunks, chunks + [len(stream)]))
+    header = struct.pack("<4sLBBBBBBBBQQLL8H8H", b"LOGG", FILE_HEADER_SIZE, 0, 0, 0, 0, 2, 6, 8, 1,
+                         FILE_HEADER_SIZE + len(body), uncompressed, len(objects), 0, *START, *stop)
+    return header.ljust(FILE_HEADER_SIZE, b"\x00") + body
+
+
+# Objects the writer produces for the messages in test_write_golden()
+written = [
+    # CAN_MESSAGE: channel 1, rx, standard 0x123
+    obj_v1(1, 0, struct.pack("<HBBL8s", 1, 0, 3, 0x123, bytes([0x11, 0x22, 0x33]))),
+    # CAN_MESSAGE: channel 2, tx, extended 0x18DAF110
+    obj_v1(1, 1_000_000, struct.pack("<HBBL8s", 2, 1, 8, 0x98DAF110, bytes(range(8)))),
+    # CAN_FD_MESSAGE: channel 1, 12 bytes with BRS
+    obj_v1(100, 2_500_000, struct.pack("<HBBLLBBB5x64s", 1, 0, 9, 0x7E0, 0, 0, 0x3, 12, bytes(range(12)))),
+    # CAN_DRIVER_ERROR: channel 1, TEC 128, REC 5
+    obj_v1(31, 3_000_000, struct.pack("<HBBL", 1, 128, 5, 0)),
+    # CAN_ERROR_EXT: channel 2, error frame
+    obj_v1(73, 4_000_000, struct.pack("<HHLBBBxLLH2x8s", 2, 0, 0, 0, 0, 0, 0, 0, 0, b"")),
+    # ETHERNET_FRAME_EX: channel 3, tx, 60 bytes
+    obj_v1(120, 5_000_000, struct.pack("<HHHHQLHHLL", 32, 0, 3, 0, 0, 0, 1, 60, 0, 0) + bytes(range(60))),
+]
+with open("golden.blf", "wb") as f:
+    f.write(blf(written, [], compress=False))
+
+# Object kinds the writer never produces, split across two compressed containers
+eth_payload = bytes([0xde, 0xad, 0xbe, 0xef])
+read_only = [
+    # CAN_MESSAGE2 with a 10 us timestamp
+    obj_v1(86, 1, struct.pack("<HBBL8sLBBH", 1, 0, 2, 0x100, bytes([1, 2]), 0, 0, 0, 0), flags=1),
+    # CAN_FD_MESSAGE_64 with a v2 header: channel 2, tx, extended, 12 bytes, BRS
+    obj_v2(101, 20_000, struct.pack("<BBBBLLLLLLLHBBL", 2, 9, 12, 0, 0x80080000, 0, 0x3000, 0, 0, 0, 0, 0, 1, 0, 0) + bytes(range(12))),
+    # APP_TEXT (65), not supported and skipped
+    obj_v1(65, 25_000, struct.pack("<LLLL", 0, 0, 5, 0) + b"hello"),
+    # ETHERNET_FRAME with a VLAN tag
+    obj_v1(71, 30_000, struct.pack("<6sH6sHHHHHQ", bytes([2, 0, 0, 0, 0, 1]), 3, bytes([0xff] * 6), 0, 0x0800, 0x8100, 5, 4, 0) + eth_payload),
+    # CAN_ERROR
+    obj_v1(2, 40_000, struct.pack("<HH", 1, 0)),
+]
+with open("golden_compressed.blf", "wb") as f:
+    # Split inside the CAN_FD_MESSAGE_64 object so it straddles both containers
+    f.write(blf(read_only, [60], compress=True))
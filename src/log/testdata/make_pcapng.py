@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+"""Generates golden.pcapng for the pcapng writer and reader tests.
+
+Written from the pcapng specification (draft-ietf-opsawg-pcapng) independently of the Rust
+code. Run from this directory: python3 make_pcapng.py
+"""
+import struct
+
+
+def pad4(data):
+    return data + b"\0" * (-len(data) % 4)
+
+
+def option(code, value):
+    return struct.pack("<HH", code, len(value)) + pad4(value)
+
+
+def block(block_type, body):
+    length = len(body) + 12
+    return struct.pack("<II", block_type, length) + body + struct.pack("<I", length)
+
+
+def shb():
+    body = struct.pack("<IHHq", 0x1A2B3C4D, 1, 0, -1)
+    body += option(4, b"libicsneo-rs") + option(0, b"")
+    return block(0x0A0D0D0A, body)
+
+
+def idb(linktype, name):
+    body = struct.pack("<HHI", linktype, 0, 0)
+    body += option(2, name) + option(9, bytes([9])) + option(0, b"")
+    return block(1, body)
+
+
+def epb(interface, ns, packet, flags=None):
+    body = struct.pack("<IIIII", interface, ns >> 32, ns & 0xFFFFFFFF, len(packet), len(packet))
+    body += pad4(packet)
+    if flags is not None:
+        body += option(2, struct.pack("<I", flags)) + option(0, b"")
+    return block(6, body)
+
+
+def can(can_id, data, flags=0, len8_dlc=0):
+    return (struct.pack(">IBBBB", can_id, len(data), flags, 0, len8_dlc) + data).ljust(16, b"\0")
+
+
+def canfd(can_id, data, flags):
+    return (struct.pack(">IBBBB", can_id, len(data), flags | 0x04, 0, 0) + data).ljust(72, b"\0")
+
+
+ORIGIN = 1_000_000
+INBOUND, OUTBOUND = 1, 2
+out = shb()
+out += idb(227, b"hscan")
+out += epb(0, ORIGIN, can(0x123, bytes([0x11, 0x22, 0x33])), INBOUND)
+out += idb(227, b"hscan2")
+out += epb(1, ORIGIN + 1_000_000, can(0x80000000 | 0x18DAF110, bytes(range(8))), OUTBOUND)
+out += epb(0, ORIGIN + 2_500_000, canfd(0x7E0, bytes(range(12)), 0x01), INBOUND)
+# TEC 128 (error passive) and REC 5
+out += epb(0, ORIGIN + 3_000_000, can(0x20000204, bytes([0, 0x20, 0, 0, 0, 0, 128, 5])))
+out += epb(1, ORIGIN + 4_000_000, can(0x20000080, bytes(8)), INBOUND)
+out += idb(1, b"ethernet")
+out += epb(2, ORIGIN + 5_000_000, bytes(range(60)), OUTBOUND)
+
+with open("golden.pcapng", "wb") as f:
+    f.write(out)
+
+
+# A big-endian section with microsecond timestamps as other tools may write it. The CAN FD
+# frame lacks CANFD_FDF like older captures, packets on the DLT_USER0 interface and the
+# custom block are skipped.
+def be_option(code, value):
+    return struct.pack(">HH", code, len(value)) + pad4(value)
+
+
+def be_block(block_type, body):
+    length = len(body) + 12
+    return struct.pack(">II", block_type, length) + body + struct.pack(">I", length)
+
+
+out = be_block(0x0A0D0D0A, struct.pack(">IHHq", 0x1A2B3C4D, 1, 0, -1))
+out += be_block(1, struct.pack(">HHI", 227, 0, 0) + be_option(2, b"vcan0") + be_option(0, b""))
+out += be_block(1, struct.pack(">HHI", 147, 0, 0))
+out += be_block(0x0BAD, b"\1\2\3\4")
+body = struct.pack(">IIIII", 0, 0, 1_500_000, 16, 16) + can(0x7FF, bytes([0xAA]))
+out += be_block(6, body + be_option(2, struct.pack(">I", OUTBOUND)) + be_option(0, b""))
+out += be_block(6, struct.pack(">IIIII", 1, 0, 1_600_000, 4, 4) + b"skip")
+frame = (struct.pack(">IBBBB", 0x100, 16, 0x02, 0, 0) + bytes(range(16))).ljust(72, b"\0")
+out += be_block(6, struct.pack(">IIIII", 0, 0, 2_000_000, 72, 72) + frame)
+
+with open("big_endian.pcapng", "wb") as f:
+    f.write(out)
@@ -0,0 +1,457 @@
+//! One chronologically ordered stream from several devices.
+//!
+//! [Merger] polls several receivers, converts the timestamps of each with its own [Timebase]
+//! to a common time and releases the messages in time order, tagged with the serial of the
+//! device they came from. Devices deliver in batches, so messages are held back for a
+//! reordering window to let slower devices catch up:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::merge::Merger;
+//!
+//! let devices = icsneo::native::find_all_devices().unwrap();
+//! for device in &devices {
+//!     icsneo::native::open_device(device).unwrap();
+//!     icsneo::native::go_online(device).unwrap();
+//!     icsneo::native::enable_message_polling(device);
+//! }
+//!
+//! let mut merger = Merger::from_devices(devices, Duration::from_millis(50));
+//! loop {
+//!     for merged in merger.poll(Duration::from_millis(100)).unwrap() {
+//!         println!("{:?} {} {:?}", merged.time, merged.serial, merged.message);
+//!     }
+//! }
+//! ```
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, SystemTime};
+
+use crate::bus::Receive;
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::timebase::Timebase;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// How long [poll](Merger::poll) waits between rounds over the sources.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How the timestamps of different devices are brought to a common time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Alignment {
+    /// By each device's clock, [to_system_time](Timebase::to_system_time). Right if the
+    /// device clocks are synchronized.
+    #[default]
+    Device,
+    /// By the host clock, [to_host_time](Timebase::to_host_time) with every received
+    /// message observed at the time it was received. Right for unsynchronized devices, but
+    /// receive latency makes it less precise.
+    Host,
+}
+
+/// A message and where and when it was received.
+#[derive(Debug, Clone)]
+pub struct MergedMessage {
+    /// Serial of the device the message came from.
+    pub serial: String,
+    /// Timestamp in the common time.
+    pub time: SystemTime,
+    pub message: Message,
+}
+
+struct Source<R> {
+    serial: String,
+    receiver: R,
+    timebase: Timebase,
+}
+
+/// A held back message. Orders by time, then by reception.
+struct Pending {
+    time: SystemTime,
+    sequence: u64,
+    received: Duration,
+    source: usize,
+    message: Message,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.time, self.sequence).cmp(&(other.time, other.sequence))
+    }
+}
+
+/// Merges the messages of several receivers in timestamp order.
+///
+/// A message is released once a message at least the window newer was received, or once it
+/// was held for the window. A message older than one already released is released right
+/// away and counted as [late](Self::late); widen the window if that happens.
+pub struct Merger<R, C: Clock = SystemClock> {
+    clock: C,
+    /// Host time at a time of `clock`, to observe timestamps with.
+    anchor: (SystemTime, Duration),
+    sources: Vec<Source<R>>,
+    alignment: Alignment,
+    window: Duration,
+    pending: BinaryHeap<Reverse<Pending>>,
+    sequence: u64,
+    newest: Option<SystemTime>,
+    released: Option<SystemTime>,
+    late: u64,
+}
+
+impl Merger<NeoDevice> {
+    /// Merges open devices that poll for messages, tagged with their serials.
+    ///
+    /// Message timestamps are nanoseconds whatever the timestamp resolution of each device,
+    /// so devices with different resolutions merge without further setup.
+    pub fn from_devices(devices: Vec<NeoDevice>, window: Duration) -> Self {
+        let mut merger = Self::new(window);
+        for device in devices {
+            merger.add_source(device.serial(), device, Timebase::new());
+        }
+        merger
+    }
+}
+
+impl<R: Receive> Merger<R> {
+    pub fn new(window: Duration) -> Self {
+        Self::with_clock(window, SystemClock::new())
+    }
+}
+
+impl<R: Receive, C: Clock> Merger<R, C> {
+    pub fn with_clock(window: Duration, clock: C) -> Self {
+        Self {
+            anchor: (SystemTime::now(), clock.now()),
+            clock,
+            sources: Vec::new(),
+            alignment: Alignment::default(),
+            window,
+            pending: BinaryHeap::new(),
+            sequence: 0,
+            newest: None,
+            released: None,
+            late: 0,
+        }
+    }
+
+    /// Adds a receiver whose messages are tagged with `serial` and timestamped by `timebase`.
+    pub fn add_source(&mut self, serial: impl Into<String>, receiver: R, timebase: Timebase) {
+        self.sources.push(Source {
+            serial: serial.into(),
+            receiver,
+            timebase,
+        });
+    }
+
+    /// Serials of the sources, in the order they were added.
+    pub fn serials(&self) -> Vec<&str> {
+        self.sources
+            .iter()
+            .map(|source| source.serial.as_str())
+            .collect()
+    }
+
+    pub fn source_mut(&mut self, serial: &str) -> Option<&mut R> {
+        self.sources
+            .iter_mut()
+            .find(|source| source.serial == serial)
+            .map(|source| &mut source.receiver)
+    }
+
+    /// Timebase of a source, which holds the drift estimates with [Alignment::Host].
+    pub fn timebase(&self, serial: &str) -> Option<&Timebase> {
+        self.sources
+            .iter()
+            .find(|source| source.serial == serial)
+            .map(|source| &source.timebase)
+    }
+
+    /// Sources and their receivers. Held back messages are dropped, [flush](Self::flush)
+    /// first to keep them.
+    pub fn into_sources(self) -> Vec<(String, R)> {
+        self.sources
+            .into_iter()
+            .map(|source| (source.serial, source.receiver))
+            .collect()
+    }
+
+    pub fn set_alignment(&mut self, alignment: Alignment) {
+        self.alignment = alignment;
+    }
+
+    pub fn alignment(&self) -> Alignment {
+        self.alignment
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Number of messages released after a newer message.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    /// Number of held back messages.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Polls every source until messages can be released or `timeout` passes. Returns the
+    /// released messages in time order, or an empty list on timeout.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<MergedMessage>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            for index in 0..self.sources.len() {
+                let messages = self.sources[index].receiver.receive(Duration::ZERO)?;
+                self.push(index, messages);
+            }
+            let released = self.release(false);
+            let now = self.clock.now();
+            if !released.is_empty() || now >= deadline {
+                return Ok(released);
+            }
+            self.clock.sleep_until((now + POLL_INTERVAL).min(deadline));
+        }
+    }
+
+    /// Releases every held back message in time order.
+    pub fn flush(&mut self) -> Vec<MergedMessage> {
+        self.release(true)
+    }
+
+    fn push(&mut self, index: usize, messages: Vec<Message>) {
+        let received = self.clock.now();
+        let host = self.anchor.0 + received.saturating_sub(self.anchor.1);
+        let timebase = &mut self.sources[index].timebase;
+        for message in messages {
+            let time = match self.alignment {
+                Alignment::Device => timebase.to_system_time(message.timestamp()),
+                Alignment::Host => {
+                    timebase.observe(message.timestamp(), host);
+                    timebase.to_host_time(message.timestamp()).unwrap_or(host)
+                }
+            };
+            self.newest = self.newest.max(Some(time));
+            self.pending.push(Reverse(Pending {
+                time,
+                sequence: self.sequence,
+                received,
+                source: index,
+                message,
+            }));
+            self.sequence += 1;
+        }
+    }
+
+    fn release(&mut self, all: bool) -> Vec<MergedMessage> {
+        let now = self.clock.now();
+        let mut released = Vec::new();
+        while let Some(Reverse(pending)) = self.pending.peek() {
+            let settled = self.newest.is_some_and(|newest| {
+                newest.duration_since(pending.time).unwrap_or_default() >= self.window
+            });
+            if !all && !settled && now.saturating_sub(pending.received) < self.window {
+                break;
+            }
+            let Reverse(pending) = self.pending.pop().unwrap();
+            if self.released.is_some_and(|last| pending.time < last) {
+                self.late += 1;
+            }
+            self.released = self.released.max(Some(pending.time));
+            released.push(MergedMessage {
+                serial: self.sources[pending.source].serial.clone(),
+                time: pending.time,
+                message: pending.message,
+            });
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::network::NetworkId;
+
+    /// Returns one scripted batch per receive.
+    #[derive(Default)]
+    struct Script(VecDeque<Vec<Message>>);
+
+    impl Receive for Script {
+        fn receive(&mut self, _timeout: Duration) -> Result<Vec<Message>> {
+            Ok(self.0.pop_front().unwrap_or_default())
+        }
+    }
+
+    fn can(arbid: u32, micros: u64) -> Message {
+        let mut m = CanMessage::new(NetworkId::HSCAN.0, arbid, &[]);
+        m.set_timestamp(micros * 1000);
+        Message::Can(m)
+    }
+
+    fn arbids(merged: &[MergedMessage]) -> Vec<(&str, u32)> {
+        merged
+            .iter()
+            .map(|merged| match &merged.message {
+                Message::Can(m) => (merged.serial.as_str(), m.arbid),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn timebase(epoch_seconds: u64) -> Timebase {
+        let mut timebase = Timebase::new();
+        timebase.set_epoch(SystemTime::UNIX_EPOCH + Duration::from_secs(epoch_seconds));
+        timebase
+    }
+
+    #[test]
+    fn test_merges_in_time_order() {
+        let clock = ManualClock::new();
+        let mut merger = Merger::with_clock(Duration::from_millis(10), clock.clone());
+        // The second device's clock counts from a second later, so its timestamps are a
+        // second smaller for the same time.
+        merger.add_source(
+            "V10001",
+            Script(VecDeque::from([
+                vec![can(1, 1_001_000), can(3, 1_003_000), can(5, 1_005_000)],
+                vec![can(7, 1_030_000)],
+            ])),
+            timebase(100),
+        );
+        merger.add_source(
+            "V20002",
+            Script(VecDeque::from([
+                vec![can(2, 2_000), can(4, 4_000)],
+                vec![can(6, 20_000)],
+            ])),
+            timebase(101),
+        );
+        assert_eq!(merger.serials(), ["V10001", "V20002"]);
+
+        // Nothing received in the first round is the window older than the newest message.
+        // The second round receives newer messages that settle all but the newest.
+        let merged = merger.poll(Duration::from_millis(100)).unwrap();
+        assert_eq!(
+            arbids(&merged),
+            [
+                ("V10001", 1),
+                ("V20002", 2),
+                ("V10001", 3),
+                ("V20002", 4),
+                ("V10001", 5),
+                ("V20002", 6)
+            ]
+        );
+        assert_eq!(
+            merged[0].time,
+            SystemTime::UNIX_EPOCH + Duration::new(101, 1_000_000)
+        );
+        assert_eq!(merged[5].time, merged[0].time + Duration::from_millis(19));
+        assert_eq!((merger.pending(), merger.late()), (1, 0));
+        assert_eq!(clock.now(), Duration::from_millis(1));
+
+        // Without newer messages the last one is released once it was held for the window.
+        assert_eq!(
+            arbids(&merger.poll(Duration::from_millis(100)).unwrap()),
+            [("V10001", 7)]
+        );
+        assert_eq!(clock.now(), Duration::from_millis(11));
+        assert_eq!(merger.pending(), 0);
+        assert!(merger.flush().is_empty());
+    }
+
+    #[test]
+    fn test_window_bounds_reordering() {
+        let clock = ManualClock::new();
+        let mut merger = Merger::with_clock(Duration::from_millis(10), clock.clone());
+        merger.add_source(
+            "V10001",
+            Script(VecDeque::from([vec![can(1, 5_000), can(3, 50_000)]])),
+            timebase(0),
+        );
+        // Messages more than the window older than received ones are late.
+        merger.add_source(
+            "V20002",
+            Script(VecDeque::from([
+                vec![],
+                vec![can(2, 1_000), can(4, 45_000)],
+            ])),
+            timebase(0),
+        );
+        assert_eq!(
+            arbids(&merger.poll(Duration::ZERO).unwrap()),
+            [("V10001", 1)]
+        );
+        assert_eq!(
+            arbids(&merger.poll(Duration::ZERO).unwrap()),
+            [("V20002", 2)]
+        );
+        assert_eq!(merger.late(), 1);
+        assert_eq!(arbids(&merger.flush()), [("V20002", 4), ("V10001", 3)]);
+        assert_eq!(merger.late(), 1);
+    }
+
+    #[test]
+    fn test_host_alignment() {
+        let clock = ManualClock::new();
+        let mut merger = Merger::with_clock(Duration::from_millis(10), clock.clone());
+        merger.set_alignment(Alignment::Host);
+        // Both device clocks were never set and started at different times.
+        merger.add_source(
+            "V10001",
+            Script(
+                (0..20)
+                    .map(|i| vec![can(i, 5_000_000 + i as u64 * 1000)])
+                    .collect(),
+            ),
+            timebase(0),
+        );
+        merger.add_source(
+            "V20002",
+            Script(
+                (0..20)
+                    .map(|i| vec![can(100 + i, 9_000_500 + i as u64 * 1000)])
+                    .collect(),
+            ),
+            timebase(0),
+        );
+        let mut merged = Vec::new();
+        // One batch per source and millisecond.
+        while merged.len() < 40 {
+            merged.extend(merger.poll(Duration::ZERO).unwrap());
+            clock.advance(Duration::from_millis(1));
+        }
+        let order = arbids(&merged);
+        for (i, pair) in order.chunks(2).enumerate() {
+            assert_eq!(pair, [("V10001", i as u32), ("V20002", 100 + i as u32)]);
+        }
+        let drift = merger.timebase("V20002").unwrap().drift().unwrap();
+        assert!(drift.abs() < 1e-3, "{drift}");
+    }
+}
@@ -0,0 +1,879 @@
+//! Owned message values.
+//!
+//! The `data` pointer of a message returned by [get_messages](crate::native::get_messages) is
+//! only valid until the next call on that device. The types here keep a copy of the payload
+//! next to the message so it can be stored, written to a log, read back and transmitted later.
+use libicsneo_sys::*;
+
+use crate::native::*;
+
+/// `ICSNEO_NETWORK_TYPE_CAN` from `network.h`.
+pub const NETWORK_TYPE_CAN: neonettype_t = 2;
+/// `ICSNEO_NETWORK_TYPE_ETHERNET` from `network.h`.
+pub const NETWORK_TYPE_ETHERNET: neonettype_t = 6;
+/// `ICSNEO_NETWORK_TYPE_LSFTCAN` from `network.h`.
+pub const NETWORK_TYPE_LSFTCAN: neonettype_t = 7;
+/// `ICSNEO_NETWORK_TYPE_SWCAN` from `network.h`.
+pub const NETWORK_TYPE_SWCAN: neonettype_t = 8;
+
+/// `ICSNEO_MESSAGE_TYPE_FRAME` from `message.h`.
+pub const MESSAGE_TYPE_FRAME: neomessagetype_t = 0x0;
+/// `ICSNEO_MESSAGE_TYPE_CAN_ERROR_COUNT` from `message.h`.
+pub const MESSAGE_TYPE_CAN_ERROR_COUNT: neomessagetype_t = 0x100;
+
+/// Payload lengths for each CAN FD DLC.
+const DLC_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Converts a CAN (FD) DLC into its payload length.
+pub fn dlc_to_len(dlc: u8) -> usize {
+    DLC_LENGTHS[usize::from(dlc.min(15))]
+}
+
+/// Converts a payload length into the smallest CAN (FD) DLC that can carry it.
+pub fn len_to_dlc(len: usize) -> u8 {
+    DLC_LENGTHS.iter().position(|&l| l >= len).unwrap_or(15) as u8
+}
+
+/// Bit positions inside `neomessage_statusbitfield_t` as `(word, mask)`, see `neomessage.h`.
+pub(crate) mod status {
+    use libicsneo_sys::neomessage_statusbitfield_t;
+
+    pub const TRANSMIT: (usize, u32) = (0, 1 << 1);
+    pub const EXTENDED: (usize, u32) = (0, 1 << 2);
+    pub const REMOTE: (usize, u32) = (0, 1 << 3);
+    pub const ERROR_FRAME: (usize, u32) = (1, 1 << 17);
+    pub const CANFD_ESI: (usize, u32) = (2, 1 << 0);
+    pub const CANFD_FDF: (usize, u32) = (2, 1 << 3);
+    pub const CANFD_BRS: (usize, u32) = (2, 1 << 4);
+
+    pub fn get(status: neomessage_statusbitfield_t, (word, mask): (usize, u32)) -> bool {
+        unsafe { status.statusBitfield[word] & mask != 0 }
+    }
+
+    /// Returns a copy of `status` with the bit updated. Status fields live in packed structs,
+    /// so they can't be borrowed mutably.
+    pub fn set(
+        status: neomessage_statusbitfield_t,
+        (word, mask): (usize, u32),
+        value: bool,
+    ) -> neomessage_statusbitfield_t {
+        let mut words = unsafe { status.statusBitfield };
+        if value {
+            words[word] |= mask;
+        } else {
+            words[word] &= !mask;
+        }
+        neomessage_statusbitfield_t {
+            statusBitfield: words,
+        }
+    }
+}
+
+/// Typed view of the `neomessage_statusbitfield_t` every message starts with.
+///
+/// Each flag of `neomessage.h` has an accessor and a setter named after it. Bits shared by
+/// two flags have an accessor for each name, [Debug] lists set bits by their first name and
+/// bits without a name by position.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MessageStatus([u32; 4]);
+
+macro_rules! status_flags {
+    ($($name:ident, $setter:ident: $word:literal, $bit:literal;)*) => {
+        impl MessageStatus {
+            $(
+                pub fn $name(&self) -> bool {
+                    self.get($word, $bit)
+                }
+
+                pub fn $setter(&mut self, value: bool) {
+                    self.set($word, $bit, value)
+                }
+            )*
+        }
+
+        /// Name, word and bit of each flag in layout order. Shared bits appear once.
+        const STATUS_FLAGS: &[(&str, usize, u32)] = &[$((stringify!($name), $word, $bit)),*];
+    };
+}
+
+status_flags! {
+    global_error, set_global_error: 0, 0;
+    transmit_message, set_transmit_message: 0, 1;
+    extended_frame, set_extended_frame: 0, 2;
+    remote_frame, set_remote_frame: 0, 3;
+    crc_error, set_crc_error: 0, 4;
+    can_error_passive, set_can_error_passive: 0, 5;
+    incomplete_frame, set_incomplete_frame: 0, 6;
+    lost_arbitration, set_lost_arbitration: 0, 7;
+    undefined_error, set_undefined_error: 0, 8;
+    can_bus_off, set_can_bus_off: 0, 9;
+    can_bus_recovered, set_can_bus_recovered: 0, 10;
+    can_bus_shorted_plus, set_can_bus_shorted_plus: 0, 11;
+    can_bus_shorted_ground, set_can_bus_shorted_ground: 0, 12;
+    checksum_error, set_checksum_error: 0, 13;
+    bad_message_bit_time_error, set_bad_message_bit_time_error: 0, 14;
+    ifr_data, set_ifr_data: 0, 15;
+    hardware_comm_error, set_hardware_comm_error: 0, 16;
+    expected_length_error, set_expected_length_error: 0, 17;
+    incoming_no_match, set_incoming_no_match: 0, 18;
+    status_break, set_status_break: 0, 19;
+    avsi_rec_overflow, set_avsi_rec_overflow: 0, 20;
+    test_trigger, set_test_trigger: 0, 21;
+    audio_comment, set_audio_comment: 0, 22;
+    gps_data, set_gps_data: 0, 23;
+    analog_digital_input, set_analog_digital_input: 0, 24;
+    text_comment, set_text_comment: 0, 25;
+    network_message_type, set_network_message_type: 0, 26;
+    vsi_tx_underrun, set_vsi_tx_underrun: 0, 27;
+    vsi_ifr_crc_bit, set_vsi_ifr_crc_bit: 0, 28;
+    init_message, set_init_message: 0, 29;
+    flexray_second_startup_frame, set_flexray_second_startup_frame: 0, 30;
+    extended, set_extended: 0, 31;
+    has_value, set_has_value: 1, 0;
+    value_is_boolean, set_value_is_boolean: 1, 1;
+    high_voltage, set_high_voltage: 1, 2;
+    long_message, set_long_message: 1, 3;
+    global_change, set_global_change: 1, 16;
+    error_frame, set_error_frame: 1, 17;
+    end_of_long_message, set_end_of_long_message: 1, 20;
+    lin_error_rx_break_not_zero, set_lin_error_rx_break_not_zero: 1, 21;
+    lin_error_rx_break_too_short, set_lin_error_rx_break_too_short: 1, 22;
+    lin_error_rx_sync_not_55, set_lin_error_rx_sync_not_55: 1, 23;
+    lin_error_rx_data_greater_eight, set_lin_error_rx_data_greater_eight: 1, 24;
+    lin_error_tx_rx_mismatch, set_lin_error_tx_rx_mismatch: 1, 25;
+    lin_error_message_id_parity, set_lin_error_message_id_parity: 1, 26;
+    lin_sync_frame_error, set_lin_sync_frame_error: 1, 27;
+    lin_id_frame_error, set_lin_id_frame_error: 1, 28;
+    lin_slave_byte_error, set_lin_slave_byte_error: 1, 29;
+    rx_timeout_error, set_rx_timeout_error: 1, 30;
+    lin_no_slave_data, set_lin_no_slave_data: 1, 31;
+    canfd_esi, set_canfd_esi: 2, 0;
+    canfd_ide, set_canfd_ide: 2, 1;
+    canfd_rtr, set_canfd_rtr: 2, 2;
+    canfd_fdf, set_canfd_fdf: 2, 3;
+    canfd_brs, set_canfd_brs: 2, 4;
+}
+
+impl MessageStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The raw `statusBitfield` words.
+    pub fn words(&self) -> [u32; 4] {
+        self.0
+    }
+
+    /// Shares its bit with [can_error_passive](Self::can_error_passive).
+    pub fn header_crc_error(&self) -> bool {
+        self.can_error_passive()
+    }
+
+    pub fn set_header_crc_error(&mut self, value: bool) {
+        self.set_can_error_passive(value)
+    }
+
+    /// Shares its bit with [flexray_second_startup_frame](Self::flexray_second_startup_frame).
+    pub fn high_speed_message(&self) -> bool {
+        self.flexray_second_startup_frame()
+    }
+
+    pub fn set_high_speed_message(&mut self, value: bool) {
+        self.set_flexray_second_startup_frame(value)
+    }
+
+    /// Shares its bit with [canfd_esi](Self::canfd_esi).
+    pub fn lin_just_break_sync(&self) -> bool {
+        self.canfd_esi()
+    }
+
+    pub fn set_lin_just_break_sync(&mut self, value: bool) {
+        self.set_canfd_esi(value)
+    }
+
+    /// Shares its bit with [canfd_ide](Self::canfd_ide).
+    pub fn lin_slave_data_too_short(&self) -> bool {
+        self.canfd_ide()
+    }
+
+    pub fn set_lin_slave_data_too_short(&mut self, value: bool) {
+        self.set_canfd_ide(value)
+    }
+
+    /// Shares its bit with [canfd_rtr](Self::canfd_rtr).
+    pub fn lin_only_update_slave_table_once(&self) -> bool {
+        self.canfd_rtr()
+    }
+
+    pub fn set_lin_only_update_slave_table_once(&mut self, value: bool) {
+        self.set_canfd_rtr(value)
+    }
+
+    fn get(&self, word: usize, bit: u32) -> bool {
+        self.0[word] >> bit & 1 == 1
+    }
+
+    fn set(&mut self, word: usize, bit: u32, value: bool) {
+        if value {
+            self.0[word] |= 1 << bit;
+        } else {
+            self.0[word] &= !(1 << bit);
+        }
+    }
+}
+
+impl From<[u32; 4]> for MessageStatus {
+    fn from(words: [u32; 4]) -> Self {
+        Self(words)
+    }
+}
+
+impl From<neomessage_statusbitfield_t> for MessageStatus {
+    fn from(status: neomessage_statusbitfield_t) -> Self {
+        Self(unsafe { status.statusBitfield })
+    }
+}
+
+impl From<MessageStatus> for neomessage_statusbitfield_t {
+    fn from(status: MessageStatus) -> Self {
+        Self {
+            statusBitfield: status.0,
+        }
+    }
+}
+
+/// Lists the set flags, like `MessageStatus { transmit_message, extended_frame }`. Set bits
+/// without a name show as `word:bit`.
+impl std::fmt::Debug for MessageStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageStatus ")?;
+        let mut set = f.debug_set();
+        for word in 0..4 {
+            for bit in 0..32 {
+                if !self.get(word, bit) {
+                    continue;
+                }
+                match STATUS_FLAGS
+                    .iter()
+                    .find(|&&(_, flag_word, flag_bit)| (flag_word, flag_bit) == (word, bit))
+                {
+                    Some((name, _, _)) => set.entry(&format_args!("{name}")),
+                    None => set.entry(&format_args!("{word}:{bit}")),
+                };
+            }
+        }
+        set.finish()
+    }
+}
+
+macro_rules! define_owned_message {
+    ($name:ident, $message:ident) => {
+        impl $name {
+            /// Copies the payload of `message` so the result no longer borrows API memory.
+            ///
+            /// # Safety
+            ///
+            /// The payload of `message` must be readable, see [data](NeoMessageFrame::data).
+            pub unsafe fn from_neo(message: &$message) -> Self {
+                let mut owned = Self {
+                    message: message.clone(),
+                    data: message.data().to_vec(),
+                };
+                owned.sync();
+                owned
+            }
+
+            pub fn data(&self) -> &[u8] {
+                &self.data
+            }
+
+            pub fn status(&self) -> MessageStatus {
+                self.message.status.into()
+            }
+
+            pub fn set_status(&mut self, status: MessageStatus) {
+                self.message.status = status.into();
+            }
+
+            pub fn set_data(&mut self, data: &[u8]) {
+                self.data = data.to_vec();
+                self.sync();
+            }
+
+            pub fn set_netid(&mut self, netid: neonetid_t) {
+                self.message.netid = netid;
+            }
+
+            pub fn set_timestamp(&mut self, timestamp: u64) {
+                self.message.timestamp = timestamp;
+            }
+
+            pub fn set_description(&mut self, description: u16) {
+                self.message.description = description;
+            }
+
+            /// Returns a [NeoMessage](NeoMessage) suitable for [transmit](crate::native::transmit).
+            /// Its `data` pointer borrows from `self`, so `self` must outlive the transmit call.
+            pub fn to_neo_message(&self) -> NeoMessage {
+                let mut message = self.message.clone();
+                message.data = self.data.as_ptr();
+                message.length = self.data.len() as _;
+                NeoMessage::from(message)
+            }
+
+            fn sync(&mut self) {
+                self.message.data = self.data.as_ptr();
+                self.message.length = self.data.len() as _;
+            }
+        }
+
+        impl Clone for $name {
+            fn clone(&self) -> Self {
+                let mut owned = Self {
+                    message: self.message.clone(),
+                    data: self.data.clone(),
+                };
+                owned.sync();
+                owned
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    .field("message", &self.message)
+                    .field("data", &self.data)
+                    .finish()
+            }
+        }
+
+        impl std::ops::Deref for $name {
+            type Target = $message;
+
+            fn deref(&self) -> &Self::Target {
+                &self.message
+            }
+        }
+    };
+}
+
+/// A [NeoMessageCan](NeoMessageCan) that owns its payload.
+pub struct CanMessage {
+    message: NeoMessageCan,
+    data: Vec<u8>,
+}
+
+define_owned_message!(CanMessage, NeoMessageCan);
+
+impl CanMessage {
+    pub fn new(netid: neonetid_t, arbid: u32, data: &[u8]) -> Self {
+        let mut message = NeoMessageCan::new();
+        message.netid = netid;
+        message.arbid = arbid;
+        message.type_ = NETWORK_TYPE_CAN;
+        let mut owned = Self {
+            message,
+            data: data.to_vec(),
+        };
+        owned.sync();
+        owned
+    }
+
+    pub fn set_arbid(&mut self, arbid: u32) {
+        self.message.arbid = arbid;
+    }
+
+    /// DLC sent on the bus, which can differ from the payload length for classic frames with
+    /// a DLC above 8.
+    pub fn set_dlc_on_wire(&mut self, dlc: u8) {
+        self.message.dlcOnWire = dlc;
+    }
+
+    pub fn is_transmit(&self) -> bool {
+        status::get(self.message.status, status::TRANSMIT)
+    }
+
+    pub fn set_transmit(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::TRANSMIT, value);
+    }
+
+    pub fn is_extended(&self) -> bool {
+        status::get(self.message.status, status::EXTENDED)
+    }
+
+    pub fn set_extended(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::EXTENDED, value);
+    }
+
+    pub fn is_remote(&self) -> bool {
+        status::get(self.message.status, status::REMOTE)
+    }
+
+    pub fn set_remote(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::REMOTE, value);
+    }
+
+    pub fn is_error_frame(&self) -> bool {
+        status::get(self.message.status, status::ERROR_FRAME)
+    }
+
+    pub fn set_error_frame(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::ERROR_FRAME, value);
+    }
+
+    pub fn is_fd(&self) -> bool {
+        status::get(self.message.status, status::CANFD_FDF)
+    }
+
+    pub fn set_fd(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::CANFD_FDF, value);
+    }
+
+    pub fn is_brs(&self) -> bool {
+        status::get(self.message.status, status::CANFD_BRS)
+    }
+
+    pub fn set_brs(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::CANFD_BRS, value);
+    }
+
+    pub fn is_esi(&self) -> bool {
+        status::get(self.message.status, status::CANFD_ESI)
+    }
+
+    pub fn set_esi(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::CANFD_ESI, value);
+    }
+}
+
+/// A [NeoMessageEth](NeoMessageEth) that owns its payload.
+pub struct EthMessage {
+    message: NeoMessageEth,
+    data: Vec<u8>,
+}
+
+define_owned_message!(EthMessage, NeoMessageEth);
+
+impl EthMessage {
+    pub fn new(netid: neonetid_t, data: &[u8]) -> Self {
+        let mut message = NeoMessageEth::new();
+        message.netid = netid;
+        message.type_ = NETWORK_TYPE_ETHERNET;
+        let mut owned = Self {
+            message,
+            data: data.to_vec(),
+        };
+        owned.sync();
+        owned
+    }
+
+    pub fn is_transmit(&self) -> bool {
+        status::get(self.message.status, status::TRANSMIT)
+    }
+
+    pub fn set_transmit(&mut self, value: bool) {
+        self.message.status = status::set(self.message.status, status::TRANSMIT, value);
+    }
+}
+
+/// A received or constructed message of one of the kinds this crate understands.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Can(CanMessage),
+    CanError(NeoMessageCanError),
+    Eth(EthMessage),
+}
+
+impl Message {
+    /// Classifies and copies a message returned by [get_messages](crate::native::get_messages).
+    /// Returns `None` for message types that aren't CAN, CAN error counts or Ethernet.
+    ///
+    /// # Safety
+    ///
+    /// The payload of `message` must be readable, see [data](NeoMessageFrame::data).
+    pub unsafe fn from_neo(message: &NeoMessage) -> Option<Self> {
+        let frame = NeoMessageFrame::from(message.clone());
+        match (frame.messageType, frame.type_) {
+            (MESSAGE_TYPE_CAN_ERROR_COUNT, _) => {
+                Some(Self::CanError(NeoMessageCanError::from(message.clone())))
+            }
+            (MESSAGE_TYPE_FRAME, NETWORK_TYPE_CAN | NETWORK_TYPE_LSFTCAN | NETWORK_TYPE_SWCAN) => {
+                Some(Self::Can(CanMessage::from_neo(&NeoMessageCan::from(
+                    message.clone(),
+                ))))
+            }
+            (MESSAGE_TYPE_FRAME, NETWORK_TYPE_ETHERNET) => Some(Self::Eth(EthMessage::from_neo(
+                &NeoMessageEth::from(message.clone()),
+            ))),
+            _ => None,
+        }
+    }
+
+    pub fn netid(&self) -> neonetid_t {
+        match self {
+            Self::Can(m) => m.netid,
+            Self::CanError(m) => m.netid,
+            Self::Eth(m) => m.netid,
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Self::Can(m) => m.timestamp,
+            Self::CanError(m) => m.timestamp,
+            Self::Eth(m) => m.timestamp,
+        }
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        match self {
+            Self::Can(m) => m.set_timestamp(timestamp),
+            Self::CanError(m) => m.timestamp = timestamp,
+            Self::Eth(m) => m.set_timestamp(timestamp),
+        }
+    }
+
+    pub fn status(&self) -> MessageStatus {
+        match self {
+            Self::Can(m) => m.status(),
+            Self::CanError(m) => m.status.into(),
+            Self::Eth(m) => m.status(),
+        }
+    }
+
+    pub fn set_status(&mut self, status: MessageStatus) {
+        match self {
+            Self::Can(m) => m.set_status(status),
+            Self::CanError(m) => m.status = status.into(),
+            Self::Eth(m) => m.set_status(status),
+        }
+    }
+
+    /// Whether this is the receipt of a transmitted message. Error counts never are.
+    pub fn is_transmit(&self) -> bool {
+        match self {
+            Self::Can(m) => m.is_transmit(),
+            Self::CanError(_) => false,
+            Self::Eth(m) => m.is_transmit(),
+        }
+    }
+
+    /// Tag of a transmitted message, which its receipt carries too. 0 if untagged, error
+    /// counts have none.
+    pub fn description(&self) -> u16 {
+        match self {
+            Self::Can(m) => m.description,
+            Self::CanError(_) => 0,
+            Self::Eth(m) => m.description,
+        }
+    }
+
+    /// Sets the tag of a message to transmit. Ignored for error counts.
+    pub fn set_description(&mut self, description: u16) {
+        match self {
+            Self::Can(m) => m.set_description(description),
+            Self::CanError(_) => {}
+            Self::Eth(m) => m.set_description(description),
+        }
+    }
+
+    /// See [CanMessage::to_neo_message](CanMessage::to_neo_message).
+    pub fn to_neo_message(&self) -> NeoMessage {
+        match self {
+            Self::Can(m) => m.to_neo_message(),
+            Self::CanError(m) => NeoMessage::from(m.clone()),
+            Self::Eth(m) => m.to_neo_message(),
+        }
+    }
+}
+
+impl From<CanMessage> for Message {
+    fn from(message: CanMessage) -> Self {
+        Self::Can(message)
+    }
+}
+
+impl From<EthMessage> for Message {
+    fn from(message: EthMessage) -> Self {
+        Self::Eth(message)
+    }
+}
+
+impl From<NeoMessageCanError> for Message {
+    fn from(message: NeoMessageCanError) -> Self {
+        Self::CanError(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dlc_conversion() {
+        assert_eq!(dlc_to_len(8), 8);
+        assert_eq!(dlc_to_len(9), 12);
+        assert_eq!(dlc_to_len(15), 64);
+        assert_eq!(dlc_to_len(200), 64);
+        assert_eq!(len_to_dlc(0), 0);
+        assert_eq!(len_to_dlc(8), 8);
+        assert_eq!(len_to_dlc(9), 9);
+        assert_eq!(len_to_dlc(13), 10);
+        assert_eq!(len_to_dlc(64), 15);
+    }
+
+    #[test]
+    fn test_can_message_owns_data() {
+        let mut message = CanMessage::new(1, 0x123, &[1, 2, 3]);
+        message.set_extended(true);
+        message.set_fd(true);
+        let mut copy = message.clone();
+        drop(message);
+        assert_eq!(copy.data(), &[1, 2, 3]);
+        assert_eq!(unsafe { NeoMessageCan::data(&copy) }, &[1, 2, 3]);
+        assert!(copy.is_extended());
+        assert!(copy.is_fd());
+        assert!(!copy.is_brs());
+
+        copy.set_netid(2);
+        copy.set_arbid(0x456);
+        copy.set_dlc_on_wire(3);
+        copy.set_timestamp(1000);
+        copy.set_description(7);
+        let neo = NeoMessageCan::from(copy.to_neo_message());
+        assert_eq!(
+            ({ neo.netid }, { neo.arbid }, neo.dlcOnWire, {
+                neo.timestamp
+            }),
+            (2, 0x456, 3, 1000)
+        );
+        assert_eq!({ neo.description }, 7);
+        assert_eq!(unsafe { neo.data() }, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_message_from_neo() {
+        // Every message below is built from owned data that outlives the conversion.
+        fn from_neo(message: &NeoMessage) -> Option<Message> {
+            unsafe { Message::from_neo(message) }
+        }
+
+        let can = CanMessage::new(1, 0x7df, &[2, 1, 0]);
+        match from_neo(&can.to_neo_message()) {
+            Some(Message::Can(m)) => {
+                assert_eq!({ m.arbid }, 0x7df);
+                assert_eq!(m.data(), &[2, 1, 0]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let eth = EthMessage::new(93, &[0xff; 14]);
+        assert!(matches!(
+            from_neo(&eth.to_neo_message()),
+            Some(Message::Eth(_))
+        ));
+
+        let mut error = NeoMessageCanError::new();
+        error.messageType = MESSAGE_TYPE_CAN_ERROR_COUNT;
+        error.transmitErrorCount = 96;
+        match from_neo(&NeoMessage::from(error)) {
+            Some(Message::CanError(m)) => assert_eq!(m.transmitErrorCount, 96),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        assert!(from_neo(&NeoMessage::new()).is_none());
+    }
+
+    type Flag = (
+        &'static str,
+        fn(&MessageStatus) -> bool,
+        fn(&mut MessageStatus, bool),
+        usize,
+        u32,
+    );
+
+    macro_rules! flags {
+        ($($name:ident, $setter:ident: $word:literal, $bit:literal;)*) => {
+            vec![$((
+                stringify!($name),
+                MessageStatus::$name as fn(&MessageStatus) -> bool,
+                MessageStatus::$setter as fn(&mut MessageStatus, bool),
+                $word,
+                $bit,
+            )),*]
+        };
+    }
+
+    /// Every flag of `neomessage_statusbitfield_t` in `neomessage.h` with its word and bit.
+    fn flags() -> Vec<Flag> {
+        flags! {
+            global_error, set_global_error: 0, 0;
+            transmit_message, set_transmit_message: 0, 1;
+            extended_frame, set_extended_frame: 0, 2;
+            remote_frame, set_remote_frame: 0, 3;
+            crc_error, set_crc_error: 0, 4;
+            can_error_passive, set_can_error_passive: 0, 5;
+            incomplete_frame, set_incomplete_frame: 0, 6;
+            lost_arbitration, set_lost_arbitration: 0, 7;
+            undefined_error, set_undefined_error: 0, 8;
+            can_bus_off, set_can_bus_off: 0, 9;
+            can_bus_recovered, set_can_bus_recovered: 0, 10;
+            can_bus_shorted_plus, set_can_bus_shorted_plus: 0, 11;
+            can_bus_shorted_ground, set_can_bus_shorted_ground: 0, 12;
+            checksum_error, set_checksum_error: 0, 13;
+            bad_message_bit_time_error, set_bad_message_bit_time_error: 0, 14;
+            ifr_data, set_ifr_data: 0, 15;
+            hardware_comm_error, set_hardware_comm_error: 0, 16;
+            expected_length_error, set_expected_length_error: 0, 17;
+            incoming_no_match, set_incoming_no_match: 0, 18;
+            status_break, set_status_break: 0, 19;
+            avsi_rec_overflow, set_avsi_rec_overflow: 0, 20;
+            test_trigger, set_test_trigger: 0, 21;
+            audio_comment, set_audio_comment: 0, 22;
+            gps_data, set_gps_data: 0, 23;
+            analog_digital_input, set_analog_digital_input: 0, 24;
+            text_comment, set_text_comment: 0, 25;
+            network_message_type, set_network_message_type: 0, 26;
+            vsi_tx_underrun, set_vsi_tx_underrun: 0, 27;
+            vsi_ifr_crc_bit, set_vsi_ifr_crc_bit: 0, 28;
+            init_message, set_init_message: 0, 29;
+            flexray_second_startup_frame, set_flexray_second_startup_frame: 0, 30;
+            extended, set_extended: 0, 31;
+            has_value, set_has_value: 1, 0;
+            value_is_boolean, set_value_is_boolean: 1, 1;
+            high_voltage, set_high_voltage: 1, 2;
+            long_message, set_long_message: 1, 3;
+            global_change, set_global_change: 1, 16;
+            error_frame, set_error_frame: 1, 17;
+            end_of_long_message, set_end_of_long_message: 1, 20;
+            lin_error_rx_break_not_zero, set_lin_error_rx_break_not_zero: 1, 21;
+            lin_error_rx_break_too_short, set_lin_error_rx_break_too_short: 1, 22;
+            lin_error_rx_sync_not_55, set_lin_error_rx_sync_not_55: 1, 23;
+            lin_error_rx_data_greater_eight, set_lin_error_rx_data_greater_eight: 1, 24;
+            lin_error_tx_rx_mismatch, set_lin_error_tx_rx_mismatch: 1, 25;
+            lin_error_message_id_parity, set_lin_error_message_id_parity: 1, 26;
+            lin_sync_frame_error, set_lin_sync_frame_error: 1, 27;
+            lin_id_frame_error, set_lin_id_frame_error: 1, 28;
+            lin_slave_byte_error, set_lin_slave_byte_error: 1, 29;
+            rx_timeout_error, set_rx_timeout_error: 1, 30;
+            lin_no_slave_data, set_lin_no_slave_data: 1, 31;
+            canfd_esi, set_canfd_esi: 2, 0;
+            canfd_ide, set_canfd_ide: 2, 1;
+            canfd_rtr, set_canfd_rtr: 2, 2;
+            canfd_fdf, set_canfd_fdf: 2, 3;
+            canfd_brs, set_canfd_brs: 2, 4;
+        }
+    }
+
+    /// Names sharing a bit with a flag in [flags].
+    fn aliases() -> Vec<Flag> {
+        flags! {
+            header_crc_error, set_header_crc_error: 0, 5;
+            high_speed_message, set_high_speed_message: 0, 30;
+            lin_just_break_sync, set_lin_just_break_sync: 2, 0;
+            lin_slave_data_too_short, set_lin_slave_data_too_short: 2, 1;
+            lin_only_update_slave_table_once, set_lin_only_update_slave_table_once: 2, 2;
+        }
+    }
+
+    #[test]
+    fn test_status_layout() {
+        let flags = flags();
+        assert_eq!(
+            flags
+                .iter()
+                .map(|&(name, _, _, word, bit)| (name, word, bit))
+                .collect::<Vec<_>>(),
+            STATUS_FLAGS
+        );
+        for (name, get, set, word, bit) in flags.into_iter().chain(aliases()) {
+            let mut status = MessageStatus::new();
+            set(&mut status, true);
+            let mut words = [0u32; 4];
+            words[word] = 1 << bit;
+            assert_eq!(status.words(), words, "{name}");
+            assert!(get(&status), "{name}");
+            set(&mut status, false);
+            assert_eq!(status, MessageStatus::new(), "{name}");
+
+            // No other bit reads as this flag, and clearing it leaves the others set.
+            for other in 0..128 {
+                let mut words = [0u32; 4];
+                words[other / 32] = 1 << (other % 32);
+                assert_eq!(
+                    get(&MessageStatus::from(words)),
+                    other == word * 32 + bit as usize,
+                    "{name} {other}"
+                );
+            }
+            let mut status = MessageStatus::from([u32::MAX; 4]);
+            set(&mut status, false);
+            let mut words = [u32::MAX; 4];
+            words[word] &= !(1 << bit);
+            assert_eq!(status.words(), words, "{name}");
+        }
+
+        // Flags don't share bits, except through the aliases.
+        let mut bits: Vec<_> = STATUS_FLAGS
+            .iter()
+            .map(|&(_, word, bit)| (word, bit))
+            .collect();
+        bits.sort();
+        bits.dedup();
+        assert_eq!(bits.len(), STATUS_FLAGS.len());
+        for (name, _, _, word, bit) in aliases() {
+            assert!(bits.contains(&(word, bit)), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_status_matches_message_accessors() {
+        let mut message = CanMessage::new(1, 0x123, &[]);
+        message.set_transmit(true);
+        message.set_extended(true);
+        message.set_remote(true);
+        message.set_error_frame(true);
+        message.set_fd(true);
+        message.set_brs(true);
+        message.set_esi(true);
+        let status = message.status();
+        assert!(status.transmit_message() && status.extended_frame() && status.remote_frame());
+        assert!(status.error_frame() && status.canfd_fdf() && status.canfd_brs());
+        assert!(status.canfd_esi());
+        assert_eq!(status.words(), [0b1110, 1 << 17, 0b11001, 0]);
+
+        let mut status = MessageStatus::new();
+        status.set_global_error(true);
+        status.set_extended_frame(true);
+        message.set_status(status);
+        assert!(message.is_extended() && !message.is_transmit() && !message.is_fd());
+        assert_eq!(Message::Can(message.clone()).status(), status);
+        assert_eq!(
+            MessageStatus::from(NeoMessageCan::from(message.to_neo_message()).status).words(),
+            [0b101, 0, 0, 0]
+        );
+
+        let mut message = Message::CanError(NeoMessageCanError::new());
+        message.set_status(status);
+        assert_eq!(message.status(), status);
+    }
+
+    #[test]
+    fn test_status_debug() {
+        assert_eq!(format!("{:?}", MessageStatus::new()), "MessageStatus {}");
+        let mut status = MessageStatus::new();
+        status.set_transmit_message(true);
+        status.set_canfd_fdf(true);
+        status.set_lin_just_break_sync(true);
+        status.set_error_frame(true);
+        assert_eq!(
+            format!("{status:?}"),
+            "MessageStatus {transmit_message, error_frame, canfd_esi, canfd_fdf}"
+        );
+        let status = MessageStatus::from([1 << 2, 1 << 4, 1 << 31, 1]);
+        assert_eq!(
+            format!("{status:?}"),
+            "MessageStatus {extended_frame, 1:4, 2:31, 3:0}"
+        );
+    }
+}
@@ -13,6 +13,7 @@ type Result<T> = std::result::Result<T, Error>;
 
 /// All errors that are returned from this library will be contained here
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// No devices were found.
     NoDevicesFound,
@@ -22,6 +23,23 @@ pub enum Error {
     CriticalError(String),
     /// API reported the NeoDevice object is not valid.
     DeviceInvalid,
+    /// Reading or writing a file or stream failed.
+    IoError(std::io::Error),
+    /// Input data (log file, database, ...) could not be parsed.
+    ParseError(String),
+    /// An argument is out of range or refers to something that doesn't exist.
+    InvalidArgument(String),
+    /// A bus protocol peer didn't answer in time.
+    Timeout(String),
+    /// A bus protocol peer sent something unexpected.
+    ProtocolError(String),
+    /// A diagnostic or calibration request was rejected with a negative response (service or
+    /// command, response code).
+    NegativeResponse(u8, u8),
+    /// A CANopen SDO transfer was aborted with this abort code.
+    SdoAbort(u32),
+    /// The device reported a transmitted message as not sent.
+    TransmitFailed(String),
 }
 
 impl std::error::Error for Error {}
@@ -33,10 +51,27 @@ impl fmt::Display for Error {
             Self::ErrorOccurred(e) => write!(f, "Error Occurred: ({:#?})", e),
             Self::CriticalError(s) => write!(f, "Critical Error: {s}"),
             Self::DeviceInvalid => write!(f, "Device Invalid"),
+            Self::IoError(e) => write!(f, "IO Error: {e}"),
+            Self::ParseError(s) => write!(f, "Parse Error: {s}"),
+            Self::InvalidArgument(s) => write!(f, "Invalid Argument: {s}"),
+            Self::Timeout(s) => write!(f, "Timeout: {s}"),
+            Self::ProtocolError(s) => write!(f, "Protocol Error: {s}"),
+            Self::NegativeResponse(service, code) => write!(
+                f,
+                "Negative Response: Service {service:#04x} rejected with {code:#04x}"
+            ),
+            Self::SdoAbort(code) => write!(f, "SDO Abort: {code:#010x}"),
+            Self::TransmitFailed(s) => write!(f, "Transmit Failed: {s}"),
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
 #[cfg(feature = "python")]
 impl std::convert::From<Error> for PyErr {
     fn from(err: Error) -> PyErr {
@@ -78,6 +113,11 @@ impl NeoDevice {
             },
         }
     }
+
+    pub fn serial(&self) -> String {
+        let serial = self.serial.iter().take_while(|&&c| c != 0).map(|&c| c as u8);
+        String::from_utf8_lossy(&serial.collect::<Vec<u8>>()).to_string()
+    }
 }
 
 #[cfg_attr(feature = "python", pymethods)]
@@ -178,7 +218,7 @@ impl NeoEvent {
 macro_rules! define_message {
     ($name:ident, $inner_name:ident) => {
         #[cfg_attr(feature = "python", pyclass)]
-        #[derive(Debug)]
+        #[derive(Debug, Clone)]
         #[repr(transparent)]
         pub struct $name(pub $inner_name);
 
@@ -238,6 +278,11 @@ define_message!(NeoMessageCan, neomessage_can_t);
 define_message!(NeoMessageCanError, neomessage_can_error_t);
 define_message!(NeoMessageEth, neomessage_eth_t);
 
+define_message_from!(NeoMessage, NeoMessageFrame);
+define_message_from!(NeoMessage, NeoMessageCan);
+define_message_from!(NeoMessage, NeoMessageCanError);
+define_message_from!(NeoMessage, NeoMessageEth);
+
 define_message_from!(NeoMessageFrame, NeoMessage);
 define_message_from!(NeoMessageFrame, NeoMessageCan);
 define_message_from!(NeoMessageFrame, NeoMessageCanError);
@@ -292,6 +337,17 @@ impl NeoMessageFrame {
             },
         }
     }
+
+    /// Payload bytes, `length` bytes at the `data` pointer.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be null or point to `length` readable bytes for as long as the returned
+    /// slice is used. For received messages it is only valid until the next
+    /// [get_messages](get_messages) call on the same device.
+    pub unsafe fn data(&self) -> &[u8] {
+        payload(self.0.data, self.0.length as usize)
+    }
 }
 
 impl NeoMessageCan {
@@ -313,6 +369,17 @@ impl NeoMessageCan {
             },
         }
     }
+
+    /// Payload bytes, `length` bytes at the `data` pointer.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be null or point to `length` readable bytes for as long as the returned
+    /// slice is used. For received messages it is only valid until the next
+    /// [get_messages](get_messages) call on the same device.
+    pub unsafe fn data(&self) -> &[u8] {
+        payload(self.0.data, self.0.length as usize)
+    }
 }
 
 impl NeoMessageCanError {
@@ -355,6 +422,27 @@ impl NeoMessageEth {
             },
         }
     }
+
+    /// Payload bytes, `length` bytes at the `data` pointer.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be null or point to `length` readable bytes for as long as the returned
+    /// slice is used. For received messages it is only valid until the next
+    /// [get_messages](get_messages) call on the same device.
+    pub unsafe fn data(&self) -> &[u8] {
+        payload(self.0.data, self.0.length as usize)
+    }
+}
+
+/// # Safety
+///
+/// See [NeoMessageFrame::data].
+unsafe fn payload<'a>(data: *const u8, length: usize) -> &'a [u8] {
+    if data.is_null() || length == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(data, length)
 }
 
 /// Used with [get_version](get_version)
@@ -0,0 +1,165 @@
+//! Network identifiers.
+//!
+//! libicsneo identifies every bus of a device with a `neonetid_t`. The `ICSNEO_NETID_*` values
+//! are preprocessor macros in `network.h` and are not exported by
+//! [libicsneo_sys](libicsneo_sys), so the common ones are defined on [NetworkId](NetworkId).
+use libicsneo_sys::neonetid_t;
+
+/// A libicsneo netid with its well-known name.
+///
+/// Converts to and from `neonetid_t`, so it can be passed anywhere the raw functions expect one:
+/// ```
+/// use icsneo::network::NetworkId;
+///
+/// let netid: u16 = NetworkId::HSCAN2.into();
+/// assert_eq!(netid, 42);
+/// assert_eq!(NetworkId::from(42).to_string(), "HSCAN 2");
+/// assert_eq!("hscan2".parse::<NetworkId>().unwrap(), NetworkId::HSCAN2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetworkId(pub neonetid_t);
+
+impl NetworkId {
+    pub const DEVICE: Self = Self(0);
+    pub const HSCAN: Self = Self(1);
+    pub const MSCAN: Self = Self(2);
+    pub const SWCAN: Self = Self(3);
+    pub const LSFTCAN: Self = Self(4);
+    pub const LIN: Self = Self(16);
+    pub const OP_ETHERNET1: Self = Self(17);
+    pub const OP_ETHERNET2: Self = Self(18);
+    pub const OP_ETHERNET3: Self = Self(19);
+    pub const HSCAN2: Self = Self(42);
+    pub const HSCAN3: Self = Self(44);
+    pub const LIN2: Self = Self(48);
+    pub const LIN3: Self = Self(49);
+    pub const LIN4: Self = Self(50);
+    pub const HSCAN4: Self = Self(61);
+    pub const HSCAN5: Self = Self(62);
+    pub const SWCAN2: Self = Self(68);
+    pub const ETHERNET: Self = Self(93);
+    pub const HSCAN6: Self = Self(96);
+    pub const HSCAN7: Self = Self(97);
+    pub const LSFTCAN2: Self = Self(99);
+    pub const ETHERNET2: Self = Self(520);
+    /// Matches any network where libicsneo accepts a filter.
+    pub const ANY: Self = Self(0xfffe);
+    pub const INVALID: Self = Self(0xffff);
+
+    const NAMES: [(Self, &'static str); 24] = [
+        (Self::DEVICE, "Device"),
+        (Self::HSCAN, "HSCAN"),
+        (Self::MSCAN, "MSCAN"),
+        (Self::SWCAN, "SWCAN"),
+        (Self::LSFTCAN, "LSFTCAN"),
+        (Self::LIN, "LIN"),
+        (Self::OP_ETHERNET1, "OP (BR) Ethernet 1"),
+        (Self::OP_ETHERNET2, "OP (BR) Ethernet 2"),
+        (Self::OP_ETHERNET3, "OP (BR) Ethernet 3"),
+        (Self::HSCAN2, "HSCAN 2"),
+        (Self::HSCAN3, "HSCAN 3"),
+        (Self::LIN2, "LIN 2"),
+        (Self::LIN3, "LIN 3"),
+        (Self::LIN4, "LIN 4"),
+        (Self::HSCAN4, "HSCAN 4"),
+        (Self::HSCAN5, "HSCAN 5"),
+        (Self::SWCAN2, "SWCAN 2"),
+        (Self::ETHERNET, "Ethernet"),
+        (Self::HSCAN6, "HSCAN 6"),
+        (Self::HSCAN7, "HSCAN 7"),
+        (Self::LSFTCAN2, "LSFTCAN 2"),
+        (Self::ETHERNET2, "Ethernet 2"),
+        (Self::ANY, "Any"),
+        (Self::INVALID, "Invalid"),
+    ];
+
+    /// The name libicsneo uses for this network, `None` for netids not listed here.
+    pub fn name(&self) -> Option<&'static str> {
+        Self::NAMES
+            .iter()
+            .find(|(id, _)| id == self)
+            .map(|(_, name)| *name)
+    }
+
+    /// A lowercase name without spaces or punctuation, usable as an interface name, e.g.
+    /// `hscan2`. Netids without a name become `net<netid>`.
+    pub fn short_name(&self) -> String {
+        match self.name() {
+            Some(name) => name
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect(),
+            None => format!("net{}", self.0),
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "NetID {}", self.0),
+        }
+    }
+}
+
+impl std::str::FromStr for NetworkId {
+    type Err = crate::native::Error;
+
+    /// Accepts the [name](NetworkId::name), the [short name](NetworkId::short_name) or a
+    /// number, case insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(netid) = s.parse::<neonetid_t>() {
+            return Ok(Self(netid));
+        }
+        if let Some(netid) = s
+            .strip_prefix("net")
+            .and_then(|n| n.parse::<neonetid_t>().ok())
+        {
+            return Ok(Self(netid));
+        }
+        Self::NAMES
+            .iter()
+            .map(|(id, _)| *id)
+            .find(|id| {
+                id.name().is_some_and(|name| name.eq_ignore_ascii_case(s))
+                    || id.short_name().eq_ignore_ascii_case(s)
+            })
+            .ok_or_else(|| crate::native::Error::ParseError(format!("unknown network {s:?}")))
+    }
+}
+
+impl From<neonetid_t> for NetworkId {
+    fn from(netid: neonetid_t) -> Self {
+        Self(netid)
+    }
+}
+
+impl From<NetworkId> for neonetid_t {
+    fn from(netid: NetworkId) -> Self {
+        netid.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(NetworkId::HSCAN.to_string(), "HSCAN");
+        assert_eq!(NetworkId::ETHERNET2.short_name(), "ethernet2");
+        assert_eq!(NetworkId::OP_ETHERNET1.short_name(), "opbrethernet1");
+        assert_eq!(NetworkId(1234).to_string(), "NetID 1234");
+        assert_eq!(NetworkId(1234).short_name(), "net1234");
+        for (id, _) in NetworkId::NAMES {
+            assert_eq!(id.to_string().parse::<NetworkId>().unwrap(), id);
+            assert_eq!(id.short_name().parse::<NetworkId>().unwrap(), id);
+        }
+        assert_eq!("net1234".parse::<NetworkId>().unwrap(), NetworkId(1234));
+        assert_eq!("97".parse::<NetworkId>().unwrap(), NetworkId::HSCAN7);
+        assert!("can0".parse::<NetworkId>().is_err());
+    }
+}
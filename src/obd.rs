@@ -0,0 +1,709 @@
+//! OBD-II (SAE J1979 / ISO 15765-4) requests on CAN.
+//!
+//! [ObdClient] sends functional requests to all emissions related ECUs at once and collects
+//! the responses of every ECU that answers within the timeout. Multi-frame responses like
+//! the VIN are reassembled, with flow control sent to the responding ECU:
+//! ```no_run
+//! use icsneo::network::NetworkId;
+//! use icsneo::obd::ObdClient;
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut obd = ObdClient::new(device, NetworkId::HSCAN);
+//! for response in obd.read_pid(0x0C).unwrap() {
+//!     println!("{:03X}: {}", response.ecu, response.value);
+//! }
+//! for response in obd.vin().unwrap() {
+//!     println!("{:03X}: {}", response.ecu, response.value);
+//! }
+//! ```
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+use crate::uds::NRC_RESPONSE_PENDING;
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub const FUNCTIONAL_ID: u32 = 0x7df;
+pub const FUNCTIONAL_ID_29BIT: u32 = 0x18db33f1;
+
+pub const MODE_CURRENT_DATA: u8 = 0x01;
+pub const MODE_FREEZE_FRAME: u8 = 0x02;
+pub const MODE_STORED_DTCS: u8 = 0x03;
+pub const MODE_PENDING_DTCS: u8 = 0x07;
+pub const MODE_VEHICLE_INFORMATION: u8 = 0x09;
+pub const MODE_PERMANENT_DTCS: u8 = 0x0a;
+
+const NEGATIVE_RESPONSE: u8 = 0x7f;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+/// How long an ECU that answered response pending has for its final response, P2*CAN
+/// from ISO 15765-4.
+const RESPONSE_PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+const PADDING: u8 = 0xcc;
+
+/// A response of one ECU, identified by the arbitration ID it responded with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response<T> {
+    pub ecu: u32,
+    pub value: T,
+}
+
+/// An OBD-II trouble code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dtc(pub u16);
+
+/// Formats the code like "P0123".
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let system = ['P', 'C', 'B', 'U'][usize::from(self.0 >> 14)];
+        write!(f, "{system}{:04X}", self.0 & 0x3fff)
+    }
+}
+
+/// A standard PID with a single value.
+#[derive(Debug)]
+pub struct PidDefinition {
+    pub pid: u8,
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Number of data bytes.
+    pub length: usize,
+    formula: fn(&[f64]) -> f64,
+}
+
+impl PidDefinition {
+    /// Converts the data bytes following the PID to the physical value.
+    pub fn decode(&self, data: &[u8]) -> Option<PidValue> {
+        let bytes: Vec<f64> = data.get(..self.length)?.iter().map(|&b| b.into()).collect();
+        Some(PidValue {
+            pid: self.pid,
+            name: self.name,
+            unit: self.unit,
+            value: (self.formula)(&bytes),
+        })
+    }
+}
+
+/// A decoded PID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidValue {
+    pub pid: u8,
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub value: f64,
+}
+
+impl fmt::Display for PidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {} {}", self.name, self.value, self.unit)
+    }
+}
+
+macro_rules! pids {
+    ($($pid:literal $name:literal $unit:literal $length:literal |$d:ident| $formula:expr;)*) => {
+        /// Mode 01 and 02 PIDs [decode_pid] knows, per SAE J1979.
+        pub static PIDS: &[PidDefinition] = &[$(PidDefinition {
+            pid: $pid,
+            name: $name,
+            unit: $unit,
+            length: $length,
+            formula: |$d| $formula,
+        }),*];
+    };
+}
+
+pids! {
+    0x04 "Calculated engine load" "%" 1 |d| d[0] * 100.0 / 255.0;
+    0x05 "Engine coolant temperature" "°C" 1 |d| d[0] - 40.0;
+    0x06 "Short term fuel trim bank 1" "%" 1 |d| d[0] * 100.0 / 128.0 - 100.0;
+    0x07 "Long term fuel trim bank 1" "%" 1 |d| d[0] * 100.0 / 128.0 - 100.0;
+    0x08 "Short term fuel trim bank 2" "%" 1 |d| d[0] * 100.0 / 128.0 - 100.0;
+    0x09 "Long term fuel trim bank 2" "%" 1 |d| d[0] * 100.0 / 128.0 - 100.0;
+    0x0a "Fuel pressure" "kPa" 1 |d| d[0] * 3.0;
+    0x0b "Intake manifold absolute pressure" "kPa" 1 |d| d[0];
+    0x0c "Engine speed" "rpm" 2 |d| (d[0] * 256.0 + d[1]) / 4.0;
+    0x0d "Vehicle speed" "km/h" 1 |d| d[0];
+    0x0e "Timing advance" "°" 1 |d| d[0] / 2.0 - 64.0;
+    0x0f "Intake air temperature" "°C" 1 |d| d[0] - 40.0;
+    0x10 "Mass air flow rate" "g/s" 2 |d| (d[0] * 256.0 + d[1]) / 100.0;
+    0x11 "Throttle position" "%" 1 |d| d[0] * 100.0 / 255.0;
+    0x1f "Run time since engine start" "s" 2 |d| d[0] * 256.0 + d[1];
+    0x21 "Distance traveled with MIL on" "km" 2 |d| d[0] * 256.0 + d[1];
+    0x22 "Fuel rail pressure" "kPa" 2 |d| (d[0] * 256.0 + d[1]) * 0.079;
+    0x23 "Fuel rail gauge pressure" "kPa" 2 |d| (d[0] * 256.0 + d[1]) * 10.0;
+    0x2c "Commanded EGR" "%" 1 |d| d[0] * 100.0 / 255.0;
+    0x2f "Fuel tank level input" "%" 1 |d| d[0] * 100.0 / 255.0;
+    0x31 "Distance traveled since codes cleared" "km" 2 |d| d[0] * 256.0 + d[1];
+    0x33 "Absolute barometric pressure" "kPa" 1 |d| d[0];
+    0x42 "Control module voltage" "V" 2 |d| (d[0] * 256.0 + d[1]) / 1000.0;
+    0x43 "Absolute load value" "%" 2 |d| (d[0] * 256.0 + d[1]) * 100.0 / 255.0;
+    0x44 "Commanded air-fuel equivalence ratio" "" 2 |d| (d[0] * 256.0 + d[1]) / 32768.0;
+    0x45 "Relative throttle position" "%" 1 |d| d[0] * 100.0 / 255.0;
+    0x46 "Ambient air temperature" "°C" 1 |d| d[0] - 40.0;
+    0x4d "Time run with MIL on" "min" 2 |d| d[0] * 256.0 + d[1];
+    0x4e "Time since trouble codes cleared" "min" 2 |d| d[0] * 256.0 + d[1];
+    0x5c "Engine oil temperature" "°C" 1 |d| d[0] - 40.0;
+    0x5e "Engine fuel rate" "L/h" 2 |d| (d[0] * 256.0 + d[1]) / 20.0;
+    0xa6 "Odometer" "km" 4 |d| (((d[0] * 256.0 + d[1]) * 256.0 + d[2]) * 256.0 + d[3]) / 10.0;
+}
+
+pub fn pid_definition(pid: u8) -> Option<&'static PidDefinition> {
+    PIDS.iter().find(|definition| definition.pid == pid)
+}
+
+/// Decodes the data bytes of a mode 01 or 02 `pid`. Returns `None` for PIDs without a
+/// single physical value (bit fields, multiple values) and truncated data.
+pub fn decode_pid(pid: u8, data: &[u8]) -> Option<PidValue> {
+    pid_definition(pid)?.decode(data)
+}
+
+/// A multi-frame response being received from one ECU.
+struct Reassembly {
+    data: Vec<u8>,
+    length: usize,
+    sequence: u8,
+}
+
+/// Sends OBD-II requests on one network and collects the responses.
+pub struct ObdClient<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    netid: neonetid_t,
+    extended_ids: bool,
+    timeout: Duration,
+    pending: VecDeque<Message>,
+}
+
+impl<B: Transmit + Receive> ObdClient<B> {
+    pub fn new(bus: B, netid: impl Into<NetworkId>) -> Self {
+        Self::with_clock(bus, netid, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> ObdClient<B, C> {
+    pub fn with_clock(bus: B, netid: impl Into<NetworkId>, clock: C) -> Self {
+        Self {
+            bus,
+            clock,
+            netid: netid.into().0,
+            extended_ids: false,
+            timeout: Duration::from_millis(100),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Requests on 0x18DB33F1 with responses from 0x18DAF1xx instead of 0x7DF and
+    /// 0x7E8-0x7EF. Off by default.
+    pub fn set_extended_ids(&mut self, extended_ids: bool) {
+        self.extended_ids = extended_ids;
+    }
+
+    /// How long to collect responses after a request, extended while a multi-frame
+    /// response is incomplete. Defaults to 100 ms.
+    ///
+    /// ECUs answering response pending (NRC 0x78) are waited for up to 5 s.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sends `request` (mode and parameters) to all ECUs and returns the positive
+    /// responses, starting with the response mode.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<Response<Vec<u8>>>> {
+        let Some(&mode) = request.first() else {
+            return Err(Error::InvalidArgument("Empty OBD request".to_string()));
+        };
+        if request.len() > 7 {
+            return Err(Error::InvalidArgument(format!(
+                "OBD request {request:02x?} doesn't fit a single frame"
+            )));
+        }
+        let mut frame = vec![request.len() as u8];
+        frame.extend_from_slice(request);
+        let id = match self.extended_ids {
+            true => FUNCTIONAL_ID_29BIT,
+            false => FUNCTIONAL_ID,
+        };
+        // Drop anything received before the request.
+        self.pending.clear();
+        self.bus.receive(Duration::ZERO)?;
+        self.transmit_frame(id, frame)?;
+
+        let mut responses = Vec::new();
+        let mut reassemblies: HashMap<u32, Reassembly> = HashMap::new();
+        // ECUs that answered response pending and haven't sent their final response.
+        let mut waiting = HashSet::new();
+        let mut deadline = self.clock.now() + self.timeout;
+        while let Some((ecu, frame)) = self.next_frame(deadline)? {
+            let payload = match frame[0] >> 4 {
+                0x0 => {
+                    let length = usize::from(frame[0] & 0xf);
+                    frame.get(1..1 + length).map(<[u8]>::to_vec)
+                }
+                0x1 if frame.len() >= 2 => {
+                    let length = (usize::from(frame[0] & 0xf) << 8) | usize::from(frame[1]);
+                    reassemblies.insert(
+                        ecu,
+                        Reassembly {
+                            data: frame[2..].to_vec(),
+                            length,
+                            sequence: 1,
+                        },
+                    );
+                    self.transmit_frame(self.physical_id(ecu), vec![0x30, 0, 0])?;
+                    deadline = self.clock.now() + self.timeout;
+                    None
+                }
+                0x2 => match reassemblies.get_mut(&ecu) {
+                    Some(reassembly) if frame[0] & 0xf == reassembly.sequence => {
+                        reassembly.data.extend_from_slice(&frame[1..]);
+                        reassembly.sequence = (reassembly.sequence + 1) & 0xf;
+                        deadline = self.clock.now() + self.timeout;
+                        if reassembly.data.len() >= reassembly.length {
+                            let mut reassembly = reassemblies.remove(&ecu).unwrap();
+                            reassembly.data.truncate(reassembly.length);
+                            Some(reassembly.data)
+                        } else {
+                            None
+                        }
+                    }
+                    // Out of sequence, the response is lost.
+                    Some(_) => {
+                        reassemblies.remove(&ecu);
+                        None
+                    }
+                    None => None,
+                },
+                _ => None,
+            };
+            let Some(payload) = payload else {
+                continue;
+            };
+            if let [NEGATIVE_RESPONSE, rejected, NRC_RESPONSE_PENDING, ..] = payload[..] {
+                if rejected == mode {
+                    waiting.insert(ecu);
+                    deadline = deadline.max(self.clock.now() + RESPONSE_PENDING_TIMEOUT);
+                }
+                continue;
+            }
+            // Once the last pending ECU answered, the others only get the usual timeout.
+            if waiting.remove(&ecu) && waiting.is_empty() {
+                deadline = self.clock.now() + self.timeout;
+            }
+            if payload.first() == Some(&(mode + POSITIVE_RESPONSE_OFFSET)) {
+                responses.push(Response {
+                    ecu,
+                    value: payload,
+                });
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Requests a mode 01, 02 or 09 `pid` and returns the data following it. A response
+    /// containing a different PID is dropped.
+    fn request_pid(&mut self, request: &[u8]) -> Result<Vec<Response<Vec<u8>>>> {
+        let header = request.len();
+        Ok(self
+            .request(request)?
+            .into_iter()
+            .filter(|response| response.value.get(1..header) == request.get(1..header))
+            .map(|response| Response {
+                ecu: response.ecu,
+                value: response.value[header..].to_vec(),
+            })
+            .collect())
+    }
+
+    /// Returns the PIDs each ECU supports in mode 01, 02 or 09, following the support
+    /// ranges (PID 0x00, 0x20, ...) while any ECU reports the next range.
+    pub fn supported_pids(&mut self, mode: u8) -> Result<Vec<Response<Vec<u8>>>> {
+        let mut supported: Vec<Response<Vec<u8>>> = Vec::new();
+        let mut base = 0u8;
+        loop {
+            let request = match mode {
+                MODE_FREEZE_FRAME => vec![mode, base, 0],
+                MODE_CURRENT_DATA | MODE_VEHICLE_INFORMATION => vec![mode, base],
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "OBD mode {mode:#04x} has no PIDs"
+                    )))
+                }
+            };
+            let mut next = false;
+            for response in self.request_pid(&request)? {
+                let Some(bits) = response.value.get(..4) else {
+                    continue;
+                };
+                let bits = u32::from_be_bytes(bits.try_into().unwrap());
+                // The last bit of the 0xE0 range would be PID 0x100.
+                let pids = (0..32)
+                    .filter(|i| bits & (0x8000_0000 >> i) != 0)
+                    .filter_map(|i| u8::try_from(u16::from(base) + i + 1).ok());
+                match supported.iter_mut().find(|s| s.ecu == response.ecu) {
+                    Some(ecu) => ecu.value.extend(pids),
+                    None => supported.push(Response {
+                        ecu: response.ecu,
+                        value: pids.collect(),
+                    }),
+                }
+                next |= bits & 1 != 0;
+            }
+            if !next || base == 0xe0 {
+                return Ok(supported);
+            }
+            base += 0x20;
+        }
+    }
+
+    /// Mode 01 data bytes of `pid`.
+    pub fn live_data(&mut self, pid: u8) -> Result<Vec<Response<Vec<u8>>>> {
+        self.request_pid(&[MODE_CURRENT_DATA, pid])
+    }
+
+    /// Mode 01 `pid` decoded to its physical value, see [decode_pid].
+    pub fn read_pid(&mut self, pid: u8) -> Result<Vec<Response<PidValue>>> {
+        let definition = pid_definition(pid)
+            .ok_or_else(|| Error::InvalidArgument(format!("Unknown OBD PID {pid:#04x}")))?;
+        Ok(decode_responses(definition, self.live_data(pid)?))
+    }
+
+    /// Mode 02 data bytes of `pid` in freeze `frame`.
+    pub fn freeze_frame(&mut self, pid: u8, frame: u8) -> Result<Vec<Response<Vec<u8>>>> {
+        self.request_pid(&[MODE_FREEZE_FRAME, pid, frame])
+    }
+
+    /// Mode 02 `pid` in freeze `frame` decoded to its physical value.
+    pub fn read_freeze_frame(&mut self, pid: u8, frame: u8) -> Result<Vec<Response<PidValue>>> {
+        let definition = pid_definition(pid)
+            .ok_or_else(|| Error::InvalidArgument(format!("Unknown OBD PID {pid:#04x}")))?;
+        Ok(decode_responses(definition, self.freeze_frame(pid, frame)?))
+    }
+
+    fn dtcs(&mut self, mode: u8) -> Result<Vec<Response<Vec<Dtc>>>> {
+        Ok(self
+            .request(&[mode])?
+            .into_iter()
+            .map(|response| Response {
+                ecu: response.ecu,
+                // Mode byte and number of DTCs, then two bytes per DTC.
+                value: response
+                    .value
+                    .get(2..)
+                    .unwrap_or_default()
+                    .chunks_exact(2)
+                    .map(|code| Dtc(u16::from_be_bytes([code[0], code[1]])))
+                    .filter(|dtc| dtc.0 != 0)
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Mode 03, confirmed DTCs.
+    pub fn stored_dtcs(&mut self) -> Result<Vec<Response<Vec<Dtc>>>> {
+        self.dtcs(MODE_STORED_DTCS)
+    }
+
+    /// Mode 07, DTCs detected during the current or last drive cycle.
+    pub fn pending_dtcs(&mut self) -> Result<Vec<Response<Vec<Dtc>>>> {
+        self.dtcs(MODE_PENDING_DTCS)
+    }
+
+    /// Mode 0A, DTCs that can't be cleared by a scan tool.
+    pub fn permanent_dtcs(&mut self) -> Result<Vec<Response<Vec<Dtc>>>> {
+        self.dtcs(MODE_PERMANENT_DTCS)
+    }
+
+    /// Mode 09 PID 02, the vehicle identification number.
+    pub fn vin(&mut self) -> Result<Vec<Response<String>>> {
+        Ok(self
+            .request_pid(&[MODE_VEHICLE_INFORMATION, 0x02])?
+            .into_iter()
+            .map(|response| Response {
+                ecu: response.ecu,
+                // The number of data items comes first, 1 for the VIN.
+                value: String::from_utf8_lossy(response.value.get(1..).unwrap_or_default())
+                    .trim_matches(char::from(0))
+                    .to_string(),
+            })
+            .collect())
+    }
+
+    /// The ID physical requests and flow control frames for `ecu` are sent on.
+    fn physical_id(&self, ecu: u32) -> u32 {
+        match self.extended_ids {
+            true => 0x18da_00f1 | (ecu & 0xff) << 8,
+            false => ecu - 8,
+        }
+    }
+
+    fn is_response_id(&self, id: u32) -> bool {
+        match self.extended_ids {
+            true => id & 0xffff_ff00 == 0x18da_f100,
+            false => (0x7e8..=0x7ef).contains(&id),
+        }
+    }
+
+    /// Returns the next frame from an ECU and its ID, or `None` at `deadline`.
+    fn next_frame(&mut self, deadline: Duration) -> Result<Option<(u32, Vec<u8>)>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                let Message::Can(m) = message else {
+                    continue;
+                };
+                if m.netid == self.netid
+                    && m.is_extended() == self.extended_ids
+                    && self.is_response_id(m.arbid)
+                    && !m.is_transmit()
+                    && !m.is_remote()
+                    && !m.is_error_frame()
+                    && !m.data().is_empty()
+                {
+                    return Ok(Some((m.arbid, m.data().to_vec())));
+                }
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let messages = self.bus.receive(deadline - now)?;
+            self.pending.extend(messages);
+        }
+    }
+
+    fn transmit_frame(&mut self, id: u32, mut data: Vec<u8>) -> Result<()> {
+        data.resize(8, PADDING);
+        let mut message = CanMessage::new(self.netid, id, &data);
+        message.set_extended(self.extended_ids);
+        message.set_dlc_on_wire(8);
+        self.bus.transmit(&Message::Can(message))
+    }
+}
+
+fn decode_responses(
+    definition: &PidDefinition,
+    responses: Vec<Response<Vec<u8>>>,
+) -> Vec<Response<PidValue>> {
+    responses
+        .into_iter()
+        .filter_map(|response| {
+            Some(Response {
+                ecu: response.ecu,
+                value: definition.decode(&response.value)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::VirtualBus;
+    use crate::isotp::{IsoTp, IsoTpConfig};
+    use std::thread::JoinHandle;
+
+    /// Simulates an ECU answering functional requests with the response of `handler`,
+    /// until no request arrives for a while.
+    fn ecu(
+        bus: &VirtualBus,
+        extended_ids: bool,
+        response_id: u32,
+        request_id: u32,
+        handler: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> JoinHandle<()> {
+        let functional_id = match extended_ids {
+            true => FUNCTIONAL_ID_29BIT,
+            false => FUNCTIONAL_ID,
+        };
+        let mut listener = bus.endpoint();
+        let mut config = IsoTpConfig::new(NetworkId::HSCAN, response_id, request_id);
+        config.extended_id = extended_ids;
+        let mut isotp = IsoTp::new(bus.endpoint(), config).unwrap();
+        std::thread::spawn(move || loop {
+            let messages = listener.receive(Duration::from_millis(500)).unwrap();
+            if messages.is_empty() {
+                return;
+            }
+            for message in messages {
+                let Message::Can(m) = message else { continue };
+                if m.arbid != functional_id {
+                    continue;
+                }
+                let request = &m.data()[1..1 + usize::from(m.data()[0])];
+                if let Some(response) = handler(request) {
+                    isotp.send(&response).unwrap();
+                }
+            }
+        })
+    }
+
+    fn engine(request: &[u8]) -> Option<Vec<u8>> {
+        match request {
+            [0x01, 0x00] => Some(vec![0x41, 0x00, 0x18, 0x18, 0x00, 0x01]),
+            [0x01, 0x20] => Some(vec![0x41, 0x20, 0x00, 0x00, 0x20, 0x00]),
+            [0x01, 0x0c] => Some(vec![0x41, 0x0c, 0x1a, 0xf8]),
+            [0x01, 0x05] => Some(vec![0x41, 0x05, 0x7b]),
+            [0x02, 0x0d, 0x00] => Some(vec![0x42, 0x0d, 0x00, 0x32]),
+            [0x03] => Some(vec![0x43, 0x02, 0x01, 0x23, 0xc1, 0x00]),
+            [0x07] => Some(vec![0x47, 0x00]),
+            [0x09, 0x02] => {
+                let mut response = vec![0x49, 0x02, 0x01];
+                response.extend_from_slice(b"1FTFW1ET5DFC10312");
+                Some(response)
+            }
+            _ => Some(vec![0x7f, request[0], 0x12]),
+        }
+    }
+
+    fn transmission(request: &[u8]) -> Option<Vec<u8>> {
+        match request {
+            [0x01, 0x00] => Some(vec![0x41, 0x00, 0x08, 0x00, 0x00, 0x00]),
+            [0x01, 0x05] => Some(vec![0x41, 0x05, 0x5a]),
+            [0x03] => Some(vec![0x43, 0x01, 0x07, 0x00]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let rpm = decode_pid(0x0c, &[0x1a, 0xf8]).unwrap();
+        assert_eq!(rpm.value, 1726.0);
+        assert_eq!(rpm.to_string(), "Engine speed = 1726 rpm");
+        assert_eq!(decode_pid(0x05, &[0x7b]).unwrap().value, 83.0);
+        assert_eq!(decode_pid(0x06, &[0x80]).unwrap().value, 0.0);
+        assert_eq!(decode_pid(0x42, &[0x31, 0x9c]).unwrap().value, 12.7);
+        assert_eq!(
+            decode_pid(0xa6, &[0x00, 0x01, 0xe2, 0x40]).unwrap().value,
+            12345.6
+        );
+        assert!(decode_pid(0x0c, &[0x1a]).is_none());
+        assert!(decode_pid(0x01, &[0, 0, 0, 0]).is_none());
+
+        assert_eq!(Dtc(0x0123).to_string(), "P0123");
+        assert_eq!(Dtc(0xc100).to_string(), "U0100");
+        assert_eq!(Dtc(0x4a1f).to_string(), "C0A1F");
+    }
+
+    #[test]
+    fn test_requests() {
+        let bus = VirtualBus::new();
+        let engine = ecu(&bus, false, 0x7e8, 0x7e0, engine);
+        let transmission = ecu(&bus, false, 0x7e9, 0x7e1, transmission);
+        let mut obd = ObdClient::new(bus.endpoint(), NetworkId::HSCAN);
+
+        let mut supported = obd.supported_pids(MODE_CURRENT_DATA).unwrap();
+        supported.sort_by_key(|response| response.ecu);
+        assert_eq!(supported.len(), 2);
+        assert_eq!(supported[0].value, [0x04, 0x05, 0x0c, 0x0d, 0x20, 0x33]);
+        assert_eq!(supported[1].value, [0x05]);
+
+        let mut temperatures = obd.read_pid(0x05).unwrap();
+        temperatures.sort_by_key(|response| response.ecu);
+        assert_eq!(temperatures.len(), 2);
+        assert_eq!(temperatures[0].value.value, 83.0);
+        assert_eq!(temperatures[1].ecu, 0x7e9);
+        assert_eq!(temperatures[1].value.value, 50.0);
+
+        // Negative responses are left out.
+        let rpm = obd.read_pid(0x0c).unwrap();
+        assert_eq!(rpm.len(), 1);
+        assert_eq!(rpm[0].value.value, 1726.0);
+        assert!(obd.live_data(0x11).unwrap().is_empty());
+        assert!(matches!(obd.read_pid(0x01), Err(Error::InvalidArgument(_))));
+
+        let speed = obd.read_freeze_frame(0x0d, 0).unwrap();
+        assert_eq!(speed[0].value.value, 50.0);
+
+        let mut stored = obd.stored_dtcs().unwrap();
+        stored.sort_by_key(|response| response.ecu);
+        assert_eq!(stored[0].value, [Dtc(0x0123), Dtc(0xc100)]);
+        assert_eq!(stored[1].value, [Dtc(0x0700)]);
+        let pending = obd.pending_dtcs().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].value.is_empty());
+        assert!(obd.permanent_dtcs().unwrap().is_empty());
+
+        // Reassembled from 3 frames.
+        let vin = obd.vin().unwrap();
+        assert_eq!(vin.len(), 1);
+        assert_eq!(vin[0].ecu, 0x7e8);
+        assert_eq!(vin[0].value, "1FTFW1ET5DFC10312");
+
+        engine.join().unwrap();
+        transmission.join().unwrap();
+    }
+
+    #[test]
+    fn test_all_pids_supported() {
+        let bus = VirtualBus::new();
+        let engine = ecu(&bus, false, 0x7e8, 0x7e0, |request| match request {
+            [0x01, base] if base % 0x20 == 0 => Some(vec![0x41, *base, 0xff, 0xff, 0xff, 0xff]),
+            _ => None,
+        });
+        let mut obd = ObdClient::new(bus.endpoint(), NetworkId::HSCAN);
+
+        let supported = obd.supported_pids(MODE_CURRENT_DATA).unwrap();
+        assert_eq!(supported.len(), 1);
+        assert_eq!(supported[0].value, (0x01..=0xff).collect::<Vec<u8>>());
+
+        engine.join().unwrap();
+    }
+
+    #[test]
+    fn test_response_pending() {
+        let bus = VirtualBus::new();
+        let mut endpoint = bus.endpoint();
+        // Needs longer than the timeout to answer.
+        let engine = std::thread::spawn(move || {
+            endpoint.receive(Duration::from_secs(1)).unwrap();
+            for data in [[0x03, 0x7f, 0x01, 0x78], [0x03, 0x41, 0x05, 0x7b]] {
+                let frame = CanMessage::new(1, 0x7e8, &data);
+                endpoint.transmit(&Message::Can(frame)).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+            }
+        });
+        let mut obd = ObdClient::new(bus.endpoint(), NetworkId::HSCAN);
+        obd.set_timeout(Duration::from_millis(50));
+
+        let temperatures = obd.read_pid(0x05).unwrap();
+        assert_eq!(temperatures.len(), 1);
+        assert_eq!(temperatures[0].value.value, 83.0);
+        engine.join().unwrap();
+    }
+
+    #[test]
+    fn test_extended_ids() {
+        let bus = VirtualBus::new();
+        let engine = ecu(&bus, true, 0x18daf110, 0x18da10f1, engine);
+        let mut obd = ObdClient::new(bus.endpoint(), NetworkId::HSCAN);
+        obd.set_extended_ids(true);
+        obd.set_timeout(Duration::from_millis(50));
+
+        let vin = obd.vin().unwrap();
+        assert_eq!(vin[0].ecu, 0x18daf110);
+        assert_eq!(vin[0].value, "1FTFW1ET5DFC10312");
+        assert!(matches!(
+            obd.request(&[0x01, 1, 2, 3, 4, 5, 6, 7]),
+            Err(Error::InvalidArgument(_))
+        ));
+        engine.join().unwrap();
+    }
+}
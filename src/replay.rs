@@ -0,0 +1,656 @@
+//! Replays recorded traffic with its original timing.
+//!
+//! [Replay] takes messages from any source, e.g. one of the [log](crate::log) readers, and
+//! transmits them so the gaps between their timestamps are reproduced on the bus:
+//! ```no_run
+//! use icsneo::log::candump::CandumpReader;
+//! use icsneo::network::NetworkId;
+//! use icsneo::replay::Replay;
+//!
+//! let mut device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//!
+//! let file = std::io::BufReader::new(std::fs::File::open("drive.log").unwrap());
+//! let mut replay = Replay::new();
+//! replay.map_netid(NetworkId::HSCAN, NetworkId::HSCAN2);
+//! replay.exclude_ids(0x700..=0x7ff);
+//! let report = replay.run(CandumpReader::new(file), &mut device).unwrap();
+//! println!("{} frames, max jitter {:?}", report.transmitted, report.max_jitter);
+//! ```
+//! Messages are scheduled against absolute deadlines, so late transmits don't accumulate into
+//! drift. Messages that are due at the same time are handed over together with
+//! [transmit_messages](crate::bus::Transmit::transmit_messages).
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::Transmit;
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// How often a paused replay checks whether it was resumed.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Pauses, resumes or stops a running [Replay] from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayControl {
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ReplayControl {
+    /// Holds transmission until [resume](ReplayControl::resume). Time spent paused shifts
+    /// the rest of the schedule, it doesn't cause a burst of catch-up frames.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Ends the replay before the next message. [run](Replay::run) returns the report so far.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Summary of a finished [run](Replay::run).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub transmitted: u64,
+    /// Messages removed by the ID filters or of a kind that can't be transmitted (error
+    /// frames and error counters).
+    pub skipped: u64,
+    /// Completed passes over the messages.
+    pub loops: u32,
+    /// How late the latest message was handed over compared to its schedule.
+    pub max_jitter: Duration,
+    pub mean_jitter: Duration,
+    /// Time from the start of the run until it returned, including pauses.
+    pub elapsed: Duration,
+}
+
+/// Transmits timestamped messages with their original spacing.
+pub struct Replay<C: Clock = SystemClock> {
+    clock: C,
+    speed: f64,
+    loops: Option<u32>,
+    resolution: u64,
+    netids: HashMap<neonetid_t, neonetid_t>,
+    include: Vec<RangeInclusive<u32>>,
+    exclude: Vec<RangeInclusive<u32>>,
+    control: ReplayControl,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock::new())
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> Replay<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            speed: 1.0,
+            loops: Some(1),
+            resolution: 1,
+            netids: HashMap::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            control: ReplayControl::default(),
+        }
+    }
+
+    /// Playback speed, 2.0 replays twice as fast as recorded. `f64::INFINITY` transmits
+    /// without waiting. Defaults to 1.0.
+    ///
+    /// At very low speeds [run](Self::run) fails once a message would be due too far out.
+    pub fn set_speed(&mut self, speed: f64) -> Result<()> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid replay speed {speed}"
+            )));
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Number of passes over the messages, `None` loops until [stopped](ReplayControl::stop).
+    /// Defaults to 1. Each pass starts right after the last message of the previous one.
+    ///
+    /// Messages of the first pass are kept in memory for the following ones.
+    pub fn set_loop_count(&mut self, loops: Option<u32>) {
+        self.loops = loops;
+    }
+
+    /// Nanoseconds per message timestamp tick. Defaults to 1, which fits messages from
+    /// libicsneo and the [log](crate::log) readers, see [Timestamps](crate::log#timestamps).
+    pub fn set_timestamp_resolution(&mut self, resolution: u16) {
+        self.resolution = u64::from(resolution.max(1));
+    }
+
+    /// Transmits messages recorded on `from` on `to` instead. Unmapped netids are kept.
+    pub fn map_netid(&mut self, from: impl Into<NetworkId>, to: impl Into<NetworkId>) {
+        self.netids.insert(from.into().0, to.into().0);
+    }
+
+    /// Only replays CAN frames with an arbitration ID in one of the included ranges. Without
+    /// any included range all IDs are replayed. Ethernet frames aren't affected.
+    pub fn include_ids(&mut self, ids: RangeInclusive<u32>) {
+        self.include.push(ids);
+    }
+
+    /// Skips CAN frames with an arbitration ID in `ids`, even if they are also included.
+    pub fn exclude_ids(&mut self, ids: RangeInclusive<u32>) {
+        self.exclude.push(ids);
+    }
+
+    /// Returns a handle to pause, resume or stop [run](Replay::run) from another thread.
+    pub fn control(&self) -> ReplayControl {
+        self.control.clone()
+    }
+
+    /// Transmits `messages` on `sink` until they, and any further loops, are done or the
+    /// replay is [stopped](ReplayControl::stop). The first message is transmitted right away.
+    ///
+    /// Read errors and transmit errors end the replay.
+    pub fn run<I, T>(&mut self, messages: I, sink: &mut T) -> Result<ReplayReport>
+    where
+        I: IntoIterator<Item = Result<Message>>,
+        T: Transmit + ?Sized,
+    {
+        self.control.stopped.store(false, Ordering::SeqCst);
+        let mut run = Run {
+            start: self.clock.now(),
+            shift: Duration::ZERO,
+            jitter_sum: Duration::ZERO,
+            report: ReplayReport::default(),
+        };
+        let mut history = Vec::new();
+        let keep = self.loops != Some(1);
+        let mut base = run.start;
+
+        let first = messages.into_iter().map(|message| self.prepare(message?));
+        let first = first.inspect(|message| {
+            if let (true, Ok(Some(message))) = (keep, message) {
+                history.push(message.clone());
+            }
+        });
+        match self.play(first, base, sink, &mut run)? {
+            Some(end) => base = end,
+            None => return Ok(run.finish(&self.clock)),
+        }
+        run.report.loops = 1;
+
+        let skipped = run.report.skipped;
+        while self.loops.is_none_or(|loops| run.report.loops < loops) && !history.is_empty() {
+            let messages = history.iter().cloned().map(|message| Ok(Some(message)));
+            match self.play(messages, base, sink, &mut run)? {
+                Some(end) => base = end,
+                None => break,
+            }
+            run.report.skipped += skipped;
+            run.report.loops += 1;
+        }
+        Ok(run.finish(&self.clock))
+    }
+
+    /// Applies the filters and netid mapping. Returns `None` for messages to skip.
+    fn prepare(&self, message: Message) -> Result<Option<Message>> {
+        let mut message = match message {
+            Message::Can(m) if m.is_error_frame() => None,
+            Message::Can(m) => {
+                let arbid = m.arbid;
+                let included =
+                    self.include.is_empty() || self.include.iter().any(|ids| ids.contains(&arbid));
+                let excluded = self.exclude.iter().any(|ids| ids.contains(&arbid));
+                (included && !excluded).then_some(Message::Can(m))
+            }
+            Message::CanError(_) => None,
+            Message::Eth(m) => Some(Message::Eth(m)),
+        };
+        match &mut message {
+            Some(Message::Can(m)) => m.set_netid(self.netid(m.netid)),
+            Some(Message::Eth(m)) => m.set_netid(self.netid(m.netid)),
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    fn netid(&self, netid: neonetid_t) -> neonetid_t {
+        self.netids.get(&netid).copied().unwrap_or(netid)
+    }
+
+    /// Plays one pass with its first message at `base`. Returns the deadline of the last
+    /// message, or `None` if the replay was stopped.
+    fn play<I, T>(
+        &self,
+        messages: I,
+        base: Duration,
+        sink: &mut T,
+        run: &mut Run,
+    ) -> Result<Option<Duration>>
+    where
+        I: Iterator<Item = Result<Option<Message>>>,
+        T: Transmit + ?Sized,
+    {
+        let mut schedule = Schedule {
+            messages,
+            base,
+            origin: None,
+            resolution: self.resolution,
+            speed: self.speed,
+            skipped: 0,
+        };
+        let mut deadline = base;
+        let mut pending = None;
+        let mut batch = Vec::new();
+        let mut lateness = Vec::new();
+        let result = loop {
+            let next = match pending.take() {
+                Some(next) => next,
+                None => match schedule.next()? {
+                    Some(next) => next,
+                    None => break Some(deadline),
+                },
+            };
+            let message;
+            (deadline, message) = next;
+            if !self.wait_until(deadline, run) {
+                break None;
+            }
+            // Everything else that is already due goes out in the same call.
+            let now = self.clock.now();
+            batch.push(message);
+            lateness.push(now.saturating_sub(deadline + run.shift));
+            while let Some((due, message)) = schedule.next()? {
+                if due + run.shift > now {
+                    pending = Some((due, message));
+                    break;
+                }
+                deadline = due;
+                batch.push(message);
+                lateness.push(now.saturating_sub(due + run.shift));
+            }
+            if batch.len() == 1 {
+                sink.transmit(&batch[0])?;
+            } else {
+                sink.transmit_messages(&batch)?;
+            }
+            lateness.drain(..).for_each(|lateness| run.record(lateness));
+            batch.clear();
+        };
+        run.report.skipped += schedule.skipped;
+        Ok(result)
+    }
+
+    /// Sleeps until `deadline` plus the time spent paused so far. Returns false if the
+    /// replay was stopped.
+    fn wait_until(&self, deadline: Duration, run: &mut Run) -> bool {
+        loop {
+            if self.control.is_stopped() {
+                return false;
+            }
+            if self.control.is_paused() {
+                let paused = self.clock.now();
+                while self.control.is_paused() && !self.control.is_stopped() {
+                    self.clock.sleep_until(self.clock.now() + POLL_INTERVAL);
+                }
+                run.shift += self.clock.now() - paused;
+                continue;
+            }
+            let now = self.clock.now();
+            let deadline = deadline + run.shift;
+            if now >= deadline {
+                return true;
+            }
+            // Wake up periodically so pause and stop take effect during long gaps.
+            self.clock.sleep_until(deadline.min(now + POLL_INTERVAL));
+        }
+    }
+}
+
+/// Assigns deadlines to the messages of one pass.
+struct Schedule<I> {
+    messages: I,
+    base: Duration,
+    origin: Option<u64>,
+    resolution: u64,
+    speed: f64,
+    skipped: u64,
+}
+
+impl<I: Iterator<Item = Result<Option<Message>>>> Schedule<I> {
+    fn next(&mut self) -> Result<Option<(Duration, Message)>> {
+        for message in self.messages.by_ref() {
+            let Some(message) = message? else {
+                self.skipped += 1;
+                continue;
+            };
+            let ns = crate::log::ticks_to_nanos(message.timestamp(), self.resolution)?;
+            let offset = ns.saturating_sub(*self.origin.get_or_insert(ns));
+            let offset =
+                Duration::try_from_secs_f64(offset as f64 / 1e9 / self.speed).map_err(|_| {
+                    Error::InvalidArgument(format!(
+                        "Replay offset of {offset} ns overflows at speed {}",
+                        self.speed
+                    ))
+                })?;
+            let deadline = self.base.checked_add(offset).ok_or_else(|| {
+                Error::InvalidArgument(format!("Replay deadline overflows at {offset:?}"))
+            })?;
+            return Ok(Some((deadline, message)));
+        }
+        Ok(None)
+    }
+}
+
+/// State of one [run](Replay::run).
+struct Run {
+    start: Duration,
+    /// Total time spent paused, added to every deadline.
+    shift: Duration,
+    jitter_sum: Duration,
+    report: ReplayReport,
+}
+
+impl Run {
+    fn record(&mut self, lateness: Duration) {
+        self.report.transmitted += 1;
+        self.report.max_jitter = self.report.max_jitter.max(lateness);
+        self.jitter_sum += lateness;
+    }
+
+    fn finish<C: Clock>(mut self, clock: &C) -> ReplayReport {
+        if self.report.transmitted > 0 {
+            let mean = self.jitter_sum.as_nanos() / u128::from(self.report.transmitted);
+            self.report.mean_jitter = Duration::from_nanos(mean as u64);
+        }
+        self.report.elapsed = clock.now() - self.start;
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MockSink;
+    use crate::clock::ManualClock;
+
+    const MS: u64 = 1_000_000;
+
+    fn can(netid: neonetid_t, arbid: u32, timestamp: u64) -> Result<Message> {
+        let mut m = CanMessage::new(netid, arbid, &[1, 2, 3]);
+        m.set_timestamp(timestamp);
+        Ok(Message::Can(m))
+    }
+
+    fn times(sink: &MockSink<ManualClock>) -> Vec<u64> {
+        let start = sink.messages[0].0;
+        sink.messages
+            .iter()
+            .map(|(time, _)| (*time - start).as_nanos() as u64 / MS)
+            .collect()
+    }
+
+    fn arbids(sink: &MockSink<ManualClock>) -> Vec<u32> {
+        sink.messages
+            .iter()
+            .map(|(_, message)| match message {
+                Message::Can(m) => m.arbid,
+                _ => 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_timing_and_speed() {
+        let clock = ManualClock::new();
+        clock.set(Duration::from_secs(5));
+        let mut sink = MockSink::new(clock.clone());
+        let mut replay = Replay::with_clock(clock.clone());
+        let messages = || {
+            vec![
+                can(1, 0x100, 1000 * MS),
+                can(1, 0x101, 1010 * MS),
+                can(1, 0x102, 1035 * MS),
+                can(1, 0x103, 1100 * MS),
+            ]
+        };
+        let report = replay.run(messages(), &mut sink).unwrap();
+        assert_eq!(times(&sink), [0, 10, 35, 100]);
+        assert_eq!(report.transmitted, 4);
+        assert_eq!(report.loops, 1);
+        assert_eq!(report.max_jitter, Duration::ZERO);
+        assert_eq!(report.elapsed, Duration::from_millis(100));
+
+        let mut sink = MockSink::new(clock.clone());
+        replay.set_speed(2.0).unwrap();
+        replay.run(messages(), &mut sink).unwrap();
+        assert_eq!(times(&sink), [0, 5, 17, 50]);
+
+        let mut sink = MockSink::new(clock.clone());
+        replay.set_speed(f64::INFINITY).unwrap();
+        replay.run(messages(), &mut sink).unwrap();
+        assert_eq!(times(&sink), [0, 0, 0, 0]);
+        assert_eq!(sink.batches, 1);
+
+        // Due too far out to fit a Duration.
+        let mut sink = MockSink::new(clock.clone());
+        replay.set_speed(1e-300).unwrap();
+        assert!(matches!(
+            replay.run(messages(), &mut sink),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert!(replay.set_speed(0.0).is_err());
+        assert!(replay.set_speed(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_resolution_and_batches() {
+        let clock = ManualClock::new();
+        let mut sink = MockSink::new(clock.clone());
+        let mut replay = Replay::with_clock(clock.clone());
+        replay.set_timestamp_resolution(25);
+        let tick = MS / 25;
+        let messages = vec![
+            can(1, 0x100, 0),
+            can(1, 0x101, 4 * tick),
+            can(1, 0x102, 4 * tick),
+            can(1, 0x103, 8 * tick),
+        ];
+        replay.run(messages, &mut sink).unwrap();
+        assert_eq!(times(&sink), [0, 4, 4, 8]);
+        assert_eq!(sink.batches, 1);
+    }
+
+    #[test]
+    fn test_deadline_overflow() {
+        let clock = ManualClock::new();
+        let mut sink = MockSink::new(clock.clone());
+        let mut replay = Replay::with_clock(clock.clone());
+        replay.set_timestamp_resolution(25);
+        assert!(matches!(
+            replay.run(vec![can(1, 0x100, u64::MAX)], &mut sink),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let mut schedule = Schedule {
+            messages: [can(1, 0x100, 0), can(1, 0x101, MS)]
+                .into_iter()
+                .map(|message| message.map(Some)),
+            base: Duration::MAX - Duration::from_nanos(MS / 2),
+            origin: None,
+            resolution: 1,
+            speed: 1.0,
+            skipped: 0,
+        };
+        assert!(schedule.next().unwrap().is_some());
+        assert!(matches!(schedule.next(), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_filters_and_netids() {
+        let clock = ManualClock::new();
+        let mut sink = MockSink::new(clock.clone());
+        let mut replay = Replay::with_clock(clock.clone());
+        replay.include_ids(0x100..=0x1ff);
+        replay.include_ids(0x700..=0x7ff);
+        replay.exclude_ids(0x7df..=0x7df);
+        replay.map_netid(NetworkId::HSCAN, NetworkId::HSCAN2);
+
+        let mut error_frame = CanMessage::new(1, 0x100, &[]);
+        error_frame.set_error_frame(true);
+        let messages = vec![
+            can(1, 0x100, 0),
+            can(1, 0x200, MS),
+            can(44, 0x7e0, 2 * MS),
+            can(1, 0x7df, 3 * MS),
+            Ok(Message::Can(error_frame)),
+            Ok(Message::CanError(NeoMessageCanError::new())),
+            Ok(Message::Eth(EthMessage::new(1, &[0xff; 14]))),
+        ];
+        let report = replay.run(messages, &mut sink).unwrap();
+        assert_eq!(report.transmitted, 3);
+        assert_eq!(report.skipped, 4);
+        assert_eq!(arbids(&sink), [0x100, 0x7e0, 0]);
+        let netids: Vec<_> = sink.messages.iter().map(|(_, m)| m.netid()).collect();
+        assert_eq!(netids, [42, 44, 42]);
+    }
+
+    #[test]
+    fn test_loops() {
+        let clock = ManualClock::new();
+        let mut sink = MockSink::new(clock.clone());
+        let mut replay = Replay::with_clock(clock.clone());
+        replay.set_loop_count(Some(3));
+        replay.exclude_ids(0x1ff..=0x1ff);
+        let messages = vec![
+            can(1, 0x100, 10 * MS),
+            can(1, 0x1ff, 15 * MS),
+            can(1, 0x101, 30 * MS),
+        ];
+        let report = replay.run(messages, &mut sink).unwrap();
+        assert_eq!(report.loops, 3);
+        assert_eq!(report.transmitted, 6);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(times(&sink), [0, 20, 20, 40, 40, 60]);
+        assert_eq!(arbids(&sink), [0x100, 0x101, 0x100, 0x101, 0x100, 0x101]);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let clock = ManualClock::new();
+        let mut sink = MockSink::new(clock.clone());
+        sink.set_latency(Duration::from_millis(3));
+        let mut replay = Replay::with_clock(clock.clone());
+        let messages = vec![
+            can(1, 0x100, 0),
+            can(1, 0x101, 2 * MS),
+            can(1, 0x102, 10 * MS),
+            can(1, 0x103, 11 * MS),
+        ];
+        let report = replay.run(messages, &mut sink).unwrap();
+        // The second frame is 1 ms late, the fourth 2 ms. Deadlines don't drift.
+        assert_eq!(times(&sink), [0, 3, 10, 13]);
+        assert_eq!(report.max_jitter, Duration::from_millis(2));
+        assert_eq!(report.mean_jitter, Duration::from_micros(750));
+        assert_eq!(sink.batches, 0);
+    }
+
+    /// Pauses the replay after a number of messages and resumes it from another thread.
+    struct PausingSink {
+        sink: MockSink<ManualClock>,
+        control: ReplayControl,
+        pause_after: usize,
+    }
+
+    impl Transmit for PausingSink {
+        fn transmit(&mut self, message: &Message) -> Result<()> {
+            self.sink.transmit(message)?;
+            if self.sink.messages.len() == self.pause_after {
+                self.control.pause();
+                let control = self.control.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(20));
+                    control.resume();
+                });
+            }
+            Ok(())
+        }
+    }
+
+    struct StoppingSink {
+        sink: MockSink<ManualClock>,
+        control: ReplayControl,
+    }
+
+    impl Transmit for StoppingSink {
+        fn transmit(&mut self, message: &Message) -> Result<()> {
+            self.sink.transmit(message)?;
+            if self.sink.messages.len() == 5 {
+                self.control.stop();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pause_and_stop() {
+        let clock = ManualClock::new();
+        let mut replay = Replay::with_clock(clock.clone());
+        let mut sink = PausingSink {
+            sink: MockSink::new(clock.clone()),
+            control: replay.control(),
+            pause_after: 2,
+        };
+        let messages = (0..4).map(|i| can(1, 0x100 + i, u64::from(i) * 10 * MS));
+        let report = replay.run(messages, &mut sink).unwrap();
+        assert_eq!(report.transmitted, 4);
+        assert_eq!(report.max_jitter, Duration::ZERO);
+        let times = times(&sink.sink);
+        assert_eq!(times[1], 10);
+        // The schedule continues with its original spacing after the pause.
+        assert!(times[2] > 20);
+        assert_eq!(times[3] - times[2], 10);
+        assert!(report.elapsed > Duration::from_millis(30));
+
+        // Stopping from the sink after five messages of an endless loop.
+        replay.set_loop_count(None);
+        let mut sink = StoppingSink {
+            sink: MockSink::new(clock.clone()),
+            control: replay.control(),
+        };
+        let messages = vec![can(1, 0x100, 0), can(1, 0x101, MS)];
+        let report = replay.run(messages, &mut sink).unwrap();
+        assert_eq!(report.transmitted, 5);
+        assert_eq!(report.loops, 2);
+        assert!(replay.control().is_stopped());
+    }
+}
@@ -0,0 +1,554 @@
+//! Periodic transmission of messages, e.g. for rest-bus simulation.
+//!
+//! A [Scheduler] holds cyclic jobs and transmits them from [run](Scheduler::run). Clones share
+//! the same jobs, so they can be added, changed and removed from other threads while it runs:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::message::{CanMessage, Message};
+//! use icsneo::network::NetworkId;
+//! use icsneo::scheduler::{CyclicJob, Scheduler};
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//!
+//! let scheduler = Scheduler::new();
+//! let frame = CanMessage::new(NetworkId::HSCAN.0, 0x100, &[0; 8]);
+//! let engine = scheduler
+//!     .add(CyclicJob::new(Message::Can(frame), Duration::from_millis(10)))
+//!     .unwrap();
+//! let mut job = CyclicJob::new(
+//!     Message::Can(CanMessage::new(NetworkId::HSCAN.0, 0x200, &[0; 8])),
+//!     Duration::from_millis(100),
+//! );
+//! job.offset = Duration::from_millis(5);
+//! scheduler.add(job).unwrap();
+//!
+//! let running = scheduler.spawn(device);
+//! std::thread::sleep(Duration::from_secs(1));
+//! scheduler.update_payload(engine, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+//! std::thread::sleep(Duration::from_secs(1));
+//! println!("{:?}", scheduler.stats(engine).unwrap());
+//! scheduler.stop();
+//! running.join().unwrap().unwrap();
+//! ```
+//! Deadlines are multiples of the period from the first transmit, so late transmits don't
+//! accumulate into drift. Cycles that are missed entirely are skipped rather than caught up.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::bus::Transmit;
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Longest a running scheduler sleeps, so new jobs and [stop](Scheduler::stop) take effect
+/// during long periods.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Identifies a job of a [Scheduler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A message transmitted every `period`.
+#[derive(Debug, Clone)]
+pub struct CyclicJob {
+    pub message: Message,
+    pub period: Duration,
+    /// Delay of the first transmit, to spread jobs of the same period over the cycle.
+    pub offset: Duration,
+    /// Copies of the message transmitted together each cycle.
+    pub burst: u32,
+}
+
+impl CyclicJob {
+    /// A job without offset transmitting one message per cycle.
+    pub fn new(message: Message, period: Duration) -> Self {
+        Self {
+            message,
+            period,
+            offset: Duration::ZERO,
+            burst: 1,
+        }
+    }
+}
+
+/// Achieved timing of a job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeriodStats {
+    /// Cycles transmitted.
+    pub cycles: u64,
+    /// Cycles skipped because the scheduler was late by more than a period.
+    pub missed: u64,
+    /// Shortest, longest and mean time between consecutive cycles.
+    pub min_period: Duration,
+    pub max_period: Duration,
+    pub mean_period: Duration,
+    /// How late a cycle was handed over compared to its deadline, at most.
+    pub max_jitter: Duration,
+}
+
+struct Entry {
+    job: CyclicJob,
+    /// Next deadline, `None` until the job is first scheduled.
+    next: Option<Duration>,
+    /// Time and deadline of the previous cycle.
+    last: Option<(Duration, Duration)>,
+    stats: PeriodStats,
+    period_sum: Duration,
+}
+
+#[derive(Default)]
+struct Jobs {
+    entries: BTreeMap<JobId, Entry>,
+    next_id: u64,
+}
+
+/// Transmits [CyclicJob]s with drift-free timing.
+#[derive(Clone)]
+pub struct Scheduler<C: Clock = SystemClock> {
+    clock: C,
+    jobs: Arc<Mutex<Jobs>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock::new())
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Adds a job. Its first cycle is `offset` after the scheduler next checks its jobs,
+    /// right away if it's running.
+    pub fn add(&self, job: CyclicJob) -> Result<JobId> {
+        check_period(job.period)?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = JobId(jobs.next_id);
+        jobs.next_id += 1;
+        jobs.entries.insert(
+            id,
+            Entry {
+                job,
+                next: None,
+                last: None,
+                stats: PeriodStats::default(),
+                period_sum: Duration::ZERO,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Removes a job, returning it if it existed.
+    pub fn remove(&self, id: JobId) -> Option<CyclicJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.entries.remove(&id).map(|entry| entry.job)
+    }
+
+    /// Removes all jobs.
+    pub fn clear(&self) {
+        self.jobs.lock().unwrap().entries.clear();
+    }
+
+    pub fn jobs(&self) -> Vec<JobId> {
+        self.jobs.lock().unwrap().entries.keys().copied().collect()
+    }
+
+    /// Returns a copy of a job.
+    pub fn job(&self, id: JobId) -> Option<CyclicJob> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.entries.get(&id).map(|entry| entry.job.clone())
+    }
+
+    /// Replaces the payload of a job's message from the next cycle on. The timing is kept.
+    pub fn update_payload(&self, id: JobId, data: &[u8]) -> Result<()> {
+        self.update(id, |job| match &mut job.message {
+            Message::Can(m) => m.set_data(data),
+            Message::Eth(m) => m.set_data(data),
+            Message::CanError(_) => {}
+        })
+    }
+
+    /// Replaces a job's message from the next cycle on. The timing is kept.
+    pub fn update_message(&self, id: JobId, message: Message) -> Result<()> {
+        self.update(id, |job| job.message = message)
+    }
+
+    /// Changes a job's period. The next cycle is one new period after the previous one.
+    pub fn set_period(&self, id: JobId, period: Duration) -> Result<()> {
+        check_period(period)?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let entry = jobs.entries.get_mut(&id).ok_or_else(|| unknown(id))?;
+        entry.job.period = period;
+        if let Some((_, deadline)) = entry.last {
+            entry.next = Some(deadline + period);
+        }
+        Ok(())
+    }
+
+    fn update(&self, id: JobId, f: impl FnOnce(&mut CyclicJob)) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let entry = jobs.entries.get_mut(&id).ok_or_else(|| unknown(id))?;
+        f(&mut entry.job);
+        Ok(())
+    }
+
+    pub fn stats(&self, id: JobId) -> Option<PeriodStats> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.entries.get(&id).map(|entry| entry.stats)
+    }
+
+    /// Ends [run](Self::run) on all clones of this scheduler.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Transmits the jobs on `sink` until [stopped](Self::stop). Messages due at the same time
+    /// are handed over together with [transmit_messages](Transmit::transmit_messages).
+    ///
+    /// Transmit errors end the run.
+    pub fn run<T: Transmit + ?Sized>(&self, sink: &mut T) -> Result<()> {
+        self.stopped.store(false, Ordering::SeqCst);
+        self.run_until(sink, None)
+    }
+
+    /// Like [run](Self::run), but returns after `duration` at the latest.
+    pub fn run_for<T: Transmit + ?Sized>(&self, sink: &mut T, duration: Duration) -> Result<()> {
+        self.stopped.store(false, Ordering::SeqCst);
+        self.run_until(sink, Some(self.clock.now() + duration))
+    }
+
+    fn run_until<T: Transmit + ?Sized>(&self, sink: &mut T, end: Option<Duration>) -> Result<()> {
+        let start = self.clock.now();
+        // Cycles that passed while the scheduler wasn't running don't count as missed.
+        for entry in self.jobs.lock().unwrap().entries.values_mut() {
+            if let Some(next) = entry.next.filter(|next| *next < start) {
+                let period = entry.job.period.as_nanos();
+                let behind = (start - next).as_nanos() % period;
+                entry.next = Some(match behind {
+                    0 => start,
+                    _ => start + Duration::from_nanos((period - behind) as u64),
+                });
+            }
+        }
+        let mut batch = Vec::new();
+        while !self.stopped.load(Ordering::SeqCst) {
+            let now = self.clock.now();
+            if end.is_some_and(|end| now >= end) {
+                break;
+            }
+            let mut wake = now + POLL_INTERVAL;
+            for entry in self.jobs.lock().unwrap().entries.values_mut() {
+                let next = *entry.next.get_or_insert(now + entry.job.offset);
+                if next <= now {
+                    for _ in 0..entry.job.burst {
+                        batch.push(entry.job.message.clone());
+                    }
+                    entry.record(now, next);
+                }
+                wake = wake.min(entry.next.unwrap_or(wake));
+            }
+            match batch.len() {
+                0 => {}
+                1 => sink.transmit(&batch[0])?,
+                _ => sink.transmit_messages(&batch)?,
+            }
+            batch.clear();
+            self.clock
+                .sleep_until(end.map_or(wake, |end| wake.min(end)));
+        }
+        Ok(())
+    }
+}
+
+impl<C: Clock + Clone + Send + 'static> Scheduler<C> {
+    /// Runs the scheduler on a new thread until [stopped](Self::stop). The thread returns the
+    /// sink, or the error that ended the run.
+    pub fn spawn<T: Transmit + Send + 'static>(&self, mut sink: T) -> JoinHandle<Result<T>> {
+        let scheduler = self.clone();
+        // Cleared here rather than on the thread, so a stop right after spawning isn't lost.
+        self.stopped.store(false, Ordering::SeqCst);
+        std::thread::spawn(move || scheduler.run_until(&mut sink, None).map(|_| sink))
+    }
+}
+
+impl Entry {
+    /// Records a cycle due at `deadline` transmitted at `now` and schedules the next one.
+    fn record(&mut self, now: Duration, deadline: Duration) {
+        let stats = &mut self.stats;
+        if let Some((last, _)) = self.last {
+            let period = now - last;
+            stats.min_period = match stats.cycles {
+                1 => period,
+                _ => stats.min_period.min(period),
+            };
+            stats.max_period = stats.max_period.max(period);
+            self.period_sum += period;
+            stats.mean_period = Duration::from_nanos(
+                (self.period_sum.as_nanos() / u128::from(stats.cycles)) as u64,
+            );
+        }
+        stats.cycles += 1;
+        stats.max_jitter = stats.max_jitter.max(now - deadline);
+        self.last = Some((now, deadline));
+
+        let period = self.job.period;
+        let mut next = deadline + period;
+        if next <= now {
+            // The first deadline after now.
+            let behind = (now - next).as_nanos();
+            let missed = behind / period.as_nanos() + 1;
+            stats.missed = stats
+                .missed
+                .saturating_add(u64::try_from(missed).unwrap_or(u64::MAX));
+            next = now + period - Duration::from_nanos((behind % period.as_nanos()) as u64);
+        }
+        self.next = Some(next);
+    }
+}
+
+fn check_period(period: Duration) -> Result<()> {
+    match period.is_zero() {
+        true => Err(Error::InvalidArgument(
+            "Cyclic job period must not be zero".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn unknown(id: JobId) -> Error {
+    Error::InvalidArgument(format!("No cyclic job {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MockSink;
+    use crate::clock::ManualClock;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn job(arbid: u32, period: Duration) -> CyclicJob {
+        CyclicJob::new(
+            Message::Can(CanMessage::new(1, arbid, &[arbid as u8])),
+            period,
+        )
+    }
+
+    /// Transmit times in ms and arbitration IDs.
+    fn sent<C: Clock>(sink: &MockSink<C>) -> Vec<(u64, u32)> {
+        sink.messages
+            .iter()
+            .map(|(time, message)| match message {
+                Message::Can(m) => (time.as_millis() as u64, m.arbid),
+                _ => panic!("unexpected {message:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_drift_free() {
+        let clock = ManualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let mut sink = MockSink::new(clock.clone());
+        // Slow transmits don't delay the following cycles.
+        sink.set_latency(3 * MS);
+        let id = scheduler.add(job(0x100, 10 * MS)).unwrap();
+        scheduler.run_for(&mut sink, 100 * MS).unwrap();
+
+        let times: Vec<_> = sent(&sink).iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, (0..10).map(|i| i * 10).collect::<Vec<_>>());
+        assert_eq!(
+            scheduler.stats(id).unwrap(),
+            PeriodStats {
+                cycles: 10,
+                missed: 0,
+                min_period: 10 * MS,
+                max_period: 10 * MS,
+                mean_period: 10 * MS,
+                max_jitter: Duration::ZERO,
+            }
+        );
+        assert_eq!(clock.now(), 100 * MS);
+    }
+
+    #[test]
+    fn test_offsets_and_bursts() {
+        let clock = ManualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let mut sink = MockSink::new(clock.clone());
+        scheduler.add(job(0x100, 10 * MS)).unwrap();
+        scheduler.add(job(0x200, 20 * MS)).unwrap();
+        let mut burst = job(0x300, 20 * MS);
+        burst.offset = 5 * MS;
+        burst.burst = 3;
+        scheduler.add(burst).unwrap();
+        scheduler.run_for(&mut sink, 40 * MS).unwrap();
+
+        let b = |time| [(time, 0x300); 3];
+        let mut expected = vec![(0, 0x100), (0, 0x200)];
+        expected.extend(b(5));
+        expected.extend([(10, 0x100), (20, 0x100), (20, 0x200)]);
+        expected.extend(b(25));
+        expected.push((30, 0x100));
+        assert_eq!(sent(&sink), expected);
+        // Messages due together are handed over together.
+        assert_eq!(sink.batches, 4);
+    }
+
+    #[test]
+    fn test_missed_cycles() {
+        let clock = ManualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let mut sink = MockSink::new(clock.clone());
+        sink.set_latency(25 * MS);
+        let id = scheduler.add(job(0x100, 10 * MS)).unwrap();
+        scheduler.run_for(&mut sink, 60 * MS).unwrap();
+
+        // Late cycles go out as soon as possible, the ones after them are skipped.
+        let times: Vec<_> = sent(&sink).iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [0, 25, 50]);
+        let stats = scheduler.stats(id).unwrap();
+        assert_eq!((stats.cycles, stats.missed), (3, 3));
+        assert_eq!(stats.max_jitter, 20 * MS);
+        assert_eq!((stats.min_period, stats.max_period), (25 * MS, 25 * MS));
+
+        // Billions of missed cycles are counted.
+        let scheduler = Scheduler::with_clock(clock.clone());
+        sink.set_latency(Duration::from_secs(5));
+        let id = scheduler.add(job(0x100, Duration::from_nanos(1))).unwrap();
+        scheduler
+            .run_for(&mut sink, Duration::from_secs(6))
+            .unwrap();
+        assert_eq!(scheduler.stats(id).unwrap().missed, 4_999_999_999);
+
+        assert!(matches!(
+            scheduler.add(job(0x100, Duration::ZERO)),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            scheduler.set_period(id, Duration::ZERO),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_mean_period_after_many_cycles() {
+        // 2^32 cycles at 1 ms, about 50 days.
+        let mut entry = Entry {
+            job: job(0x100, MS),
+            next: None,
+            last: Some((Duration::ZERO, Duration::ZERO)),
+            stats: PeriodStats {
+                cycles: 1 << 32,
+                ..PeriodStats::default()
+            },
+            period_sum: MS * u32::MAX,
+        };
+        entry.record(MS, MS);
+        assert_eq!(entry.stats.mean_period, MS);
+        assert_eq!(entry.stats.cycles, (1 << 32) + 1);
+    }
+
+    #[test]
+    fn test_update_and_remove() {
+        let clock = ManualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let mut sink = MockSink::new(clock.clone());
+        let id = scheduler.add(job(0x100, 10 * MS)).unwrap();
+        let other = scheduler.add(job(0x200, 10 * MS)).unwrap();
+        assert_eq!(scheduler.jobs(), [id, other]);
+        scheduler.run_for(&mut sink, 20 * MS).unwrap();
+
+        scheduler.update_payload(id, &[1, 2, 3]).unwrap();
+        assert_eq!(scheduler.remove(other).unwrap().period, 10 * MS);
+        assert!(scheduler.remove(other).is_none());
+        assert!(matches!(
+            scheduler.update_payload(other, &[]),
+            Err(Error::InvalidArgument(_))
+        ));
+        // The next cycle was due at 20 ms, the new period counts from the one at 10 ms. The
+        // run ends right before the one at 60 ms.
+        scheduler.set_period(id, 25 * MS).unwrap();
+        scheduler.run_for(&mut sink, 40 * MS).unwrap();
+        assert_eq!(
+            sent(&sink),
+            [
+                (0, 0x100),
+                (0, 0x200),
+                (10, 0x100),
+                (10, 0x200),
+                (35, 0x100)
+            ]
+        );
+        let Message::Can(m) = &sink.messages.last().unwrap().1 else {
+            unreachable!()
+        };
+        assert_eq!(m.data(), [1, 2, 3]);
+
+        // Time while not running isn't counted as missed cycles.
+        clock.advance(Duration::from_secs(1));
+        sink.messages.clear();
+        scheduler.run_for(&mut sink, 30 * MS).unwrap();
+        assert_eq!(sent(&sink), [(1060, 0x100), (1085, 0x100)]);
+        assert_eq!(scheduler.stats(id).unwrap().missed, 0);
+
+        scheduler.clear();
+        sink.messages.clear();
+        scheduler.run_for(&mut sink, 30 * MS).unwrap();
+        assert!(sink.messages.is_empty());
+    }
+
+    #[test]
+    fn test_spawn() {
+        let scheduler = Scheduler::new();
+        let id = scheduler.add(job(0x100, 5 * MS)).unwrap();
+        let running = scheduler.spawn(MockSink::new(SystemClock::new()));
+        std::thread::sleep(30 * MS);
+        scheduler.update_payload(id, &[0xaa]).unwrap();
+        let added = scheduler.add(job(0x200, 5 * MS)).unwrap();
+        std::thread::sleep(30 * MS);
+        scheduler.stop();
+        let sink = running.join().unwrap().unwrap();
+
+        let payloads: Vec<_> = sink
+            .messages
+            .iter()
+            .filter_map(|(_, message)| match message {
+                Message::Can(m) if m.arbid == 0x100 => Some(m.data()[0]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(payloads[0], 0x00);
+        assert_eq!(*payloads.last().unwrap(), 0xaa);
+        assert!(scheduler.stats(id).unwrap().cycles >= 6);
+        assert!(scheduler.stats(added).unwrap().cycles >= 2);
+    }
+}
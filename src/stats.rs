@@ -0,0 +1,612 @@
+//! Per-network traffic statistics and bus load.
+//!
+//! [BusStats] consumes received messages and keeps rolling windows of frame, byte and error
+//! frame rates per network. With the network's bitrates it also estimates the bus load from
+//! the exact length of every frame on the wire, stuff bits included:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::bus::Receive;
+//! use icsneo::network::NetworkId;
+//! use icsneo::stats::{Bitrates, BusStats};
+//!
+//! let mut device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut stats = BusStats::new(Duration::from_secs(1));
+//! let bitrates = Bitrates::from_device(&device, NetworkId::HSCAN).unwrap();
+//! stats.set_bitrates(NetworkId::HSCAN, bitrates);
+//! loop {
+//!     stats.record_all(&device.receive(Duration::from_millis(100)).unwrap());
+//!     if let Some(hscan) = stats.network(NetworkId::HSCAN) {
+//!         println!("{:.0} frames/s, {:.1}% load", hscan.frames_per_second,
+//!             hscan.bus_load.unwrap_or(0.0) * 100.0);
+//!     }
+//! }
+//! ```
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Buckets a window is divided into. Old traffic leaves the window one bucket at a time.
+const BUCKETS: u32 = 20;
+
+/// CRC delimiter, ACK slot, ACK delimiter and end of frame.
+const FRAME_TRAILER_BITS: u32 = 10;
+/// Intermission between frames.
+const INTERFRAME_BITS: u32 = 3;
+/// Error flag, the shortest one, and error delimiter.
+const ERROR_FRAME_BITS: u32 = 6 + 8;
+/// Preamble, start frame delimiter, frame check sequence and interpacket gap of an Ethernet
+/// frame, which aren't part of the received data.
+const ETHERNET_OVERHEAD_BYTES: usize = 8 + 4 + 12;
+/// Shortest Ethernet frame without frame check sequence.
+const ETHERNET_MIN_BYTES: usize = 60;
+
+/// Bitrates of a network in bit/s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bitrates {
+    /// Arbitration bitrate of CAN, or the bitrate of other networks.
+    pub nominal: u64,
+    /// Data phase bitrate of CAN FD frames with bit rate switch.
+    pub data: Option<u64>,
+}
+
+impl Bitrates {
+    /// Reads the bitrates of a network with [get_baudrate] and [get_fd_baudrate]. The data
+    /// bitrate is `None` if the network has none.
+    pub fn from_device(device: &NeoDevice, netid: impl Into<NetworkId>) -> Result<Self> {
+        let netid = netid.into();
+        let nominal = get_baudrate(device, netid.0);
+        if nominal <= 0 {
+            return Err(Error::InvalidArgument(format!(
+                "No bitrate for network {netid}"
+            )));
+        }
+        let data = get_fd_baudrate(device, netid.0);
+        Ok(Self {
+            nominal: nominal as u64,
+            data: (data > 0).then_some(data as u64),
+        })
+    }
+}
+
+/// Length of a CAN frame on the wire, split by bitrate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameBits {
+    /// Bits sent at the nominal (arbitration) bitrate, including the interframe space.
+    pub nominal: u32,
+    /// Bits sent at the data bitrate of CAN FD frames with bit rate switch.
+    pub data: u32,
+}
+
+impl FrameBits {
+    /// Bits of a CAN or CAN FD frame with the stuff bits its content causes. Error frames
+    /// count as the shortest possible error frame.
+    pub fn of(frame: &CanMessage) -> Self {
+        if frame.is_error_frame() {
+            return Self {
+                nominal: ERROR_FRAME_BITS + INTERFRAME_BITS,
+                data: 0,
+            };
+        }
+        let data = if frame.is_remote() {
+            &[][..]
+        } else {
+            frame.data()
+        };
+        let dlc = len_to_dlc(data.len());
+        let mut bits = Bits::default();
+        bits.push(false, 1); // SOF
+        let arbid = u64::from(frame.arbid);
+        if frame.is_extended() {
+            bits.push_value(arbid >> 18, 11);
+            bits.push(true, 1); // SRR
+            bits.push(true, 1); // IDE
+            bits.push_value(arbid, 18);
+        } else {
+            bits.push_value(arbid, 11);
+        }
+        if frame.is_fd() {
+            bits.push(false, 1); // RRS
+            if !frame.is_extended() {
+                bits.push(false, 1); // IDE
+            }
+            bits.push(true, 1); // FDF
+            bits.push(false, 1); // res
+            bits.push(frame.is_brs(), 1);
+            let arbitration = bits.len();
+            bits.push(frame.is_esi(), 1);
+            bits.push_value(u64::from(dlc), 4);
+            data.iter()
+                .for_each(|&byte| bits.push_value(u64::from(byte), 8));
+            // Stuff count and CRC have a fixed stuff bit before them and after every 4 bits.
+            let (crc, fixed_stuff) = if data.len() > 16 { (21, 7) } else { (17, 6) };
+            let (stuffed_arbitration, stuffed) = bits.stuffed(arbitration);
+            let data_bits = stuffed - stuffed_arbitration + 4 + crc + fixed_stuff + 1;
+            return match frame.is_brs() {
+                true => Self {
+                    nominal: stuffed_arbitration + FRAME_TRAILER_BITS - 1 + INTERFRAME_BITS,
+                    data: data_bits,
+                },
+                false => Self {
+                    nominal: stuffed_arbitration + data_bits + FRAME_TRAILER_BITS - 1
+                        + INTERFRAME_BITS,
+                    data: 0,
+                },
+            };
+        }
+        bits.push(frame.is_remote(), 1); // RTR
+        bits.push(false, 2); // IDE and r0, or r1 and r0 of extended frames
+
+        // Classic frames carry at most 8 bytes, larger DLCs still mean 8.
+        bits.push_value(u64::from(dlc.min(15)), 4);
+        data.iter()
+            .take(8)
+            .for_each(|&byte| bits.push_value(u64::from(byte), 8));
+        bits.push_value(u64::from(crc15(&bits.bits)), 15);
+        let (stuffed, _) = bits.stuffed(bits.len());
+        Self {
+            nominal: stuffed + FRAME_TRAILER_BITS + INTERFRAME_BITS,
+            data: 0,
+        }
+    }
+
+    /// Time the frame occupies the bus. Data phase bits use the nominal bitrate if there is
+    /// no data bitrate.
+    pub fn duration(&self, bitrates: &Bitrates) -> Duration {
+        let nominal = bitrates.nominal.max(1);
+        let data = bitrates.data.unwrap_or(nominal).max(1);
+        let nanos = u64::from(self.nominal) * 1_000_000_000 / nominal
+            + u64::from(self.data) * 1_000_000_000 / data;
+        Duration::from_nanos(nanos)
+    }
+}
+
+/// Unstuffed bits of the dynamically stuffed part of a frame.
+#[derive(Default)]
+struct Bits {
+    bits: Vec<bool>,
+}
+
+impl Bits {
+    fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    fn push(&mut self, bit: bool, count: usize) {
+        self.bits.extend(std::iter::repeat_n(bit, count));
+    }
+
+    /// Pushes the low `count` bits of `value`, most significant first.
+    fn push_value(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.bits.push(value >> i & 1 == 1);
+        }
+    }
+
+    /// Lengths with stuff bits of the first `split` bits and of all bits. A stuff bit is
+    /// inserted after 5 equal bits and counts towards the next run.
+    fn stuffed(&self, split: usize) -> (u32, u32) {
+        let mut length = 0;
+        let mut at_split = 0;
+        let mut run = 0;
+        let mut last = None;
+        for (i, &bit) in self.bits.iter().enumerate() {
+            if i == split {
+                at_split = length;
+            }
+            if last == Some(bit) {
+                run += 1;
+            } else {
+                last = Some(bit);
+                run = 1;
+            }
+            length += 1;
+            if run == 5 {
+                length += 1;
+                last = Some(!bit);
+                run = 1;
+            }
+        }
+        if split >= self.bits.len() {
+            at_split = length;
+        }
+        (at_split, length)
+    }
+}
+
+/// CRC of classic CAN frames.
+fn crc15(bits: &[bool]) -> u16 {
+    bits.iter().fold(0u16, |crc, &bit| {
+        let feedback = bit ^ (crc >> 14 & 1 == 1);
+        let crc = crc << 1 & 0x7fff;
+        if feedback {
+            crc ^ 0x4599
+        } else {
+            crc
+        }
+    })
+}
+
+/// Traffic of a network within a window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkStats {
+    /// Frames, including error frames.
+    pub frames: u64,
+    /// Payload bytes.
+    pub bytes: u64,
+    pub error_frames: u64,
+    pub frames_per_second: f64,
+    pub bytes_per_second: f64,
+    pub error_frames_per_second: f64,
+    /// Fraction of the time the bus was busy, `None` if the bitrates aren't known.
+    pub bus_load: Option<f64>,
+    /// Totals since the statistics were created or [reset](BusStats::reset).
+    pub total_frames: u64,
+    pub total_bytes: u64,
+    pub total_error_frames: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    /// Index of the bucket counted from the start of the statistics.
+    index: u64,
+    frames: u64,
+    bytes: u64,
+    error_frames: u64,
+    busy: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Network {
+    buckets: VecDeque<Bucket>,
+    total_frames: u64,
+    total_bytes: u64,
+    total_error_frames: u64,
+}
+
+/// Rolling traffic statistics per network.
+pub struct BusStats<C: Clock = SystemClock> {
+    clock: C,
+    window: Duration,
+    start: Duration,
+    bitrates: HashMap<neonetid_t, Bitrates>,
+    networks: BTreeMap<neonetid_t, Network>,
+}
+
+impl BusStats {
+    /// Statistics over the last `window`.
+    pub fn new(window: Duration) -> Self {
+        Self::with_clock(window, SystemClock::new())
+    }
+}
+
+impl<C: Clock> BusStats<C> {
+    pub fn with_clock(window: Duration, clock: C) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            window: window.max(Duration::from_nanos(u64::from(BUCKETS))),
+            bitrates: HashMap::new(),
+            networks: BTreeMap::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Bitrates to estimate the bus load of a network with.
+    pub fn set_bitrates(&mut self, netid: impl Into<NetworkId>, bitrates: Bitrates) {
+        self.bitrates.insert(netid.into().0, bitrates);
+    }
+
+    /// Counts a message at the current time. Transmitted messages count too, they use the
+    /// bus like any other. Error counter updates are ignored.
+    pub fn record(&mut self, message: &Message) {
+        let (netid, bytes, error, bits) = match message {
+            Message::Can(m) => (
+                m.netid,
+                if m.is_error_frame() {
+                    0
+                } else {
+                    m.data().len()
+                },
+                m.is_error_frame(),
+                Some(FrameBits::of(m)),
+            ),
+            Message::Eth(m) => (m.netid, m.data().len(), false, None),
+            Message::CanError(_) => return,
+        };
+        let busy = match (self.bitrates.get(&netid), bits) {
+            (Some(bitrates), Some(bits)) => bits.duration(bitrates),
+            (Some(bitrates), None) => {
+                let bytes = bytes.max(ETHERNET_MIN_BYTES) + ETHERNET_OVERHEAD_BYTES;
+                FrameBits {
+                    nominal: bytes as u32 * 8,
+                    data: 0,
+                }
+                .duration(bitrates)
+            }
+            (None, _) => Duration::ZERO,
+        };
+        let index = self.bucket_index();
+        let network = self.networks.entry(netid).or_default();
+        if network
+            .buckets
+            .back()
+            .is_none_or(|bucket| bucket.index != index)
+        {
+            network.buckets.push_back(Bucket {
+                index,
+                ..Bucket::default()
+            });
+        }
+        let bucket = network.buckets.back_mut().unwrap();
+        bucket.frames += 1;
+        bucket.bytes += bytes as u64;
+        bucket.error_frames += u64::from(error);
+        bucket.busy += busy;
+        network.total_frames += 1;
+        network.total_bytes += bytes as u64;
+        network.total_error_frames += u64::from(error);
+        // Buckets are dropped once they left the window.
+        let oldest = index.saturating_sub(u64::from(BUCKETS));
+        while network
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.index <= oldest)
+        {
+            network.buckets.pop_front();
+        }
+    }
+
+    pub fn record_all(&mut self, messages: &[Message]) {
+        messages.iter().for_each(|message| self.record(message));
+    }
+
+    /// Statistics of a network over the window, `None` if nothing was recorded on it.
+    pub fn network(&self, netid: impl Into<NetworkId>) -> Option<NetworkStats> {
+        let netid = netid.into().0;
+        let network = self.networks.get(&netid)?;
+        let index = self.bucket_index();
+        // The current bucket is partly over, so the window reaches into the oldest one.
+        let oldest = index.saturating_sub(u64::from(BUCKETS));
+        let mut stats = NetworkStats {
+            total_frames: network.total_frames,
+            total_bytes: network.total_bytes,
+            total_error_frames: network.total_error_frames,
+            ..NetworkStats::default()
+        };
+        let mut busy = Duration::ZERO;
+        for bucket in network
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.index > oldest)
+        {
+            stats.frames += bucket.frames;
+            stats.bytes += bucket.bytes;
+            stats.error_frames += bucket.error_frames;
+            busy += bucket.busy;
+        }
+        // Until a whole window passed, rates are over the time since the start.
+        let elapsed = (self.clock.now() - self.start).min(self.window);
+        if !elapsed.is_zero() {
+            let seconds = elapsed.as_secs_f64();
+            stats.frames_per_second = stats.frames as f64 / seconds;
+            stats.bytes_per_second = stats.bytes as f64 / seconds;
+            stats.error_frames_per_second = stats.error_frames as f64 / seconds;
+            if self.bitrates.contains_key(&netid) {
+                stats.bus_load = Some((busy.as_secs_f64() / seconds).min(1.0));
+            }
+        }
+        Some(stats)
+    }
+
+    /// Statistics of every network something was recorded on.
+    pub fn networks(&self) -> Vec<(NetworkId, NetworkStats)> {
+        self.networks
+            .keys()
+            .filter_map(|&netid| Some((NetworkId(netid), self.network(netid)?)))
+            .collect()
+    }
+
+    /// Forgets all traffic. Bitrates are kept.
+    pub fn reset(&mut self) {
+        self.networks.clear();
+        self.start = self.clock.now();
+    }
+
+    /// Index of the bucket the current time falls into. Bucket `n` ends `n` bucket widths
+    /// after the start.
+    fn bucket_index(&self) -> u64 {
+        let width = (self.window / BUCKETS).as_nanos().max(1);
+        ((self.clock.now() - self.start).as_nanos() / width) as u64 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const CLASSIC: Bitrates = Bitrates {
+        nominal: 500_000,
+        data: None,
+    };
+    const FD: Bitrates = Bitrates {
+        nominal: 500_000,
+        data: Some(2_000_000),
+    };
+
+    fn bits(bits: &[u8]) -> Bits {
+        Bits {
+            bits: bits.iter().map(|&bit| bit == 1).collect(),
+        }
+    }
+
+    #[test]
+    fn test_stuff_bits_count_towards_the_next_run() {
+        assert_eq!(bits(&[0, 0, 0, 0]).stuffed(4), (4, 4));
+        assert_eq!(bits(&[0, 0, 0, 0, 0]).stuffed(5), (6, 6));
+        assert_eq!(bits(&[0, 0, 0, 0, 0, 1, 1, 1, 1]).stuffed(5), (6, 11));
+        assert_eq!(bits(&[0, 1, 0, 1, 0, 1]).stuffed(3), (3, 6));
+    }
+
+    #[test]
+    fn test_classic_frame_bits() {
+        // 34 dominant bits from SOF to CRC get 6 stuff bits.
+        let frame = CanMessage::new(NetworkId::HSCAN.0, 0, &[]);
+        assert_eq!(
+            FrameBits::of(&frame),
+            FrameBits {
+                nominal: 53,
+                data: 0
+            }
+        );
+        assert_eq!(
+            FrameBits::of(&frame).duration(&CLASSIC),
+            Duration::from_micros(106)
+        );
+
+        // Without stuff bits a standard frame with 8 bytes is 111 bits long, the worst case
+        // adds 24.
+        for data in [[0x55; 8], [0x00; 8], [0xff; 8], [0x0f; 8]] {
+            let bits = FrameBits::of(&CanMessage::new(NetworkId::HSCAN.0, 0x7ff, &data)).nominal;
+            assert!((111..=135).contains(&bits), "{bits}");
+        }
+        // An extended frame with 8 bytes is 131 to 160 bits long.
+        let mut frame = CanMessage::new(NetworkId::HSCAN.0, 0x18daf110, &[0xaa; 8]);
+        frame.set_extended(true);
+        assert!((131..=160).contains(&FrameBits::of(&frame).nominal));
+    }
+
+    #[test]
+    fn test_fd_frame_bits() {
+        let mut frame = CanMessage::new(NetworkId::HSCAN.0, 0, &[0; 64]);
+        frame.set_fd(true);
+        frame.set_brs(true);
+        // SOF, identifier, RRS and IDE get 2 stuff bits. The data phase is ESI, DLC, 512
+        // data bits with 102 stuff bits, stuff count, CRC21, 7 fixed stuff bits and the CRC
+        // delimiter.
+        let bits = FrameBits::of(&frame);
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 31,
+                data: 652
+            }
+        );
+        assert_eq!(bits.duration(&FD), Duration::from_micros(62 + 326));
+
+        // Without bit rate switch the whole frame is sent at the nominal bitrate.
+        frame.set_brs(false);
+        assert_eq!(
+            FrameBits::of(&frame),
+            FrameBits {
+                nominal: 683,
+                data: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_rates_are_over_a_rolling_window() {
+        let clock = ManualClock::new();
+        let mut stats = BusStats::with_clock(Duration::from_secs(1), clock.clone());
+        assert_eq!(stats.network(NetworkId::HSCAN), None);
+
+        for _ in 0..10 {
+            stats.record(&Message::Can(CanMessage::new(
+                NetworkId::HSCAN.0,
+                0x100,
+                &[0; 8],
+            )));
+        }
+        let mut error = CanMessage::new(NetworkId::HSCAN.0, 0, &[]);
+        error.set_error_frame(true);
+        stats.record(&Message::Can(error));
+        stats.record(&Message::CanError(NeoMessageCanError::new()));
+
+        // Rates are over the time since the start until a whole window passed.
+        clock.set(Duration::from_millis(500));
+        let hscan = stats.network(NetworkId::HSCAN).unwrap();
+        assert_eq!((hscan.frames, hscan.bytes, hscan.error_frames), (11, 80, 1));
+        assert_eq!(hscan.frames_per_second, 22.0);
+        assert_eq!(hscan.bytes_per_second, 160.0);
+        assert_eq!(hscan.error_frames_per_second, 2.0);
+        assert_eq!(hscan.bus_load, None);
+
+        clock.set(Duration::from_millis(600));
+        stats.record(&Message::Can(CanMessage::new(
+            NetworkId::HSCAN.0,
+            0x100,
+            &[0; 4],
+        )));
+        clock.set(Duration::from_millis(1200));
+        let hscan = stats.network(NetworkId::HSCAN).unwrap();
+        assert_eq!((hscan.frames, hscan.bytes, hscan.error_frames), (1, 4, 0));
+        assert_eq!(hscan.frames_per_second, 1.0);
+        assert_eq!(
+            (
+                hscan.total_frames,
+                hscan.total_bytes,
+                hscan.total_error_frames
+            ),
+            (12, 84, 1)
+        );
+
+        clock.set(Duration::from_millis(1700));
+        assert_eq!(stats.network(NetworkId::HSCAN).unwrap().frames, 0);
+        stats.reset();
+        assert_eq!(stats.network(NetworkId::HSCAN), None);
+    }
+
+    #[test]
+    fn test_bus_load_from_bitrates() {
+        let clock = ManualClock::new();
+        let mut stats = BusStats::with_clock(Duration::from_secs(1), clock.clone());
+        stats.set_bitrates(NetworkId::HSCAN, FD);
+        stats.set_bitrates(
+            NetworkId::ETHERNET,
+            Bitrates {
+                nominal: 100_000_000,
+                data: None,
+            },
+        );
+        let mut frame = CanMessage::new(NetworkId::HSCAN.0, 0, &[0; 64]);
+        frame.set_fd(true);
+        frame.set_brs(true);
+        for i in 0..1000 {
+            clock.set(Duration::from_millis(i));
+            stats.record(&Message::Can(frame.clone()));
+            // Short frames are padded to 64 bytes, 84 with preamble and interpacket gap.
+            stats.record(&Message::Eth(EthMessage::new(
+                NetworkId::ETHERNET.0,
+                &[0; 14],
+            )));
+        }
+        // A moment later the first bucket would leave the window.
+        clock.set(Duration::from_millis(999));
+        let load = stats.network(NetworkId::HSCAN).unwrap().bus_load.unwrap();
+        assert!((load - 0.388 / 0.999).abs() < 1e-9, "{load}");
+        let load = stats
+            .network(NetworkId::ETHERNET)
+            .unwrap()
+            .bus_load
+            .unwrap();
+        assert!((load - 0.00672 / 0.999).abs() < 1e-9, "{load}");
+        assert_eq!(stats.networks().len(), 2);
+    }
+}
@@ -0,0 +1,288 @@
+//! Conversion of message timestamps.
+//!
+//! libicsneo multiplies device timestamps by the device's
+//! [timestamp resolution](crate::native::get_timestamp_resolution) before returning them, so
+//! message `timestamp` fields are nanoseconds since [DEVICE_EPOCH] on every device. A
+//! [Timebase] turns them into [Duration], [SystemTime] and, with the `chrono` feature,
+//! `chrono::DateTime`. Fed with the host time messages arrive at, it also estimates how far
+//! the device clock drifts from the host clock:
+//! ```no_run
+//! use std::time::{Duration, SystemTime};
+//!
+//! use icsneo::bus::Receive;
+//! use icsneo::timebase::Timebase;
+//!
+//! let mut device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut timebase = Timebase::new();
+//! loop {
+//!     for message in device.receive(Duration::from_millis(100)).unwrap() {
+//!         timebase.observe(message.timestamp(), SystemTime::now());
+//!         println!("{:?}", timebase.to_system_time(message.timestamp()));
+//!     }
+//!     if let Some(drift) = timebase.drift() {
+//!         println!("Device clock drifts {drift:.1} ppm");
+//!     }
+//! }
+//! ```
+use std::time::{Duration, SystemTime};
+
+/// Time from the Unix epoch to the device epoch, 2007-01-01 00:00:00 UTC.
+pub const DEVICE_EPOCH: Duration = Duration::from_secs(1_167_609_600);
+
+/// Converts timestamps of one device.
+#[derive(Debug, Clone)]
+pub struct Timebase {
+    epoch: SystemTime,
+    fit: Fit,
+}
+
+/// Least squares fit of host time over device time. Times are relative to the first
+/// observation so they keep their precision as `f64`.
+#[derive(Debug, Clone, Default)]
+struct Fit {
+    first: Option<(Duration, SystemTime)>,
+    count: f64,
+    sum_device: f64,
+    sum_host: f64,
+    sum_device_squared: f64,
+    sum_device_host: f64,
+}
+
+impl Fit {
+    fn add(&mut self, device: Duration, host: SystemTime) {
+        let (first_device, first_host) = *self.first.get_or_insert((device, host));
+        let x = signed_seconds(device, first_device);
+        let y = match host.duration_since(first_host) {
+            Ok(after) => after.as_secs_f64(),
+            Err(before) => -before.duration().as_secs_f64(),
+        };
+        self.count += 1.0;
+        self.sum_device += x;
+        self.sum_host += y;
+        self.sum_device_squared += x * x;
+        self.sum_device_host += x * y;
+    }
+
+    /// Host seconds per device second, `None` until the device times of the observations
+    /// differ.
+    fn slope(&self) -> Option<f64> {
+        let denominator = self.count * self.sum_device_squared - self.sum_device * self.sum_device;
+        if self.count < 2.0 || denominator <= f64::EPSILON {
+            return None;
+        }
+        Some((self.count * self.sum_device_host - self.sum_device * self.sum_host) / denominator)
+    }
+
+    /// Host time at a device time. With a single observation the clocks are assumed to run
+    /// at the same rate. `None` if the time is out of the range of `SystemTime`.
+    fn host_time(&self, device: Duration) -> Option<SystemTime> {
+        let (first_device, first_host) = self.first?;
+        let slope = self.slope().unwrap_or(1.0);
+        let intercept = (self.sum_host - slope * self.sum_device) / self.count;
+        let seconds = intercept + slope * signed_seconds(device, first_device);
+        let offset = Duration::try_from_secs_f64(seconds.abs()).ok()?;
+        if seconds >= 0.0 {
+            first_host.checked_add(offset)
+        } else {
+            first_host.checked_sub(offset)
+        }
+    }
+}
+
+fn signed_seconds(time: Duration, origin: Duration) -> f64 {
+    if time >= origin {
+        (time - origin).as_secs_f64()
+    } else {
+        -(origin - time).as_secs_f64()
+    }
+}
+
+impl Timebase {
+    /// A timebase for timestamps counted from [DEVICE_EPOCH].
+    pub fn new() -> Self {
+        Self {
+            epoch: SystemTime::UNIX_EPOCH + DEVICE_EPOCH,
+            fit: Fit::default(),
+        }
+    }
+
+    /// Wall-clock time of timestamp 0.
+    pub fn epoch(&self) -> SystemTime {
+        self.epoch
+    }
+
+    /// Sets the wall-clock time of timestamp 0, for devices whose clock doesn't count from
+    /// [DEVICE_EPOCH].
+    pub fn set_epoch(&mut self, epoch: SystemTime) {
+        self.epoch = epoch;
+    }
+
+    /// Time since the device epoch.
+    pub fn to_duration(&self, timestamp: u64) -> Duration {
+        Duration::from_nanos(timestamp)
+    }
+
+    /// Wall-clock time by the device clock.
+    pub fn to_system_time(&self, timestamp: u64) -> SystemTime {
+        self.epoch + self.to_duration(timestamp)
+    }
+
+    /// Wall-clock time by the device clock.
+    #[cfg(feature = "chrono")]
+    pub fn to_date_time(&self, timestamp: u64) -> chrono::DateTime<chrono::Utc> {
+        self.to_system_time(timestamp).into()
+    }
+
+    /// Records that a timestamp was seen at `host` time. Timestamps should be taken as close
+    /// to their reception as possible, receive latency shows up as offset and noise.
+    pub fn observe(&mut self, timestamp: u64, host: SystemTime) {
+        self.fit.add(self.to_duration(timestamp), host);
+    }
+
+    /// Forgets all observations.
+    pub fn clear_observations(&mut self) {
+        self.fit = Fit::default();
+    }
+
+    /// Number of [observations](Self::observe).
+    pub fn observations(&self) -> usize {
+        self.fit.count as usize
+    }
+
+    /// How much faster the device clock runs than the host clock in parts per million,
+    /// negative if it runs slower. `None` until two observations with different timestamps
+    /// were made.
+    pub fn drift(&self) -> Option<f64> {
+        let slope = self.fit.slope()?;
+        Some((1.0 / slope - 1.0) * 1e6)
+    }
+
+    /// Wall-clock time by the host clock, estimated from the observations. Unlike
+    /// [to_system_time](Self::to_system_time) this is right even if the device clock was never
+    /// set or drifts. `None` without observations, or if the estimate is out of range.
+    pub fn to_host_time(&self, timestamp: u64) -> Option<SystemTime> {
+        self.fit.host_time(self.to_duration(timestamp))
+    }
+
+    /// Host time estimated like [to_host_time](Self::to_host_time).
+    #[cfg(feature = "chrono")]
+    pub fn to_host_date_time(&self, timestamp: u64) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.to_host_time(timestamp).map(Into::into)
+    }
+}
+
+impl Default for Timebase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(since_epoch: Duration) -> u64 {
+        since_epoch.as_nanos() as u64
+    }
+
+    fn assert_close(a: SystemTime, b: SystemTime, tolerance: Duration) {
+        let difference = a
+            .duration_since(b)
+            .unwrap_or_else(|before| before.duration());
+        assert!(difference <= tolerance, "{a:?} {b:?}");
+    }
+
+    #[test]
+    fn test_conversions() {
+        let timebase = Timebase::new();
+        assert_eq!(timebase.to_duration(1_000_000_025), Duration::new(1, 25));
+        assert_eq!(
+            timebase.to_duration(u64::MAX),
+            Duration::from_nanos(u64::MAX)
+        );
+        assert_eq!(
+            timebase.to_system_time(1_500_000_000),
+            SystemTime::UNIX_EPOCH + Duration::new(1_167_609_601, 500_000_000)
+        );
+
+        let mut timebase = timebase;
+        timebase.set_epoch(SystemTime::UNIX_EPOCH);
+        assert_eq!(timebase.epoch(), SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            timebase.to_system_time(2_000_000_000),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2)
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_date_time() {
+        let timebase = Timebase::new();
+        assert_eq!(
+            timebase.to_date_time(1_000_000_000).to_rfc3339(),
+            "2007-01-01T00:00:01+00:00"
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut timebase = Timebase::new();
+        assert_eq!(timebase.drift(), None);
+        assert_eq!(timebase.to_host_time(0), None);
+
+        // The device clock was never set and runs 50 ppm fast. Receive latency alternates
+        // between 1 and 3 ms.
+        let host_start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let device_start = Duration::from_secs(5);
+        timebase.observe(timestamp(device_start), host_start);
+        assert_eq!(timebase.drift(), None);
+        assert_close(
+            timebase
+                .to_host_time(timestamp(device_start + Duration::from_secs(1)))
+                .unwrap(),
+            host_start + Duration::from_secs(1),
+            Duration::ZERO,
+        );
+
+        timebase.clear_observations();
+        for second in 0..600u64 {
+            let host = Duration::from_secs(second);
+            let device = device_start + host + host * 50 / 1_000_000;
+            let latency = Duration::from_millis(if second & 1 == 0 { 1 } else { 3 });
+            timebase.observe(timestamp(device), host_start + host + latency);
+        }
+        assert_eq!(timebase.observations(), 600);
+        let drift = timebase.drift().unwrap();
+        assert!((drift - 50.0).abs() < 0.1, "{drift}");
+
+        // An hour later the estimate is off by the mean latency and the little drift error.
+        let host = Duration::from_secs(3600);
+        let device = device_start + host + host * 50 / 1_000_000;
+        assert_close(
+            timebase.to_host_time(timestamp(device)).unwrap(),
+            host_start + host + Duration::from_millis(2),
+            Duration::from_millis(1),
+        );
+        // Without the fit the device clock is far off.
+        assert_close(
+            timebase.to_system_time(timestamp(device)),
+            SystemTime::UNIX_EPOCH + DEVICE_EPOCH + device,
+            Duration::ZERO,
+        );
+
+        // A wild fit puts far away timestamps out of range.
+        timebase.clear_observations();
+        let device_start = Duration::from_secs(1_000_000_000);
+        timebase.observe(timestamp(device_start), SystemTime::UNIX_EPOCH);
+        timebase.observe(
+            timestamp(device_start + Duration::from_secs(1)),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10_000_000_000),
+        );
+        assert_eq!(timebase.to_host_time(0), None);
+        assert_eq!(timebase.to_host_time(u64::MAX), None);
+    }
+}
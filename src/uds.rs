@@ -0,0 +1,779 @@
+//! Unified Diagnostic Services (ISO 14229) client over [ISO-TP](crate::isotp).
+//!
+//! [UdsClient] sends one request at a time and waits for its response, following response
+//! pending (NRC 0x78) answers until the final one. Negative responses are returned as
+//! [Error::NegativeResponse]:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::isotp::{IsoTp, IsoTpConfig};
+//! use icsneo::network::NetworkId;
+//! use icsneo::uds::{DiagnosticSession, UdsClient};
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let config = IsoTpConfig::new(NetworkId::HSCAN, 0x7E0, 0x7E8);
+//! let mut client = UdsClient::new(IsoTp::new(device, config).unwrap());
+//! client.diagnostic_session_control(DiagnosticSession::EXTENDED).unwrap();
+//! client.set_keepalive(Some(Duration::from_secs(2)));
+//! let vin = client.read_data_by_identifier(0xF190).unwrap();
+//! println!("{}", String::from_utf8_lossy(&vin));
+//! ```
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::isotp::IsoTp;
+use crate::native::*;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+const ECU_RESET: u8 = 0x11;
+const READ_DTC_INFORMATION: u8 = 0x19;
+const READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+const SECURITY_ACCESS: u8 = 0x27;
+const WRITE_DATA_BY_IDENTIFIER: u8 = 0x2e;
+const ROUTINE_CONTROL: u8 = 0x31;
+const REQUEST_DOWNLOAD: u8 = 0x34;
+const TRANSFER_DATA: u8 = 0x36;
+const REQUEST_TRANSFER_EXIT: u8 = 0x37;
+const TESTER_PRESENT: u8 = 0x3e;
+
+const NEGATIVE_RESPONSE: u8 = 0x7f;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+/// requestCorrectlyReceived-ResponsePending, the server needs more than P2.
+pub const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+/// Sub-function of DiagnosticSessionControl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DiagnosticSession(pub u8);
+
+impl DiagnosticSession {
+    pub const DEFAULT: Self = Self(0x01);
+    pub const PROGRAMMING: Self = Self(0x02);
+    pub const EXTENDED: Self = Self(0x03);
+    pub const SAFETY_SYSTEM: Self = Self(0x04);
+}
+
+/// Sub-function of ECUReset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResetType(pub u8);
+
+impl ResetType {
+    pub const HARD: Self = Self(0x01);
+    pub const KEY_OFF_ON: Self = Self(0x02);
+    pub const SOFT: Self = Self(0x03);
+}
+
+/// Sub-function of RoutineControl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoutineControl {
+    Start = 0x01,
+    Stop = 0x02,
+    RequestResults = 0x03,
+}
+
+/// Server timing reported by DiagnosticSessionControl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTimings {
+    /// Time until the server starts responding.
+    pub p2: Duration,
+    /// Time until the server responds after a response pending answer.
+    pub p2_star: Duration,
+}
+
+impl Default for SessionTimings {
+    fn default() -> Self {
+        Self {
+            p2: Duration::from_millis(50),
+            p2_star: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Computes the key for a SecurityAccess seed. The algorithms are specific to each
+/// manufacturer, so they are supplied by the caller.
+///
+/// Closures taking the level and the seed implement this trait.
+pub trait SeedKey {
+    /// Returns the key for `seed` of the odd requestSeed `level`.
+    fn key(&mut self, level: u8, seed: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl<F: FnMut(u8, &[u8]) -> Result<Vec<u8>>> SeedKey for F {
+    fn key(&mut self, level: u8, seed: &[u8]) -> Result<Vec<u8>> {
+        self(level, seed)
+    }
+}
+
+/// A diagnostic trouble code and its status, as reported by ReadDTCInformation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dtc {
+    /// The 3 byte DTC, with the failure type in the low byte.
+    pub code: u32,
+    /// statusOfDTC bits.
+    pub status: u8,
+}
+
+impl Dtc {
+    pub const STATUS_TEST_FAILED: u8 = 0x01;
+    pub const STATUS_PENDING: u8 = 0x04;
+    pub const STATUS_CONFIRMED: u8 = 0x08;
+
+    pub fn is_test_failed(&self) -> bool {
+        self.status & Self::STATUS_TEST_FAILED != 0
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status & Self::STATUS_PENDING != 0
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.status & Self::STATUS_CONFIRMED != 0
+    }
+}
+
+/// Formats the code like "P0123-1A", the SAE J2012 code followed by the failure type.
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let system = ['P', 'C', 'B', 'U'][(self.code >> 22) as usize & 3];
+        write!(
+            f,
+            "{system}{:04X}-{:02X}",
+            (self.code >> 8) & 0x3fff,
+            self.code & 0xff
+        )
+    }
+}
+
+struct Connection<B, C: Clock> {
+    isotp: IsoTp<B, C>,
+    last_request: Duration,
+}
+
+/// The background thread sending TesterPresent.
+struct Keepalive {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A UDS client talking to one server through an [IsoTp] connection.
+pub struct UdsClient<B, C: Clock = SystemClock> {
+    connection: Arc<Mutex<Connection<B, C>>>,
+    clock: C,
+    timings: SessionTimings,
+    keepalive: Option<Keepalive>,
+}
+
+impl<B: Transmit + Receive> UdsClient<B> {
+    pub fn new(isotp: IsoTp<B>) -> Self {
+        Self::with_clock(isotp, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> UdsClient<B, C> {
+    pub fn with_clock(isotp: IsoTp<B, C>, clock: C) -> Self {
+        Self {
+            connection: Arc::new(Mutex::new(Connection {
+                isotp,
+                last_request: clock.now(),
+            })),
+            clock,
+            timings: SessionTimings::default(),
+            keepalive: None,
+        }
+    }
+
+    pub fn timings(&self) -> SessionTimings {
+        self.timings
+    }
+
+    /// Overrides the response timeouts. [diagnostic_session_control](Self::diagnostic_session_control)
+    /// sets them to the values reported by the server.
+    pub fn set_timings(&mut self, timings: SessionTimings) {
+        self.timings = timings;
+    }
+
+    /// Sends `request` and returns the positive response, starting with the response
+    /// service ID.
+    ///
+    /// Responses to other services are stale and skipped, without extending the wait.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let Some(&service) = request.first() else {
+            return Err(Error::InvalidArgument("Empty UDS request".to_string()));
+        };
+        let mut connection = self.connection.lock().unwrap();
+        connection.isotp.send(request)?;
+        connection.last_request = self.clock.now();
+
+        let mut deadline = self.clock.now() + self.timings.p2;
+        loop {
+            let timeout = deadline.saturating_sub(self.clock.now());
+            let response = match connection.isotp.receive(timeout) {
+                Err(Error::Timeout(_)) => {
+                    return Err(Error::Timeout(format!(
+                        "No response to UDS service {service:#04x}"
+                    )))
+                }
+                response => response?,
+            };
+            match response[..] {
+                [NEGATIVE_RESPONSE, rejected, NRC_RESPONSE_PENDING, ..] if rejected == service => {
+                    deadline = self.clock.now() + self.timings.p2_star;
+                }
+                [NEGATIVE_RESPONSE, rejected, code, ..] if rejected == service => {
+                    return Err(Error::NegativeResponse(service, code));
+                }
+                [first, ..] if first == service.wrapping_add(POSITIVE_RESPONSE_OFFSET) => {
+                    return Ok(response);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a request and checks that the response is at least `length` bytes long and
+    /// echoes the request bytes after the service ID up to `echo`.
+    fn checked_request(&mut self, request: &[u8], echo: usize, length: usize) -> Result<Vec<u8>> {
+        let response = self.request(request)?;
+        if response.len() < length.max(echo) || response[1..echo] != request[1..echo] {
+            return Err(Error::ProtocolError(format!(
+                "Unexpected response {response:02x?} to UDS request {request:02x?}"
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Switches to `session` and adopts the server's P2 and P2* timings.
+    pub fn diagnostic_session_control(
+        &mut self,
+        session: DiagnosticSession,
+    ) -> Result<SessionTimings> {
+        let response = self.checked_request(&[DIAGNOSTIC_SESSION_CONTROL, session.0], 2, 2)?;
+        if let [_, _, p2_high, p2_low, star_high, star_low, ..] = response[..] {
+            self.timings = SessionTimings {
+                p2: Duration::from_millis(u64::from(u16::from_be_bytes([p2_high, p2_low]))),
+                p2_star: Duration::from_millis(
+                    u64::from(u16::from_be_bytes([star_high, star_low])) * 10,
+                ),
+            };
+        }
+        Ok(self.timings)
+    }
+
+    pub fn ecu_reset(&mut self, reset: ResetType) -> Result<()> {
+        self.checked_request(&[ECU_RESET, reset.0], 2, 2)?;
+        Ok(())
+    }
+
+    /// Unlocks the odd security `level` with the key `seed_key` computes for the seed.
+    /// A seed of all zeros means the level is unlocked already.
+    pub fn security_access(&mut self, level: u8, seed_key: &mut impl SeedKey) -> Result<()> {
+        if level & 1 == 0 || level > 0x7e {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid SecurityAccess level {level:#04x}"
+            )));
+        }
+        // The seed is at least one byte.
+        let response = self.checked_request(&[SECURITY_ACCESS, level], 2, 3)?;
+        let seed = &response[2..];
+        if seed.iter().all(|&byte| byte == 0) {
+            return Ok(());
+        }
+        let mut request = vec![SECURITY_ACCESS, level + 1];
+        request.extend(seed_key.key(level, seed)?);
+        self.checked_request(&request, 2, 2)?;
+        Ok(())
+    }
+
+    pub fn read_data_by_identifier(&mut self, identifier: u16) -> Result<Vec<u8>> {
+        let [high, low] = identifier.to_be_bytes();
+        let response = self.checked_request(&[READ_DATA_BY_IDENTIFIER, high, low], 3, 3)?;
+        Ok(response[3..].to_vec())
+    }
+
+    pub fn write_data_by_identifier(&mut self, identifier: u16, data: &[u8]) -> Result<()> {
+        let mut request = vec![WRITE_DATA_BY_IDENTIFIER];
+        request.extend(identifier.to_be_bytes());
+        request.extend_from_slice(data);
+        self.checked_request(&request, 3, 3)?;
+        Ok(())
+    }
+
+    /// Starts, stops or polls `routine` and returns the routine status record.
+    pub fn routine_control(
+        &mut self,
+        control: RoutineControl,
+        routine: u16,
+        options: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut request = vec![ROUTINE_CONTROL, control as u8];
+        request.extend(routine.to_be_bytes());
+        request.extend_from_slice(options);
+        let response = self.checked_request(&request, 4, 4)?;
+        Ok(response[4..].to_vec())
+    }
+
+    /// Sends ReadDTCInformation `report` with its `parameters` and returns the response
+    /// after the report type.
+    pub fn read_dtc_information(&mut self, report: u8, parameters: &[u8]) -> Result<Vec<u8>> {
+        let mut request = vec![READ_DTC_INFORMATION, report];
+        request.extend_from_slice(parameters);
+        let response = self.checked_request(&request, 2, 2)?;
+        Ok(response[2..].to_vec())
+    }
+
+    /// reportNumberOfDTCByStatusMask, the number of DTCs matching `mask`.
+    pub fn read_dtc_count(&mut self, mask: u8) -> Result<u16> {
+        match self.read_dtc_information(0x01, &[mask])?[..] {
+            [_, _, high, low, ..] => Ok(u16::from_be_bytes([high, low])),
+            _ => Err(Error::ProtocolError(
+                "Truncated DTC count response".to_string(),
+            )),
+        }
+    }
+
+    /// reportDTCByStatusMask, all DTCs matching `mask`.
+    pub fn read_dtcs(&mut self, mask: u8) -> Result<Vec<Dtc>> {
+        let response = self.read_dtc_information(0x02, &[mask])?;
+        // The status availability mask comes first.
+        Ok(response
+            .get(1..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|record| Dtc {
+                code: u32::from_be_bytes([0, record[0], record[1], record[2]]),
+                status: record[3],
+            })
+            .collect())
+    }
+
+    /// Requests a download of `size` bytes to `address`, with 4 byte address and size.
+    /// Returns the maximum length of TransferData requests, including service ID and block
+    /// sequence counter.
+    pub fn request_download(&mut self, address: u32, size: u32, data_format: u8) -> Result<usize> {
+        let mut request = vec![REQUEST_DOWNLOAD, data_format, 0x44];
+        request.extend(address.to_be_bytes());
+        request.extend(size.to_be_bytes());
+        let response = self.checked_request(&request, 1, 2)?;
+        let length = usize::from(response[1] >> 4);
+        if length == 0 || length > 8 || response.len() < 2 + length {
+            return Err(Error::ProtocolError(format!(
+                "Invalid RequestDownload response {response:02x?}"
+            )));
+        }
+        Ok(response[2..2 + length]
+            .iter()
+            .fold(0, |max, &byte| max << 8 | usize::from(byte)))
+    }
+
+    /// Sends one block of a download and returns the transferResponseParameterRecord.
+    pub fn transfer_data(&mut self, sequence: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut request = vec![TRANSFER_DATA, sequence];
+        request.extend_from_slice(data);
+        let response = self.checked_request(&request, 2, 2)?;
+        Ok(response[2..].to_vec())
+    }
+
+    pub fn request_transfer_exit(&mut self) -> Result<Vec<u8>> {
+        let response = self.checked_request(&[REQUEST_TRANSFER_EXIT], 1, 1)?;
+        Ok(response[1..].to_vec())
+    }
+
+    /// Downloads `data` to `address`: RequestDownload, TransferData in blocks as large as
+    /// the server allows and RequestTransferExit.
+    pub fn download(&mut self, address: u32, data: &[u8], data_format: u8) -> Result<()> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| Error::InvalidArgument("Download larger than 4 GiB".to_string()))?;
+        let max_length = self.request_download(address, size, data_format)?;
+        if max_length <= 2 {
+            return Err(Error::ProtocolError(format!(
+                "Server accepts TransferData of {max_length} bytes"
+            )));
+        }
+        for (i, block) in data.chunks(max_length - 2).enumerate() {
+            // The block sequence counter starts at 1 and wraps to 0.
+            self.transfer_data((i + 1) as u8, block)?;
+        }
+        self.request_transfer_exit()?;
+        Ok(())
+    }
+
+    /// Sends TesterPresent and waits for the response.
+    pub fn tester_present(&mut self) -> Result<()> {
+        self.checked_request(&[TESTER_PRESENT, 0x00], 2, 2)?;
+        Ok(())
+    }
+}
+
+impl<B: Transmit + Receive + Send + 'static, C: Clock + Clone + Send + 'static> UdsClient<B, C> {
+    /// Keeps the session alive by sending TesterPresent, without asking for a response,
+    /// whenever no request was sent for `interval`. `None` stops it.
+    ///
+    /// The requests are sent from a background thread. Errors sending them are ignored,
+    /// the next request will report a problem with the connection.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = None;
+        let Some(interval) = interval else {
+            return;
+        };
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let connection = self.connection.clone();
+        let clock = self.clock.clone();
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let (stopped, wake) = &*stop;
+                let mut stopped = stopped.lock().unwrap();
+                while !*stopped {
+                    let due = connection.lock().unwrap().last_request + interval;
+                    let now = clock.now();
+                    if now < due {
+                        // Sleeps in real time and checks the clock again, so clocks that
+                        // don't follow real time only delay the request.
+                        stopped = wake.wait_timeout(stopped, due - now).unwrap().0;
+                        continue;
+                    }
+                    let mut connection = connection.lock().unwrap();
+                    let _ = connection
+                        .isotp
+                        .send(&[TESTER_PRESENT, SUPPRESS_POSITIVE_RESPONSE]);
+                    connection.last_request = clock.now();
+                }
+            }
+        });
+        self.keepalive = Some(Keepalive {
+            stop,
+            thread: Some(thread),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{VirtualBus, VirtualEndpoint};
+    use crate::clock::ManualClock;
+    use crate::isotp::IsoTpConfig;
+    use crate::message::*;
+    use crate::network::NetworkId;
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    const MS: Duration = Duration::from_millis(1);
+    /// Upper bound for the keepalive thread to notice the clock moved.
+    const WAIT: Duration = Duration::from_secs(1);
+
+    fn client(bus: &VirtualBus) -> UdsClient<VirtualEndpoint> {
+        let config = IsoTpConfig::new(NetworkId::HSCAN, 0x7e0, 0x7e8);
+        let mut client = UdsClient::new(IsoTp::new(bus.endpoint(), config).unwrap());
+        client.set_timings(SessionTimings {
+            p2: Duration::from_millis(100),
+            p2_star: Duration::from_millis(200),
+        });
+        client
+    }
+
+    /// Answers requests with the responses of `handler` until none arrive for a while,
+    /// then returns all requests received.
+    fn server(
+        bus: &VirtualBus,
+        mut handler: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    ) -> JoinHandle<Vec<Vec<u8>>> {
+        let config = IsoTpConfig::new(NetworkId::HSCAN, 0x7e8, 0x7e0);
+        let mut isotp = IsoTp::new(bus.endpoint(), config).unwrap();
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Ok(request) = isotp.receive(Duration::from_millis(500)) {
+                for response in handler(&request) {
+                    isotp.send(&response).unwrap();
+                }
+                requests.push(request);
+            }
+            requests
+        })
+    }
+
+    fn positive(request: &[u8], echo: usize, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut response = vec![request[0] + 0x40];
+        response.extend_from_slice(&request[1..echo]);
+        response.extend_from_slice(data);
+        vec![response]
+    }
+
+    #[test]
+    fn test_session_and_security() {
+        let bus = VirtualBus::new();
+        let server = server(&bus, |request| match request {
+            [0x10, _] => positive(request, 2, &[0x00, 0x32, 0x01, 0xf4]),
+            [0x11, 0x01] => positive(request, 2, &[]),
+            [0x27, 0x01] => positive(request, 2, &[0x12, 0x34]),
+            [0x27, 0x02, 0xed, 0xcb] => positive(request, 2, &[]),
+            [0x27, 0x02, ..] => vec![vec![0x7f, 0x27, 0x35]],
+            [0x27, 0x03] => positive(request, 2, &[0, 0]),
+            [0x27, 0x05] => positive(request, 2, &[]),
+            _ => vec![vec![0x7f, request[0], 0x11]],
+        });
+        let mut client = client(&bus);
+
+        let timings = client
+            .diagnostic_session_control(DiagnosticSession::EXTENDED)
+            .unwrap();
+        assert_eq!(timings.p2, Duration::from_millis(50));
+        assert_eq!(timings.p2_star, Duration::from_secs(5));
+        assert_eq!(client.timings(), timings);
+        client.ecu_reset(ResetType::HARD).unwrap();
+
+        let mut key = |level: u8, seed: &[u8]| -> Result<Vec<u8>> {
+            assert_eq!(level, 1);
+            Ok(seed.iter().map(|byte| !byte).collect())
+        };
+        client.security_access(0x01, &mut key).unwrap();
+        let mut wrong = |_: u8, _: &[u8]| -> Result<Vec<u8>> { Ok(vec![0, 0]) };
+        assert!(matches!(
+            client.security_access(0x01, &mut wrong),
+            Err(Error::NegativeResponse(0x27, 0x35))
+        ));
+        // Already unlocked, the key isn't needed.
+        let mut unused = |_: u8, _: &[u8]| -> Result<Vec<u8>> { panic!() };
+        client.security_access(0x03, &mut unused).unwrap();
+        assert!(matches!(
+            client.security_access(0x05, &mut unused),
+            Err(Error::ProtocolError(_))
+        ));
+        assert!(matches!(
+            client.security_access(0x02, &mut unused),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            client.request(&[0x85, 0x01]),
+            Err(Error::NegativeResponse(0x85, 0x11))
+        ));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 9);
+    }
+
+    #[test]
+    fn test_data_and_dtcs() {
+        let bus = VirtualBus::new();
+        let server = server(&bus, |request| match request {
+            [0x22, 0xf1, 0x90] => positive(request, 3, b"1FTFW1ET5DFC10312"),
+            [0x2e, 0xf1, 0x98, ..] => positive(request, 3, &[]),
+            [0x31, 0x01, 0xff, 0x00, ..] => positive(request, 4, &[0x00]),
+            [0x19, 0x01, 0xff] => positive(request, 2, &[0xff, 0x01, 0x00, 0x02]),
+            [0x19, 0x02, 0xff] => positive(
+                request,
+                2,
+                &[0xff, 0x01, 0x23, 0x1a, 0x09, 0xc1, 0x00, 0x00, 0x24],
+            ),
+            // Stale response to another service first.
+            [0x22, 0x12, 0x34] => vec![vec![0x50, 0x01], vec![0x62, 0x12, 0x35, 0x00]],
+            _ => vec![vec![0x7f, request[0], 0x31]],
+        });
+        let mut client = client(&bus);
+
+        assert_eq!(
+            client.read_data_by_identifier(0xf190).unwrap(),
+            b"1FTFW1ET5DFC10312"
+        );
+        client
+            .write_data_by_identifier(0xf198, &[1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(
+            client
+                .routine_control(RoutineControl::Start, 0xff00, &[0x01])
+                .unwrap(),
+            [0x00]
+        );
+        assert_eq!(client.read_dtc_count(0xff).unwrap(), 2);
+        let dtcs = client.read_dtcs(0xff).unwrap();
+        assert_eq!(dtcs.len(), 2);
+        assert_eq!(dtcs[0].to_string(), "P0123-1A");
+        assert!(dtcs[0].is_test_failed() && dtcs[0].is_confirmed());
+        assert_eq!(dtcs[1].to_string(), "U0100-00");
+        assert!(dtcs[1].is_pending() && !dtcs[1].is_test_failed());
+        // The response echoes the wrong identifier.
+        assert!(matches!(
+            client.read_data_by_identifier(0x1234),
+            Err(Error::ProtocolError(_))
+        ));
+        assert!(matches!(
+            client.read_data_by_identifier(0x0000),
+            Err(Error::NegativeResponse(0x22, 0x31))
+        ));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[1], [0x2e, 0xf1, 0x98, 1, 2, 3, 4]);
+        assert_eq!(requests[2], [0x31, 0x01, 0xff, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_download() {
+        let bus = VirtualBus::new();
+        let server = server(&bus, |request| match request {
+            [0x34, 0x00, 0x44, ..] => positive(request, 1, &[0x20, 0x00, 0x12]),
+            // Takes longer than P2 for the first block.
+            [0x36, 0x01, ..] => {
+                let mut responses = vec![vec![0x7f, 0x36, 0x78]; 2];
+                responses.extend(positive(request, 2, &[]));
+                responses
+            }
+            [0x36, ..] => positive(request, 2, &[]),
+            [0x37] => positive(request, 1, &[0xbe, 0xef]),
+            _ => vec![vec![0x7f, request[0], 0x22]],
+        });
+        let mut client = client(&bus);
+
+        let data: Vec<u8> = (0..40).collect();
+        client.download(0x8000, &data, 0x00).unwrap();
+        assert!(matches!(
+            client.tester_present(),
+            Err(Error::NegativeResponse(0x3e, 0x22))
+        ));
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[0],
+            [0x34, 0x00, 0x44, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 40]
+        );
+        // Blocks of 0x12 bytes minus service ID and counter.
+        assert_eq!(requests[1][..3], [0x36, 0x01, 0]);
+        assert_eq!(requests[1].len(), 0x12);
+        assert_eq!(requests[3][..3], [0x36, 0x03, 32]);
+        assert_eq!(requests[3].len(), 10);
+        assert_eq!(requests[4], [0x37]);
+    }
+
+    /// Responses to a request and how long after it each one arrives.
+    type Handler = Box<dyn FnMut(&[u8]) -> Vec<(Duration, Vec<u8>)> + Send>;
+
+    /// A server answering single frame requests on a [ManualClock]. Waiting for a response
+    /// advances the clock to it, or by the whole timeout if none is due by then.
+    struct ScriptedServer {
+        clock: ManualClock,
+        handler: Handler,
+        requests: Sender<Vec<u8>>,
+        responses: VecDeque<(Duration, Message)>,
+    }
+
+    impl Transmit for ScriptedServer {
+        fn transmit(&mut self, message: &Message) -> Result<()> {
+            let Message::Can(m) = message else {
+                return Ok(());
+            };
+            let request = m.data()[1..=usize::from(m.data()[0])].to_vec();
+            for (delay, response) in (self.handler)(&request) {
+                let mut frame = vec![response.len() as u8];
+                frame.extend(response);
+                let frame = CanMessage::new(1, 0x7e8, &frame);
+                self.responses
+                    .push_back((self.clock.now() + delay, Message::Can(frame)));
+            }
+            self.responses
+                .make_contiguous()
+                .sort_by_key(|(due, _)| *due);
+            let _ = self.requests.send(request);
+            Ok(())
+        }
+    }
+
+    impl Receive for ScriptedServer {
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+            let deadline = self.clock.now() + timeout;
+            match self.responses.front() {
+                Some(&(due, _)) if due <= deadline => {
+                    self.clock.sleep_until(due);
+                    Ok(vec![self.responses.pop_front().unwrap().1])
+                }
+                _ => {
+                    self.clock.sleep_until(deadline);
+                    Ok(Vec::new())
+                }
+            }
+        }
+    }
+
+    fn scripted_client(
+        clock: &ManualClock,
+        handler: impl FnMut(&[u8]) -> Vec<(Duration, Vec<u8>)> + Send + 'static,
+    ) -> (UdsClient<ScriptedServer, ManualClock>, Receiver<Vec<u8>>) {
+        let (sender, requests) = std::sync::mpsc::channel();
+        let server = ScriptedServer {
+            clock: clock.clone(),
+            handler: Box::new(handler),
+            requests: sender,
+            responses: VecDeque::new(),
+        };
+        let config = IsoTpConfig::new(NetworkId::HSCAN, 0x7e0, 0x7e8);
+        let isotp = IsoTp::with_clock(server, config, clock.clone()).unwrap();
+        let mut client = UdsClient::with_clock(isotp, clock.clone());
+        client.set_timings(SessionTimings {
+            p2: Duration::from_millis(100),
+            p2_star: Duration::from_millis(200),
+        });
+        (client, requests)
+    }
+
+    #[test]
+    fn test_timeout_and_keepalive() {
+        let clock = ManualClock::new();
+        let (mut client, requests) = scripted_client(&clock, |request| match request {
+            [0x3e, 0x80] => vec![],
+            [0x3e, 0x00] => vec![(Duration::ZERO, vec![0x7e, 0x00])],
+            // Response pending without a final response.
+            _ => vec![(MS * 50, vec![0x7f, request[0], 0x78])],
+        });
+
+        // P2* counts from the response pending.
+        assert!(matches!(
+            client.read_data_by_identifier(0xf190),
+            Err(Error::Timeout(_))
+        ));
+        assert_eq!(clock.now(), MS * 250);
+        assert_eq!(requests.recv().unwrap(), [0x22, 0xf1, 0x90]);
+
+        client.set_keepalive(Some(MS * 40));
+        clock.advance(MS * 40);
+        assert_eq!(requests.recv_timeout(WAIT).unwrap(), [0x3e, 0x80]);
+        client.tester_present().unwrap();
+        client.set_keepalive(None);
+        assert_eq!(requests.recv().unwrap(), [0x3e, 0x00]);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_stale_responses() {
+        let clock = ManualClock::new();
+        // Keeps answering another service for much longer than P2.
+        let (mut client, _requests) = scripted_client(&clock, |_| {
+            (1..=10).map(|i| (MS * 50 * i, vec![0x51, 0x01])).collect()
+        });
+
+        assert!(matches!(
+            client.read_data_by_identifier(0xf190),
+            Err(Error::Timeout(_))
+        ));
+        assert_eq!(clock.now(), MS * 100);
+    }
+}
@@ -0,0 +1,914 @@
+//! XCP on CAN master (ASAM MCD-1 XCP) for measurement and calibration.
+//!
+//! [XcpMaster] sends commands to one slave and waits for each response. Memory is read with
+//! SHORT_UPLOAD and written with DOWNLOAD; DAQ lists make the slave send measurements on its
+//! own, which [receive_daq](XcpMaster::receive_daq) decodes:
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use icsneo::network::NetworkId;
+//! use icsneo::xcp::{DaqList, OdtEntry, XcpMaster};
+//!
+//! let device = icsneo::native::find_all_devices().unwrap().remove(0);
+//! icsneo::native::open_device(&device).unwrap();
+//! icsneo::native::go_online(&device).unwrap();
+//! icsneo::native::enable_message_polling(&device);
+//!
+//! let mut xcp = XcpMaster::new(device, NetworkId::HSCAN, 0x7f0, 0x7f1);
+//! xcp.connect().unwrap();
+//! let gain = xcp.short_upload(0x4000_1000, 0, 4).unwrap();
+//! xcp.download(0x4000_1000, 0, &[0, 0, 0x80, 0x3f]).unwrap();
+//!
+//! let odt = vec![OdtEntry { address: 0x4000_2000, extension: 0, size: 2 }];
+//! xcp.configure_daq(&[DaqList { event: 1, prescaler: 1, priority: 0, odts: vec![odt] }])
+//!     .unwrap();
+//! xcp.start_daq().unwrap();
+//! for sample in xcp.receive_daq(Duration::from_secs(1)).unwrap() {
+//!     println!("{:?} {:?}", sample.timestamp, sample.values);
+//! }
+//! ```
+//! Only slaves with byte address granularity and absolute ODT numbers as identification
+//! field are supported.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use libicsneo_sys::neonetid_t;
+
+use crate::bus::{Receive, Transmit};
+use crate::clock::{Clock, SystemClock};
+use crate::message::*;
+use crate::native::*;
+use crate::network::NetworkId;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const CONNECT: u8 = 0xff;
+const DISCONNECT: u8 = 0xfe;
+const GET_STATUS: u8 = 0xfd;
+const SET_MTA: u8 = 0xf6;
+const SHORT_UPLOAD: u8 = 0xf4;
+const DOWNLOAD: u8 = 0xf0;
+const SET_DAQ_PTR: u8 = 0xe2;
+const WRITE_DAQ: u8 = 0xe1;
+const SET_DAQ_LIST_MODE: u8 = 0xe0;
+const START_STOP_DAQ_LIST: u8 = 0xde;
+const START_STOP_SYNCH: u8 = 0xdd;
+const GET_DAQ_RESOLUTION_INFO: u8 = 0xd9;
+const FREE_DAQ: u8 = 0xd6;
+const ALLOC_DAQ: u8 = 0xd5;
+const ALLOC_ODT: u8 = 0xd4;
+const ALLOC_ODT_ENTRY: u8 = 0xd3;
+
+const PID_RESPONSE: u8 = 0xff;
+const PID_ERROR: u8 = 0xfe;
+/// Highest packet identifier of DAQ packets.
+const PID_DAQ_MAX: u8 = 0xfb;
+
+const DAQ_LIST_MODE_TIMESTAMP: u8 = 0x10;
+
+pub const RESOURCE_CALIBRATION: u8 = 0x01;
+pub const RESOURCE_DAQ: u8 = 0x04;
+pub const RESOURCE_STIMULATION: u8 = 0x08;
+pub const RESOURCE_PROGRAMMING: u8 = 0x10;
+
+/// What the slave reported on CONNECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectInfo {
+    /// Available resources, see the `RESOURCE_` constants.
+    pub resource: u8,
+    pub comm_mode_basic: u8,
+    /// Largest command and response packet.
+    pub max_cto: u8,
+    /// Largest DAQ packet.
+    pub max_dto: u16,
+    pub protocol_version: u8,
+    pub transport_version: u8,
+}
+
+impl ConnectInfo {
+    /// Multi-byte values are Motorola byte order.
+    pub fn is_big_endian(&self) -> bool {
+        self.comm_mode_basic & 0x01 != 0
+    }
+
+    /// Size of the smallest addressable element in bytes.
+    pub fn address_granularity(&self) -> usize {
+        1 << ((self.comm_mode_basic >> 1) & 3)
+    }
+}
+
+/// What the slave reported on GET_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub session_status: u8,
+    /// Resources protected with seed and key.
+    pub protection_status: u8,
+    pub session_configuration_id: u16,
+}
+
+/// One element sampled into an ODT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OdtEntry {
+    pub address: u32,
+    pub extension: u8,
+    /// Size in bytes, at most 8.
+    pub size: u8,
+}
+
+/// A DAQ list: ODTs sampled together on an event of the slave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaqList {
+    pub event: u16,
+    /// Sample on every `prescaler`th event.
+    pub prescaler: u8,
+    pub priority: u8,
+    /// Each ODT is sent in one DAQ packet.
+    pub odts: Vec<Vec<OdtEntry>>,
+}
+
+/// One received DAQ packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaqSample {
+    pub daq: u16,
+    pub odt: u8,
+    /// Slave time the list was sampled, from the timestamp in the list's first ODT. Counter
+    /// wraps are accounted for, so it keeps increasing.
+    pub timestamp: Option<Duration>,
+    /// Bus timestamp of the packet.
+    pub received: u64,
+    /// Values of the ODT entries in the slave's byte order.
+    pub values: Vec<u64>,
+}
+
+/// Size and resolution of DAQ timestamps.
+#[derive(Debug, Clone, Copy)]
+struct TimestampFormat {
+    size: usize,
+    /// Nanoseconds per tick.
+    tick: u64,
+}
+
+/// A configured DAQ list.
+struct ActiveList {
+    list: DaqList,
+    first_pid: Option<u8>,
+    /// Last raw timestamp and the ticks added for counter wraps.
+    last_timestamp: Option<(u64, u64)>,
+}
+
+/// An XCP master talking to one slave on CAN.
+pub struct XcpMaster<B, C: Clock = SystemClock> {
+    bus: B,
+    clock: C,
+    netid: neonetid_t,
+    tx_id: u32,
+    rx_id: u32,
+    fd: bool,
+    timeout: Duration,
+    info: Option<ConnectInfo>,
+    timestamp: Option<TimestampFormat>,
+    lists: Vec<ActiveList>,
+    pending: VecDeque<Message>,
+    /// DAQ packets received while waiting for responses, and their bus timestamps.
+    packets: VecDeque<(Vec<u8>, u64)>,
+}
+
+impl<B: Transmit + Receive> XcpMaster<B> {
+    /// A master sending commands on `tx_id` and receiving responses and DAQ packets on
+    /// `rx_id`. IDs above 0x7FF are 29 bit.
+    pub fn new(bus: B, netid: impl Into<NetworkId>, tx_id: u32, rx_id: u32) -> Self {
+        Self::with_clock(bus, netid, tx_id, rx_id, SystemClock::new())
+    }
+}
+
+impl<B: Transmit + Receive, C: Clock> XcpMaster<B, C> {
+    pub fn with_clock(
+        bus: B,
+        netid: impl Into<NetworkId>,
+        tx_id: u32,
+        rx_id: u32,
+        clock: C,
+    ) -> Self {
+        Self {
+            bus,
+            clock,
+            netid: netid.into().0,
+            tx_id,
+            rx_id,
+            fd: false,
+            timeout: Duration::from_millis(100),
+            info: None,
+            timestamp: None,
+            lists: Vec::new(),
+            pending: VecDeque::new(),
+            packets: VecDeque::new(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Sends commands as CAN FD frames. Off by default.
+    pub fn set_fd(&mut self, fd: bool) {
+        self.fd = fd;
+    }
+
+    /// Time to wait for each response (T1). Defaults to 100 ms.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// What the slave reported on [connect](Self::connect).
+    pub fn connect_info(&self) -> Option<ConnectInfo> {
+        self.info
+    }
+
+    pub fn connect(&mut self) -> Result<ConnectInfo> {
+        self.info = None;
+        let response = self.command(&[CONNECT, 0x00], 8)?;
+        let comm_mode_basic = response[2];
+        let max_dto = [response[4], response[5]];
+        let info = ConnectInfo {
+            resource: response[1],
+            comm_mode_basic,
+            max_cto: response[3],
+            max_dto: match comm_mode_basic & 0x01 {
+                0 => u16::from_le_bytes(max_dto),
+                _ => u16::from_be_bytes(max_dto),
+            },
+            protocol_version: response[6],
+            transport_version: response[7],
+        };
+        // The shortest commands, like SHORT_UPLOAD, need 8 bytes.
+        if info.max_cto < 8 {
+            return Err(Error::ProtocolError(format!(
+                "XCP MAX_CTO of {} bytes is less than 8",
+                info.max_cto
+            )));
+        }
+        if info.address_granularity() != 1 {
+            return Err(Error::ProtocolError(format!(
+                "XCP address granularity of {} bytes isn't supported",
+                info.address_granularity()
+            )));
+        }
+        self.info = Some(info);
+        Ok(info)
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.command(&[DISCONNECT], 1)?;
+        self.info = None;
+        Ok(())
+    }
+
+    pub fn get_status(&mut self) -> Result<Status> {
+        let response = self.command(&[GET_STATUS], 6)?;
+        Ok(Status {
+            session_status: response[1],
+            protection_status: response[2],
+            session_configuration_id: self.read_u16(&response[4..6]),
+        })
+    }
+
+    /// Reads `size` bytes at `address` with one SHORT_UPLOAD. `size` is limited by the
+    /// slave's MAX_CTO, see [upload](Self::upload) for more.
+    pub fn short_upload(&mut self, address: u32, extension: u8, size: u8) -> Result<Vec<u8>> {
+        let max = self.max_cto()? - 1;
+        if size == 0 || usize::from(size) > max {
+            return Err(Error::InvalidArgument(format!(
+                "SHORT_UPLOAD of {size} bytes, the slave allows 1 to {max}"
+            )));
+        }
+        let mut request = vec![SHORT_UPLOAD, size, 0, extension];
+        request.extend(self.u32_bytes(address));
+        let response = self.command(&request, 1 + usize::from(size))?;
+        Ok(response[1..1 + usize::from(size)].to_vec())
+    }
+
+    /// Reads `size` bytes at `address` with as many SHORT_UPLOADs as needed.
+    pub fn upload(&mut self, address: u32, extension: u8, size: usize) -> Result<Vec<u8>> {
+        let max = self.max_cto()? - 1;
+        // The last byte has to be addressable, so no chunk address wraps.
+        let last = u32::try_from(size.saturating_sub(1))
+            .ok()
+            .and_then(|last| address.checked_add(last));
+        if last.is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "Upload of {size} bytes at {address:#x} exceeds the address space"
+            )));
+        }
+        let mut data = Vec::with_capacity(size);
+        while data.len() < size {
+            let chunk = (size - data.len()).min(max);
+            let offset = data.len() as u32;
+            data.extend(self.short_upload(address + offset, extension, chunk as u8)?);
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` at `address`: SET_MTA and DOWNLOAD commands of up to MAX_CTO - 2
+    /// bytes each.
+    pub fn download(&mut self, address: u32, extension: u8, data: &[u8]) -> Result<()> {
+        let max = self.max_cto()? - 2;
+        self.set_mta(address, extension)?;
+        for chunk in data.chunks(max) {
+            let mut request = vec![DOWNLOAD, chunk.len() as u8];
+            request.extend_from_slice(chunk);
+            self.command(&request, 1)?;
+        }
+        Ok(())
+    }
+
+    fn set_mta(&mut self, address: u32, extension: u8) -> Result<()> {
+        let mut request = vec![SET_MTA, 0, 0, extension];
+        request.extend(self.u32_bytes(address));
+        self.command(&request, 1)?;
+        Ok(())
+    }
+
+    /// Replaces the slave's DAQ configuration with `lists`, stopping any running
+    /// measurement. Timestamps are requested if the slave supports them.
+    pub fn configure_daq(&mut self, lists: &[DaqList]) -> Result<()> {
+        let info = self.info.ok_or_else(not_connected)?;
+        let resolution = self.command(&[GET_DAQ_RESOLUTION_INFO], 8)?;
+        let mode = resolution[5];
+        let size = usize::from(mode & 0x07);
+        self.timestamp = match size {
+            1 | 2 | 4 => {
+                let ticks = u64::from(self.read_u16(&resolution[6..8]));
+                let unit = 10u64.pow(u32::from(mode >> 4).min(9));
+                Some(TimestampFormat {
+                    size,
+                    tick: ticks * unit,
+                })
+            }
+            _ => None,
+        };
+
+        // Check every ODT fits a DAQ packet before changing anything.
+        let max_dto = usize::from(info.max_dto).min(if self.fd { 64 } else { 8 });
+        for (daq, list) in lists.iter().enumerate() {
+            for (odt, entries) in list.odts.iter().enumerate() {
+                let timestamp = match odt {
+                    0 => self.timestamp.map_or(0, |format| format.size),
+                    _ => 0,
+                };
+                let size: usize = entries.iter().map(|entry| usize::from(entry.size)).sum();
+                if 1 + timestamp + size > max_dto || entries.iter().any(|e| e.size > 8) {
+                    return Err(Error::InvalidArgument(format!(
+                        "ODT {odt} of DAQ list {daq} doesn't fit a DAQ packet of {max_dto} bytes"
+                    )));
+                }
+            }
+        }
+
+        self.lists.clear();
+        self.command(&[FREE_DAQ], 1)?;
+        let mut request = vec![ALLOC_DAQ, 0];
+        request.extend(self.u16_bytes(lists.len() as u16));
+        self.command(&request, 1)?;
+        for (daq, list) in lists.iter().enumerate() {
+            let mut request = vec![ALLOC_ODT, 0];
+            request.extend(self.u16_bytes(daq as u16));
+            request.push(list.odts.len() as u8);
+            self.command(&request, 1)?;
+        }
+        for (daq, list) in lists.iter().enumerate() {
+            for (odt, entries) in list.odts.iter().enumerate() {
+                let mut request = vec![ALLOC_ODT_ENTRY, 0];
+                request.extend(self.u16_bytes(daq as u16));
+                request.extend([odt as u8, entries.len() as u8]);
+                self.command(&request, 1)?;
+            }
+        }
+        for (daq, list) in lists.iter().enumerate() {
+            for (odt, entries) in list.odts.iter().enumerate() {
+                let mut request = vec![SET_DAQ_PTR, 0];
+                request.extend(self.u16_bytes(daq as u16));
+                request.extend([odt as u8, 0]);
+                self.command(&request, 1)?;
+                // The pointer moves to the next entry after each write.
+                for entry in entries {
+                    let mut request = vec![WRITE_DAQ, 0xff, entry.size, entry.extension];
+                    request.extend(self.u32_bytes(entry.address));
+                    self.command(&request, 1)?;
+                }
+            }
+            let mode = match self.timestamp {
+                Some(_) => DAQ_LIST_MODE_TIMESTAMP,
+                None => 0,
+            };
+            let mut request = vec![SET_DAQ_LIST_MODE, mode];
+            request.extend(self.u16_bytes(daq as u16));
+            request.extend(self.u16_bytes(list.event));
+            request.extend([list.prescaler, list.priority]);
+            self.command(&request, 1)?;
+        }
+        self.lists = lists
+            .iter()
+            .map(|list| ActiveList {
+                list: list.clone(),
+                first_pid: None,
+                last_timestamp: None,
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Starts all configured DAQ lists at once.
+    pub fn start_daq(&mut self) -> Result<()> {
+        for daq in 0..self.lists.len() {
+            let mut request = vec![START_STOP_DAQ_LIST, 0x02];
+            request.extend(self.u16_bytes(daq as u16));
+            let response = self.command(&request, 2)?;
+            self.lists[daq].first_pid = Some(response[1]);
+            self.lists[daq].last_timestamp = None;
+        }
+        self.command(&[START_STOP_SYNCH, 0x01], 1)?;
+        Ok(())
+    }
+
+    /// Stops all DAQ lists.
+    pub fn stop_daq(&mut self) -> Result<()> {
+        self.command(&[START_STOP_SYNCH, 0x00], 1)?;
+        Ok(())
+    }
+
+    /// Returns the DAQ packets received since the last call. Waits up to `timeout` if there
+    /// are none yet, returning an empty list if none arrive. Packets of unknown ODTs are
+    /// dropped.
+    pub fn receive_daq(&mut self, timeout: Duration) -> Result<Vec<DaqSample>> {
+        if self.packets.is_empty() {
+            let deadline = self.clock.now() + timeout;
+            if let Some(packet) = self.next_packet(deadline)? {
+                self.packets.push_back(packet);
+            }
+        }
+        // Take what else arrived without waiting.
+        while let Some(packet) = self.next_packet(Duration::ZERO)? {
+            self.packets.push_back(packet);
+        }
+        let packets: Vec<_> = self.packets.drain(..).collect();
+        Ok(packets
+            .into_iter()
+            .filter_map(|(packet, received)| self.decode_daq(&packet, received))
+            .collect())
+    }
+
+    fn decode_daq(&mut self, packet: &[u8], received: u64) -> Option<DaqSample> {
+        let pid = packet[0];
+        let big_endian = self.info?.is_big_endian();
+        let format = self.timestamp;
+        let (daq, list) = self.lists.iter_mut().enumerate().find(|(_, list)| {
+            list.first_pid.is_some_and(|first| {
+                (first..first.saturating_add(list.list.odts.len() as u8)).contains(&pid)
+            })
+        })?;
+        let odt = pid - list.first_pid?;
+        let mut data = &packet[1..];
+        if let (0, Some(format)) = (odt, format) {
+            let raw = read_uint(data.get(..format.size)?, big_endian);
+            data = &data[format.size..];
+            let (last, offset) = list.last_timestamp.unwrap_or((raw, 0));
+            let offset = match raw < last {
+                true => offset + (1 << (8 * format.size)),
+                false => offset,
+            };
+            list.last_timestamp = Some((raw, offset));
+        }
+        let timestamp = list
+            .last_timestamp
+            .zip(format)
+            .map(|((raw, offset), format)| Duration::from_nanos(format.tick * (raw + offset)));
+        let mut values = Vec::new();
+        for entry in list.list.odts.get(usize::from(odt))? {
+            let size = usize::from(entry.size);
+            values.push(read_uint(data.get(..size)?, big_endian));
+            data = &data[size..];
+        }
+        Some(DaqSample {
+            daq: daq as u16,
+            odt,
+            timestamp,
+            received,
+            values,
+        })
+    }
+
+    /// Sends a command and returns the positive response, which has to be at least
+    /// `length` bytes long. DAQ packets received meanwhile are kept.
+    fn command(&mut self, request: &[u8], length: usize) -> Result<Vec<u8>> {
+        if let Some(info) = self.info {
+            if request.len() > usize::from(info.max_cto) {
+                return Err(Error::InvalidArgument(format!(
+                    "XCP command of {} bytes exceeds MAX_CTO {}",
+                    request.len(),
+                    info.max_cto
+                )));
+            }
+        }
+        self.transmit_frame(request)?;
+        let deadline = self.clock.now() + self.timeout;
+        loop {
+            let Some((response, received)) = self.next_packet(deadline)? else {
+                return Err(Error::Timeout(format!(
+                    "No response to XCP command {:#04x}",
+                    request[0]
+                )));
+            };
+            match response[0] {
+                PID_RESPONSE if response.len() >= length => return Ok(response),
+                PID_RESPONSE => {
+                    return Err(Error::ProtocolError(format!(
+                        "XCP response {response:02x?} too short"
+                    )))
+                }
+                PID_ERROR => {
+                    let code = response.get(1).copied().unwrap_or(0);
+                    return Err(Error::NegativeResponse(request[0], code));
+                }
+                pid if pid <= PID_DAQ_MAX => self.packets.push_back((response, received)),
+                // Events and service requests.
+                _ => {}
+            }
+        }
+    }
+
+    fn max_cto(&self) -> Result<usize> {
+        Ok(self.info.ok_or_else(not_connected)?.max_cto.into())
+    }
+
+    fn read_u16(&self, bytes: &[u8]) -> u16 {
+        read_uint(bytes, self.info.is_some_and(|info| info.is_big_endian())) as u16
+    }
+
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        match self.info.is_some_and(|info| info.is_big_endian()) {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        }
+    }
+
+    fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        match self.info.is_some_and(|info| info.is_big_endian()) {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        }
+    }
+
+    /// Returns the next packet from the slave and its bus timestamp, or `None` at
+    /// `deadline`.
+    fn next_packet(&mut self, deadline: Duration) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                let Message::Can(m) = message else {
+                    continue;
+                };
+                if m.netid == self.netid
+                    && m.arbid == self.rx_id
+                    && m.is_extended() == (self.rx_id > 0x7ff)
+                    && !m.is_transmit()
+                    && !m.is_remote()
+                    && !m.is_error_frame()
+                    && !m.data().is_empty()
+                {
+                    return Ok(Some((m.data().to_vec(), m.timestamp)));
+                }
+            }
+            // Checks the bus once even if the deadline passed.
+            let now = self.clock.now();
+            let messages = self.bus.receive(deadline.saturating_sub(now))?;
+            if messages.is_empty() && now >= deadline {
+                return Ok(None);
+            }
+            self.pending.extend(messages);
+        }
+    }
+
+    fn transmit_frame(&mut self, data: &[u8]) -> Result<()> {
+        let mut data = data.to_vec();
+        // Padded to 8 bytes, which many slaves require.
+        let length = dlc_to_len(len_to_dlc(data.len().max(8)));
+        data.resize(length, 0);
+        let mut message = CanMessage::new(self.netid, self.tx_id, &data);
+        message.set_extended(self.tx_id > 0x7ff);
+        message.set_fd(self.fd);
+        message.set_brs(self.fd);
+        message.set_dlc_on_wire(len_to_dlc(length));
+        self.bus.transmit(&Message::Can(message))
+    }
+}
+
+fn not_connected() -> Error {
+    Error::InvalidArgument("Not connected to an XCP slave".to_string())
+}
+
+fn read_uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let fold = |value: u64, byte: &u8| value << 8 | u64::from(*byte);
+    match big_endian {
+        true => bytes.iter().fold(0, fold),
+        false => bytes.iter().rev().fold(0, fold),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{VirtualBus, VirtualEndpoint};
+    use std::thread::JoinHandle;
+
+    const BASE: u32 = 0x1000;
+
+    /// A little endian slave with 256 bytes of memory at `BASE` and 2 byte DAQ timestamps of
+    /// 10 us, answering until nothing is received for a while. Once DAQ is started it sends
+    /// three samples of the configured lists with timestamps wrapping the counter. Returns
+    /// the final memory.
+    fn slave(bus: &VirtualBus) -> JoinHandle<Vec<u8>> {
+        let mut endpoint = bus.endpoint();
+        std::thread::spawn(move || {
+            let mut memory: Vec<u8> = (0..=255).collect();
+            let mut mta = 0;
+            // ODT entries of each DAQ list and the DAQ pointer.
+            let mut lists: Vec<Vec<Vec<(u32, u8)>>> = Vec::new();
+            let mut pointer = (0, 0);
+            let send = |endpoint: &mut VirtualEndpoint, data: &[u8]| {
+                let frame = CanMessage::new(1, 0x7f1, data);
+                endpoint.transmit(&Message::Can(frame)).unwrap();
+            };
+            let address = |data: &[u8]| u32::from_le_bytes(data[4..8].try_into().unwrap());
+            loop {
+                let messages = endpoint.receive(Duration::from_millis(300)).unwrap();
+                if messages.is_empty() {
+                    return memory;
+                }
+                for message in messages {
+                    let Message::Can(m) = message else { continue };
+                    if m.arbid != 0x7f0 {
+                        continue;
+                    }
+                    let data = m.data();
+                    let index = |address: u32| (address - BASE) as usize;
+                    let response = match data[0] {
+                        CONNECT => vec![0xff, 0x15, 0x00, 8, 8, 0, 1, 1],
+                        GET_STATUS => vec![0xff, 0x00, 0x01, 0, 0x34, 0x12],
+                        SHORT_UPLOAD => {
+                            let start = index(address(data));
+                            let mut response = vec![0xff];
+                            response.extend(&memory[start..start + usize::from(data[1])]);
+                            response
+                        }
+                        SET_MTA => {
+                            mta = index(address(data));
+                            vec![0xff]
+                        }
+                        DOWNLOAD => {
+                            let size = usize::from(data[1]);
+                            memory[mta..mta + size].copy_from_slice(&data[2..2 + size]);
+                            mta += size;
+                            vec![0xff]
+                        }
+                        GET_DAQ_RESOLUTION_INFO => vec![0xff, 1, 8, 1, 8, 0x32, 10, 0],
+                        FREE_DAQ => {
+                            lists.clear();
+                            vec![0xff]
+                        }
+                        ALLOC_DAQ => {
+                            lists.resize(usize::from(data[2]), Vec::new());
+                            vec![0xff]
+                        }
+                        ALLOC_ODT => {
+                            lists[usize::from(data[2])].resize(usize::from(data[4]), Vec::new());
+                            vec![0xff]
+                        }
+                        ALLOC_ODT_ENTRY | SET_DAQ_LIST_MODE => vec![0xff],
+                        // Select, which returns the PID of the first ODT.
+                        START_STOP_DAQ_LIST => {
+                            let first = lists[..usize::from(data[2])]
+                                .iter()
+                                .map(|list| list.len() as u8)
+                                .sum();
+                            vec![0xff, first]
+                        }
+                        SET_DAQ_PTR => {
+                            pointer = (usize::from(data[2]), usize::from(data[4]));
+                            vec![0xff]
+                        }
+                        WRITE_DAQ => {
+                            lists[pointer.0][pointer.1].push((address(data), data[2]));
+                            vec![0xff]
+                        }
+                        START_STOP_SYNCH if data[1] == 0x01 => {
+                            send(&mut endpoint, &[0xff]);
+                            let mut pid = 0;
+                            for (daq, list) in lists.iter().enumerate() {
+                                for timestamp in [0xfff0u16, 0xfffe, 0x0004] {
+                                    for (odt, entries) in list.iter().enumerate() {
+                                        let mut packet = vec![pid + odt as u8];
+                                        if odt == 0 {
+                                            packet.extend(timestamp.to_le_bytes());
+                                        }
+                                        for &(address, size) in entries {
+                                            let start = index(address);
+                                            packet
+                                                .extend(&memory[start..start + usize::from(size)]);
+                                        }
+                                        send(&mut endpoint, &packet);
+                                    }
+                                }
+                                pid += lists[daq].len() as u8;
+                            }
+                            continue;
+                        }
+                        START_STOP_SYNCH => vec![0xff],
+                        // ERR_CMD_UNKNOWN
+                        _ => vec![0xfe, 0x20],
+                    };
+                    send(&mut endpoint, &response);
+                }
+            }
+        })
+    }
+
+    /// Commands sent by the master, without padding.
+    fn commands(sniffed: &[Message]) -> Vec<u8> {
+        sniffed
+            .iter()
+            .filter_map(|message| match message {
+                Message::Can(m) if m.arbid == 0x7f0 => Some(m.data()[0]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_read_uint() {
+        assert_eq!(read_uint(&[0x34, 0x12], false), 0x1234);
+        assert_eq!(read_uint(&[0x12, 0x34], true), 0x1234);
+        assert_eq!(read_uint(&[0xff], true), 0xff);
+        assert_eq!(read_uint(&[], false), 0);
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let bus = VirtualBus::new();
+        let slave = slave(&bus);
+        let mut sniffer = bus.endpoint();
+        let mut master = XcpMaster::new(bus.endpoint(), NetworkId::HSCAN, 0x7f0, 0x7f1);
+
+        assert!(matches!(
+            master.short_upload(BASE, 0, 4),
+            Err(Error::InvalidArgument(_))
+        ));
+        let info = master.connect().unwrap();
+        assert_eq!(info.max_cto, 8);
+        assert_eq!(info.max_dto, 8);
+        assert!(!info.is_big_endian());
+        assert_eq!(info.resource & RESOURCE_DAQ, RESOURCE_DAQ);
+        assert_eq!(
+            master.get_status().unwrap(),
+            Status {
+                session_status: 0,
+                protection_status: 1,
+                session_configuration_id: 0x1234,
+            }
+        );
+
+        assert_eq!(master.short_upload(BASE + 4, 0, 4).unwrap(), [4, 5, 6, 7]);
+        assert!(matches!(
+            master.short_upload(BASE, 0, 8),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(
+            master.upload(BASE + 10, 0, 20).unwrap(),
+            (10..30).collect::<Vec<u8>>()
+        );
+        assert!(matches!(
+            master.upload(u32::MAX - 3, 0, 5),
+            Err(Error::InvalidArgument(_))
+        ));
+        master.download(BASE + 100, 0, &[0xaa; 13]).unwrap();
+        assert_eq!(
+            master.upload(BASE + 99, 0, 15).unwrap()[..3],
+            [99, 0xaa, 0xaa]
+        );
+
+        let sniffed = sniffer.receive(Duration::ZERO).unwrap();
+        // Commands are padded to 8 bytes.
+        assert!(sniffed.iter().all(
+            |message| matches!(message, Message::Can(m) if m.arbid != 0x7f0 || m.data().len() == 8)
+        ));
+        // SET_MTA then DOWNLOADs of 6 bytes.
+        let commands = commands(&sniffed);
+        let download = commands.iter().position(|&c| c == SET_MTA).unwrap();
+        assert_eq!(
+            commands[download..download + 4],
+            [SET_MTA, DOWNLOAD, DOWNLOAD, DOWNLOAD]
+        );
+
+        master.disconnect().unwrap_err();
+        let memory = slave.join().unwrap();
+        assert_eq!(memory[100..113], [0xaa; 13]);
+        assert_eq!(memory[113], 113);
+    }
+
+    #[test]
+    fn test_negative_response_and_timeout() {
+        let bus = VirtualBus::new();
+        let _slave = slave(&bus);
+        let mut master = XcpMaster::new(bus.endpoint(), NetworkId::HSCAN, 0x7f0, 0x7f1);
+        master.connect().unwrap();
+        assert!(matches!(
+            master.disconnect(),
+            Err(Error::NegativeResponse(DISCONNECT, 0x20))
+        ));
+
+        let mut master = XcpMaster::new(bus.endpoint(), NetworkId::HSCAN, 0x7e0, 0x7e1);
+        master.set_timeout(Duration::from_millis(20));
+        assert!(matches!(master.connect(), Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn test_invalid_max_cto() {
+        let bus = VirtualBus::new();
+        let mut endpoint = bus.endpoint();
+        let slave = std::thread::spawn(move || {
+            endpoint.receive(Duration::from_secs(1)).unwrap();
+            let frame = CanMessage::new(1, 0x7f1, &[0xff, 0x15, 0x00, 1, 8, 0, 1, 1]);
+            endpoint.transmit(&Message::Can(frame)).unwrap();
+        });
+        let mut master = XcpMaster::new(bus.endpoint(), NetworkId::HSCAN, 0x7f0, 0x7f1);
+        assert!(matches!(master.connect(), Err(Error::ProtocolError(_))));
+        assert!(master.connect_info().is_none());
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_daq() {
+        let bus = VirtualBus::new();
+        let _slave = slave(&bus);
+        let mut master = XcpMaster::new(bus.endpoint(), NetworkId::HSCAN, 0x7f0, 0x7f1);
+        master.connect().unwrap();
+
+        let entry = |address, size| OdtEntry {
+            address: BASE + address,
+            extension: 0,
+            size,
+        };
+        let lists = [
+            DaqList {
+                event: 0,
+                prescaler: 1,
+                priority: 0,
+                odts: vec![vec![entry(0x10, 2), entry(0x20, 1)], vec![entry(0x30, 4)]],
+            },
+            DaqList {
+                event: 1,
+                prescaler: 1,
+                priority: 0,
+                odts: vec![vec![entry(0x40, 4)]],
+            },
+        ];
+        // 1 PID, 2 timestamp and 6 data bytes exceed 8.
+        let too_long = DaqList {
+            odts: vec![vec![entry(0, 6)]],
+            ..lists[1].clone()
+        };
+        assert!(matches!(
+            master.configure_daq(&[too_long]),
+            Err(Error::InvalidArgument(_))
+        ));
+        master.configure_daq(&lists).unwrap();
+        master.start_daq().unwrap();
+
+        let mut samples = Vec::new();
+        while samples.len() < 9 {
+            let received = master.receive_daq(Duration::from_millis(200)).unwrap();
+            assert!(!received.is_empty());
+            samples.extend(received);
+        }
+        master.stop_daq().unwrap();
+
+        let us = Duration::from_micros;
+        let first: Vec<_> = samples.iter().filter(|s| s.daq == 0).collect();
+        assert_eq!(first.len(), 6);
+        assert_eq!((first[0].odt, first[1].odt), (0, 1));
+        assert_eq!(first[0].values, [0x1110, 0x20]);
+        assert_eq!(first[1].values, [0x3332_3130]);
+        // The second ODT has the timestamp of the first.
+        assert_eq!(first[0].timestamp, Some(us(0xfff0 * 10)));
+        assert_eq!(first[1].timestamp, Some(us(0xfff0 * 10)));
+        assert_eq!(first[2].timestamp, Some(us(0xfffe * 10)));
+        assert_eq!(first[4].timestamp, Some(us(0x1_0004 * 10)));
+
+        let second: Vec<_> = samples.iter().filter(|s| s.daq == 1).collect();
+        assert_eq!(second.len(), 3);
+        assert_eq!(second[0].values, [0x4342_4140]);
+        assert_eq!(second[2].timestamp, Some(us(0x1_0004 * 10)));
+    }
+}